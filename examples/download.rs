@@ -1,7 +1,5 @@
-use std::time::Duration;
-
 use aws_config::BehaviorVersion;
-use rcs3ud::{DownloadInput, DownloadStrategy, S3Src, download};
+use rcs3ud::{DownloadDest, DownloadInput, DownloadStrategy, S3Src, download};
 use sipper::Sipper;
 use tokio::fs::File;
 
@@ -22,11 +20,21 @@ async fn main() {
             bucket: "rcs3ud",
             object_key: "README.md",
         },
-        dest: &mut dest,
+        dest: DownloadDest::File(&mut dest),
         strategy: DownloadStrategy::Warm,
-        retry_interval: Duration::from_secs(5),
+        customer_key: None,
+        client_side_key: None,
+        decompress: false,
+        capabilities: Default::default(),
+        retry_policy: Default::default(),
         saved_progress: Default::default(),
+        progress_store: None,
         amount_limiter: None,
+        progress_interval: None,
+        save_policy: Default::default(),
+        split_range_after: None,
+        missing_content_length: Default::default(),
+        control: Default::default(),
     })
     .await
     .pin();
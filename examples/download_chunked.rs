@@ -0,0 +1,56 @@
+use std::num::NonZero;
+
+use aws_config::BehaviorVersion;
+use rcs3ud::{
+    DownloadDest, DownloadInput, DownloadStrategy, FileProgressStore, S3Src, download_chunked,
+};
+use sipper::Sipper;
+use tokio::fs::File;
+
+#[tokio::main]
+async fn main() {
+    let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+    let client = aws_sdk_s3::Client::new(&config);
+    // Don't truncate, so that an interrupted download can be resumed
+    let mut dest = File::options()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open("Downloaded README.md")
+        .await
+        .unwrap();
+    let mut straw = download_chunked(
+        DownloadInput {
+            client: &client,
+            src: S3Src {
+                bucket: "rcs3ud",
+                object_key: "README.md",
+            },
+            dest: DownloadDest::File(&mut dest),
+            strategy: DownloadStrategy::Warm,
+            customer_key: None,
+            client_side_key: None,
+            decompress: false,
+            capabilities: Default::default(),
+            retry_policy: Default::default(),
+            saved_progress: Default::default(),
+            progress_store: Some(Box::new(FileProgressStore::new(
+                "download_chunked_progress.ron",
+            ))),
+            amount_limiter: None,
+            progress_interval: None,
+            save_policy: Default::default(),
+            split_range_after: None,
+            missing_content_length: Default::default(),
+            control: Default::default(),
+        },
+        NonZero::new(1000).unwrap(),
+    )
+    .await
+    .pin();
+    while let Some(event) = straw.sip().await {
+        println!("{event:#?}");
+    }
+    straw.await.unwrap();
+    println!("Downloaded successfully.");
+}
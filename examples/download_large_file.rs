@@ -0,0 +1,72 @@
+use std::io::ErrorKind;
+
+use aws_config::BehaviorVersion;
+use rcs3ud::{
+    DownloadChunkedObjectsEvent, DownloadChunkedObjectsInput, DownloadChunkedObjectsProgress,
+    DownloadStrategy, S3Src, download_chunked_objects,
+};
+use sipper::Sipper;
+use tokio::{
+    fs::{File, remove_file},
+    io::{AsyncReadExt, AsyncWriteExt},
+};
+
+/// Downloads the chunks uploaded by the `upload_large_file` example
+#[tokio::main]
+async fn main() {
+    let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+    let client = aws_sdk_s3::Client::new(&config);
+    let progress_file = "download_large_file_progress.ron";
+    let mut dest = File::options()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open("Downloaded README.md")
+        .await
+        .unwrap();
+    let mut straw = download_chunked_objects(DownloadChunkedObjectsInput {
+        client: &client,
+        src: S3Src {
+            bucket: "rcs3ud",
+            object_key: "README.md",
+        },
+        dest: &mut dest,
+        strategy: DownloadStrategy::Warm,
+        client_side_key: None,
+        retry_policy: Default::default(),
+        progress: {
+            match File::options().read(true).open(progress_file).await {
+                Ok(mut file) => {
+                    let mut s = String::new();
+                    file.read_to_string(&mut s).await.unwrap();
+                    ron::from_str::<DownloadChunkedObjectsProgress>(&s).unwrap()
+                }
+                Err(e) => match e.kind() {
+                    ErrorKind::NotFound => Default::default(),
+                    _ => panic!("{e:#?}"),
+                },
+            }
+        },
+        amount_limiter: None,
+        progress_interval: None,
+    })
+    .pin();
+    while let Some(event) = straw.sip().await {
+        println!("{event:#?}");
+        if let DownloadChunkedObjectsEvent::SaveProgress(saved_progress) = event {
+            File::options()
+                .create(true)
+                .truncate(true)
+                .write(true)
+                .open(progress_file)
+                .await
+                .unwrap()
+                .write_all(ron::to_string(&saved_progress).unwrap().as_bytes())
+                .await
+                .unwrap();
+        }
+    }
+    straw.await.unwrap();
+    println!("Downloaded successfully.");
+    remove_file(progress_file).await.unwrap();
+}
@@ -1,7 +1,7 @@
-use std::time::Duration;
-
 use aws_config::BehaviorVersion;
-use rcs3ud::{DownloadInput, DownloadStrategy, FileBackedAmountLimiter, S3Src, download};
+use rcs3ud::{
+    DownloadDest, DownloadInput, DownloadStrategy, FileBackedAmountLimiter, S3Src, download,
+};
 use sipper::Sipper;
 use tokio::fs::File;
 
@@ -22,15 +22,25 @@ async fn main() {
             bucket: "rcs3ud",
             object_key: "README.md",
         },
-        dest: &mut dest,
+        dest: DownloadDest::File(&mut dest),
         strategy: DownloadStrategy::Warm,
-        retry_interval: Duration::from_secs(5),
+        customer_key: None,
+        client_side_key: None,
+        decompress: false,
+        capabilities: Default::default(),
+        retry_policy: Default::default(),
         saved_progress: Default::default(),
+        progress_store: None,
         amount_limiter: Some(Box::new(FileBackedAmountLimiter::new(
             "internet_usage.ron".into(),
             2000,
             "Example: Download README.md".into(),
         ))),
+        progress_interval: None,
+        save_policy: Default::default(),
+        split_range_after: None,
+        missing_content_length: Default::default(),
+        control: Default::default(),
     })
     .await
     .pin();
@@ -0,0 +1,36 @@
+use std::{num::NonZeroU16, time::Duration};
+
+use aws_config::BehaviorVersion;
+use aws_sdk_s3::types::Tier;
+use rcs3ud::{RestoreInput, S3Src, WaitForRestoreStrategy, wait_until_restored};
+use sipper::Sipper;
+
+#[tokio::main]
+async fn main() {
+    let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+    let client = aws_sdk_s3::Client::new(&config);
+    let mut straw = wait_until_restored(
+        RestoreInput {
+            client: &client,
+            src: S3Src {
+                bucket: "rcs3ud",
+                object_key: "Cold README.md",
+            },
+            tier: Tier::Bulk,
+            adjust_incompatible_tier: false,
+            days: NonZeroU16::MIN,
+            wait_for_restore_strategy: WaitForRestoreStrategy::PollGet(Duration::from_secs(
+                // 30 minutes
+                60 * 30,
+            )),
+            retry_policy: Default::default(),
+        },
+        Default::default(),
+    )
+    .pin();
+    while let Some(event) = straw.sip().await {
+        println!("{event:#?}");
+    }
+    straw.await.unwrap();
+    println!("Restored successfully.");
+}
@@ -0,0 +1,42 @@
+use std::{num::NonZeroU16, time::Duration};
+
+use aws_config::BehaviorVersion;
+use aws_sdk_s3::types::Tier;
+use rcs3ud::{DownloadColdInput, SyncDownInput, WaitForRestoreStrategy, sync_down};
+use sipper::Sipper;
+
+#[tokio::main]
+async fn main() {
+    let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+    let client = aws_sdk_s3::Client::new(&config);
+    let mut straw = sync_down(SyncDownInput {
+        client: &client,
+        bucket: "rcs3ud",
+        prefix: "examples/",
+        dest: "Downloaded examples".into(),
+        cold: DownloadColdInput {
+            tier: Tier::Bulk,
+            adjust_incompatible_tier: true,
+            restore_days: NonZeroU16::MIN,
+            wait_for_restore_stratey: WaitForRestoreStrategy::PollGet(Duration::from_secs(
+                // 30 minutes
+                60 * 30,
+            )),
+        },
+        retry_policy: Default::default(),
+        amount_limiter: None,
+        progress: Default::default(),
+        progress_interval: Some(Duration::from_secs(1)),
+        control: Default::default(),
+    })
+    .pin();
+    while let Some(event) = straw.sip().await {
+        println!("{event:#?}");
+    }
+    let output = straw.await.unwrap();
+    println!(
+        "Downloaded {} files, {} were already up to date.",
+        output.downloaded.len(),
+        output.up_to_date.len()
+    );
+}
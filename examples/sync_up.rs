@@ -0,0 +1,37 @@
+use aws_config::BehaviorVersion;
+use aws_sdk_s3::types::StorageClass;
+use rcs3ud::{AnyTime, SyncUpInput, UnlimitedAmountLimiter, sync_up};
+use sipper::Sipper;
+
+#[tokio::main]
+async fn main() {
+    let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+    let client = aws_sdk_s3::Client::new(&config);
+    let mut straw = sync_up(SyncUpInput {
+        client: &client,
+        src: "examples".into(),
+        bucket: "rcs3ud",
+        prefix: "examples/",
+        storage_class: StorageClass::Standard,
+        encryption: Default::default(),
+        retry_policy: Default::default(),
+        operation_scheduler: Box::new(AnyTime),
+        amount_limiter: Box::new(UnlimitedAmountLimiter),
+        compare: Default::default(),
+        skip_touched: None,
+        mirror: None,
+        find_duplicates: None,
+        progress: Default::default(),
+        control: Default::default(),
+    })
+    .pin();
+    while let Some(event) = straw.sip().await {
+        println!("{event:#?}");
+    }
+    let output = straw.await.unwrap();
+    println!(
+        "Uploaded {} files, {} were already up to date.",
+        output.uploaded.len(),
+        output.up_to_date.len()
+    );
+}
@@ -1,5 +1,3 @@
-use std::time::Duration;
-
 use aws_config::BehaviorVersion;
 use aws_sdk_s3::types::StorageClass;
 use rcs3ud::{AnyTime, S3Dest, UnlimitedAmountLimiter, UploadInput, upload, upload_file};
@@ -16,11 +14,21 @@ async fn main() {
             bucket: "rcs3ud",
             object_key: "README.md",
             storage_class: StorageClass::DeepArchive,
+            encryption: Default::default(),
         },
-        retry_interval: Duration::from_secs(5),
+        retry_policy: Default::default(),
         operation_scheduler: Box::new(AnyTime),
         amount_limiter: Box::new(UnlimitedAmountLimiter),
         tagging: Default::default(),
+        key_suffix: Default::default(),
+        checksum: None,
+        multipart_threshold: None,
+        client_side_encryption: None,
+        compression: None,
+        metadata: Default::default(),
+        storage_class_check: None,
+        capabilities: Default::default(),
+        control: Default::default(),
     })
     .pin();
     while let Some(event) = straw.sip().await {
@@ -1,5 +1,3 @@
-use std::time::Duration;
-
 use aws_config::BehaviorVersion;
 use aws_sdk_s3::types::StorageClass;
 use rcs3ud::{AnyTime, FileBackedAmountLimiter, S3Dest, UploadInput, upload, upload_file};
@@ -16,8 +14,9 @@ async fn main() {
             bucket: "rcs3ud",
             object_key: "README.md",
             storage_class: StorageClass::Standard,
+            encryption: Default::default(),
         },
-        retry_interval: Duration::from_secs(5),
+        retry_policy: Default::default(),
         operation_scheduler: Box::new(AnyTime),
         amount_limiter: Box::new(FileBackedAmountLimiter::new(
             "internet_usage.ron".into(),
@@ -25,6 +24,15 @@ async fn main() {
             "Example: Upload README.md".into(),
         )),
         tagging: Default::default(),
+        key_suffix: Default::default(),
+        checksum: None,
+        multipart_threshold: None,
+        client_side_encryption: None,
+        compression: None,
+        metadata: Default::default(),
+        storage_class_check: None,
+        capabilities: Default::default(),
+        control: Default::default(),
     })
     .pin();
     while let Some(event) = straw.sip().await {
@@ -0,0 +1,57 @@
+use aws_config::{BehaviorVersion, Region};
+use aws_sdk_s3::types::StorageClass;
+use rcs3ud::{
+    AnyTime, ChecksumAlgorithm, S3Dest, UnlimitedAmountLimiter, UploadMultiDest, UploadMultiInput,
+    upload_file, upload_multi,
+};
+use sipper::Sipper;
+
+#[tokio::main]
+async fn main() {
+    let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+    let client = aws_sdk_s3::Client::new(&config);
+    let dr_config = aws_config::defaults(BehaviorVersion::latest())
+        .region(Region::from_static("eu-west-1"))
+        .load()
+        .await;
+    let dr_client = aws_sdk_s3::Client::new(&dr_config);
+    let mut straw = upload_multi(UploadMultiInput {
+        src: upload_file("README.md".into()).await.unwrap(),
+        dests: vec![
+            UploadMultiDest {
+                client: &client,
+                dest: S3Dest {
+                    bucket: "rcs3ud",
+                    object_key: "README.md",
+                    storage_class: StorageClass::Standard,
+                    encryption: Default::default(),
+                },
+            },
+            UploadMultiDest {
+                client: &dr_client,
+                dest: S3Dest {
+                    bucket: "rcs3ud-dr",
+                    object_key: "README.md",
+                    storage_class: StorageClass::DeepArchive,
+                    encryption: Default::default(),
+                },
+            },
+        ],
+        retry_policy: Default::default(),
+        operation_scheduler: Box::new(AnyTime),
+        amount_limiter: Box::new(UnlimitedAmountLimiter),
+        tagging: Default::default(),
+        metadata: Default::default(),
+        key_suffix: Default::default(),
+        checksum: Some(ChecksumAlgorithm::Sha256),
+        multipart_threshold: None,
+        progress: Default::default(),
+        control: Default::default(),
+    })
+    .pin();
+    while let Some(event) = straw.sip().await {
+        println!("{event:#?}");
+    }
+    straw.await.unwrap();
+    println!("Uploaded successfully.");
+}
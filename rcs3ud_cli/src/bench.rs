@@ -0,0 +1,331 @@
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt, io,
+    num::NonZero,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use aws_sdk_s3::{
+    error::{DisplayErrorContext, SdkError},
+    operation::delete_object::DeleteObjectError,
+    types::StorageClass,
+};
+use rcs3ud::{
+    AnyTime, ChunkLayout, DownloadChunkedObjectsError, DownloadChunkedObjectsInput,
+    DownloadChunkedObjectsProgress, DownloadStrategy, FileProgressStore, HumanBytes, HumanRate,
+    ProgressStore, ProgressStoreError, RetryPolicy, S3Dest, S3Src, UnlimitedAmountLimiter,
+    UploadChunkedError, UploadChunkedInput, download_chunked_objects,
+    serde::{Deserialize, Serialize},
+    upload_chunked,
+};
+use sipper::Sipper;
+use tokio::fs::{File, remove_file, write};
+
+/// The best settings found by `bench`, by bucket
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(crate = "rcs3ud::serde")]
+pub struct BenchDefaults {
+    pub buckets: BTreeMap<String, BucketDefaults>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(crate = "rcs3ud::serde")]
+pub struct BucketDefaults {
+    pub chunk_size: NonZero<usize>,
+    /// How many chunks to upload at the same time. Files saved by older versions only benchmarked 1.
+    #[serde(default = "one")]
+    pub max_concurrency: NonZero<usize>,
+    /// The upload speed measured with the chunk size and concurrency, in bytes per second
+    pub speed: f64,
+    /// The speed of downloading the chunks of the chunk size, in bytes per second
+    #[serde(default)]
+    pub download_speed: Option<f64>,
+}
+
+fn one() -> NonZero<usize> {
+    NonZero::new(1).unwrap()
+}
+
+impl BenchDefaults {
+    /// Empty if the file doesn't exist yet
+    pub async fn load(path: &str) -> Result<Self, ProgressStoreError> {
+        Ok(FileProgressStore::new(path)
+            .load()
+            .await?
+            .unwrap_or_default())
+    }
+
+    /// Saved the same way as progress, so that a crash while saving doesn't lose the previous defaults
+    pub async fn save(&self, path: &str) -> Result<(), ProgressStoreError> {
+        FileProgressStore::new(path).save(self).await
+    }
+}
+
+#[derive(Debug)]
+pub enum BenchError {
+    /// Writing the generated data, or removing the temporary files
+    Io(io::Error),
+    Upload(UploadChunkedError),
+    Download(DownloadChunkedObjectsError),
+    Delete(SdkError<DeleteObjectError>),
+}
+
+impl fmt::Display for BenchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "Error writing or removing the temporary files: {e}"),
+            Self::Upload(e) => write!(f, "Error uploading: {e}"),
+            Self::Download(e) => write!(f, "Error downloading: {e}"),
+            Self::Delete(e) => write!(
+                f,
+                "Error deleting the uploaded chunks: {}",
+                DisplayErrorContext(e)
+            ),
+        }
+    }
+}
+
+impl Error for BenchError {}
+
+/// Uploads `len` bytes of generated data with each chunk size and concurrency, downloads the chunks of each chunk size once,
+/// deletes the uploaded objects, and returns the chunk size and concurrency that uploaded the fastest.
+/// The uploaded objects and the temporary files are deleted even if the benchmark fails.
+pub async fn bench(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    prefix: &str,
+    len: usize,
+    chunk_sizes: &[NonZero<usize>],
+    concurrencies: &[NonZero<usize>],
+    retry_policy: RetryPolicy,
+) -> Result<BucketDefaults, BenchError> {
+    let src = std::env::temp_dir().join(format!("rcs3ud-bench-{}", std::process::id()));
+    let dest = src.with_extension("download");
+    let result = async {
+        write(
+            &src,
+            (0..len)
+                .map(|i| (i.wrapping_mul(31) % 251) as u8)
+                .collect::<Vec<_>>(),
+        )
+        .await
+        .map_err(BenchError::Io)?;
+        let mut best: Option<BucketDefaults> = None;
+        for &chunk_size in chunk_sizes {
+            let object_key = format!("{prefix}/{chunk_size}");
+            let result = bench_chunk_size(
+                client,
+                bucket,
+                &object_key,
+                &src,
+                &dest,
+                len,
+                chunk_size,
+                concurrencies,
+                retry_policy,
+            )
+            .await;
+            // Deleted even if some chunks weren't uploaded, which S3 doesn't mind
+            let deleted = delete_chunks(client, bucket, &object_key, len, chunk_size).await;
+            let best_for_chunk_size = result?;
+            deleted?;
+            if best
+                .as_ref()
+                .is_none_or(|best| best_for_chunk_size.speed > best.speed)
+            {
+                best = Some(best_for_chunk_size);
+            }
+        }
+        Ok(best.expect("Must specify at least 1 chunk size"))
+    }
+    .await;
+    // Not uploaded or downloaded yet if an earlier step failed
+    let removed = [remove_temp_file(&src).await, remove_temp_file(&dest).await];
+    let best = result?;
+    removed.into_iter().collect::<Result<(), _>>()?;
+    Ok(best)
+}
+
+/// Uploads the data with each concurrency, and downloads it once
+#[allow(clippy::too_many_arguments)]
+async fn bench_chunk_size(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    object_key: &str,
+    src: &Path,
+    dest: &Path,
+    len: usize,
+    chunk_size: NonZero<usize>,
+    concurrencies: &[NonZero<usize>],
+    retry_policy: RetryPolicy,
+) -> Result<BucketDefaults, BenchError> {
+    let human_chunk_size = HumanBytes {
+        len: chunk_size.get(),
+        units: Default::default(),
+    };
+    let mut best_for_chunk_size: Option<BucketDefaults> = None;
+    // Every concurrency uploads the same chunks, so they overwrite each other
+    for &max_concurrency in concurrencies {
+        let speed = bench_upload(
+            client,
+            bucket,
+            object_key,
+            src.to_owned(),
+            chunk_size,
+            max_concurrency,
+            retry_policy,
+        )
+        .await?;
+        println!(
+            "Chunk size {human_chunk_size}, concurrency {max_concurrency}: {}",
+            HumanRate {
+                bytes_per_second: speed,
+                units: Default::default()
+            }
+        );
+        if best_for_chunk_size
+            .as_ref()
+            .is_none_or(|best| speed > best.speed)
+        {
+            best_for_chunk_size = Some(BucketDefaults {
+                chunk_size,
+                max_concurrency,
+                speed,
+                download_speed: None,
+            });
+        }
+    }
+    let mut best_for_chunk_size = best_for_chunk_size.expect("Must specify at least 1 concurrency");
+    let download_speed = bench_download(
+        client,
+        bucket,
+        object_key,
+        dest,
+        len,
+        chunk_size,
+        retry_policy,
+    )
+    .await?;
+    println!(
+        "Chunk size {human_chunk_size}, download: {}",
+        HumanRate {
+            bytes_per_second: download_speed,
+            units: Default::default()
+        }
+    );
+    best_for_chunk_size.download_speed = Some(download_speed);
+    Ok(best_for_chunk_size)
+}
+
+async fn delete_chunks(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    object_key: &str,
+    len: usize,
+    chunk_size: NonZero<usize>,
+) -> Result<(), BenchError> {
+    for chunk in 0..len.div_ceil(chunk_size.get()) {
+        client
+            .delete_object()
+            .bucket(bucket)
+            .key(format!("{object_key}/{chunk}"))
+            .send()
+            .await
+            .map_err(BenchError::Delete)?;
+    }
+    Ok(())
+}
+
+/// Removes a temporary file, which is fine if it wasn't created
+async fn remove_temp_file(path: &Path) -> Result<(), BenchError> {
+    match remove_file(path).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(BenchError::Io(e)),
+        _ => Ok(()),
+    }
+}
+
+async fn bench_upload(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    object_key: &str,
+    src: PathBuf,
+    chunk_size: NonZero<usize>,
+    max_concurrency: NonZero<usize>,
+    retry_policy: RetryPolicy,
+) -> Result<f64, BenchError> {
+    let len = tokio::fs::metadata(&src)
+        .await
+        .map_err(BenchError::Io)?
+        .len();
+    let start = Instant::now();
+    let mut straw = upload_chunked(UploadChunkedInput {
+        client,
+        src,
+        more_srcs: Vec::new(),
+        src_len: None,
+        dest: S3Dest {
+            bucket,
+            object_key,
+            storage_class: StorageClass::Standard,
+            encryption: Default::default(),
+        },
+        retry_policy,
+        retry_budget: Default::default(),
+        capabilities: Default::default(),
+        operation_scheduler: Box::new(AnyTime),
+        amount_limiter: Box::new(UnlimitedAmountLimiter),
+        chunk_size,
+        progress: Default::default(),
+        progress_store: None,
+        max_concurrency,
+        mode: Default::default(),
+        save_policy: Default::default(),
+        checksum: None,
+        client_side_encryption: None,
+        tree_hash: false,
+        control: Default::default(),
+    })
+    .pin();
+    while straw.sip().await.is_some() {}
+    straw.await.map_err(BenchError::Upload)?;
+    Ok(len as f64 / start.elapsed().as_secs_f64())
+}
+
+/// Downloads the chunks that [`bench_upload`] uploaded into `dest`
+async fn bench_download(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    object_key: &str,
+    dest: &Path,
+    len: usize,
+    chunk_size: NonZero<usize>,
+    retry_policy: RetryPolicy,
+) -> Result<f64, BenchError> {
+    let mut file = File::create(dest).await.map_err(BenchError::Io)?;
+    let start = Instant::now();
+    let mut straw = download_chunked_objects(DownloadChunkedObjectsInput {
+        client,
+        src: S3Src { bucket, object_key },
+        dest: &mut file,
+        strategy: DownloadStrategy::Warm,
+        client_side_key: None,
+        retry_policy,
+        // The layout is known, so the tags don't need to be read
+        progress: DownloadChunkedObjectsProgress {
+            layout: Some(ChunkLayout {
+                len,
+                chunk_size,
+                chunks_count: len.div_ceil(chunk_size.get()),
+            }),
+            ..Default::default()
+        },
+        amount_limiter: None,
+        progress_interval: None,
+    })
+    .pin();
+    while straw.sip().await.is_some() {}
+    straw.await.map_err(BenchError::Download)?;
+    Ok(len as f64 / start.elapsed().as_secs_f64())
+}
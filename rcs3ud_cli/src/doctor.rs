@@ -0,0 +1,167 @@
+use std::{fmt, num::NonZero, path::Path, time::Duration};
+
+use aws_sdk_s3::error::DisplayErrorContext;
+use rcs3ud::{
+    FileBackedAmountLimiter, FileProgressStore, HumanBytes, MAX_CLOCK_SKEW, ProgressStore,
+    UploadChunkedProgress, measure_clock_skew, time::UtcDateTime,
+};
+
+use crate::bench::BenchDefaults;
+
+/// Something that `doctor` checked
+pub enum Finding {
+    Ok(String),
+    Problem { what: String, fix: String },
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ok(what) => write!(f, "ok: {what}"),
+            Self::Problem { what, fix } => write!(f, "problem: {what}\n  fix: {fix}"),
+        }
+    }
+}
+
+pub struct DoctorInput<'a> {
+    pub client: &'a aws_sdk_s3::Client,
+    pub bucket: Option<&'a str>,
+    pub amount_limiter_file: Option<&'a str>,
+    /// Required with `amount_limiter_file`
+    pub amount_limit: Option<NonZero<usize>>,
+    pub progress_files: &'a [String],
+    pub bench_file: Option<&'a str>,
+    /// Queue items older than this are probably from a process that crashed
+    pub stale_after: Duration,
+}
+
+/// Checks the state files and the bucket, so that a stuck or failing upload can be explained
+pub async fn doctor(input: DoctorInput<'_>) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    if let Some(bucket) = input.bucket {
+        findings.push(
+            match input.client.head_bucket().bucket(bucket).send().await {
+                Ok(_) => Finding::Ok(format!("Can access bucket {bucket}")),
+                Err(e) => Finding::Problem {
+                    what: format!("Can't access bucket {bucket}: {}", DisplayErrorContext(&e)),
+                    fix: "Check the credentials, the region, and the bucket name".into(),
+                },
+            },
+        );
+        findings.push(match measure_clock_skew(input.client, bucket).await {
+            // Well within what S3 allows, so that the clock can drift during a long upload
+            Ok(skew) if skew.abs() < Duration::from_secs(60) => {
+                Finding::Ok(format!("The clock is {skew} off from S3's"))
+            }
+            Ok(skew) => Finding::Problem {
+                what: format!(
+                    "The clock is {skew} off from S3's. S3 rejects requests when it's more than {MAX_CLOCK_SKEW} off."
+                ),
+                fix: "Sync the clock with NTP, or upload with --compensate-clock-skew".into(),
+            },
+            Err(e) => Finding::Problem {
+                what: format!("Can't check the clock: {e:?}"),
+                fix: "Check the network connection".into(),
+            },
+        });
+    }
+    if let (Some(path), Some(limit)) = (input.amount_limiter_file, input.amount_limit) {
+        check_amount_limiter_file(&mut findings, path, limit, input.stale_after).await;
+    }
+    for path in input.progress_files {
+        findings.push(
+            match ProgressStore::<UploadChunkedProgress>::load(&FileProgressStore::new(path)).await
+            {
+                Ok(None) => Finding::Ok(format!("{path} doesn't have an upload in progress")),
+                Ok(Some(progress)) => Finding::Ok(format!(
+                    "{path} has {} uploaded chunks. If the upload was interrupted, run it again with --progress-file {path} to resume it.",
+                    progress.completed.len()
+                )),
+                Err(e) => Finding::Problem {
+                    what: format!("Can't read {path}: {e:?}"),
+                    fix: "Delete it to start the upload over, or repair it with `repair` if it was kept with --keep-progress-file".into(),
+                },
+            },
+        );
+    }
+    if let Some(path) = input.bench_file {
+        findings.push(match tokio::fs::read_to_string(path).await {
+            Ok(s) => match ron::from_str::<BenchDefaults>(&s) {
+                Ok(defaults) => Finding::Ok(format!(
+                    "{path} has chunk sizes for {} buckets",
+                    defaults.buckets.len()
+                )),
+                Err(e) => Finding::Problem {
+                    what: format!("Can't parse {path}: {e}"),
+                    fix: "Delete it and run `bench` again".into(),
+                },
+            },
+            Err(e) => Finding::Problem {
+                what: format!("Can't read {path}: {e:?}"),
+                fix: "Check the path, or run `bench` to create it".into(),
+            },
+        });
+    }
+    findings
+}
+
+async fn check_amount_limiter_file(
+    findings: &mut Vec<Finding>,
+    path: &str,
+    limit: NonZero<usize>,
+    stale_after: Duration,
+) {
+    if !Path::new(path).exists() {
+        findings.push(Finding::Problem {
+            what: format!("{path} doesn't exist"),
+            fix: "Check the path. It is created by the first upload that uses it.".into(),
+        });
+        return;
+    }
+    let usage = match FileBackedAmountLimiter::new(path.into(), limit.get(), "".into())
+        .read_only()
+        .try_usage()
+        .await
+    {
+        Ok(usage) => usage,
+        Err(e) => {
+            findings.push(Finding::Problem {
+                what: format!("Can't read {path}: {e:?}"),
+                fix: "Fix the file, or delete it, which forgets the amount used this month".into(),
+            });
+            return;
+        }
+    };
+    let human = |len| HumanBytes {
+        len,
+        units: Default::default(),
+    };
+    findings.push(Finding::Ok(format!(
+        "{path} used {} of {} this month, with {} queue items",
+        human(usage.used_this_month),
+        human(usage.limit),
+        usage.queue.len()
+    )));
+    let now = UtcDateTime::now();
+    for item in usage.queue {
+        if now - item.time_added > stale_after {
+            findings.push(Finding::Problem {
+                what: format!(
+                    "{} ({}) {} {} since {}",
+                    item.description,
+                    item.id,
+                    if item.started {
+                        "has been transferring"
+                    } else {
+                        "has been waiting for"
+                    },
+                    human(item.amount),
+                    item.time_added
+                ),
+                fix: format!(
+                    "If no process is running it anymore, remove it from the queue in {path}, so that it stops holding back the queue"
+                ),
+            });
+        }
+    }
+}
@@ -1,149 +1,908 @@
-use std::{io::ErrorKind, num::NonZero, time::Duration};
+mod bench;
+mod doctor;
+mod trace;
+
+use std::{collections::BTreeMap, iter, num::NonZero, time::Duration};
 
 use aws_config::BehaviorVersion;
 use aws_sdk_s3::types::StorageClass;
-use clap::Parser;
+use bench::{BenchDefaults, bench};
+use clap::{Args, CommandFactory, Parser, ValueEnum, error::ErrorKind};
+use doctor::{DoctorInput, Finding, doctor};
 use rcs3ud::{
-    AmountLimiter, AnyTime, FileBackedAmountLimiter, S3Dest, UnlimitedAmountLimiter,
-    UploadChunkedEvent, UploadChunkedInput, UploadChunkedProgress, UploadInput, upload,
-    upload_chunked, upload_file,
+    AmountLimiter, AmountLimiterInspect, AnyTime, AuditLog, AuditOutcome, AuditRecord,
+    ChecksumAlgorithm, ChunkedUploadMode, FileBackedAmountLimiter, FileProgressStore, HumanBytes,
+    HumanRate, KeySuffix, ProgressStore, RepairChunkedInput, RetryPolicy, S3Dest, S3Src,
+    SaveProgressPolicy, SerializationFormat, SkewedTimeSource, StorageClassCheck, Transfer,
+    TransferOutput, UnlimitedAmountLimiter, UploadChunkedInput, UploadChunkedOutput,
+    UploadChunkedProgress, UploadInput, get_tags, measure_clock_skew, put_tags,
+    record_retry_decisions, repair_chunked, run_labeled, time::UtcDateTime, timestamped,
+    upload_device, upload_file, upload_files, verify_chunked,
 };
 use sipper::Sipper;
-use tokio::{
-    fs::{File, remove_file},
-    io::{AsyncReadExt, AsyncWriteExt},
-};
+use tokio::{fs::read_to_string, sync::mpsc};
+use trace::{Trace, replay};
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum KeySuffixArg {
+    Timestamp,
+    Sequence,
+}
+
+impl From<KeySuffixArg> for KeySuffix {
+    fn from(value: KeySuffixArg) -> Self {
+        match value {
+            KeySuffixArg::Timestamp => Self::Timestamp,
+            KeySuffixArg::Sequence => Self::Sequence,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum ChecksumArg {
+    Sha256,
+    Crc32c,
+}
+
+impl From<ChecksumArg> for ChecksumAlgorithm {
+    fn from(value: ChecksumArg) -> Self {
+        match value {
+            ChecksumArg::Sha256 => Self::Sha256,
+            ChecksumArg::Crc32c => Self::Crc32c,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum StorageClassCheckArg {
+    Warn,
+    Correct,
+}
+
+impl From<StorageClassCheckArg> for StorageClassCheck {
+    fn from(value: StorageClassCheckArg) -> Self {
+        match value {
+            StorageClassCheckArg::Warn => Self::Warn,
+            StorageClassCheckArg::Correct => Self::Correct,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum FormatArg {
+    Ron,
+    Json,
+}
+
+impl From<FormatArg> for SerializationFormat {
+    fn from(value: FormatArg) -> Self {
+        match value {
+            FormatArg::Ron => Self::Ron,
+            FormatArg::Json => Self::Json,
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+struct RetryArgs {
+    /// Retry every this many seconds, instead of backing off exponentially
+    #[arg(long, value_parser = parse_retry_interval)]
+    retry_interval: Option<Duration>,
+}
+
+impl RetryArgs {
+    fn policy(&self) -> RetryPolicy {
+        self.retry_interval
+            .map_or_else(RetryPolicy::default, RetryPolicy::fixed)
+    }
+}
+
+fn parse_retry_interval(secs: &str) -> Result<Duration, String> {
+    let secs = secs.parse::<f64>().map_err(|e| e.to_string())?;
+    Duration::try_from_secs_f64(secs)
+        .map_err(|_| "must be a non-negative number of seconds".to_owned())
+}
 
 #[derive(Debug, Parser)]
 #[command(version, about)]
+#[allow(clippy::large_enum_variant)]
 enum Command {
     Upload {
         #[arg(long)]
         src: String,
+        /// A file that continues --src, such as the next volume of a split archive.
+        /// Can be used more than once, in order.
+        #[arg(long = "more-src")]
+        more_srcs: Vec<String>,
+        /// The length of --src in bytes, for a block device such as /dev/sda, whose length can't be read from its metadata
+        #[arg(long, conflicts_with = "more_srcs")]
+        src_len: Option<usize>,
         #[arg(long)]
         bucket: String,
         #[arg(long)]
         object_key: String,
         #[arg(long)]
         storage_class: StorageClass,
-        #[arg(long)]
-        retry_interval: Option<f64>,
+        #[command(flatten)]
+        retry: RetryArgs,
         #[arg(long)]
         amount_limiter_file: Option<String>,
         #[arg(long)]
         amount_limit: Option<usize>,
+        /// Count this percent more than the uploaded bytes towards the amount limit, for TLS, HTTP headers, and retries
+        #[arg(long, default_value_t = 0.0)]
+        amount_overhead_percent: f64,
         #[arg(long)]
         description: Option<String>,
+        /// Let this upload through the amount limiter before waiting uploads with a lower priority
+        #[arg(long, default_value_t = 0)]
+        amount_priority: i32,
         #[arg(long)]
         chunked: bool,
+        /// Compute the SHA-256 tree hash of the file and save it in the progress file (only with --chunked).
+        /// The chunk size must be a power of two MiB.
+        #[arg(long, requires = "chunked")]
+        tree_hash: bool,
+        /// Only upload the part of the file starting at this byte (not supported with --chunked)
+        #[arg(long, conflicts_with = "chunked")]
+        offset: Option<usize>,
+        /// Only upload this many bytes, instead of until the end of the file (not supported with --chunked)
+        #[arg(long, conflicts_with = "chunked")]
+        length: Option<usize>,
         #[arg(long)]
         max_chunk_size: Option<NonZero<usize>>,
+        /// How many chunks to upload at the same time (only with --chunked). Defaults to the one saved by `bench`, or 1.
+        #[arg(long, requires = "chunked")]
+        max_concurrency: Option<NonZero<usize>>,
+        /// Upload the chunks as parts of a single object instead of as separate objects (only with --chunked).
+        /// Parts are billed at the STANDARD storage class until the upload completes.
+        #[arg(long, requires = "chunked")]
+        multipart: bool,
         #[arg(long)]
         progress_file: Option<String>,
+        /// Don't delete the progress file after a chunked upload finishes, so that it can be used with `repair`
+        #[arg(long)]
+        keep_progress_file: bool,
+        /// Only write the progress file after this many chunks, instead of after every chunk (only with --chunked)
+        #[arg(long, requires = "chunked")]
+        save_every_chunks: Option<NonZero<usize>>,
+        /// Append a timestamp or sequence number to the object key (not supported with --chunked)
+        #[arg(long, conflicts_with = "chunked")]
+        key_suffix: Option<KeySuffixArg>,
+        /// Compute a checksum before uploading, so that S3 rejects corrupted data (not used with --multipart)
+        #[arg(long)]
+        checksum: Option<ChecksumArg>,
+        /// After uploading, check that the object is in --storage-class, and warn or copy it into that class if it isn't (not used with --chunked)
+        #[arg(long, conflicts_with = "chunked")]
+        storage_class_check: Option<StorageClassCheckArg>,
+        /// Append every event with a timestamp, and every retry decision, to this file. See `replay`.
+        #[arg(long)]
+        trace_file: Option<String>,
+        /// Shown with every event and in the amount limiter file, to tell transfers apart. Defaults to the object key.
+        #[arg(long)]
+        label: Option<String>,
+        /// Use the chunk size and concurrency saved by `bench` for the bucket, if --max-chunk-size or --max-concurrency aren't specified
+        #[arg(long)]
+        bench_file: Option<String>,
+        /// Measure how far the clock is off from S3's and sign requests with S3's time, if the clock can't be fixed
+        #[arg(long)]
+        compensate_clock_skew: bool,
+        /// Append what was uploaded where, and whether it succeeded, to this audit log
+        #[arg(long)]
+        audit_log_file: Option<String>,
+    },
+    /// Make the retry decisions recorded by `upload --trace-file` again, and print the ones that come out differently
+    Replay {
+        #[arg(long)]
+        trace_file: String,
+        /// Decide with a fixed retry interval of this many seconds instead of the recorded retry policy, to see what it would have done
+        #[arg(long, value_parser = parse_retry_interval)]
+        retry_interval: Option<Duration>,
+    },
+    /// Upload generated data with different chunk sizes and concurrencies to find the fastest ones, and download it with each chunk size
+    Bench {
+        #[arg(long)]
+        bucket: String,
+        /// The data is uploaded under this prefix and deleted afterwards
+        #[arg(long, default_value = "rcs3ud-bench")]
+        prefix: String,
+        /// Bytes of data to upload with each chunk size
+        #[arg(long, default_value_t = 64_000_000)]
+        len: usize,
+        #[arg(long = "chunk-size", default_values_t = [
+            NonZero::new(4_000_000).unwrap(),
+            NonZero::new(16_000_000).unwrap(),
+            NonZero::new(64_000_000).unwrap(),
+        ])]
+        chunk_sizes: Vec<NonZero<usize>>,
+        /// How many chunks to upload at the same time. Every chunk size is uploaded with each of these.
+        #[arg(long = "concurrency", default_values_t = [
+            NonZero::new(1).unwrap(),
+            NonZero::new(4).unwrap(),
+        ])]
+        concurrencies: Vec<NonZero<usize>>,
+        /// Save the fastest chunk size and concurrency for the bucket in this file
+        #[arg(long)]
+        bench_file: Option<String>,
+        #[command(flatten)]
+        retry: RetryArgs,
+    },
+    /// Print an object's tags, or change them
+    Tag {
+        #[arg(long)]
+        bucket: String,
+        #[arg(long)]
+        object_key: String,
+        /// Set a tag, as `key=value`. Tags that aren't set or removed are kept.
+        #[arg(long)]
+        set: Vec<String>,
+        /// Remove the tag with this key
+        #[arg(long)]
+        remove: Vec<String>,
+        #[command(flatten)]
+        retry: RetryArgs,
+    },
+    /// Check the chunks of a chunked upload in S3 without the local file. Exits with 1 if a chunk is missing or different.
+    Verify {
+        #[arg(long)]
+        bucket: String,
+        #[arg(long)]
+        object_key: String,
+        /// The progress file of the chunked upload, kept with `upload --keep-progress-file`
+        #[arg(long)]
+        progress_file: String,
+        #[command(flatten)]
+        retry: RetryArgs,
+    },
+    /// Check the chunks of a chunked upload and re-upload the ones that are missing or corrupted
+    Repair {
+        #[arg(long)]
+        src: String,
+        #[arg(long)]
+        bucket: String,
+        #[arg(long)]
+        object_key: String,
+        #[arg(long)]
+        storage_class: StorageClass,
+        /// The progress file of the chunked upload, kept with `upload --keep-progress-file`
+        #[arg(long)]
+        progress_file: String,
+        #[command(flatten)]
+        retry: RetryArgs,
+        #[arg(long)]
+        amount_limiter_file: Option<String>,
+        #[arg(long)]
+        amount_limit: Option<usize>,
+        /// Count this percent more than the uploaded bytes towards the amount limit, for TLS, HTTP headers, and retries
+        #[arg(long, default_value_t = 0.0)]
+        amount_overhead_percent: f64,
+        #[arg(long)]
+        description: Option<String>,
+        /// Checksum to upload repaired chunks with
+        #[arg(long)]
+        checksum: Option<ChecksumArg>,
+    },
+    /// Check the state files and the bucket, and print what's wrong and how to fix it. Exits with 1 if there are problems.
+    Doctor {
+        #[arg(long)]
+        bucket: Option<String>,
+        #[arg(long, requires = "amount_limit")]
+        amount_limiter_file: Option<String>,
+        #[arg(long)]
+        amount_limit: Option<NonZero<usize>>,
+        #[arg(long = "progress-file")]
+        progress_files: Vec<String>,
+        #[arg(long)]
+        bench_file: Option<String>,
+        /// Amount limiter queue items older than this many hours are reported, since they may be from a process that crashed
+        #[arg(long, default_value_t = 24.0)]
+        stale_after_hours: f64,
+    },
+    /// Print how much of the amount limit is used this month and what is waiting for it
+    Usage {
+        #[arg(long)]
+        amount_limiter_file: String,
+        #[arg(long)]
+        amount_limit: usize,
+    },
+    /// Check that no entry in an audit log was changed, removed, or added. Exits with 1 if one was.
+    VerifyAuditLog {
+        #[arg(long)]
+        audit_log_file: String,
+    },
+    /// Verify an audit log and print every entry, with the hashes, in this format
+    ExportAuditLog {
+        #[arg(long)]
+        audit_log_file: String,
+        #[arg(long, value_enum, default_value_t = FormatArg::Json)]
+        format: FormatArg,
     },
 }
 
+/// Reads the progress of a chunked upload that was kept with `--keep-progress-file`
+async fn load_progress(progress_file: &str) -> UploadChunkedProgress {
+    ProgressStore::<UploadChunkedProgress>::load(&FileProgressStore::new(progress_file))
+        .await
+        .unwrap()
+        .expect("The progress file doesn't exist")
+}
+
 #[tokio::main]
 async fn main() {
     let command = Command::parse();
     match command {
         Command::Upload {
             src,
+            more_srcs,
+            src_len,
             bucket,
             object_key,
             storage_class,
-            retry_interval,
+            retry,
             amount_limiter_file,
             amount_limit,
+            amount_overhead_percent,
             description,
+            amount_priority,
             chunked,
+            tree_hash,
+            offset,
+            length,
             max_chunk_size,
+            max_concurrency,
+            multipart,
             progress_file,
+            keep_progress_file,
+            save_every_chunks,
+            key_suffix,
+            checksum,
+            storage_class_check,
+            trace_file,
+            bench_file,
+            compensate_clock_skew,
+            label,
+            audit_log_file,
         } => {
+            let label = label.unwrap_or_else(|| object_key.clone());
+            let mut trace = Trace::open(trace_file.as_deref()).await;
             let amount_limiter: Box<dyn AmountLimiter> =
                 amount_limiter_file.map_or(Box::new(UnlimitedAmountLimiter), |file| {
-                    Box::new(FileBackedAmountLimiter::new(
-                        file.into(),
-                        amount_limit.expect("Must specify amount limit to use amount limiter file"),
-                        description.unwrap_or_default().into(),
-                    ))
+                    Box::new(
+                        FileBackedAmountLimiter::new(
+                            file.into(),
+                            amount_limit
+                                .expect("Must specify amount limit to use amount limiter file"),
+                            description.unwrap_or_else(|| label.clone()).into(),
+                        )
+                        .with_overhead(amount_overhead_percent / 100.0)
+                        .with_priority(amount_priority),
+                    )
                 });
-            let retry_interval =
-                retry_interval.map_or(Duration::from_secs(5), |s| Duration::from_secs_f64(s));
+            let retry_policy = retry.policy();
             let operation_scheduler = Box::new(AnyTime);
+            let parameters = BTreeMap::from([
+                ("src".to_owned(), src.clone()),
+                ("storage_class".to_owned(), storage_class.to_string()),
+                ("chunked".to_owned(), chunked.to_string()),
+                ("multipart".to_owned(), multipart.to_string()),
+            ]);
             let dest = S3Dest {
                 bucket: &bucket,
                 object_key: &object_key,
-                storage_class: storage_class,
+                storage_class,
+                encryption: Default::default(),
             };
             let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
-            let client = aws_sdk_s3::Client::new(&config);
-            if !chunked {
-                let mut straw = upload(UploadInput {
+            let client = if compensate_clock_skew {
+                let skew = measure_clock_skew(&aws_sdk_s3::Client::new(&config), &bucket)
+                    .await
+                    .unwrap();
+                println!("The clock is {skew} off from S3's.");
+                aws_sdk_s3::Client::from_conf(
+                    aws_sdk_s3::config::Builder::from(&config)
+                        .time_source(SkewedTimeSource { skew })
+                        .build(),
+                )
+            } else {
+                aws_sdk_s3::Client::new(&config)
+            };
+            let transfer = if !chunked {
+                let mut src = if let Some(src_len) = src_len {
+                    Ok(upload_device(src.into(), src_len))
+                } else if more_srcs.is_empty() {
+                    upload_file(src.into()).await
+                } else {
+                    upload_files(iter::once(src).chain(more_srcs).map(Into::into)).await
+                }
+                .unwrap();
+                let offset = offset.unwrap_or_default();
+                if offset > src.len {
+                    Command::command()
+                        .error(
+                            ErrorKind::ValueValidation,
+                            "--offset is past the end of the file",
+                        )
+                        .exit();
+                }
+                let len = length.unwrap_or(src.len - offset);
+                if offset.checked_add(len).is_none_or(|end| end > src.len) {
+                    Command::command()
+                        .error(
+                            ErrorKind::ValueValidation,
+                            "--offset + --length is past the end of the file",
+                        )
+                        .exit();
+                }
+                src.offset = offset;
+                src.len = len;
+                Transfer::Upload(UploadInput {
                     client: &client,
-                    src: upload_file(src.into()).await.unwrap(),
+                    src,
                     dest,
-                    retry_interval,
+                    retry_policy,
                     operation_scheduler,
                     amount_limiter,
                     tagging: Default::default(),
+                    key_suffix: key_suffix.map(Into::into).unwrap_or_default(),
+                    checksum: checksum.map(Into::into),
+                    multipart_threshold: None,
+                    client_side_encryption: None,
+                    compression: None,
+                    metadata: Default::default(),
+                    storage_class_check: storage_class_check.map(Into::into),
+                    capabilities: Default::default(),
+                    control: Default::default(),
                 })
-                .pin();
-                while let Some(event) = straw.sip().await {
-                    println!("{event:#?}");
-                }
-                straw.await.unwrap();
-                println!("Uploaded successfully.");
             } else {
-                let progress_file =
-                    progress_file.expect("Must specify progress file with chunked uploads");
-                let mut straw = upload_chunked(UploadChunkedInput {
+                let progress_file = progress_file
+                    .as_ref()
+                    .expect("Must specify progress file with chunked uploads");
+                let bench_defaults = match &bench_file {
+                    Some(bench_file) => BenchDefaults::load(bench_file)
+                        .await
+                        .unwrap()
+                        .buckets
+                        .remove(&bucket),
+                    None => None,
+                };
+                Transfer::UploadChunked(UploadChunkedInput {
                     client: &client,
                     src: src.into(),
+                    more_srcs: more_srcs.into_iter().map(Into::into).collect(),
+                    src_len,
                     dest,
-                    retry_interval,
+                    retry_policy,
+                    retry_budget: Default::default(),
+                    capabilities: Default::default(),
                     operation_scheduler,
                     amount_limiter,
-                    progress: {
-                        match { File::options().read(true).open(&progress_file).await } {
-                            Ok(mut file) => {
-                                let mut s = String::new();
-                                file.read_to_string(&mut s).await.unwrap();
-                                let saved_progress =
-                                    ron::from_str::<UploadChunkedProgress>(&s).unwrap();
-                                saved_progress
-                            }
-                            Err(e) => match e.kind() {
-                                ErrorKind::NotFound => Default::default(),
-                                _ => panic!("{e:#?}"),
-                            },
+                    progress: Default::default(),
+                    progress_store: Some(Box::new({
+                        let store = FileProgressStore::new(progress_file);
+                        // Keep the progress to repair or verify the upload later
+                        if keep_progress_file {
+                            store.keep_on_success()
+                        } else {
+                            store
                         }
+                    })),
+                    chunk_size: max_chunk_size
+                        .or(bench_defaults.as_ref().map(|defaults| defaults.chunk_size))
+                        .unwrap_or({
+                            // AWS limit of 5 GB
+                            NonZero::new(5_000_000_000).unwrap()
+                        }),
+                    max_concurrency: max_concurrency
+                        .or(bench_defaults
+                            .as_ref()
+                            .map(|defaults| defaults.max_concurrency))
+                        .unwrap_or(NonZero::new(1).unwrap()),
+                    mode: if multipart {
+                        ChunkedUploadMode::Multipart
+                    } else {
+                        ChunkedUploadMode::SeparateObjects
+                    },
+                    save_policy: SaveProgressPolicy {
+                        every_chunks: save_every_chunks,
+                        every: None,
                     },
-                    chunk_size: max_chunk_size.unwrap_or({
-                        // AWS limit of 5 GB
-                        NonZero::new(5_000_000_000).unwrap()
-                    }),
+                    checksum: checksum.map(Into::into),
+                    client_side_encryption: None,
+                    tree_hash,
+                    control: Default::default(),
+                })
+            };
+            trace.record_policy(&retry_policy).await;
+            let (decision_sender, mut decisions) = mpsc::unbounded_channel();
+            let result = record_retry_decisions(
+                move |decision| {
+                    let _ = decision_sender.send(decision);
+                },
+                async {
+                    let mut straw = timestamped(run_labeled(label, transfer)).pin();
+                    while let Some(event) = straw.sip().await {
+                        println!("{:#?}", event.event);
+                        // A retry is decided before its event is sent
+                        while let Ok(decision) = decisions.try_recv() {
+                            trace.record_decision(&decision).await;
+                        }
+                        trace.record(&event).await;
+                    }
+                    straw.await
+                },
+            )
+            .await;
+            // Giving up doesn't send an event
+            while let Ok(decision) = decisions.try_recv() {
+                trace.record_decision(&decision).await;
+            }
+            if let Some(audit_log_file) = audit_log_file {
+                let (outcome, checksum) = match &result {
+                    Ok(TransferOutput::Upload(output)) => (
+                        AuditOutcome::Succeeded(
+                            [
+                                Some(("object_key".to_owned(), output.object_key.clone())),
+                                output
+                                    .e_tag
+                                    .clone()
+                                    .map(|e_tag| ("e_tag".to_owned(), e_tag)),
+                                output
+                                    .version_id
+                                    .clone()
+                                    .map(|version_id| ("version_id".to_owned(), version_id)),
+                            ]
+                            .into_iter()
+                            .flatten()
+                            .collect(),
+                        ),
+                        output.checksum,
+                    ),
+                    Ok(TransferOutput::UploadChunked(output)) => (
+                        AuditOutcome::Succeeded(
+                            [
+                                Some(("len".to_owned(), output.len.to_string())),
+                                output
+                                    .e_tag
+                                    .clone()
+                                    .map(|e_tag| ("e_tag".to_owned(), e_tag)),
+                                output
+                                    .version_id
+                                    .clone()
+                                    .map(|version_id| ("version_id".to_owned(), version_id)),
+                                output
+                                    .tree_hash
+                                    .map(|tree_hash| ("tree_hash".to_owned(), tree_hash.to_hex())),
+                            ]
+                            .into_iter()
+                            .flatten()
+                            .collect(),
+                        ),
+                        None,
+                    ),
+                    Ok(_) => (AuditOutcome::Succeeded(Default::default()), None),
+                    Err(e) => (AuditOutcome::Failed(format!("{e:?}")), None),
+                };
+                AuditLog::new(audit_log_file)
+                    .append(AuditRecord {
+                        time: UtcDateTime::now(),
+                        operation: "upload".into(),
+                        bucket: bucket.clone(),
+                        object_key: object_key.clone(),
+                        parameters,
+                        outcome,
+                        checksum,
+                    })
+                    .await
+                    .unwrap();
+            }
+            match result.unwrap() {
+                TransferOutput::Upload(output) => {
+                    println!("Uploaded successfully to {}.", output.object_key);
+                }
+                TransferOutput::UploadChunked(UploadChunkedOutput {
+                    tree_hash: Some(tree_hash),
+                    ..
+                }) => {
+                    println!("Uploaded successfully. Tree hash: {tree_hash}");
+                }
+                _ => {
+                    println!("Uploaded successfully.");
+                }
+            }
+        }
+        Command::Replay {
+            trace_file,
+            retry_interval,
+        } => {
+            let replayed = replay(
+                &read_to_string(&trace_file).await.unwrap(),
+                retry_interval.map(RetryPolicy::fixed),
+            );
+            let show = |next_attempt: Option<UtcDateTime>| {
+                next_attempt.map_or_else(|| "gave up".to_owned(), |time| format!("retry at {time}"))
+            };
+            let changed = replayed
+                .iter()
+                .filter(|replayed| replayed.recorded != replayed.replayed)
+                .inspect(|replayed| {
+                    println!(
+                        "Attempt {} at {}: recorded {}, replayed {}",
+                        replayed.recorded.attempts,
+                        replayed.recorded.decided_at,
+                        show(replayed.recorded.next_attempt),
+                        show(replayed.replayed.next_attempt)
+                    );
                 })
-                .pin();
+                .count();
+            println!("{} decisions, {changed} different", replayed.len());
+            // With the recorded policy, every decision should come out the same
+            if retry_interval.is_none() && changed > 0 {
+                std::process::exit(1);
+            }
+        }
+        Command::Bench {
+            bucket,
+            prefix,
+            len,
+            chunk_sizes,
+            concurrencies,
+            bench_file,
+            retry,
+        } => {
+            let retry_policy = retry.policy();
+            let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+            let client = aws_sdk_s3::Client::new(&config);
+            let best = bench(
+                &client,
+                &bucket,
+                &prefix,
+                len,
+                &chunk_sizes,
+                &concurrencies,
+                retry_policy,
+            )
+            .await
+            .unwrap();
+            println!(
+                "Fastest chunk size: {}, concurrency {} ({})",
+                HumanBytes {
+                    len: best.chunk_size.get(),
+                    units: Default::default()
+                },
+                best.max_concurrency,
+                HumanRate {
+                    bytes_per_second: best.speed,
+                    units: Default::default()
+                }
+            );
+            if let Some(bench_file) = bench_file {
+                let mut defaults = BenchDefaults::load(&bench_file).await.unwrap();
+                defaults.buckets.insert(bucket, best);
+                defaults.save(&bench_file).await.unwrap();
+            }
+        }
+        Command::Tag {
+            bucket,
+            object_key,
+            set,
+            remove,
+            retry,
+        } => {
+            let retry_policy = retry.policy();
+            let src = S3Src {
+                bucket: &bucket,
+                object_key: &object_key,
+            };
+            let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+            let client = aws_sdk_s3::Client::new(&config);
+            let mut straw = get_tags(&client, src, retry_policy).pin();
+            while let Some(event) = straw.sip().await {
+                println!("{event:#?}");
+            }
+            let mut tags = straw.await.unwrap();
+            if !set.is_empty() || !remove.is_empty() {
+                for key in remove {
+                    tags.remove(&key);
+                }
+                for tag in set {
+                    let (key, value) = tag
+                        .split_once('=')
+                        .expect("Tags must be specified as key=value");
+                    tags.insert(key.to_owned(), value.to_owned());
+                }
+                let mut straw = put_tags(&client, src, &tags, retry_policy).pin();
                 while let Some(event) = straw.sip().await {
                     println!("{event:#?}");
-                    if let UploadChunkedEvent::SaveProgress(saved_progress) = event {
-                        File::options()
-                            .create(true)
-                            .truncate(true)
-                            .write(true)
-                            .open(&progress_file)
-                            .await
-                            .unwrap()
-                            .write_all(ron::to_string(&saved_progress).unwrap().as_bytes())
-                            .await
-                            .unwrap();
-                    }
                 }
                 straw.await.unwrap();
-                println!("Uploaded successfully.");
-                remove_file(progress_file).await.unwrap();
             }
+            for (key, value) in &tags {
+                println!("{key}={value}");
+            }
+        }
+        Command::Repair {
+            src,
+            bucket,
+            object_key,
+            storage_class,
+            progress_file,
+            retry,
+            amount_limiter_file,
+            amount_limit,
+            amount_overhead_percent,
+            description,
+            checksum,
+        } => {
+            let amount_limiter: Box<dyn AmountLimiter> =
+                amount_limiter_file.map_or(Box::new(UnlimitedAmountLimiter), |file| {
+                    Box::new(
+                        FileBackedAmountLimiter::new(
+                            file.into(),
+                            amount_limit
+                                .expect("Must specify amount limit to use amount limiter file"),
+                            description.unwrap_or_default().into(),
+                        )
+                        .with_overhead(amount_overhead_percent / 100.0),
+                    )
+                });
+            let retry_policy = retry.policy();
+            let progress = load_progress(&progress_file).await;
+            let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+            let client = aws_sdk_s3::Client::new(&config);
+            let mut straw = repair_chunked(RepairChunkedInput {
+                client: &client,
+                src: src.into(),
+                dest: S3Dest {
+                    bucket: &bucket,
+                    object_key: &object_key,
+                    storage_class,
+                    encryption: Default::default(),
+                },
+                progress: &progress,
+                retry_policy,
+                operation_scheduler: Box::new(AnyTime),
+                amount_limiter,
+                checksum: checksum.map(Into::into),
+            })
+            .pin();
+            while let Some(event) = straw.sip().await {
+                println!("{event:#?}");
+            }
+            let output = straw.await.unwrap();
+            println!(
+                "Repaired {} chunks: {:?}",
+                output.repaired.len(),
+                output.repaired
+            );
+        }
+        Command::Verify {
+            bucket,
+            object_key,
+            progress_file,
+            retry,
+        } => {
+            let retry_policy = retry.policy();
+            let progress = load_progress(&progress_file).await;
+            let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+            let client = aws_sdk_s3::Client::new(&config);
+            let mut straw = verify_chunked(
+                &client,
+                S3Src {
+                    bucket: &bucket,
+                    object_key: &object_key,
+                },
+                &progress,
+                retry_policy,
+            )
+            .pin();
+            while let Some(event) = straw.sip().await {
+                println!("{event:#?}");
+            }
+            let output = straw.await.unwrap();
+            println!("{output:#?}");
+            if !output.is_ok() {
+                std::process::exit(1);
+            }
+        }
+        Command::Doctor {
+            bucket,
+            amount_limiter_file,
+            amount_limit,
+            progress_files,
+            bench_file,
+            stale_after_hours,
+        } => {
+            let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+            let client = aws_sdk_s3::Client::new(&config);
+            let findings = doctor(DoctorInput {
+                client: &client,
+                bucket: bucket.as_deref(),
+                amount_limiter_file: amount_limiter_file.as_deref(),
+                amount_limit,
+                progress_files: &progress_files,
+                bench_file: bench_file.as_deref(),
+                stale_after: Duration::from_secs_f64(stale_after_hours * 60.0 * 60.0),
+            })
+            .await;
+            for finding in &findings {
+                println!("{finding}");
+            }
+            if findings
+                .iter()
+                .any(|finding| matches!(finding, Finding::Problem { .. }))
+            {
+                std::process::exit(1);
+            }
+        }
+        Command::Usage {
+            amount_limiter_file,
+            amount_limit,
+        } => {
+            let usage =
+                FileBackedAmountLimiter::new(amount_limiter_file.into(), amount_limit, "".into())
+                    .read_only()
+                    .usage()
+                    .await;
+            let human = |len| HumanBytes {
+                len,
+                units: Default::default(),
+            };
+            println!(
+                "Used {} of {} this month, {} remaining.",
+                human(usage.used_this_month),
+                human(usage.limit),
+                human(usage.remaining())
+            );
+            if usage.queue.is_empty() {
+                println!("Nothing is waiting.");
+            } else {
+                println!(
+                    "{} in the queue, in the order they are let through:",
+                    human(usage.queue_total())
+                );
+                for item in usage.queue {
+                    println!(
+                        "{} {} ({}), added at {}, {}",
+                        human(item.amount),
+                        item.description,
+                        item.id,
+                        item.time_added,
+                        if item.started {
+                            "started".to_owned()
+                        } else {
+                            format!("waiting with priority {}", item.priority)
+                        }
+                    );
+                }
+            }
+        }
+        Command::VerifyAuditLog { audit_log_file } => {
+            match AuditLog::new(audit_log_file).verify().await {
+                Ok(entries) => match entries.last() {
+                    Some(last) => println!(
+                        "All {} entries are intact. The last hash is {}.",
+                        entries.len(),
+                        last.hash
+                    ),
+                    None => println!("The audit log is empty."),
+                },
+                Err(e) => {
+                    println!("{e}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::ExportAuditLog {
+            audit_log_file,
+            format,
+        } => {
+            println!(
+                "{}",
+                AuditLog::new(audit_log_file)
+                    .export(format.into())
+                    .await
+                    .unwrap()
+            );
         }
     }
 }
@@ -0,0 +1,91 @@
+use std::fmt::Debug;
+
+use rcs3ud::{RetryDecision, RetryPolicy, Timestamped};
+use tokio::{fs::File, io::AsyncWriteExt};
+
+/// Appends every event with the time it was sent to a file,
+/// so that failures in transfers that run for days can be looked at afterwards.
+/// Retry policies and decisions are written as RON on lines of their own, so that [`replay`] can read them.
+pub struct Trace {
+    file: Option<File>,
+}
+
+impl Trace {
+    pub async fn open(path: Option<&str>) -> Self {
+        let file = match path {
+            Some(path) => Some(
+                File::options()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await
+                    .unwrap(),
+            ),
+            None => None,
+        };
+        Self { file }
+    }
+
+    pub async fn record(&mut self, event: &Timestamped<impl Debug>) {
+        if let Some(file) = &mut self.file {
+            file.write_all(
+                format!("{} +{:?} {:?}\n", event.time, event.elapsed, event.event).as_bytes(),
+            )
+            .await
+            .unwrap();
+        }
+    }
+
+    /// Writes the policy that the decisions after it are made with
+    pub async fn record_policy(&mut self, policy: &RetryPolicy) {
+        self.write_line("policy", ron::to_string(policy).unwrap())
+            .await;
+    }
+
+    pub async fn record_decision(&mut self, decision: &RetryDecision) {
+        self.write_line("decision", ron::to_string(decision).unwrap())
+            .await;
+    }
+
+    async fn write_line(&mut self, kind: &str, ron: String) {
+        if let Some(file) = &mut self.file {
+            file.write_all(format!("{kind} {ron}\n").as_bytes())
+                .await
+                .unwrap();
+        }
+    }
+}
+
+/// A decision from a trace, and the decision that is made again from the same inputs
+pub struct Replayed {
+    pub recorded: RetryDecision,
+    pub replayed: RetryDecision,
+}
+
+/// Makes every retry decision in a trace again, with the policy that was recorded before it, or with `policy` if it is given
+pub fn replay(trace: &str, policy: Option<RetryPolicy>) -> Vec<Replayed> {
+    let mut recorded_policy = None;
+    trace
+        .lines()
+        .filter_map(|line| {
+            if let Some(ron) = line.strip_prefix("policy ") {
+                recorded_policy = Some(ron::from_str::<RetryPolicy>(ron).unwrap());
+                None
+            } else {
+                let recorded =
+                    ron::from_str::<RetryDecision>(line.strip_prefix("decision ")?).unwrap();
+                let policy = policy
+                    .or(recorded_policy)
+                    .expect("The trace has a decision before a policy");
+                Some(Replayed {
+                    recorded,
+                    replayed: policy.decide(
+                        recorded.attempts,
+                        recorded.decided_at,
+                        recorded.random,
+                    ),
+                })
+            }
+        })
+        .collect()
+}
@@ -1,6 +1,9 @@
 use dyn_clone::DynClone;
 use futures::future::BoxFuture;
 use sipper::FutureExt;
+use time::{PrimitiveDateTime, Time, UtcDateTime, UtcOffset};
+
+use crate::StartOfNextMonthExt;
 
 pub trait AmountLimiter: DynClone + Send {
     /// This function is called before uploading or downloading.
@@ -15,6 +18,13 @@ pub trait AmountLimiter: DynClone + Send {
         &'a self,
         id: &'a str,
     ) -> BoxFuture<'a, Option<Box<dyn AmountReservation + 'a>>>;
+
+    /// The earliest time that [`AmountLimiter::reserve`] is expected to let `len` through, without reserving it.
+    /// This is used to wait for the limiter and the [`crate::OperationScheduler`] at the same time, instead of one after the other.
+    fn available_at<'a>(&'a self, len: usize, id: &'a str) -> BoxFuture<'a, UtcDateTime> {
+        let _ = (len, id);
+        std::future::ready(UtcDateTime::now()).boxed()
+    }
 }
 
 dyn_clone::clone_trait_object!(AmountLimiter);
@@ -23,7 +33,66 @@ pub trait AmountReservation: Send {
     /// This function is called after uploading or downloading.
     /// This function is used to clean up any data from [`LenLimiter::reserve`].
     /// This function will only called once.
-    fn mark_complete(&self) -> BoxFuture<()>;
+    fn mark_complete(&self) -> BoxFuture<'_, ()>;
+
+    /// This function is called instead of [`AmountReservation::mark_complete`] if the operation was cancelled before it started uploading or downloading.
+    /// The reserved amount should be released without counting it as used.
+    fn cancel(&self) -> BoxFuture<'_, ()> {
+        std::future::ready(()).boxed()
+    }
+
+    /// This function is called instead of [`AmountReservation::mark_complete`] if the amount that was used is only known afterwards,
+    /// such as a download of an object without a content length. `len` should be counted as used instead of the reserved amount.
+    fn mark_complete_with_len(&self, len: usize) -> BoxFuture<'_, ()> {
+        let _ = len;
+        self.mark_complete()
+    }
+
+    /// This function is called when an attempt fails or is cancelled after transferring `len` bytes, which will have to be transferred again.
+    /// `len` should be counted as used right away, and the reservation should stay for the next attempt.
+    /// It can be called any number of times before the reservation is completed or cancelled.
+    fn record_partial(&self, len: usize) -> BoxFuture<'_, ()> {
+        let _ = len;
+        std::future::ready(()).boxed()
+    }
+}
+
+/// An amount that is reserved or waiting to be reserved
+#[derive(Debug, Clone)]
+pub struct QueuedAmount {
+    pub id: String,
+    /// What reserved it, such as the description of a [`crate::FileBackedAmountLimiter`]
+    pub description: String,
+    pub amount: usize,
+    pub time_added: UtcDateTime,
+    pub priority: i32,
+    /// It was let through, and is being uploaded or downloaded
+    pub started: bool,
+}
+
+/// How much of an [`AmountLimiter`]'s limit is used, and what is waiting for it
+#[derive(Debug, Clone)]
+pub struct AmountLimiterUsage {
+    pub limit: usize,
+    pub used_this_month: usize,
+    /// In the order that the amounts will be let through, after the amounts that were already let through but didn't finish
+    pub queue: Vec<QueuedAmount>,
+}
+
+impl AmountLimiterUsage {
+    /// The amount that can still be used this month, not counting the queue
+    pub fn remaining(&self) -> usize {
+        self.limit.saturating_sub(self.used_this_month)
+    }
+
+    pub fn queue_total(&self) -> usize {
+        self.queue.iter().map(|item| item.amount).sum()
+    }
+}
+
+/// An [`AmountLimiter`] that can report its usage, such as to see why an upload is waiting
+pub trait AmountLimiterInspect {
+    fn usage(&self) -> BoxFuture<'_, AmountLimiterUsage>;
 }
 
 #[derive(Clone)]
@@ -51,7 +120,94 @@ impl AmountLimiter for UnlimitedAmountLimiter {
 
 pub struct UnlimitedAmountReservation;
 impl AmountReservation for UnlimitedAmountReservation {
-    fn mark_complete(&self) -> BoxFuture<()> {
+    fn mark_complete(&self) -> BoxFuture<'_, ()> {
         std::future::ready(()).boxed()
     }
 }
+
+/// When `len` can be used with a monthly `limit`, after the amounts before it in the queue.
+/// If the limit would be exceeded, it waits for the limit to reset at the start of a month, at midnight in `utc_offset`.
+pub(crate) fn monthly_available_at(
+    used_this_month: usize,
+    queue_total: usize,
+    len: usize,
+    limit: usize,
+    now: UtcDateTime,
+    utc_offset: UtcOffset,
+) -> UtcDateTime {
+    // "Stretch" the limit if it would be impossible to do the operation with the specified limit
+    if used_this_month + queue_total + len <= limit.max(len) {
+        return now;
+    }
+    // Even if we used more data than allotted this month, we just have to wait for this month to be over and then our limit resets.
+    // So after waiting that month, we just need to let the items before us in the queue complete.
+    // A limit of 0 is stretched like any limit smaller than the operation, instead of dividing by 0
+    let months_to_wait = 1 + (queue_total + len) / limit.max(1);
+    // It's not *guaranteed* that after that time it will be our turn again, because a process could end up using its reserved data in the next month.
+    let mut date = now.to_offset(utc_offset).date();
+    for _ in 0..months_to_wait {
+        date = date.start_of_next_month();
+    }
+    PrimitiveDateTime::new(date, Time::MIDNIGHT)
+        .assume_offset(utc_offset)
+        .to_utc()
+}
+
+#[cfg(test)]
+mod tests {
+    use time::{Date, Month, Time, UtcDateTime, UtcOffset};
+
+    use super::monthly_available_at;
+
+    #[test]
+    fn waits_for_the_limit_to_reset() {
+        let now = UtcDateTime::new(
+            Date::from_calendar_date(2025, Month::December, 13).unwrap(),
+            Time::from_hms(15, 0, 0).unwrap(),
+        );
+        assert_eq!(
+            monthly_available_at(200, 300, 500, 1000, now, UtcOffset::UTC),
+            now
+        );
+        assert_eq!(
+            monthly_available_at(200, 300, 600, 1000, now, UtcOffset::UTC),
+            UtcDateTime::new(
+                Date::from_calendar_date(2026, Month::January, 1).unwrap(),
+                Time::MIDNIGHT
+            )
+        );
+        // The queue before it needs two more months of the limit
+        assert_eq!(
+            monthly_available_at(200, 1500, 600, 1000, now, UtcOffset::UTC),
+            UtcDateTime::new(
+                Date::from_calendar_date(2026, Month::March, 1).unwrap(),
+                Time::MIDNIGHT
+            )
+        );
+        // The limit resets at midnight where the ISP is
+        assert_eq!(
+            monthly_available_at(
+                200,
+                300,
+                600,
+                1000,
+                now,
+                UtcOffset::from_hms(-8, 0, 0).unwrap()
+            ),
+            UtcDateTime::new(
+                Date::from_calendar_date(2026, Month::January, 1).unwrap(),
+                Time::from_hms(8, 0, 0).unwrap()
+            )
+        );
+        // Larger than the limit, so it only waits for the queue
+        assert_eq!(
+            monthly_available_at(0, 0, 1500, 1000, now, UtcOffset::UTC),
+            now
+        );
+        assert_eq!(
+            monthly_available_at(0, 0, 1500, 0, now, UtcOffset::UTC),
+            now
+        );
+        assert!(monthly_available_at(200, 0, 1500, 0, now, UtcOffset::UTC) > now);
+    }
+}
@@ -0,0 +1,226 @@
+use std::{collections::BTreeMap, io, path::PathBuf};
+
+use fs4::tokio::AsyncFileExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use time::UtcDateTime;
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncWriteExt},
+};
+
+use crate::{Checksum, SerializationError, SerializationFormat};
+
+/// The previous hash of the first entry
+const FIRST_PREVIOUS_HASH: &str =
+    "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// What an operation did, which an [`AuditLog`] records
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub time: UtcDateTime,
+    /// Such as `upload`
+    pub operation: String,
+    pub bucket: String,
+    pub object_key: String,
+    /// Such as the source file and the storage class
+    pub parameters: BTreeMap<String, String>,
+    pub outcome: AuditOutcome,
+    /// The checksum that S3 verified the object with, if any
+    pub checksum: Option<Checksum>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuditOutcome {
+    /// With details of the result, such as the version id
+    Succeeded(BTreeMap<String, String>),
+    /// With the error
+    Failed(String),
+}
+
+/// A record in an [`AuditLog`], chained to the entry before it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub record: AuditRecord,
+    /// The [`AuditEntry::hash`] of the entry before it, or zeros for the first entry
+    pub previous_hash: String,
+    /// The SHA-256, in hex, of the previous hash and the record as JSON
+    pub hash: String,
+}
+
+impl AuditEntry {
+    fn new(record: AuditRecord, previous_hash: String) -> Self {
+        let hash = hash(&record, &previous_hash);
+        Self {
+            record,
+            previous_hash,
+            hash,
+        }
+    }
+}
+
+fn hash(record: &AuditRecord, previous_hash: &str) -> String {
+    Sha256::new()
+        .chain_update(previous_hash)
+        .chain_update(serde_json::to_string(record).unwrap())
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+#[derive(Debug, Error)]
+pub enum AuditLogError {
+    #[error("Failed to open file")]
+    Open(io::Error),
+    #[error("Failed to lock file")]
+    Lock(io::Error),
+    #[error("Failed to read file")]
+    Read(io::Error),
+    #[error("Failed to parse line {line}")]
+    Parse {
+        line: usize,
+        source: serde_json::Error,
+    },
+    #[error("Failed to write file")]
+    Write(io::Error),
+    #[error("Failed to unlock file")]
+    Unlock(io::Error),
+    #[error("Entry {entry} was changed, removed, or added after it was written")]
+    Tampered { entry: usize },
+    #[error("Failed to export")]
+    Export(SerializationError),
+}
+
+/// An append-only log of operations, for keeping records of what was uploaded where.
+/// Each entry includes the hash of the entry before it, so changing, removing, or inserting an entry is detected by [`AuditLog::verify`],
+/// unless every entry after it is written again too. Keep a copy of the last hash somewhere else to detect that.
+/// The file has one entry as JSON on each line, and is locked while it is appended to, so many processes can share it.
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub async fn append(&self, record: AuditRecord) -> Result<AuditEntry, AuditLogError> {
+        let mut file = File::options()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&self.path)
+            .await
+            .map_err(AuditLogError::Open)?;
+        file.lock_exclusive().map_err(AuditLogError::Lock)?;
+        let mut s = String::new();
+        file.read_to_string(&mut s)
+            .await
+            .map_err(AuditLogError::Read)?;
+        let previous_hash = match parse(&s)?.pop() {
+            Some(entry) => entry.hash,
+            None => FIRST_PREVIOUS_HASH.to_owned(),
+        };
+        let entry = AuditEntry::new(record, previous_hash);
+        file.write_all(format!("{}\n", serde_json::to_string(&entry).unwrap()).as_bytes())
+            .await
+            .map_err(AuditLogError::Write)?;
+        file.flush().await.map_err(AuditLogError::Write)?;
+        file.unlock_async().await.map_err(AuditLogError::Unlock)?;
+        Ok(entry)
+    }
+
+    /// Reads every entry, without verifying them. A log that doesn't exist yet is empty.
+    pub async fn read(&self) -> Result<Vec<AuditEntry>, AuditLogError> {
+        match tokio::fs::read_to_string(&self.path).await {
+            Ok(s) => parse(&s),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(AuditLogError::Read(e)),
+        }
+    }
+
+    /// Reads every entry and checks that the chain of hashes is unbroken
+    pub async fn verify(&self) -> Result<Vec<AuditEntry>, AuditLogError> {
+        let entries = self.read().await?;
+        let mut previous_hash = FIRST_PREVIOUS_HASH;
+        for (index, entry) in entries.iter().enumerate() {
+            if entry.previous_hash != previous_hash
+                || entry.hash != hash(&entry.record, &entry.previous_hash)
+            {
+                Err(AuditLogError::Tampered { entry: index })?;
+            }
+            previous_hash = &entry.hash;
+        }
+        Ok(entries)
+    }
+
+    /// Verifies the log and writes every entry in `format`, such as to hand them to someone else,
+    /// who can check the hashes without this crate
+    pub async fn export(&self, format: SerializationFormat) -> Result<String, AuditLogError> {
+        format
+            .serialize_pretty(&self.verify().await?)
+            .map_err(AuditLogError::Export)
+    }
+}
+
+fn parse(s: &str) -> Result<Vec<AuditEntry>, AuditLogError> {
+    s.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(index, line)| {
+            serde_json::from_str(line).map_err(|source| AuditLogError::Parse {
+                line: index + 1,
+                source,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use time::UtcDateTime;
+
+    use super::{AuditLog, AuditLogError, AuditOutcome, AuditRecord};
+
+    #[tokio::test]
+    async fn detects_changed_entries() {
+        let path =
+            std::env::temp_dir().join(format!("rcs3ud-test-{:016x}.jsonl", fastrand::u64(..)));
+        let log = AuditLog::new(&path);
+        for object_key in ["a", "b", "c"] {
+            log.append(AuditRecord {
+                time: UtcDateTime::now(),
+                operation: "upload".into(),
+                bucket: "bucket".into(),
+                object_key: object_key.into(),
+                parameters: [("storage_class".into(), "DEEP_ARCHIVE".into())].into(),
+                outcome: AuditOutcome::Succeeded(Default::default()),
+                checksum: None,
+            })
+            .await
+            .unwrap();
+        }
+        let entries = log.verify().await.unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[1].previous_hash, entries[0].hash);
+
+        let s = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, s.replacen("\"b\"", "\"d\"", 1)).unwrap();
+        assert!(matches!(
+            log.verify().await,
+            Err(AuditLogError::Tampered { entry: 1 })
+        ));
+        // Removing the entry breaks the chain at the entry after it
+        let mut lines = s.lines().collect::<Vec<_>>();
+        lines.remove(1);
+        std::fs::write(&path, lines.join("\n")).unwrap();
+        assert!(matches!(
+            log.verify().await,
+            Err(AuditLogError::Tampered { entry: 1 })
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    io::{self, ErrorKind},
+    mem,
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+use sipper::{Sipper, Straw, sipper};
+use thiserror::Error;
+use tokio::fs::{OpenOptions, metadata, remove_file};
+
+use crate::{
+    S3Src, UploadChunkedError, UploadChunkedEvent, UploadChunkedInput, UploadChunkedProgress,
+    VerifyChunkedError, VerifyChunkedEvent, VerifyChunkedOutput, upload_chunked, verify_chunked,
+};
+
+/// What to do with the local file after the upload was verified
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AfterVerified {
+    Keep,
+    Delete,
+    /// Keeps the file but makes it empty, for when something else expects it to exist
+    Truncate,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BackupStage {
+    #[default]
+    Uploading,
+    Verifying,
+    /// The upload was verified, and the [`AfterVerified`] action is next
+    Verified,
+    Done,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct BackupProgress {
+    pub upload: UploadChunkedProgress,
+    pub stage: BackupStage,
+}
+
+pub struct BackupInput<'a> {
+    /// `upload.progress` is ignored. `progress.upload` is used instead.
+    pub upload: UploadChunkedInput<'a>,
+    pub progress: BackupProgress,
+    pub action: AfterVerified,
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Error)]
+pub enum BackupError {
+    #[error("Error uploading the file")]
+    Upload(UploadChunkedError),
+    #[error("Error verifying the upload")]
+    Verify(VerifyChunkedError),
+    #[error("The uploaded chunks don't match the upload's progress, so the local file was kept")]
+    VerificationFailed(VerifyChunkedOutput),
+    /// Without checksums, verification only compares sizes, which isn't enough to delete or truncate the local file
+    #[error("Deleting or truncating the local file needs a checksum on the upload")]
+    NoChecksum,
+    #[error("The local file changed after it was uploaded, so it was kept")]
+    SourceChanged,
+    #[error("Error deleting or truncating the local file")]
+    Action(io::Error),
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum BackupEvent {
+    SaveProgress(BackupProgress),
+    UploadChunkedEvent(UploadChunkedEvent),
+    VerifyChunkedEvent(VerifyChunkedEvent),
+    Verified(VerifyChunkedOutput),
+}
+
+/// Uploads a file with [`upload_chunked`], verifies it with [`verify_chunked`],
+/// and only then deletes or truncates the local file.
+/// Every step is saved in [`BackupProgress`], so the workflow can be resumed after a restart.
+///
+/// Unless the action is [`AfterVerified::Keep`], [`UploadChunkedInput::checksum`] is required
+/// so that verification compares checksums and not only sizes.
+pub fn backup_and_verify_then(
+    mut input: BackupInput<'_>,
+) -> impl Straw<(), BackupEvent, BackupError> {
+    sipper(async move |mut sender| {
+        let client = input.upload.client;
+        let path = input.upload.src.clone();
+        let s3_src = S3Src {
+            bucket: input.upload.dest.bucket,
+            object_key: input.upload.dest.object_key,
+        };
+        let retry_policy = input.upload.retry_policy;
+        let mut progress = input.progress;
+        let destructive = input.action != AfterVerified::Keep;
+        if destructive && input.upload.checksum.is_none() {
+            Err(BackupError::NoChecksum)?;
+        }
+        if progress.stage == BackupStage::Uploading {
+            input.upload.progress = mem::take(&mut progress.upload);
+            let mut upload_progress = input.upload.progress.clone();
+            upload_chunked(input.upload)
+                .with(|event| match event {
+                    UploadChunkedEvent::SaveProgress(saved_progress) => {
+                        upload_progress = saved_progress.clone();
+                        BackupEvent::SaveProgress(BackupProgress {
+                            upload: saved_progress,
+                            stage: BackupStage::Uploading,
+                        })
+                    }
+                    event => BackupEvent::UploadChunkedEvent(event),
+                })
+                .run(sender.clone())
+                .await
+                .map_err(BackupError::Upload)?;
+            progress.upload = upload_progress;
+            progress.stage = BackupStage::Verifying;
+            sender
+                .send(BackupEvent::SaveProgress(progress.clone()))
+                .await;
+        }
+        if progress.stage == BackupStage::Verifying {
+            let output = verify_chunked(client, s3_src, &progress.upload, retry_policy)
+                .with(BackupEvent::VerifyChunkedEvent)
+                .run(sender.clone())
+                .await
+                .map_err(BackupError::Verify)?;
+            // Chunks from progress saved without checksums were only checked by size
+            if !output.is_ok() || (destructive && !output.no_checksum.is_empty()) {
+                return Err(BackupError::VerificationFailed(output));
+            }
+            sender.send(BackupEvent::Verified(output)).await;
+            progress.stage = BackupStage::Verified;
+            sender
+                .send(BackupEvent::SaveProgress(progress.clone()))
+                .await;
+        }
+        if progress.stage == BackupStage::Verified {
+            // The file could have been written to after it was uploaded, such as between a restart and resuming
+            if destructive && source_changed(&path, &progress.upload).await? {
+                Err(BackupError::SourceChanged)?;
+            }
+            match input.action {
+                AfterVerified::Keep => {}
+                AfterVerified::Delete => match remove_file(&path).await {
+                    Ok(()) => {}
+                    // Already deleted before the progress was saved
+                    Err(e) if e.kind() == ErrorKind::NotFound => {}
+                    Err(e) => Err(BackupError::Action(e))?,
+                },
+                AfterVerified::Truncate => {
+                    OpenOptions::new()
+                        .write(true)
+                        .open(&path)
+                        .await
+                        .map_err(BackupError::Action)?
+                        .set_len(0)
+                        .await
+                        .map_err(BackupError::Action)?;
+                }
+            }
+            progress.stage = BackupStage::Done;
+            sender
+                .send(BackupEvent::SaveProgress(progress.clone()))
+                .await;
+        }
+        Ok(())
+    })
+}
+
+/// Compares the len and modified time of `path` with the ones the upload recorded
+async fn source_changed(
+    path: &Path,
+    progress: &UploadChunkedProgress,
+) -> Result<bool, BackupError> {
+    let metadata = match metadata(path).await {
+        Ok(metadata) => metadata,
+        // Already deleted before the progress was saved
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => Err(BackupError::Action(e))?,
+    };
+    let len = usize::try_from(metadata.len()).unwrap();
+    let modified = metadata.modified().ok();
+    Ok(
+        match progress.sources.iter().find(|file| file.path == path) {
+            Some(file) => file.len != len || (file.modified.is_some() && file.modified != modified),
+            None => {
+                progress.len != Some(len)
+                    || (progress.modified.is_some() && progress.modified != modified)
+            }
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::UploadChunkedProgress;
+
+    use super::source_changed;
+
+    #[tokio::test]
+    async fn detects_changed_source() {
+        let path = std::env::temp_dir().join(format!("rcs3ud-test-{:016x}.bin", fastrand::u64(..)));
+        std::fs::write(&path, [0; 100]).unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+        let mut progress = UploadChunkedProgress::default();
+        progress.len = Some(100);
+        progress.modified = metadata.modified().ok();
+        assert!(!source_changed(&path, &progress).await.unwrap());
+        std::fs::write(&path, [0; 101]).unwrap();
+        assert!(source_changed(&path, &progress).await.unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert!(!source_changed(&path, &progress).await.unwrap());
+    }
+}
@@ -0,0 +1,275 @@
+use aws_sdk_s3::{
+    error::{ProvideErrorMetadata, SdkError},
+    operation::{
+        delete_object::DeleteObjectError, put_object::PutObjectError,
+        put_object_tagging::PutObjectTaggingError, restore_object::RestoreObjectError,
+    },
+    primitives::ByteStream,
+    types::{GlacierJobParameters, RestoreRequest, StorageClass, Tag, Tagging, Tier},
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use sipper::{Sipper, Straw, sipper};
+use thiserror::Error;
+
+use crate::{
+    RetriesExhausted, RetryPolicy, Retrying,
+    maybe_retryable_sdk_error::IntoMaybeRetryable,
+    retry::{KeepRetryingExt, MaybeRetryable},
+};
+
+/// The features that an S3-compatible endpoint has, found with [`probe_capabilities`].
+/// Operations given these skip or substitute the features that are missing, and send a [`Degradation`] event for each one.
+/// The default is everything that AWS S3 has.
+#[derive(Debug, Clone)]
+pub struct Capabilities {
+    pub tagging: bool,
+    /// The `x-amz-checksum-*` headers, see [`crate::UploadInput::checksum`]
+    pub checksums: bool,
+    /// Restoring archived objects. Without it, cold downloads are downloaded like warm ones.
+    pub restore: bool,
+    /// Storage classes that were rejected. Uploads with them use `STANDARD` instead.
+    pub unsupported_storage_classes: Vec<StorageClass>,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            tagging: true,
+            checksums: true,
+            restore: true,
+            unsupported_storage_classes: Vec::new(),
+        }
+    }
+}
+
+impl Capabilities {
+    /// The storage class to upload with, or `STANDARD` if `storage_class` isn't supported
+    pub(crate) fn storage_class(&self, storage_class: &StorageClass) -> StorageClass {
+        if self.unsupported_storage_classes.contains(storage_class) {
+            StorageClass::Standard
+        } else {
+            storage_class.clone()
+        }
+    }
+}
+
+/// A feature that was skipped or substituted because of the [`Capabilities`]
+#[derive(Debug, Clone)]
+pub enum Degradation {
+    /// Uploaded without tags
+    Tagging,
+    /// Uploaded without a checksum, so S3 can't check that the data wasn't corrupted on the way
+    Checksum,
+    StorageClass {
+        requested: StorageClass,
+        used: StorageClass,
+    },
+    /// Downloaded without restoring the object first
+    Restore,
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Error)]
+pub enum ProbeError {
+    #[error("Error uploading the probe object")]
+    PutObject(SdkError<PutObjectError>),
+    #[error("Error tagging the probe object")]
+    PutObjectTagging(SdkError<PutObjectTaggingError>),
+    #[error("Error restoring the probe object")]
+    RestoreObject(SdkError<RestoreObjectError>),
+    #[error("Error deleting the probe object")]
+    DeleteObject(SdkError<DeleteObjectError>),
+    #[error("Gave up retrying")]
+    RetriesExhausted(#[from] RetriesExhausted),
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum ProbeEvent {
+    PutObjectError(Retrying<SdkError<PutObjectError>>),
+    PutObjectTaggingError(Retrying<SdkError<PutObjectTaggingError>>),
+    RestoreObjectError(Retrying<SdkError<RestoreObjectError>>),
+    DeleteObjectError(Retrying<SdkError<DeleteObjectError>>),
+}
+
+/// Error codes that S3-compatible endpoints respond with for features that they don't have
+fn is_unsupported(code: Option<&str>) -> bool {
+    matches!(
+        code,
+        Some(
+            "NotImplemented"
+                | "NotSupported"
+                | "InvalidArgument"
+                | "InvalidRequest"
+                | "InvalidStorageClass"
+                | "MethodNotAllowed"
+        )
+    )
+}
+
+/// Like [`IntoMaybeRetryable::into_maybe_retryable`], but a missing feature isn't retried even though it's a server error
+fn probe_retryable<E: ProvideErrorMetadata>(
+    e: SdkError<E>,
+) -> MaybeRetryable<SdkError<E>, SdkError<E>> {
+    if is_unsupported(e.code()) {
+        MaybeRetryable::NotRetryable(e)
+    } else {
+        e.into_maybe_retryable()
+    }
+}
+
+/// Finds out what the endpoint supports by uploading a tiny object to the bucket, trying each feature on it, and deleting it.
+/// Each of the `storage_classes` is tried by uploading the object with it.
+/// Objects in archive storage classes are billed for a minimum duration, so only try the storage classes that will be used.
+pub fn probe_capabilities<'a>(
+    client: &'a aws_sdk_s3::Client,
+    bucket: &'a str,
+    storage_classes: &'a [StorageClass],
+    retry_policy: RetryPolicy,
+) -> impl Straw<Capabilities, ProbeEvent, ProbeError> {
+    sipper(async move |sender| {
+        const BODY: &[u8] = b"rcs3ud capability probe";
+        let key = format!(".rcs3ud-probe-{:016x}", fastrand::u64(..));
+        let put = async |storage_class: Option<&StorageClass>, checksum: bool| {
+            (async || {
+                client
+                    .put_object()
+                    .bucket(bucket)
+                    .key(&key)
+                    .body(ByteStream::from_static(BODY))
+                    .set_storage_class(storage_class.cloned())
+                    .set_checksum_crc32_c(
+                        checksum.then(|| STANDARD.encode(crc32c::crc32c(BODY).to_be_bytes())),
+                    )
+                    .send()
+                    .await
+                    .map_err(|e| probe_retryable(e).map(ProbeError::PutObject))
+            })
+            .keep_retrying(retry_policy)
+            .with(ProbeEvent::PutObjectError)
+            .run(sender.clone())
+            .await
+        };
+        // Everything else is tried on this object
+        put(None, false).await?;
+        let mut capabilities = Capabilities {
+            checksums: match put(None, true).await {
+                Ok(_) => true,
+                Err(ProbeError::PutObject(e)) if is_unsupported(e.code()) => false,
+                Err(e) => Err(e)?,
+            },
+            ..Default::default()
+        };
+        capabilities.tagging = match (async || {
+            client
+                .put_object_tagging()
+                .bucket(bucket)
+                .key(&key)
+                .tagging(
+                    Tagging::builder()
+                        .tag_set(Tag::builder().key("rcs3ud").value("probe").build().unwrap())
+                        .build()
+                        .unwrap(),
+                )
+                .send()
+                .await
+                .map_err(|e| probe_retryable(e).map(ProbeError::PutObjectTagging))
+        })
+        .keep_retrying(retry_policy)
+        .with(ProbeEvent::PutObjectTaggingError)
+        .run(sender.clone())
+        .await
+        {
+            Ok(_) => true,
+            Err(ProbeError::PutObjectTagging(e)) if is_unsupported(e.code()) => false,
+            Err(e) => Err(e)?,
+        };
+        capabilities.restore = match (async || {
+            client
+                .restore_object()
+                .bucket(bucket)
+                .key(&key)
+                .restore_request(
+                    RestoreRequest::builder()
+                        .days(1)
+                        .glacier_job_parameters(
+                            GlacierJobParameters::builder()
+                                .tier(Tier::Bulk)
+                                .build()
+                                .unwrap(),
+                        )
+                        .build(),
+                )
+                .send()
+                .await
+                .map_err(|e| probe_retryable(e).map(ProbeError::RestoreObject))
+        })
+        .keep_retrying(retry_policy)
+        .with(ProbeEvent::RestoreObjectError)
+        .run(sender.clone())
+        .await
+        {
+            // The object isn't archived, but the endpoint knows how to restore objects
+            Err(ProbeError::RestoreObject(e)) if e.code() == Some("InvalidObjectState") => true,
+            Ok(_) => true,
+            Err(ProbeError::RestoreObject(e)) if is_unsupported(e.code()) => false,
+            Err(e) => Err(e)?,
+        };
+        for storage_class in storage_classes {
+            match put(Some(storage_class), false).await {
+                Ok(_) => {}
+                Err(ProbeError::PutObject(e)) if is_unsupported(e.code()) => {
+                    capabilities
+                        .unsupported_storage_classes
+                        .push(storage_class.clone());
+                }
+                Err(e) => Err(e)?,
+            }
+        }
+        (async || {
+            client
+                .delete_object()
+                .bucket(bucket)
+                .key(&key)
+                .send()
+                .await
+                .map_err(|e| e.into_maybe_retryable().map(ProbeError::DeleteObject))
+        })
+        .keep_retrying(retry_policy)
+        .with(ProbeEvent::DeleteObjectError)
+        .run(sender.clone())
+        .await?;
+        Ok(capabilities)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use aws_sdk_s3::types::StorageClass;
+
+    use super::{Capabilities, is_unsupported};
+
+    #[test]
+    fn unsupported_codes() {
+        assert!(is_unsupported(Some("NotImplemented")));
+        assert!(is_unsupported(Some("InvalidStorageClass")));
+        assert!(!is_unsupported(Some("AccessDenied")));
+        assert!(!is_unsupported(None));
+    }
+
+    #[test]
+    fn substitutes_storage_class() {
+        let capabilities = Capabilities {
+            unsupported_storage_classes: vec![StorageClass::DeepArchive],
+            ..Default::default()
+        };
+        assert_eq!(
+            capabilities.storage_class(&StorageClass::DeepArchive),
+            StorageClass::Standard
+        );
+        assert_eq!(
+            capabilities.storage_class(&StorageClass::Glacier),
+            StorageClass::Glacier
+        );
+    }
+}
@@ -0,0 +1,71 @@
+use std::io;
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+
+use crate::UploadSrc;
+
+#[derive(Debug, Clone, Copy)]
+pub enum ChecksumAlgorithm {
+    Sha256,
+    /// Much faster to compute than SHA-256, but only detects accidental corruption
+    Crc32c,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Checksum {
+    Sha256([u8; 32]),
+    Crc32c(u32),
+}
+
+impl Checksum {
+    /// The format used by the `x-amz-checksum-*` headers
+    pub fn to_base64(&self) -> String {
+        match self {
+            Self::Sha256(digest) => STANDARD.encode(digest),
+            Self::Crc32c(crc) => STANDARD.encode(crc.to_be_bytes()),
+        }
+    }
+}
+
+/// Reads the part of the file that will be uploaded and computes its checksum.
+/// S3 compares it with the checksum of the data it receives, and rejects the upload if they are different.
+pub(crate) async fn compute_checksum(
+    src: &UploadSrc,
+    algorithm: ChecksumAlgorithm,
+) -> io::Result<Checksum> {
+    let mut file = src.reader().await?;
+    let mut buf = vec![0; 1024 * 1024];
+    let mut sha256 = Sha256::new();
+    let mut crc = 0;
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        match algorithm {
+            ChecksumAlgorithm::Sha256 => sha256.update(&buf[..n]),
+            ChecksumAlgorithm::Crc32c => crc = crc32c::crc32c_append(crc, &buf[..n]),
+        }
+    }
+    Ok(match algorithm {
+        ChecksumAlgorithm::Sha256 => Checksum::Sha256(sha256.finalize().into()),
+        ChecksumAlgorithm::Crc32c => Checksum::Crc32c(crc),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Checksum;
+
+    #[test]
+    fn base64() {
+        assert_eq!(
+            Checksum::Sha256(Default::default()).to_base64(),
+            "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="
+        );
+        assert_eq!(Checksum::Crc32c(0x01020304).to_base64(), "AQIDBA==");
+    }
+}
@@ -0,0 +1,422 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, ready},
+};
+
+use aws_sdk_s3::primitives::{ByteStream, SdkBody};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use bytes::{Bytes, BytesMut};
+use http_body::{Body, Frame, SizeHint};
+use ring::{
+    aead::{AES_256_GCM, Aad, LessSafeKey, NONCE_LEN, Nonce, UnboundKey},
+    rand::{SecureRandom, SystemRandom},
+};
+use thiserror::Error;
+
+/// Files are encrypted in segments of this size, so that a download can be resumed and decrypted from any segment.
+/// Parts of client-side encrypted multipart uploads must be a multiple of this size.
+pub const SEGMENT_SIZE: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+pub(crate) const ENCRYPTED_SEGMENT_SIZE: usize = SEGMENT_SIZE + TAG_LEN;
+/// The rest of the nonce is the segment number and whether it's the last segment
+pub(crate) const NONCE_PREFIX_LEN: usize = 7;
+const KEY_ID_METADATA: &str = "rcs3ud-cse-key-id";
+const NONCE_METADATA: &str = "rcs3ud-cse-nonce";
+
+/// A 256-bit AES-GCM key for client-side encryption, so that objects are unreadable to anyone with access to the bucket but not the key.
+/// Only the id is shown when debug printing it.
+#[derive(Clone)]
+pub struct ClientSideKey {
+    id: Arc<str>,
+    key: Arc<LessSafeKey>,
+}
+
+impl ClientSideKey {
+    /// The `id` is stored in the metadata of the objects encrypted with this key, to tell which key is needed to decrypt them.
+    pub fn new(id: impl Into<Arc<str>>, key: [u8; 32]) -> Self {
+        Self {
+            id: id.into(),
+            key: Arc::new(LessSafeKey::new(
+                UnboundKey::new(&AES_256_GCM, &key).expect("AES-256 keys are 32 bytes"),
+            )),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl fmt::Debug for ClientSideKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientSideKey")
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ClientSideEncryptionError {
+    #[error("Error generating a random nonce")]
+    Random,
+    #[error("The object was encrypted with the key {0}, not the key that was given")]
+    WrongKey(String),
+    #[error("The encryption metadata of the object is invalid")]
+    InvalidMetadata,
+    #[error("The object could not be decrypted, so it was changed or corrupted")]
+    Decrypt,
+    #[error("The object ended in the middle of an encrypted segment")]
+    Truncated,
+}
+
+/// How one object is encrypted
+#[derive(Debug, Clone)]
+pub(crate) struct ObjectEncryption {
+    key: ClientSideKey,
+    nonce_prefix: [u8; NONCE_PREFIX_LEN],
+}
+
+impl ObjectEncryption {
+    /// Uses a new random nonce prefix, since a nonce must never be used twice with the same key
+    pub(crate) fn new(key: ClientSideKey) -> Result<Self, ClientSideEncryptionError> {
+        let mut nonce_prefix = [0; NONCE_PREFIX_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce_prefix)
+            .map_err(|_| ClientSideEncryptionError::Random)?;
+        Ok(Self { key, nonce_prefix })
+    }
+
+    pub(crate) fn with_nonce_prefix(
+        key: ClientSideKey,
+        nonce_prefix: [u8; NONCE_PREFIX_LEN],
+    ) -> Self {
+        Self { key, nonce_prefix }
+    }
+
+    pub(crate) fn nonce_prefix(&self) -> [u8; NONCE_PREFIX_LEN] {
+        self.nonce_prefix
+    }
+
+    /// The object metadata needed to decrypt the object
+    pub(crate) fn metadata(&self) -> HashMap<String, String> {
+        HashMap::from([
+            (KEY_ID_METADATA.to_owned(), self.key.id.to_string()),
+            (
+                NONCE_METADATA.to_owned(),
+                STANDARD.encode(self.nonce_prefix),
+            ),
+        ])
+    }
+
+    /// Returns `None` if the object wasn't encrypted by rcs3ud
+    pub(crate) fn from_metadata(
+        metadata: Option<&HashMap<String, String>>,
+        key: &ClientSideKey,
+    ) -> Result<Option<Self>, ClientSideEncryptionError> {
+        let Some(metadata) = metadata else {
+            return Ok(None);
+        };
+        let (Some(key_id), Some(nonce_prefix)) =
+            (metadata.get(KEY_ID_METADATA), metadata.get(NONCE_METADATA))
+        else {
+            return Ok(None);
+        };
+        if key_id.as_str() != key.id() {
+            Err(ClientSideEncryptionError::WrongKey(key_id.clone()))?;
+        }
+        let nonce_prefix = STANDARD
+            .decode(nonce_prefix)
+            .ok()
+            .and_then(|nonce_prefix| nonce_prefix.try_into().ok())
+            .ok_or(ClientSideEncryptionError::InvalidMetadata)?;
+        Ok(Some(Self::with_nonce_prefix(key.clone(), nonce_prefix)))
+    }
+
+    fn nonce(&self, segment: u32, last: bool) -> Nonce {
+        let mut nonce = [0; NONCE_LEN];
+        nonce[..NONCE_PREFIX_LEN].copy_from_slice(&self.nonce_prefix);
+        nonce[NONCE_PREFIX_LEN..NONCE_LEN - 1].copy_from_slice(&segment.to_be_bytes());
+        nonce[NONCE_LEN - 1] = last.into();
+        Nonce::assume_unique_for_key(nonce)
+    }
+}
+
+/// How a part of a client-side encrypted multipart upload is encrypted
+#[derive(Debug, Clone)]
+pub struct PartEncryption {
+    pub(crate) object: ObjectEncryption,
+    /// Where the part starts in the file, a multiple of [`SEGMENT_SIZE`]
+    pub(crate) offset: usize,
+    /// `true` for the last part
+    pub(crate) ends_object: bool,
+}
+
+/// The length of a file of `len` bytes after it is encrypted
+pub(crate) fn encrypted_len(len: usize) -> usize {
+    len + len.div_ceil(SEGMENT_SIZE).max(1) * TAG_LEN
+}
+
+/// The length of an encrypted object after it is decrypted
+pub(crate) fn decrypted_len(encrypted_len: usize) -> usize {
+    encrypted_len.saturating_sub(encrypted_len.div_ceil(ENCRYPTED_SEGMENT_SIZE) * TAG_LEN)
+}
+
+/// Where the segment that starts at `offset` in the file starts in the encrypted object
+pub(crate) fn encrypted_offset(offset: usize) -> usize {
+    offset / SEGMENT_SIZE * ENCRYPTED_SEGMENT_SIZE
+}
+
+/// Encrypts `len` bytes that start at `offset` in the file, which must be a multiple of [`SEGMENT_SIZE`].
+/// If `ends_object` is `false`, `len` must also be a multiple of [`SEGMENT_SIZE`].
+/// Retries of the request read and encrypt the file again.
+pub(crate) fn encrypt_byte_stream(
+    byte_stream: ByteStream,
+    encryption: &ObjectEncryption,
+    offset: usize,
+    len: usize,
+    ends_object: bool,
+) -> ByteStream {
+    let encryption = encryption.clone();
+    ByteStream::new(byte_stream.into_inner().map(move |body| {
+        SdkBody::from_body_1_x(EncryptingBody {
+            inner: body,
+            encryption: encryption.clone(),
+            segment: (offset / SEGMENT_SIZE) as u32,
+            buffer: BytesMut::new(),
+            remaining: len,
+            ends_object,
+            done: false,
+        })
+    }))
+}
+
+struct EncryptingBody {
+    inner: SdkBody,
+    encryption: ObjectEncryption,
+    /// The number of the next segment
+    segment: u32,
+    buffer: BytesMut,
+    /// Bytes that weren't read from the file yet
+    remaining: usize,
+    ends_object: bool,
+    done: bool,
+}
+
+impl Body for EncryptingBody {
+    type Data = Bytes;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        loop {
+            if this.done {
+                return Poll::Ready(None);
+            }
+            if this.buffer.len() >= SEGMENT_SIZE || this.remaining == 0 {
+                let mut segment = this.buffer.split_to(this.buffer.len().min(SEGMENT_SIZE));
+                let last = this.remaining == 0 && this.buffer.is_empty();
+                this.encryption
+                    .key
+                    .key
+                    .seal_in_place_append_tag(
+                        this.encryption
+                            .nonce(this.segment, last && this.ends_object),
+                        Aad::empty(),
+                        &mut segment,
+                    )
+                    .map_err(|_| "Error encrypting a segment")?;
+                this.segment += 1;
+                this.done = last;
+                return Poll::Ready(Some(Ok(Frame::data(segment.freeze()))));
+            }
+            match ready!(Pin::new(&mut this.inner).poll_frame(cx)) {
+                Some(Ok(frame)) => {
+                    if let Ok(data) = frame.into_data() {
+                        this.remaining = this.remaining.saturating_sub(data.len());
+                        this.buffer.extend_from_slice(&data);
+                    }
+                }
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => {
+                    return Poll::Ready(Some(Err(
+                        "The file ended before all of it was encrypted".into()
+                    )));
+                }
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.done
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        if self.done {
+            return SizeHint::with_exact(0);
+        }
+        SizeHint::with_exact(encrypted_len(self.buffer.len() + self.remaining) as u64)
+    }
+}
+
+/// Decrypts an encrypted object as it's downloaded, one segment at a time
+pub(crate) struct Decryptor {
+    encryption: ObjectEncryption,
+    /// The number of the next segment
+    segment: u32,
+    buffer: Vec<u8>,
+    /// Encrypted bytes that weren't given to [`Decryptor::push`] yet
+    remaining: usize,
+}
+
+impl Decryptor {
+    /// Starts decrypting at `offset` in the encrypted object, which must be at the start of a segment
+    pub(crate) fn new(encryption: ObjectEncryption, offset: usize, encrypted_len: usize) -> Self {
+        Self {
+            encryption,
+            segment: (offset / ENCRYPTED_SEGMENT_SIZE) as u32,
+            buffer: Vec::new(),
+            remaining: encrypted_len - offset,
+        }
+    }
+
+    /// Returns the decrypted bytes of the segments that are complete.
+    /// The bytes of an incomplete segment are kept until the rest of the segment is pushed.
+    pub(crate) fn push(&mut self, bytes: &[u8]) -> Result<Vec<u8>, ClientSideEncryptionError> {
+        self.buffer.extend_from_slice(bytes);
+        self.remaining = self.remaining.saturating_sub(bytes.len());
+        let mut decrypted = Vec::new();
+        let mut start = 0;
+        loop {
+            let buffered = self.buffer.len() - start;
+            if buffered < ENCRYPTED_SEGMENT_SIZE && !(self.remaining == 0 && buffered > 0) {
+                break;
+            }
+            let last = self.remaining == 0 && buffered <= ENCRYPTED_SEGMENT_SIZE;
+            let end = start + buffered.min(ENCRYPTED_SEGMENT_SIZE);
+            let segment = self
+                .encryption
+                .key
+                .key
+                .open_in_place(
+                    self.encryption.nonce(self.segment, last),
+                    Aad::empty(),
+                    &mut self.buffer[start..end],
+                )
+                .map_err(|_| ClientSideEncryptionError::Decrypt)?;
+            decrypted.extend_from_slice(segment);
+            self.segment += 1;
+            start = end;
+        }
+        self.buffer.drain(..start);
+        Ok(decrypted)
+    }
+
+    /// Returns `true` if there are no bytes of an incomplete segment
+    pub(crate) fn is_segment_boundary(&self) -> bool {
+        self.buffer.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use ring::aead::Aad;
+
+    use super::{
+        ClientSideKey, Decryptor, ENCRYPTED_SEGMENT_SIZE, ObjectEncryption, SEGMENT_SIZE,
+        decrypted_len, encrypted_len, encrypted_offset,
+    };
+
+    fn encrypt(encryption: &ObjectEncryption, data: &[u8]) -> Vec<u8> {
+        let mut encrypted = Vec::new();
+        let segments = data.chunks(SEGMENT_SIZE).collect::<Vec<_>>();
+        let segments = if segments.is_empty() {
+            vec![&data[..0]]
+        } else {
+            segments
+        };
+        for (i, segment) in segments.iter().enumerate() {
+            let mut segment = BytesMut::from(*segment);
+            encryption
+                .key
+                .key
+                .seal_in_place_append_tag(
+                    encryption.nonce(i as u32, i == segments.len() - 1),
+                    Aad::empty(),
+                    &mut segment,
+                )
+                .unwrap();
+            encrypted.extend_from_slice(&segment);
+        }
+        encrypted
+    }
+
+    #[test]
+    fn lengths() {
+        assert_eq!(encrypted_len(0), 16);
+        assert_eq!(encrypted_len(SEGMENT_SIZE), ENCRYPTED_SEGMENT_SIZE);
+        assert_eq!(encrypted_len(SEGMENT_SIZE + 1), ENCRYPTED_SEGMENT_SIZE + 17);
+        for len in [0, 1, SEGMENT_SIZE, SEGMENT_SIZE + 1, 3 * SEGMENT_SIZE] {
+            assert_eq!(decrypted_len(encrypted_len(len)), len);
+        }
+        assert_eq!(
+            encrypted_offset(2 * SEGMENT_SIZE),
+            2 * ENCRYPTED_SEGMENT_SIZE
+        );
+    }
+
+    #[test]
+    fn decrypts_in_pieces_and_from_the_middle() {
+        let encryption = ObjectEncryption::new(ClientSideKey::new("test", [7; 32])).unwrap();
+        let data = (0..2 * SEGMENT_SIZE + 100)
+            .map(|i| i as u8)
+            .collect::<Vec<_>>();
+        let encrypted = encrypt(&encryption, &data);
+        assert_eq!(encrypted.len(), encrypted_len(data.len()));
+
+        let mut decryptor = Decryptor::new(encryption.clone(), 0, encrypted.len());
+        let mut decrypted = Vec::new();
+        for piece in encrypted.chunks(1000) {
+            decrypted.extend(decryptor.push(piece).unwrap());
+        }
+        assert!(decryptor.is_segment_boundary());
+        assert_eq!(decrypted, data);
+
+        let offset = encrypted_offset(SEGMENT_SIZE);
+        let mut decryptor = Decryptor::new(encryption.clone(), offset, encrypted.len());
+        assert_eq!(
+            decryptor.push(&encrypted[offset..]).unwrap(),
+            data[SEGMENT_SIZE..]
+        );
+
+        // Cutting off the last segment can't go unnoticed
+        let mut decryptor = Decryptor::new(encryption, 0, offset);
+        assert!(decryptor.push(&encrypted[..offset]).is_err());
+    }
+
+    #[test]
+    fn metadata() {
+        let key = ClientSideKey::new("2025", [1; 32]);
+        let encryption = ObjectEncryption::new(key.clone()).unwrap();
+        let metadata = encryption.metadata();
+        let read = ObjectEncryption::from_metadata(Some(&metadata), &key)
+            .unwrap()
+            .unwrap();
+        assert_eq!(read.nonce_prefix(), encryption.nonce_prefix());
+        assert!(
+            ObjectEncryption::from_metadata(Some(&metadata), &ClientSideKey::new("2026", [1; 32]))
+                .is_err()
+        );
+        assert!(
+            ObjectEncryption::from_metadata(None, &key)
+                .unwrap()
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,118 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use aws_sdk_s3::{
+    config::{
+        ConfigBag, Intercept, RuntimeComponents,
+        interceptors::BeforeDeserializationInterceptorContextRef,
+    },
+    error::{BoxError, SdkError},
+    operation::head_bucket::HeadBucketError,
+    primitives::{DateTime, DateTimeFormat},
+};
+use aws_smithy_async::time::TimeSource;
+use thiserror::Error;
+use time::{Duration, UtcDateTime};
+
+/// S3 rejects requests signed with a time that is further than this from its clock
+pub const MAX_CLOCK_SKEW: Duration = Duration::minutes(15);
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Error)]
+pub enum ClockSkewError {
+    #[error("Error requesting the bucket, without a response")]
+    Request(SdkError<HeadBucketError>),
+    #[error("The response didn't have a Date header")]
+    NoDate,
+    #[error("The Date header {0:?} couldn't be parsed")]
+    InvalidDate(String),
+}
+
+/// Saves the `Date` header of the response, and when it was received
+#[derive(Debug, Clone, Default)]
+struct DateInterceptor(Arc<Mutex<Option<(String, SystemTime)>>>);
+
+impl Intercept for DateInterceptor {
+    fn name(&self) -> &'static str {
+        "DateInterceptor"
+    }
+
+    fn read_before_deserialization(
+        &self,
+        context: &BeforeDeserializationInterceptorContextRef<'_>,
+        _runtime_components: &RuntimeComponents,
+        _cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        if let Some(date) = context.response().headers().get("date") {
+            *self.0.lock().unwrap() = Some((date.to_owned(), SystemTime::now()));
+        }
+        Ok(())
+    }
+}
+
+/// How far ahead S3's clock is of this computer's clock, found with the `Date` header of a `HeadBucket` response.
+/// The response is used even if the request fails, such as with `RequestTimeTooSkewed` because the clock is too far off.
+/// The `Date` header only has whole seconds, so the result can be off by up to a second.
+/// If it's more than [`MAX_CLOCK_SKEW`], requests will fail unless the client uses a [`SkewedTimeSource`].
+pub async fn measure_clock_skew(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+) -> Result<Duration, ClockSkewError> {
+    let interceptor = DateInterceptor::default();
+    let result = client
+        .head_bucket()
+        .bucket(bucket)
+        .customize()
+        .interceptor(interceptor.clone())
+        .send()
+        .await;
+    let Some((date, received)) = interceptor.0.lock().unwrap().take() else {
+        return Err(match result {
+            Err(e) => ClockSkewError::Request(e),
+            Ok(_) => ClockSkewError::NoDate,
+        });
+    };
+    let server_time = DateTime::from_str(&date, DateTimeFormat::HttpDate)
+        .ok()
+        .and_then(|date| SystemTime::try_from(date).ok())
+        .ok_or(ClockSkewError::InvalidDate(date))?;
+    Ok(UtcDateTime::from(server_time) - UtcDateTime::from(received))
+}
+
+/// The time of this computer's clock, corrected by the clock skew, so that requests are signed with S3's time.
+/// Give it to [`aws_sdk_s3::config::Builder::time_source`].
+#[derive(Debug, Clone, Copy)]
+pub struct SkewedTimeSource {
+    /// From [`measure_clock_skew`]
+    pub skew: Duration,
+}
+
+impl TimeSource for SkewedTimeSource {
+    fn now(&self) -> SystemTime {
+        (UtcDateTime::from(SystemTime::now()) + self.skew).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use aws_smithy_async::time::TimeSource;
+    use time::{Duration, UtcDateTime};
+
+    use super::SkewedTimeSource;
+
+    #[test]
+    fn adds_skew() {
+        let skewed = UtcDateTime::from(
+            SkewedTimeSource {
+                skew: Duration::minutes(-20),
+            }
+            .now(),
+        );
+        let difference = UtcDateTime::from(SystemTime::now()) - skewed;
+        assert!(difference >= Duration::minutes(20) && difference < Duration::minutes(21));
+    }
+}
@@ -0,0 +1,191 @@
+use std::{collections::HashMap, io, path::PathBuf};
+
+use async_compression::tokio::{
+    bufread::{GzipEncoder, ZstdEncoder},
+    write::{GzipDecoder, ZstdDecoder},
+};
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncWriteExt, BufReader},
+};
+
+use crate::UploadSrc;
+
+const COMPRESSION_METADATA: &str = "rcs3ud-compression";
+const ORIGINAL_SIZE_METADATA: &str = "rcs3ud-original-size";
+const COMPRESSED_SIZE_METADATA: &str = "rcs3ud-compressed-size";
+
+/// How to compress files before uploading them, see [`crate::UploadInput::compression`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Zstd,
+    /// Slower and compresses less than zstd, but can be decompressed by almost anything
+    Gzip,
+}
+
+impl Compression {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Zstd => "zstd",
+            Self::Gzip => "gzip",
+        }
+    }
+
+    /// The metadata that [`crate::download`] uses to decompress the object
+    pub(crate) fn metadata(
+        self,
+        original_size: usize,
+        compressed_size: usize,
+    ) -> HashMap<String, String> {
+        HashMap::from([
+            (COMPRESSION_METADATA.into(), self.name().into()),
+            (ORIGINAL_SIZE_METADATA.into(), original_size.to_string()),
+            (COMPRESSED_SIZE_METADATA.into(), compressed_size.to_string()),
+        ])
+    }
+
+    /// The compression of a downloaded object, or `None` if it wasn't compressed when it was uploaded.
+    /// Returns the name if the compression isn't known.
+    pub(crate) fn from_metadata(
+        metadata: Option<&HashMap<String, String>>,
+    ) -> Result<Option<Self>, String> {
+        match metadata.and_then(|metadata| metadata.get(COMPRESSION_METADATA)) {
+            None => Ok(None),
+            Some(name) => [Self::Zstd, Self::Gzip]
+                .into_iter()
+                .find(|compression| compression.name() == name)
+                .map(Some)
+                .ok_or_else(|| name.clone()),
+        }
+    }
+}
+
+/// The size of the file before it was compressed, if the object was compressed when it was uploaded
+pub(crate) fn original_size(metadata: Option<&HashMap<String, String>>) -> Option<usize> {
+    metadata?.get(ORIGINAL_SIZE_METADATA)?.parse().ok()
+}
+
+/// A compressed copy of an [`UploadSrc`] in the temporary directory, which is deleted when this is dropped
+pub(crate) struct CompressedFile {
+    pub(crate) path: PathBuf,
+    pub(crate) len: usize,
+}
+
+impl Drop for CompressedFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Compresses the part of the file that will be uploaded, reading and writing it a bit at a time.
+/// S3 needs to know the length of an object before it's uploaded, so it can't be compressed while it's being uploaded.
+pub(crate) async fn compress_to_temp_file(
+    src: &UploadSrc,
+    compression: Compression,
+) -> io::Result<CompressedFile> {
+    let reader = BufReader::new(src.reader().await?);
+    let mut compressed = CompressedFile {
+        path: std::env::temp_dir().join(format!(
+            "rcs3ud-{:016x}.{}",
+            fastrand::u64(..),
+            compression.name()
+        )),
+        len: 0,
+    };
+    let mut dest = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&compressed.path)
+        .await?;
+    let len = match compression {
+        Compression::Zstd => tokio::io::copy(&mut ZstdEncoder::new(reader), &mut dest).await?,
+        Compression::Gzip => tokio::io::copy(&mut GzipEncoder::new(reader), &mut dest).await?,
+    };
+    dest.flush().await?;
+    compressed.len = len as usize;
+    Ok(compressed)
+}
+
+/// Decompresses a downloaded object a piece at a time
+pub(crate) enum Decompressor {
+    Zstd(ZstdDecoder<Vec<u8>>),
+    Gzip(GzipDecoder<Vec<u8>>),
+}
+
+impl Decompressor {
+    pub(crate) fn new(compression: Compression) -> Self {
+        match compression {
+            Compression::Zstd => Self::Zstd(ZstdDecoder::new(Vec::new())),
+            Compression::Gzip => Self::Gzip(GzipDecoder::new(Vec::new())),
+        }
+    }
+
+    /// Decompresses the next piece of the object, returning the decompressed bytes that are ready
+    pub(crate) async fn push(&mut self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Zstd(decoder) => {
+                decoder.write_all(bytes).await?;
+                decoder.flush().await?;
+                Ok(std::mem::take(decoder.get_mut()))
+            }
+            Self::Gzip(decoder) => {
+                decoder.write_all(bytes).await?;
+                decoder.flush().await?;
+                Ok(std::mem::take(decoder.get_mut()))
+            }
+        }
+    }
+
+    /// Returns the rest of the decompressed bytes, or an error if the object ended too early
+    pub(crate) async fn finish(&mut self) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Zstd(decoder) => {
+                decoder.shutdown().await?;
+                Ok(std::mem::take(decoder.get_mut()))
+            }
+            Self::Gzip(decoder) => {
+                decoder.shutdown().await?;
+                Ok(std::mem::take(decoder.get_mut()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Compression, Decompressor, compress_to_temp_file};
+    use crate::{UploadData, UploadSrc};
+
+    #[tokio::test]
+    async fn round_trip() {
+        let data = b"abcdefgh".repeat(10_000);
+        let path = std::env::temp_dir().join(format!("rcs3ud-test-{:016x}", fastrand::u64(..)));
+        std::fs::write(&path, &data).unwrap();
+        for compression in [Compression::Zstd, Compression::Gzip] {
+            let src = UploadSrc {
+                data: UploadData::File(path.clone()),
+                offset: 8,
+                len: data.len() - 16,
+            };
+            let compressed = compress_to_temp_file(&src, compression).await.unwrap();
+            assert!(compressed.len < src.len / 10);
+            let metadata = compression.metadata(src.len, compressed.len);
+            assert_eq!(
+                Compression::from_metadata(Some(&metadata)),
+                Ok(Some(compression))
+            );
+            let bytes = std::fs::read(&compressed.path).unwrap();
+            let mut decompressor = Decompressor::new(compression);
+            let mut decompressed = Vec::new();
+            for chunk in bytes.chunks(100) {
+                decompressed.extend(decompressor.push(chunk).await.unwrap());
+            }
+            decompressed.extend(decompressor.finish().await.unwrap());
+            assert_eq!(decompressed, &data[8..data.len() - 8]);
+            let temp_path = compressed.path.clone();
+            drop(compressed);
+            assert!(!temp_path.exists());
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+}
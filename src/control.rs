@@ -0,0 +1,174 @@
+use std::{sync::Arc, time::Duration};
+
+use thiserror::Error;
+use time::UtcDateTime;
+use tokio::{sync::watch, time::timeout};
+use tokio_util::sync::CancellationToken;
+
+use crate::{AmountLimiter, AmountReservation};
+
+/// The operation stopped before it finished, without failing.
+/// Operations that save their progress can be resumed from where they stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum Cancelled {
+    /// With [`ControlHandle::cancel`]
+    #[error("The operation was cancelled")]
+    Requested,
+    /// The deadline set with [`ControlHandle::with_deadline`] passed
+    #[error("The operation's deadline passed")]
+    DeadlineExceeded,
+}
+
+/// Lets an application pause, resume, or cancel an operation while it is running.
+/// Keep a clone of the handle and give the other one to the operation's input.
+///
+/// Pausing stops the operation from starting new requests, but requests that already started are finished.
+/// Cancelling stops the operation as soon as possible, including requests that are in progress.
+#[derive(Debug, Clone)]
+pub struct ControlHandle {
+    paused: Arc<watch::Sender<bool>>,
+    cancellation_token: CancellationToken,
+    deadline: Option<UtcDateTime>,
+}
+
+impl Default for ControlHandle {
+    fn default() -> Self {
+        Self {
+            paused: Arc::new(watch::channel(false).0),
+            cancellation_token: CancellationToken::new(),
+            deadline: None,
+        }
+    }
+}
+
+impl ControlHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn pause(&self) {
+        self.paused.send_replace(true);
+    }
+
+    pub fn resume(&self) {
+        self.paused.send_replace(false);
+    }
+
+    /// Stops the operation like [`ControlHandle::cancel`] when `deadline` passes, such as so that a backup doesn't run into business hours.
+    /// It fails with [`Cancelled::DeadlineExceeded`] instead of [`Cancelled::Requested`], after saving its progress.
+    pub fn with_deadline(self, deadline: UtcDateTime) -> Self {
+        Self {
+            deadline: Some(deadline),
+            ..self
+        }
+    }
+
+    /// Cancelling can't be undone
+    pub fn cancel(&self) {
+        self.cancellation_token.cancel();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    /// Also true once the deadline passed
+    pub fn is_cancelled(&self) -> bool {
+        self.cancellation_token.is_cancelled() || self.time_left() == Some(Duration::ZERO)
+    }
+
+    /// How long until the deadline, or `None` if there isn't one
+    fn time_left(&self) -> Option<Duration> {
+        self.deadline.map(|deadline| {
+            (deadline - UtcDateTime::now())
+                .try_into()
+                .unwrap_or_default()
+        })
+    }
+
+    /// A handle that is paused with this one and cancelled when this one is, but can also be cancelled on its own
+    pub(crate) fn child(&self) -> Self {
+        Self {
+            paused: self.paused.clone(),
+            cancellation_token: self.cancellation_token.child_token(),
+            deadline: self.deadline,
+        }
+    }
+
+    /// Waits until the operation isn't paused
+    pub(crate) async fn wait_while_paused(&self) -> Result<(), Cancelled> {
+        let mut paused = self.paused.subscribe();
+        self.run(paused.wait_for(|paused| !paused))
+            .await?
+            // The sender can't be dropped because we have it
+            .unwrap();
+        Ok(())
+    }
+
+    /// Runs the future until it finishes, the operation is cancelled, or the deadline passes
+    pub(crate) async fn run<F: Future>(&self, future: F) -> Result<F::Output, Cancelled> {
+        let future = self.cancellation_token.run_until_cancelled(future);
+        match self.time_left() {
+            Some(time_left) => timeout(time_left, future)
+                .await
+                .map_err(|_| Cancelled::DeadlineExceeded)?,
+            None => future.await,
+        }
+        .ok_or(Cancelled::Requested)
+    }
+
+    /// Reserves the amount, or removes it from the limiter if the operation is cancelled while waiting
+    pub(crate) async fn reserve<'a>(
+        &self,
+        amount_limiter: &'a dyn AmountLimiter,
+        len: usize,
+        id: &'a str,
+    ) -> Result<Box<dyn AmountReservation + 'a>, Cancelled> {
+        match self.run(amount_limiter.reserve(len, id)).await {
+            Ok(reservation) => Ok(reservation),
+            Err(cancelled) => {
+                if let Some(reservation) = amount_limiter.get_reservation(id).await {
+                    reservation.cancel().await;
+                }
+                Err(cancelled)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use time::UtcDateTime;
+
+    use super::{Cancelled, ControlHandle};
+
+    #[test]
+    fn clones_control_the_same_operation() {
+        let control = ControlHandle::new();
+        let operation = control.clone();
+        control.pause();
+        assert!(operation.is_paused());
+        control.resume();
+        assert!(!operation.is_paused());
+        control.cancel();
+        assert!(operation.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn stops_at_deadline() {
+        let control =
+            ControlHandle::new().with_deadline(UtcDateTime::now() + Duration::from_millis(50));
+        assert_eq!(control.run(async { 1 }).await, Ok(1));
+        assert!(!control.is_cancelled());
+        assert_eq!(
+            control.run(std::future::pending::<()>()).await,
+            Err(Cancelled::DeadlineExceeded)
+        );
+        assert!(control.is_cancelled());
+        let control = ControlHandle::new();
+        control.cancel();
+        assert_eq!(control.run(async { 1 }).await, Err(Cancelled::Requested));
+    }
+}
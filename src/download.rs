@@ -1,64 +1,143 @@
 use std::{
-    io,
-    num::TryFromIntError,
-    time::{Duration, SystemTime},
+    io::{self, SeekFrom},
+    num::{NonZeroU16, NonZeroU32, NonZeroUsize, TryFromIntError},
+    time::{Duration, Instant, SystemTime},
 };
 
-use crate::{AmountLimiter, retry::KeepRetryingExt};
+use crate::{
+    AmountLimiter, AmountReservation, Cancelled, Capabilities, ClientSideEncryptionError,
+    ClientSideKey, Compression, ControlHandle, CustomerKey, Degradation, ExpectedRestoreDuration,
+    ProgressStore, ProgressStoreError, RestoreError, RestoreEvent, RestoreInput, RestoreStage,
+    RetriesExhausted, RetryPolicy, Retrying, SaveProgressPolicy, TransferProgress,
+    WaitForRestoreStrategy,
+    client_side_encryption::{
+        Decryptor, ENCRYPTED_SEGMENT_SIZE, ObjectEncryption, decrypted_len, encrypted_offset,
+    },
+    compression::{Decompressor, original_size},
+    progress_store::run_saving_progress,
+    restore_step,
+    retry::{KeepRetryingExt, MaybeRetryable},
+    transfer_progress::{ProgressThrottle, RateMeter},
+};
 use aws_sdk_s3::{
     error::SdkError,
-    operation::{
-        get_object::GetObjectError, head_object::HeadObjectError,
-        restore_object::RestoreObjectError,
-    },
-    primitives::ByteStreamError,
-    types::{GlacierJobParameters, RestoreRequest, Tier},
+    operation::{get_object::GetObjectError, head_object::HeadObjectError},
+    primitives::{ByteStreamError, DateTime},
+    types::Tier,
 };
+use bytes::Bytes;
 use serde::{Deserialize, Serialize};
-use sipper::{Sipper, Straw, sipper};
+use sipper::{Sender, Sipper, Straw, sipper};
 use thiserror::Error;
-use tokio::{io::AsyncWriteExt, time::sleep};
+use time::UtcDateTime;
+use tokio::{
+    fs::File,
+    io::{AsyncSeekExt, AsyncWrite, AsyncWriteExt},
+};
 
-use crate::maybe_retryable_sdk_error::IntoMaybeRetryable;
+use crate::{maybe_retryable_sdk_error::IntoMaybeRetryable, save_policy::SaveTracker};
 
+#[derive(Clone)]
 pub struct DownloadColdInput {
     pub tier: Tier,
+    /// See [`RestoreInput::adjust_incompatible_tier`]
+    pub adjust_incompatible_tier: bool,
+    /// See [`RestoreInput::days`]. If the restored copy expires before the download finishes, the object is restored again.
+    pub restore_days: NonZeroU16,
     pub wait_for_restore_stratey: WaitForRestoreStrategy,
 }
 
+#[derive(Clone)]
 pub enum DownloadStrategy {
     /// For storage classes that don't need a restore, such as `STANDARD`.
     Warm,
     Cold(DownloadColdInput),
 }
 
-pub enum WaitForRestoreStrategy {
-    /// Polls the object until it's restored.
-    ///
-    /// # Warning
-    /// Using a short durations, such as duration shorter than 30 minutes, will result in high costs.
-    /// Even with a 30 minute interval, restoring will cost $0.0048 if it takes 48 hours.
-    PollGet(Duration),
+/// What to do when S3 doesn't say how long the object is.
+/// Some S3-compatible endpoints leave out the `Content-Length` header.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MissingContentLength {
+    /// Fail with [`DownloadError::NoContentLength`]
+    #[default]
+    Fail,
+    /// Download with one request until the end of the body, with [`TransferProgress::total`] as `None`.
+    /// [`download_chunked`] also does this, since it needs the length to request the chunks.
+    /// If there is an amount limiter, nothing is reserved before downloading and the downloaded length is counted afterwards
+    /// with [`crate::AmountReservation::mark_complete_with_len`].
+    StreamToEof,
 }
 
+#[derive(Debug, Clone, Copy)]
 pub struct S3Src<'a> {
     pub bucket: &'a str,
     pub object_key: &'a str,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct RestoreInitiatedProgress {
-    /// Contains the time right after the restore request was completed, or the time after the last head object request was completed.
-    last_checked: SystemTime,
+/// Where [`download`] writes the object
+pub enum DownloadDest<'a> {
+    /// When resuming a download, the file must be opened without truncating it.
+    /// [`download`] starts over if the file is shorter than the saved progress.
+    File(&'a mut File),
+    /// Streams the object into anything else, such as a pipe or a hash, without touching the disk.
+    /// A writer can't go back, so the download always starts from the beginning, even with saved progress.
+    /// It isn't shut down at the end, in case more is written to it.
+    Writer(&'a mut (dyn AsyncWrite + Unpin + Send)),
 }
 
-#[derive(Debug, Default, Clone, Serialize, Deserialize)]
-pub enum DownloadStage {
-    #[default]
-    WillInitiateRestore,
-    RestoreInitiated(RestoreInitiatedProgress),
-    /// The file is ready to download or downloading
-    RestoreComplete,
+impl<'a> From<&'a mut File> for DownloadDest<'a> {
+    fn from(file: &'a mut File) -> Self {
+        Self::File(file)
+    }
+}
+
+impl DownloadDest<'_> {
+    async fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match self {
+            Self::File(file) => file.write_all(bytes).await,
+            Self::Writer(writer) => writer.write_all(bytes).await,
+        }
+    }
+
+    /// How many bytes a previous download could have written
+    async fn len(&mut self) -> io::Result<u64> {
+        match self {
+            Self::File(file) => Ok(file.metadata().await?.len()),
+            Self::Writer(_) => Ok(0),
+        }
+    }
+
+    /// Where the next byte is written. Always 0 for a writer.
+    async fn position(&mut self) -> io::Result<u64> {
+        match self {
+            Self::File(file) => file.stream_position().await,
+            Self::Writer(_) => Ok(0),
+        }
+    }
+
+    /// Only called with the length of the previous download, which is always 0 for a writer
+    async fn seek(&mut self, pos: u64) -> io::Result<()> {
+        match self {
+            Self::File(file) => file.seek(SeekFrom::Start(pos)).await.map(|_| ()),
+            Self::Writer(_) => Ok(()),
+        }
+    }
+
+    /// Removes the bytes after `len`, such as when starting over with an object that is shorter
+    async fn set_len(&mut self, len: u64) -> io::Result<()> {
+        match self {
+            Self::File(file) => file.set_len(len).await,
+            Self::Writer(_) => Ok(()),
+        }
+    }
+
+    /// Makes sure the written bytes are saved before saving progress that says they are
+    async fn sync_data(&mut self) -> io::Result<()> {
+        match self {
+            Self::File(file) => file.sync_data().await,
+            Self::Writer(writer) => writer.flush().await,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -71,19 +150,127 @@ pub struct SavedReservation {
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct SavedProgress {
     reservation: Option<SavedReservation>,
-    stage: DownloadStage,
+    pub(crate) stage: RestoreStage,
+    #[serde(default)]
+    len: Option<usize>,
+    /// Bytes that have been written to the file. An interrupted download resumes from here.
+    #[serde(default)]
+    downloaded: usize,
+    /// The object is being decrypted, so `len` and `downloaded` are of the decrypted file
+    #[serde(default)]
+    client_side_encrypted: bool,
+    /// The ETag of the object that `downloaded` is of, so that a resumed download doesn't append another object to it
+    #[serde(default)]
+    e_tag: Option<String>,
+}
+
+impl SavedProgress {
+    /// Forgets the bytes that were downloaded, so that the download starts over
+    fn restart(&mut self) {
+        self.len = None;
+        self.downloaded = 0;
+        self.client_side_encrypted = false;
+        self.e_tag = None;
+    }
+
+    /// Records the ETag of the object, or starts over if the object was replaced since the saved bytes were downloaded.
+    /// Returns `true` if it started over.
+    fn check_e_tag(&mut self, e_tag: Option<&str>) -> bool {
+        let changed = self.downloaded > 0
+            && matches!((&self.e_tag, e_tag), (Some(saved), Some(e_tag)) if saved != e_tag);
+        if changed {
+            self.restart();
+        }
+        if self.e_tag.is_none() {
+            self.e_tag = e_tag.map(Into::into);
+        }
+        changed
+    }
+}
+
+/// Removes the bytes of the previous object, before saving progress that says there aren't any.
+/// Bytes before `file_start`, where the object starts in the file, are kept.
+async fn restart_file(dest: &mut DownloadDest<'_>, file_start: u64) -> Result<(), DownloadError> {
+    dest.seek(file_start)
+        .await
+        .map_err(DownloadError::WriteError)?;
+    dest.set_len(file_start)
+        .await
+        .map_err(DownloadError::WriteError)
+}
+
+/// S3 responds with 412 Precondition Failed when the object doesn't match `If-Match`
+fn is_precondition_failed<E>(e: &SdkError<E>) -> bool {
+    e.raw_response()
+        .is_some_and(|response| response.status().as_u16() == 412)
 }
 
 pub struct DownloadInput<'a> {
     pub client: &'a aws_sdk_s3::Client,
     pub src: S3Src<'a>,
-    pub dest: &'a mut tokio::fs::File,
+    pub dest: DownloadDest<'a>,
     pub strategy: DownloadStrategy,
-    pub retry_interval: Duration,
+    /// The key that the object was uploaded with, if it was uploaded with [`crate::Encryption::CustomerKey`]
+    pub customer_key: Option<CustomerKey>,
+    /// Decrypts objects that were uploaded with [`crate::UploadInput::client_side_encryption`].
+    /// Objects that weren't encrypted are downloaded as they are.
+    pub client_side_key: Option<ClientSideKey>,
+    /// Decompresses objects that were uploaded with [`crate::UploadInput::compression`].
+    /// Objects that weren't compressed are downloaded as they are.
+    /// A compressed object is downloaded with one request, even with [`download_chunked`], and starts over if the download is interrupted,
+    /// since it can only be decompressed from the start.
+    pub decompress: bool,
+    /// Without restore, a [`DownloadStrategy::Cold`] download is downloaded like a warm one. See [`crate::probe_capabilities`].
+    pub capabilities: Capabilities,
+    pub retry_policy: RetryPolicy,
     /// It is recommended to save progress when downloading cold objects.
     /// Otherwise you can set this to `Default::default()`.
     pub saved_progress: SavedProgress,
+    /// Loads the progress when the download starts, instead of using [`DownloadInput::saved_progress`] if there is saved progress,
+    /// and saves it on every [`DownloadEvent::UpdateSavedProgress`], so that the events don't need to be saved.
+    pub progress_store: Option<Box<dyn ProgressStore<SavedProgress>>>,
     pub amount_limiter: Option<Box<dyn AmountLimiter>>,
+    /// Minimum time between [`DownloadEvent::DownloadProgress`] events, so that a slow consumer doesn't hold back the download.
+    /// Progress in between is coalesced into the next event, and the final progress is always sent.
+    /// Other events, such as [`DownloadEvent::UpdateSavedProgress`], are never coalesced.
+    /// `None` sends an event for every piece of data received and written.
+    /// [`DownloadProgress::Restoring`] is sent before each restore status check, and isn't throttled.
+    pub progress_interval: Option<Duration>,
+    /// How often [`download_chunked`] saves progress between chunks.
+    /// [`download`] only uses [`SaveProgressPolicy::every`], see [`WHOLE_SAVE_INTERVAL`].
+    pub save_policy: SaveProgressPolicy,
+    /// When a range of [`download_chunked`] fails this many times in a row, it is split in half and the first half is tried on its own.
+    /// This keeps going until the range is [`MIN_SPLIT_RANGE_SIZE`], so a bad region doesn't keep the whole chunk from downloading.
+    /// `None` retries the same range every time.
+    pub split_range_after: Option<NonZeroU32>,
+    pub missing_content_length: MissingContentLength,
+    /// Pauses or cancels the download. Pausing takes effect before the next request, such as the next chunk of [`download_chunked`].
+    /// When the download is cancelled after the amount was reserved, the reservation is kept so that the download can be resumed with the saved progress.
+    pub control: ControlHandle,
+}
+
+/// How often [`download`] saves progress if [`SaveProgressPolicy::every`] is `None`
+pub const WHOLE_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// [`DownloadInput::split_range_after`] doesn't split ranges smaller than this
+pub const MIN_SPLIT_RANGE_SIZE: usize = 1024 * 1024;
+
+/// The end of the first half of the range from `start` to `end`, or `None` if it is too small to split.
+/// The first half is a multiple of `align`, so that encrypted segments aren't split.
+fn split_range(start: usize, end: usize, align: usize) -> Option<usize> {
+    let len = end - start;
+    let first = (len / 2).max(MIN_SPLIT_RANGE_SIZE).next_multiple_of(align);
+    (first < len).then(|| start + first)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DownloadOutput {
+    /// The length of the downloaded file, after it was decrypted and decompressed.
+    /// This includes the bytes that were written before the download was resumed.
+    pub bytes_written: usize,
+    /// When the object was uploaded. Not known if the download was already finished when it was resumed.
+    pub last_modified: Option<SystemTime>,
+    pub e_tag: Option<String>,
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -97,286 +284,1035 @@ pub enum DownloadError {
     GetObjectError(SdkError<GetObjectError>),
     #[error("Error while downloading the object")]
     DownloadStreamError(ByteStreamError),
-    #[error("Error writing to the file")]
+    #[error("Error writing to the file or writer")]
     WriteError(io::Error),
     #[error("Error restoring the object")]
-    RestoreError(SdkError<RestoreObjectError>),
+    Restore(RestoreError),
     #[error("Expected object to be restoring but restored, but it isn't")]
     NotRestoringOrRestored,
-    #[error("Could not parse the value of x-amz-restore")]
-    UnknownRestoreString,
-    #[error("Error checking the restore status of the object")]
+    #[error("Error getting the length of the object")]
     HeadError(SdkError<HeadObjectError>),
+    /// `expected` is where the object ends in the file
+    #[error("The file is only {actual} bytes after downloading, but it should be {expected} bytes")]
+    FileLengthMismatch { expected: u64, actual: u64 },
+    #[error("Error decrypting the object")]
+    ClientSideEncryption(ClientSideEncryptionError),
+    /// Resuming would write decrypted data after encrypted data, or the other way around
+    #[error(
+        "The object's client-side encryption or the key changed since the download was started"
+    )]
+    ClientSideEncryptionChanged,
+    #[error("The object was compressed with {0}, which can't be decompressed")]
+    UnknownCompression(String),
+    #[error("Error decompressing the object")]
+    Decompress(io::Error),
+    /// A compressed object can't be resumed, so the object must have changed since the download was started
+    #[error("The object was compressed since the download was started")]
+    CompressionChanged,
+    #[error("Error loading or saving the progress")]
+    ProgressStore(#[from] ProgressStoreError),
+    #[error("Gave up retrying")]
+    RetriesExhausted(#[from] RetriesExhausted),
+    #[error("The download was cancelled")]
+    Cancelled(#[from] Cancelled),
 }
 
+/// Where the download is, so that one progress bar can show a cold download from the restore request to the end
 #[derive(Debug, Clone, Copy)]
-pub struct DownloadProgress {
-    pub downloaded_from_s3: usize,
-    pub written_to_file: usize,
-    pub total: usize,
+pub enum DownloadProgress {
+    /// Waiting for the cold object to be restored
+    Restoring {
+        /// Time since the restore request
+        elapsed: Duration,
+        expected: Option<ExpectedRestoreDuration>,
+    },
+    Transferring {
+        /// [`TransferProgress::transferred`] is the bytes written to the file, after they were decrypted and decompressed
+        transfer: TransferProgress,
+        /// Bytes received from S3, which are ahead of the file while they are being written
+        downloaded_from_s3: usize,
+    },
+    /// Syncing the file and checking that its length matches the object
+    Verifying,
+}
+
+/// The bytes that a download got to, for [`DownloadProgress::Transferring`]
+#[derive(Debug, Clone, Copy)]
+struct DownloadCounts {
+    downloaded_from_s3: usize,
+    written_to_file: usize,
+    total: Option<usize>,
+}
+
+impl DownloadCounts {
+    /// The progress event, with the speeds at `now`
+    fn event(self, rate_meter: &mut RateMeter, now: Instant) -> DownloadEvent {
+        DownloadEvent::DownloadProgress(DownloadProgress::Transferring {
+            transfer: rate_meter.progress(self.written_to_file, self.total, now),
+            downloaded_from_s3: self.downloaded_from_s3,
+        })
+    }
 }
 
 #[derive(Debug)]
 pub enum DownloadEvent {
+    /// A feature was skipped because of [`DownloadInput::capabilities`]
+    Degraded(Degradation),
     GettingObjectLen,
     ReservingDownloadAmount,
-    CheckObjectLenError(SdkError<HeadObjectError>),
-    DownloadError(SdkError<GetObjectError>),
+    /// Other operations are before this one in the [`DownloadInput::amount_limiter`]'s queue, or the limit was reached.
+    /// The limiter expects to let the download through at this time.
+    WaitingForAmount(UtcDateTime),
+    CheckObjectLenError(Retrying<SdkError<HeadObjectError>>),
+    DownloadError(Retrying<SdkError<GetObjectError>>),
+    /// The connection failed in the middle of downloading a chunk. The chunk will be downloaded again.
+    DownloadStreamError(Retrying<ByteStreamError>),
+    /// The range kept failing, so only the range from `start` to `end` will be tried next
+    SplitRange {
+        start: usize,
+        end: usize,
+    },
     DownloadProgress(DownloadProgress),
-    RestoreError(SdkError<RestoreObjectError>),
-    RestoreInitiated,
-    /// Restore status was checked, and restoring is in progress
-    NotYetRestored,
-    /// The object is restored and available to download
-    RestoreComplete,
-    CheckStatusError(SdkError<HeadObjectError>),
+    /// The object was replaced since the saved progress was downloaded, so the download starts over
+    ObjectChanged,
+    RestoreEvent(RestoreEvent),
+    /// The restored copy expired before the download finished, so the object will be restored again.
+    /// [`download_chunked`] keeps the chunks that were already downloaded.
+    RestoreExpired,
     UpdateSavedProgress(SavedProgress),
     MarkingReservationComplete,
+    /// Waiting for [`ControlHandle::resume`] before the next request
+    Paused,
+    /// Sent last when the download is cancelled
+    Cancelled,
+}
+
+/// Waits while the download is paused
+async fn wait_while_paused(
+    sender: &mut Sender<DownloadEvent>,
+    control: &ControlHandle,
+) -> Result<(), Cancelled> {
+    if control.is_paused() {
+        sender.send(DownloadEvent::Paused).await;
+    }
+    control.wait_while_paused().await
+}
+
+fn download_warm(
+    input: &mut DownloadInput<'_>,
+    progress: &mut SavedProgress,
+    file_start: u64,
+    chunk_size: Option<NonZeroUsize>,
+    reservation: Option<&dyn AmountReservation>,
+) -> impl Straw<DownloadOutput, DownloadEvent, DownloadError> {
+    sipper(async move |sender| match chunk_size {
+        None => {
+            download_whole(input, progress, file_start, reservation)
+                .run(sender)
+                .await
+        }
+        Some(chunk_size) => {
+            download_ranges(input, progress, file_start, chunk_size, reservation)
+                .run(sender)
+                .await
+        }
+    })
 }
 
-fn download_warm(input: &mut DownloadInput<'_>) -> impl Straw<(), DownloadEvent, DownloadError> {
+/// Downloads the object with one request.
+/// Progress is saved every [`SaveProgressPolicy::every`], or [`WHOLE_SAVE_INTERVAL`] if it's `None`,
+/// so that a restarted download continues with a ranged request from the last saved byte.
+fn download_whole(
+    input: &mut DownloadInput<'_>,
+    progress: &mut SavedProgress,
+    file_start: u64,
+    reservation: Option<&dyn AmountReservation>,
+) -> impl Straw<DownloadOutput, DownloadEvent, DownloadError> {
     sipper(async move |mut sender| {
-        let mut output = (async || {
+        if progress.downloaded > 0 {
+            let file_len = input.dest.len().await.map_err(DownloadError::WriteError)?;
+            if file_len < file_start + progress.downloaded as u64 {
+                // The file was truncated or replaced, so the saved bytes aren't there anymore
+                progress.downloaded = 0;
+                progress.len = None;
+            }
+            if progress.len == Some(progress.downloaded) {
+                return Ok(DownloadOutput {
+                    bytes_written: progress.downloaded,
+                    ..Default::default()
+                });
+            }
             input
-                .client
-                .get_object()
-                .bucket(input.src.bucket)
-                .key(input.src.object_key)
-                .send()
+                .dest
+                .seek(file_start + progress.downloaded as u64)
                 .await
-                .map_err(|e| e.into_maybe_retryable().map(DownloadError::GetObjectError))
-        })
-        .keep_retrying(input.retry_interval)
-        .with(DownloadEvent::DownloadError)
-        .run(sender.clone())
-        .await?;
-        let mut progress = DownloadProgress {
-            total: output
-                .content_length
-                .ok_or(DownloadError::NoContentLength)?
-                .try_into()
-                .map_err(DownloadError::ContentLengthConversion)?,
-            downloaded_from_s3: 0,
-            written_to_file: 0,
+                .map_err(DownloadError::WriteError)?;
+        }
+        let (start, range_start, mut output) = loop {
+            wait_while_paused(&mut sender, &input.control).await?;
+            let start = progress.downloaded;
+            // An encrypted object is resumed from the first segment that wasn't written
+            let range_start = if progress.client_side_encrypted {
+                encrypted_offset(start)
+            } else {
+                start
+            };
+            // Only the rest of the same object is appended to the saved bytes
+            let if_match = progress.e_tag.clone().filter(|_| start > 0);
+            let result = (async || {
+                input
+                    .control
+                    .run(
+                        input
+                            .client
+                            .get_object()
+                            .bucket(input.src.bucket)
+                            .key(input.src.object_key)
+                            .set_sse_customer_algorithm(
+                                input.customer_key.as_ref().map(CustomerKey::algorithm),
+                            )
+                            .set_sse_customer_key(input.customer_key.as_ref().map(CustomerKey::key))
+                            .set_sse_customer_key_md5(
+                                input.customer_key.as_ref().map(CustomerKey::key_md5),
+                            )
+                            .set_range((range_start > 0).then(|| format!("bytes={range_start}-")))
+                            .set_if_match(if_match.clone())
+                            .send(),
+                    )
+                    .await
+                    .map_err(|e| MaybeRetryable::NotRetryable(e.into()))?
+                    .map_err(|e| e.into_maybe_retryable().map(DownloadError::GetObjectError))
+            })
+            .keep_retrying(input.retry_policy)
+            .with(DownloadEvent::DownloadError)
+            .run(sender.clone())
+            .await;
+            // Endpoints that ignore `If-Match` still return the ETag of the new object
+            let changed = match &result {
+                Err(DownloadError::GetObjectError(e)) => start > 0 && is_precondition_failed(e),
+                Err(_) => false,
+                Ok(output) => progress.check_e_tag(output.e_tag()),
+            };
+            if !changed {
+                break (start, range_start, result?);
+            }
+            progress.restart();
+            sender.send(DownloadEvent::ObjectChanged).await;
+            restart_file(&mut input.dest, file_start).await?;
+            sender
+                .send(DownloadEvent::UpdateSavedProgress(progress.clone()))
+                .await;
+        };
+        let mut download_output = DownloadOutput {
+            bytes_written: 0,
+            last_modified: last_modified(output.last_modified()),
+            e_tag: output.e_tag.clone(),
+        };
+        let content_length = match output.content_length {
+            Some(content_length) => Some(
+                usize::try_from(content_length).map_err(DownloadError::ContentLengthConversion)?,
+            ),
+            None if input.missing_content_length == MissingContentLength::StreamToEof => None,
+            None => Err(DownloadError::NoContentLength)?,
+        };
+        let encryption = match &input.client_side_key {
+            Some(key) => ObjectEncryption::from_metadata(output.metadata(), key)
+                .map_err(DownloadError::ClientSideEncryption)?,
+            None => None,
+        };
+        if start > 0 && encryption.is_some() != progress.client_side_encrypted {
+            Err(DownloadError::ClientSideEncryptionChanged)?;
+        }
+        progress.client_side_encrypted = encryption.is_some();
+        let compression = if input.decompress {
+            Compression::from_metadata(output.metadata())
+                .map_err(DownloadError::UnknownCompression)?
+        } else {
+            None
+        };
+        if start > 0 && compression.is_some() {
+            Err(DownloadError::CompressionChanged)?;
+        }
+        let mut decompressor = compression.map(Decompressor::new);
+        let mut decryptor = match encryption {
+            Some(encryption) => {
+                // The last segment can't be found without the length
+                let content_length = content_length.ok_or(DownloadError::NoContentLength)?;
+                Some(Decryptor::new(
+                    encryption,
+                    range_start,
+                    range_start + content_length,
+                ))
+            }
+            None => None,
         };
-        while let Some(bytes) = output
+        progress.len = if decompressor.is_some() {
+            original_size(output.metadata())
+        } else {
+            content_length.map(|content_length| {
+                let len = range_start + content_length;
+                if decryptor.is_some() {
+                    decrypted_len(len)
+                } else {
+                    len
+                }
+            })
+        };
+        let mut download_progress = DownloadCounts {
+            total: progress.len,
+            downloaded_from_s3: start,
+            written_to_file: start,
+        };
+        let mut rate_meter = RateMeter::new(start, Instant::now());
+        let mut throttle = ProgressThrottle::new(input.progress_interval);
+        let mut save_tracker = SaveTracker::new(
+            SaveProgressPolicy {
+                every_chunks: None,
+                every: Some(input.save_policy.every.unwrap_or(WHOLE_SAVE_INTERVAL)),
+            },
+            Instant::now(),
+        );
+        let result: Result<(), DownloadError> = async {
+            while let Some(bytes) = input
+                .control
+                .run(output.body.try_next())
+                .await?
+                .map_err(DownloadError::DownloadStreamError)?
+            {
+                download_progress.downloaded_from_s3 += bytes.len();
+                let now = Instant::now();
+                if throttle.update(now) {
+                    sender
+                        .send(download_progress.event(&mut rate_meter, now))
+                        .await;
+                }
+                let bytes = match &mut decryptor {
+                    // Only whole segments are decrypted, so the saved progress is always at the start of a segment
+                    Some(decryptor) => Bytes::from(
+                        decryptor
+                            .push(&bytes)
+                            .map_err(DownloadError::ClientSideEncryption)?,
+                    ),
+                    None => bytes,
+                };
+                let bytes = match &mut decompressor {
+                    Some(decompressor) => Bytes::from(
+                        decompressor
+                            .push(&bytes)
+                            .await
+                            .map_err(DownloadError::Decompress)?,
+                    ),
+                    None => bytes,
+                };
+                input
+                    .dest
+                    .write_all(&bytes)
+                    .await
+                    .map_err(DownloadError::WriteError)?;
+                download_progress.written_to_file += bytes.len();
+                let now = Instant::now();
+                if throttle.update(now) {
+                    sender
+                        .send(download_progress.event(&mut rate_meter, now))
+                        .await;
+                }
+                // A compressed object can't be resumed, so there's no point in saving progress
+                if decompressor.is_none() && save_tracker.chunk_done(Instant::now(), false) {
+                    // Make sure the bytes are actually on the disk before saving progress that says they are
+                    input
+                        .dest
+                        .sync_data()
+                        .await
+                        .map_err(DownloadError::WriteError)?;
+                    progress.downloaded = download_progress.written_to_file;
+                    sender
+                        .send(DownloadEvent::UpdateSavedProgress(progress.clone()))
+                        .await;
+                }
+            }
+            Ok(())
+        }
+        .await;
+        if result.is_err()
+            && let Some(reservation) = reservation
+        {
+            // The bytes after the saved progress will be downloaded again when the download is resumed
+            reservation
+                .record_partial(
+                    download_progress
+                        .downloaded_from_s3
+                        .saturating_sub(progress.downloaded),
+                )
+                .await;
+        }
+        result?;
+        if decryptor
+            .as_ref()
+            .is_some_and(|decryptor| !decryptor.is_segment_boundary())
+        {
+            Err(DownloadError::ClientSideEncryption(
+                ClientSideEncryptionError::Truncated,
+            ))?;
+        }
+        if let Some(decompressor) = &mut decompressor {
+            let bytes = decompressor
+                .finish()
+                .await
+                .map_err(DownloadError::Decompress)?;
+            input
+                .dest
+                .write_all(&bytes)
+                .await
+                .map_err(DownloadError::WriteError)?;
+            download_progress.written_to_file += bytes.len();
+        }
+        progress.downloaded = download_progress.written_to_file;
+        progress.len = Some(progress.downloaded);
+        if throttle.pending {
+            sender
+                .send(download_progress.event(&mut rate_meter, Instant::now()))
+                .await;
+        }
+        download_output.bytes_written = progress.downloaded;
+        Ok(download_output)
+    })
+}
+
+/// Errors that cause a chunk to be downloaded again
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Error)]
+enum RangeRetry {
+    #[error("Error creating the download request")]
+    GetObject(SdkError<GetObjectError>),
+    #[error("Error while downloading the object")]
+    Stream(ByteStreamError),
+}
+
+struct RangeResponse {
+    bytes: Bytes,
+    last_modified: Option<SystemTime>,
+    e_tag: Option<String>,
+}
+
+/// Downloads the bytes from `start` to `end`
+async fn get_range(
+    input: &DownloadInput<'_>,
+    start: usize,
+    end: usize,
+    if_match: Option<&str>,
+) -> Result<RangeResponse, MaybeRetryable<DownloadError, RangeRetry>> {
+    let output = input
+        .client
+        .get_object()
+        .bucket(input.src.bucket)
+        .key(input.src.object_key)
+        .set_sse_customer_algorithm(input.customer_key.as_ref().map(CustomerKey::algorithm))
+        .set_sse_customer_key(input.customer_key.as_ref().map(CustomerKey::key))
+        .set_sse_customer_key_md5(input.customer_key.as_ref().map(CustomerKey::key_md5))
+        .range(format!("bytes={}-{}", start, end - 1))
+        .set_if_match(if_match.map(Into::into))
+        .send()
+        .await
+        .map_err(|e| match e.into_maybe_retryable() {
+            MaybeRetryable::Retryable(e) => MaybeRetryable::Retryable(RangeRetry::GetObject(e)),
+            MaybeRetryable::NotRetryable(e) => {
+                MaybeRetryable::NotRetryable(DownloadError::GetObjectError(e))
+            }
+        })?;
+    Ok(RangeResponse {
+        last_modified: last_modified(output.last_modified()),
+        e_tag: output.e_tag,
+        bytes: output
             .body
-            .try_next()
+            .collect()
             .await
-            .map_err(DownloadError::DownloadStreamError)?
-        {
-            progress.downloaded_from_s3 += bytes.len();
-            sender.send(DownloadEvent::DownloadProgress(progress)).await;
+            .map(|bytes| bytes.into_bytes())
+            .map_err(|e| MaybeRetryable::Retryable(RangeRetry::Stream(e)))?,
+    })
+}
+
+fn last_modified(last_modified: Option<&DateTime>) -> Option<SystemTime> {
+    last_modified.and_then(|time| SystemTime::try_from(*time).ok())
+}
+
+/// Downloads the object with one request for each chunk, saving progress after each chunk.
+/// If the download is interrupted, it resumes from the last chunk that was written instead of from the start.
+/// Each chunk is buffered in memory before it is written.
+fn download_ranges(
+    input: &mut DownloadInput<'_>,
+    progress: &mut SavedProgress,
+    file_start: u64,
+    chunk_size: NonZeroUsize,
+    reservation: Option<&dyn AmountReservation>,
+) -> impl Straw<DownloadOutput, DownloadEvent, DownloadError> {
+    sipper(async move |mut sender| {
+        let mut output = DownloadOutput::default();
+        // The metadata is needed to decrypt or decompress the object, so it's read again when resuming
+        let (total, encryption) = match (progress.len, &input.client_side_key) {
+            (Some(len), None) if !input.decompress => (len, None),
+            _ => {
+                sender.send(DownloadEvent::GettingObjectLen).await;
+                let head = (async || {
+                    input
+                        .client
+                        .head_object()
+                        .bucket(input.src.bucket)
+                        .key(input.src.object_key)
+                        .set_sse_customer_algorithm(
+                            input.customer_key.as_ref().map(CustomerKey::algorithm),
+                        )
+                        .set_sse_customer_key(input.customer_key.as_ref().map(CustomerKey::key))
+                        .set_sse_customer_key_md5(
+                            input.customer_key.as_ref().map(CustomerKey::key_md5),
+                        )
+                        .send()
+                        .await
+                        .map_err(|e| e.into_maybe_retryable().map(DownloadError::HeadError))
+                })
+                .keep_retrying(input.retry_policy)
+                .with(DownloadEvent::CheckObjectLenError)
+                .run(sender.clone())
+                .await?;
+                output.last_modified = last_modified(head.last_modified());
+                output.e_tag = head.e_tag.clone();
+                if progress.check_e_tag(head.e_tag()) {
+                    sender.send(DownloadEvent::ObjectChanged).await;
+                    restart_file(&mut input.dest, file_start).await?;
+                }
+                let len: usize = match head.content_length() {
+                    Some(len) => len
+                        .try_into()
+                        .map_err(DownloadError::ContentLengthConversion)?,
+                    // Chunks can't be requested without knowing where the object ends
+                    None if input.missing_content_length == MissingContentLength::StreamToEof => {
+                        return download_whole(input, progress, file_start, reservation)
+                            .run(sender)
+                            .await;
+                    }
+                    None => Err(DownloadError::NoContentLength)?,
+                };
+                if input.decompress
+                    && Compression::from_metadata(head.metadata())
+                        .map_err(DownloadError::UnknownCompression)?
+                        .is_some()
+                {
+                    // The object can only be decompressed from the start
+                    return download_whole(input, progress, file_start, reservation)
+                        .run(sender)
+                        .await;
+                }
+                let encryption = match &input.client_side_key {
+                    Some(key) => ObjectEncryption::from_metadata(head.metadata(), key)
+                        .map_err(DownloadError::ClientSideEncryption)?,
+                    None => None,
+                };
+                if progress.downloaded > 0 && encryption.is_some() != progress.client_side_encrypted
+                {
+                    Err(DownloadError::ClientSideEncryptionChanged)?;
+                }
+                progress.client_side_encrypted = encryption.is_some();
+                let encryption = encryption.map(|encryption| (encryption, len));
+                let len = if encryption.is_some() {
+                    decrypted_len(len)
+                } else {
+                    len
+                };
+                if progress.len.is_none() {
+                    progress.len = Some(len);
+                    sender
+                        .send(DownloadEvent::UpdateSavedProgress(progress.clone()))
+                        .await;
+                }
+                (len, encryption)
+            }
+        };
+        // Ranges of encrypted objects are whole segments
+        let (chunk_size, align) = match &encryption {
+            Some(_) => (
+                chunk_size.get().next_multiple_of(ENCRYPTED_SEGMENT_SIZE),
+                ENCRYPTED_SEGMENT_SIZE,
+            ),
+            None => (chunk_size.get(), 1),
+        };
+        let file_len = input.dest.len().await.map_err(DownloadError::WriteError)?;
+        if file_len < file_start + progress.downloaded as u64 {
+            // The file was truncated or replaced, or the download is streamed into a writer
+            progress.downloaded = 0;
+        }
+        input
+            .dest
+            .seek(file_start + progress.downloaded as u64)
+            .await
+            .map_err(DownloadError::WriteError)?;
+        let mut download_progress = DownloadCounts {
+            downloaded_from_s3: progress.downloaded,
+            written_to_file: progress.downloaded,
+            total: Some(total),
+        };
+        let mut rate_meter = RateMeter::new(progress.downloaded, Instant::now());
+        let mut throttle = ProgressThrottle::new(input.progress_interval);
+        let mut save_tracker = SaveTracker::new(input.save_policy, Instant::now());
+        while progress.downloaded < total {
+            wait_while_paused(&mut sender, &input.control).await?;
+            let start = progress.downloaded;
+            let (range_start, range_total) = match &encryption {
+                Some((_, encrypted_len)) => (encrypted_offset(start), *encrypted_len),
+                None => (start, total),
+            };
+            let mut range_end = (range_start + chunk_size).min(range_total);
+            let mut failures = 0;
+            // Only the rest of the same object is appended to the saved bytes
+            let if_match = progress.e_tag.clone();
+            let result = ({
+                let mut sender = sender.clone();
+                let input = &*input;
+                let end = &mut range_end;
+                let if_match = if_match.as_deref();
+                async move || {
+                    let result = input
+                        .control
+                        .run(get_range(input, range_start, *end, if_match))
+                        .await
+                        .map_err(|e| MaybeRetryable::NotRetryable(e.into()))?;
+                    if let Err(MaybeRetryable::Retryable(_)) = &result
+                        && let Some(split_range_after) = input.split_range_after
+                    {
+                        failures += 1;
+                        if failures >= split_range_after.get()
+                            && let Some(split_end) = split_range(range_start, *end, align)
+                        {
+                            failures = 0;
+                            *end = split_end;
+                            sender
+                                .send(DownloadEvent::SplitRange {
+                                    start: range_start,
+                                    end: split_end,
+                                })
+                                .await;
+                        }
+                    }
+                    result
+                }
+            })
+            .keep_retrying(input.retry_policy)
+            .with(
+                |Retrying {
+                     error,
+                     next_attempt,
+                 }| match error {
+                    RangeRetry::GetObject(error) => DownloadEvent::DownloadError(Retrying {
+                        error,
+                        next_attempt,
+                    }),
+                    RangeRetry::Stream(error) => DownloadEvent::DownloadStreamError(Retrying {
+                        error,
+                        next_attempt,
+                    }),
+                },
+            )
+            .run(sender.clone())
+            .await;
+            // Endpoints that ignore `If-Match` still return the ETag of the new object
+            let changed = match &result {
+                Err(DownloadError::GetObjectError(e)) => is_precondition_failed(e),
+                Err(_) => false,
+                Ok(response) => progress.check_e_tag(response.e_tag.as_deref()),
+            };
+            if changed {
+                // The new object may have a different length, so it's downloaded like one without a known length
+                progress.restart();
+                sender.send(DownloadEvent::ObjectChanged).await;
+                restart_file(&mut input.dest, file_start).await?;
+                sender
+                    .send(DownloadEvent::UpdateSavedProgress(progress.clone()))
+                    .await;
+                return download_whole(input, progress, file_start, reservation)
+                    .run(sender)
+                    .await;
+            }
+            let response = result?;
+            output.last_modified = response.last_modified;
+            output.e_tag = response.e_tag;
+            let bytes = response.bytes;
+            let bytes = match &encryption {
+                Some((encryption, encrypted_len)) => Bytes::from(
+                    Decryptor::new(encryption.clone(), range_start, *encrypted_len)
+                        .push(&bytes)
+                        .map_err(DownloadError::ClientSideEncryption)?,
+                ),
+                None => bytes,
+            };
+            let end = start + bytes.len();
+            download_progress.downloaded_from_s3 = end;
+            let now = Instant::now();
+            if throttle.update(now) {
+                sender
+                    .send(download_progress.event(&mut rate_meter, now))
+                    .await;
+            }
             input
                 .dest
                 .write_all(&bytes)
                 .await
                 .map_err(DownloadError::WriteError)?;
-            progress.written_to_file += bytes.len();
-            sender.send(DownloadEvent::DownloadProgress(progress)).await;
+            // Make sure the chunk is actually on the disk before saving progress that says it is
+            input
+                .dest
+                .sync_data()
+                .await
+                .map_err(DownloadError::WriteError)?;
+            download_progress.written_to_file = end;
+            let now = Instant::now();
+            if throttle.update(now) {
+                sender
+                    .send(download_progress.event(&mut rate_meter, now))
+                    .await;
+            }
+            progress.downloaded = end;
+            if save_tracker.chunk_done(Instant::now(), end == total) {
+                sender
+                    .send(DownloadEvent::UpdateSavedProgress(progress.clone()))
+                    .await;
+            }
+        }
+        if throttle.pending {
+            sender
+                .send(download_progress.event(&mut rate_meter, Instant::now()))
+                .await;
         }
-        Ok(())
+        output.bytes_written = progress.downloaded;
+        Ok(output)
     })
 }
 
 pub async fn download(
+    input: DownloadInput<'_>,
+) -> impl Straw<DownloadOutput, DownloadEvent, DownloadError> {
+    download_impl(input, None)
+}
+
+/// Like [`download`], but downloads `chunk_size` bytes at a time with ranged requests,
+/// so that a network failure or program restart only loses the chunk that was being downloaded.
+/// Save the [`DownloadEvent::UpdateSavedProgress`] events to be able to resume.
+pub async fn download_chunked(
+    input: DownloadInput<'_>,
+    chunk_size: NonZeroUsize,
+) -> impl Straw<DownloadOutput, DownloadEvent, DownloadError> {
+    download_impl(input, Some(chunk_size))
+}
+
+fn download_impl(
     mut input: DownloadInput<'_>,
-) -> impl Straw<(), DownloadEvent, DownloadError> {
+    chunk_size: Option<NonZeroUsize>,
+) -> impl Straw<DownloadOutput, DownloadEvent, DownloadError> {
     sipper(async move |mut sender| {
+        let result = match input.progress_store.take() {
+            None => download_stages(input, chunk_size).run(sender.clone()).await,
+            Some(store) => {
+                async {
+                    if let Some(progress) = store.load().await? {
+                        input.saved_progress = progress;
+                    }
+                    run_saving_progress(
+                        download_stages(input, chunk_size),
+                        &*store,
+                        sender.clone(),
+                        |event| match event {
+                            DownloadEvent::UpdateSavedProgress(progress) => Some(progress),
+                            _ => None,
+                        },
+                    )
+                    .await?
+                }
+                .await
+            }
+        };
+        if let Err(DownloadError::Cancelled(_)) = &result {
+            sender.send(DownloadEvent::Cancelled).await;
+        }
+        result
+    })
+}
+
+/// Makes sure the file is on the disk and ends where the object ends.
+/// `start` is where the object starts in the file, which isn't 0 for the chunks of [`crate::download_chunked_objects`].
+/// A file that was resumed without truncating it can have old bytes after the end, which are removed.
+/// A writer is only flushed.
+async fn verify_file(
+    dest: &mut DownloadDest<'_>,
+    start: u64,
+    len: Option<usize>,
+) -> Result<(), DownloadError> {
+    let dest = match dest {
+        DownloadDest::File(file) => file,
+        DownloadDest::Writer(writer) => {
+            return writer.flush().await.map_err(DownloadError::WriteError);
+        }
+    };
+    if let Some(len) = len {
+        let end = start + len as u64;
+        let actual = dest
+            .metadata()
+            .await
+            .map_err(DownloadError::WriteError)?
+            .len();
+        if actual < end {
+            Err(DownloadError::FileLengthMismatch {
+                expected: end,
+                actual,
+            })?;
+        }
+        // Nothing after a chunk was written yet, since the chunks are downloaded in order
+        if actual > end {
+            dest.set_len(end).await.map_err(DownloadError::WriteError)?;
+        }
+    }
+    dest.sync_all().await.map_err(DownloadError::WriteError)
+}
+
+/// Reserves the amount, telling how long it's expected to wait if other operations are before it
+async fn reserve_download<'a>(
+    sender: &mut Sender<DownloadEvent>,
+    amount_limiter: &'a dyn AmountLimiter,
+    control: &ControlHandle,
+    len: usize,
+    id: &'a str,
+) -> Result<Box<dyn AmountReservation + 'a>, Cancelled> {
+    sender.send(DownloadEvent::ReservingDownloadAmount).await;
+    let available_at = control.run(amount_limiter.available_at(len, id)).await?;
+    if available_at > UtcDateTime::now() {
+        sender
+            .send(DownloadEvent::WaitingForAmount(available_at))
+            .await;
+    }
+    control.reserve(amount_limiter, len, id).await
+}
+
+/// Reserves the amount, restores the object if it's cold, and downloads it
+fn download_stages(
+    mut input: DownloadInput<'_>,
+    chunk_size: Option<NonZeroUsize>,
+) -> impl Straw<DownloadOutput, DownloadEvent, DownloadError> {
+    sipper(async move |mut sender| {
+        // Where the object starts in the file, such as the offset of a chunk
+        let file_start = input
+            .dest
+            .position()
+            .await
+            .map_err(DownloadError::WriteError)?;
         let amount_limiter = input.amount_limiter.clone();
         let id = format!("download:{}/{}", input.src.bucket, input.src.object_key);
+        // Nothing is reserved when the length isn't known, and the downloaded length is counted at the end instead
+        let mut reserved_without_len = false;
         let reservation = if let Some(amount_limiter) = &amount_limiter {
             Some({
                 if let Some(reservation) = &input.saved_progress.reservation {
+                    reserved_without_len = reservation.amount == 0;
                     if let Some(reservation) = amount_limiter.get_reservation(&id).await {
                         reservation
                     } else {
-                        sender.send(DownloadEvent::ReservingDownloadAmount).await;
-                        amount_limiter.reserve(reservation.amount, &id).await
+                        reserve_download(
+                            &mut sender,
+                            &**amount_limiter,
+                            &input.control,
+                            reservation.amount,
+                            &id,
+                        )
+                        .await?
                     }
                 } else {
                     sender.send(DownloadEvent::GettingObjectLen).await;
-                    let len: usize = (async || {
+                    let len = (async || {
                         input
                             .client
                             .head_object()
                             .bucket(input.src.bucket)
                             .key(input.src.object_key)
+                            .set_sse_customer_algorithm(
+                                input.customer_key.as_ref().map(CustomerKey::algorithm),
+                            )
+                            .set_sse_customer_key(input.customer_key.as_ref().map(CustomerKey::key))
+                            .set_sse_customer_key_md5(
+                                input.customer_key.as_ref().map(CustomerKey::key_md5),
+                            )
                             .send()
                             .await
                             .map_err(|e| e.into_maybe_retryable().map(DownloadError::HeadError))
                     })
-                    .keep_retrying(input.retry_interval)
+                    .keep_retrying(input.retry_policy)
                     .with(DownloadEvent::CheckObjectLenError)
                     .run(sender.clone())
                     .await?
-                    .content_length()
-                    .unwrap()
-                    .try_into()
-                    .unwrap();
-                    sender.send(DownloadEvent::ReservingDownloadAmount).await;
-                    amount_limiter.reserve(len, &id).await
+                    .content_length();
+                    let len = match len {
+                        Some(len) => len
+                            .try_into()
+                            .map_err(DownloadError::ContentLengthConversion)?,
+                        None if input.missing_content_length
+                            == MissingContentLength::StreamToEof =>
+                        {
+                            reserved_without_len = true;
+                            0
+                        }
+                        None => Err(DownloadError::NoContentLength)?,
+                    };
+                    reserve_download(&mut sender, &**amount_limiter, &input.control, len, &id)
+                        .await?
                 }
             })
         } else {
             None
         };
         let mut progress = input.saved_progress.clone();
-        loop {
-            match progress.stage {
-                DownloadStage::WillInitiateRestore => {
-                    match &input.strategy {
-                        DownloadStrategy::Warm => {
-                            download_warm(&mut input).run(sender.clone()).await?;
-                            break;
-                        }
-                        DownloadStrategy::Cold(cold_input) => {
-                            match (async || {
-                                input
-                                    .client
-                                    .restore_object()
-                                    .bucket(input.src.bucket)
-                                    .key(input.src.object_key)
-                                    .restore_request(
-                                        RestoreRequest::builder()
-                                            .days(1)
-                                            .glacier_job_parameters(
-                                                GlacierJobParameters::builder()
-                                                    .tier(cold_input.tier.clone())
-                                                    .build()
-                                                    // Will always be Ok since we specified tier
-                                                    .unwrap(),
-                                            )
-                                            .build(),
-                                    )
-                                    .send()
-                                    .await
-                                    .map_err(|e| e.into_maybe_retryable())
-                            })
-                            .keep_retrying(input.retry_interval)
-                            .with(DownloadEvent::RestoreError)
-                            .run(sender.clone())
-                            .await
-                            {
-                                Ok(_) => Ok(()),
-                                Err(e) => {
-                                    if let SdkError::ServiceError(e) = &e
-                                        && e.err().meta().code() == Some("RestoreAlreadyInProgress")
-                                    {
-                                        // This is ok, we can just wait for it to be restored
-                                        Ok(())
-                                    } else {
-                                        Err(DownloadError::RestoreError(e))
-                                    }
-                                }
-                            }?;
-                            sender.send(DownloadEvent::RestoreInitiated).await;
-                            progress.stage =
-                                DownloadStage::RestoreInitiated(RestoreInitiatedProgress {
-                                    last_checked: SystemTime::now(),
-                                });
-                            sender
-                                .send(DownloadEvent::UpdateSavedProgress(progress.clone()))
-                                .await;
-                        }
-                    }
-                }
-                DownloadStage::RestoreInitiated(restore_progress) => match &input.strategy {
-                    DownloadStrategy::Warm => unreachable!(),
-                    DownloadStrategy::Cold(cold_input) => {
-                        match cold_input.wait_for_restore_stratey {
-                            WaitForRestoreStrategy::PollGet(poll_interval) => {
-                                sleep(poll_interval.saturating_sub(
-                                    restore_progress.last_checked.elapsed().unwrap_or_default(),
-                                ))
-                                .await;
-                                match (async || {
-                                    input
-                                        .client
-                                        .head_object()
-                                        .bucket(input.src.bucket)
-                                        .key(input.src.object_key)
-                                        .send()
-                                        .await
-                                        .map_err(|e| {
-                                            e.into_maybe_retryable().map(DownloadError::HeadError)
-                                        })
-                                })
-                                .keep_retrying(input.retry_interval)
-                                .with(DownloadEvent::CheckStatusError)
-                                .run(sender.clone())
-                                .await?
-                                .restore()
+        let restore_input = match &input.strategy {
+            DownloadStrategy::Warm => None,
+            DownloadStrategy::Cold(_) if !input.capabilities.restore => {
+                sender
+                    .send(DownloadEvent::Degraded(Degradation::Restore))
+                    .await;
+                None
+            }
+            DownloadStrategy::Cold(cold_input) => Some(RestoreInput {
+                client: input.client,
+                src: input.src,
+                tier: cold_input.tier.clone(),
+                adjust_incompatible_tier: cold_input.adjust_incompatible_tier,
+                days: cold_input.restore_days,
+                wait_for_restore_strategy: cold_input.wait_for_restore_stratey.clone(),
+                retry_policy: input.retry_policy,
+            }),
+        };
+        let output = if let Some(restore_input) = restore_input {
+            loop {
+                match &progress.stage {
+                    RestoreStage::RestoreComplete => {
+                        match download_warm(
+                            &mut input,
+                            &mut progress,
+                            file_start,
+                            chunk_size,
+                            reservation.as_deref(),
+                        )
+                        .run(sender.clone())
+                        .await
+                        {
+                            Ok(output) => {
+                                break output;
+                            }
+                            Err(e) => {
+                                if let DownloadError::GetObjectError(SdkError::ServiceError(
+                                    service_error,
+                                )) = &e
+                                    && service_error.err().is_invalid_object_state()
                                 {
-                                    None => {
-                                        // The restored object probably expired and became cold again since we restored it.
-                                        // Let's restore it again.
-                                        progress.stage = DownloadStage::WillInitiateRestore;
-                                        sender
-                                            .send(DownloadEvent::UpdateSavedProgress(
-                                                progress.clone(),
-                                            ))
-                                            .await;
-                                    }
-                                    Some(message) => {
-                                        if message.starts_with("ongoing-request=\"false\"") {
-                                            sender.send(DownloadEvent::RestoreComplete).await;
-                                            progress.stage = DownloadStage::RestoreComplete;
-                                            sender
-                                                .send(DownloadEvent::UpdateSavedProgress(
-                                                    progress.clone(),
-                                                ))
-                                                .await;
-                                        } else if message.starts_with("ongoing-request=\"true\"") {
-                                            sender.send(DownloadEvent::NotYetRestored).await;
-                                            progress.stage = DownloadStage::RestoreInitiated(
-                                                RestoreInitiatedProgress {
-                                                    last_checked: SystemTime::now(),
-                                                },
-                                            );
-                                            sender
-                                                .send(DownloadEvent::UpdateSavedProgress(
-                                                    progress.clone(),
-                                                ))
-                                                .await;
-                                        } else {
-                                            break Err(DownloadError::UnknownRestoreString)?;
-                                        }
-                                    }
+                                    // The restored object probably expired and became cold again since we restored it.
+                                    // Let's restore it again.
+                                    sender.send(DownloadEvent::RestoreExpired).await;
+                                    progress.stage = RestoreStage::WillInitiateRestore;
+                                    sender
+                                        .send(DownloadEvent::UpdateSavedProgress(progress.clone()))
+                                        .await;
+                                } else {
+                                    Err(e)?;
                                 }
                             }
                         }
                     }
-                },
-                DownloadStage::RestoreComplete => {
-                    match download_warm(&mut input).run(sender.clone()).await {
-                        Ok(_) => {
-                            break;
-                        }
-                        Err(e) => {
-                            if let DownloadError::GetObjectError(SdkError::ServiceError(
-                                service_error,
-                            )) = &e
-                                && service_error.err().is_invalid_object_state()
-                            {
-                                // The restored object probably expired and became cold again since we restored it.
-                                // Let's restore it again.
-                                progress.stage = DownloadStage::WillInitiateRestore;
-                                sender
-                                    .send(DownloadEvent::UpdateSavedProgress(progress.clone()))
-                                    .await;
-                            } else {
-                                Err(e)?;
-                            }
+                    stage => {
+                        if let RestoreStage::RestoreInitiated(restore_progress) = stage {
+                            sender
+                                .send(DownloadEvent::DownloadProgress(
+                                    DownloadProgress::Restoring {
+                                        elapsed: restore_progress
+                                            .initiated
+                                            .elapsed()
+                                            .unwrap_or_default(),
+                                        expected: restore_progress.expected_duration,
+                                    },
+                                ))
+                                .await;
                         }
+                        let stage = input
+                            .control
+                            .run(
+                                restore_step(&restore_input, stage)
+                                    .with(DownloadEvent::RestoreEvent)
+                                    .run(sender.clone()),
+                            )
+                            .await?
+                            .map_err(DownloadError::Restore)?;
+                        progress.stage = stage;
+                        sender
+                            .send(DownloadEvent::UpdateSavedProgress(progress.clone()))
+                            .await;
                     }
                 }
             }
-        }
+        } else {
+            download_warm(
+                &mut input,
+                &mut progress,
+                file_start,
+                chunk_size,
+                reservation.as_deref(),
+            )
+            .run(sender.clone())
+            .await?
+        };
+        sender
+            .send(DownloadEvent::DownloadProgress(DownloadProgress::Verifying))
+            .await;
+        verify_file(&mut input.dest, file_start, progress.len).await?;
         if let Some(reservation) = reservation {
             sender.send(DownloadEvent::MarkingReservationComplete).await;
-            reservation.mark_complete().await;
+            match progress.len {
+                Some(len) if reserved_without_len => reservation.mark_complete_with_len(len).await,
+                _ => reservation.mark_complete().await,
+            }
         }
-        Ok(())
+        Ok(output)
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{MIN_SPLIT_RANGE_SIZE, SavedProgress, split_range};
+
+    #[test]
+    fn changed_e_tag_starts_over() {
+        let mut progress = SavedProgress::default();
+        assert!(!progress.check_e_tag(Some("\"a\"")));
+        progress.len = Some(1000);
+        progress.downloaded = 500;
+        // Resuming the same object keeps the saved bytes
+        assert!(!progress.check_e_tag(Some("\"a\"")));
+        assert_eq!(progress.downloaded, 500);
+        assert!(progress.check_e_tag(Some("\"b\"")));
+        assert_eq!(progress.downloaded, 0);
+        assert_eq!(progress.len, None);
+        assert_eq!(progress.e_tag.as_deref(), Some("\"b\""));
+    }
+
+    #[test]
+    fn split_range_halves() {
+        let start = 10;
+        assert_eq!(
+            split_range(start, start + 8 * MIN_SPLIT_RANGE_SIZE, 1),
+            Some(start + 4 * MIN_SPLIT_RANGE_SIZE)
+        );
+        assert_eq!(
+            split_range(start, start + MIN_SPLIT_RANGE_SIZE + 1, 1),
+            Some(start + MIN_SPLIT_RANGE_SIZE)
+        );
+        assert_eq!(split_range(start, start + MIN_SPLIT_RANGE_SIZE, 1), None);
+        // Encrypted segments are kept whole
+        assert_eq!(
+            split_range(start, start + 2 * MIN_SPLIT_RANGE_SIZE, 1000),
+            Some(start + MIN_SPLIT_RANGE_SIZE.next_multiple_of(1000))
+        );
+        assert_eq!(
+            split_range(start, start + MIN_SPLIT_RANGE_SIZE + 1, 1000),
+            None
+        );
+    }
+}
@@ -0,0 +1,276 @@
+use std::{io, num::NonZeroUsize, time::Duration};
+
+use aws_sdk_s3::{error::SdkError, operation::get_object_tagging::GetObjectTaggingError};
+use serde::{Deserialize, Serialize};
+use sipper::{Sipper, Straw, sipper};
+use thiserror::Error;
+use tokio::io::AsyncSeekExt;
+
+use crate::{
+    AmountLimiter, ClientSideKey, DownloadDest, DownloadError, DownloadEvent, DownloadInput,
+    DownloadStrategy, GetTagsError, RestoreError, RestoreEvent, RestoreInput, RestoreStage,
+    RetryPolicy, Retrying, S3Src, SavedProgress, Tags, download, get_tags, initiate_restore,
+};
+
+/// How a file was split by [`crate::upload_chunked`], read from the tags of the first chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkLayout {
+    pub len: usize,
+    pub chunk_size: NonZeroUsize,
+    pub chunks_count: usize,
+}
+
+impl ChunkLayout {
+    pub fn from_tags(tags: &Tags) -> Result<Self, ChunkLayoutError> {
+        fn parse<T: std::str::FromStr>(
+            tags: &Tags,
+            key: &'static str,
+        ) -> Result<T, ChunkLayoutError> {
+            tags.get(key)
+                .ok_or(ChunkLayoutError::MissingTag(key))?
+                .parse()
+                .map_err(|_| ChunkLayoutError::InvalidTag(key))
+        }
+        Ok(Self {
+            len: parse(tags, "total_len")?,
+            chunk_size: parse(tags, "chunk_size")?,
+            chunks_count: parse(tags, "chunks_count")?,
+        })
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ChunkLayoutError {
+    #[error("The first chunk doesn't have the {0} tag")]
+    MissingTag(&'static str),
+    #[error("The {0} tag of the first chunk is invalid")]
+    InvalidTag(&'static str),
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct DownloadChunkedObjectsProgress {
+    pub layout: Option<ChunkLayout>,
+    /// For cold objects, restores are initiated for all chunks before downloading, so that they are restored at the same time
+    pub restores_initiated: usize,
+    /// The restore stage of each chunk, by chunk number.
+    /// Each chunk is downloaded as soon as it is restored, while the restores of the chunks after it are still in progress.
+    /// Progress saved by older versions doesn't have it, so those chunks start their restore status checks over.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub restores: Vec<RestoreStage>,
+    pub chunks_downloaded: usize,
+    /// Progress of the chunk that is being downloaded
+    pub chunk: SavedProgress,
+}
+
+pub struct DownloadChunkedObjectsInput<'a> {
+    pub client: &'a aws_sdk_s3::Client,
+    /// The key that was given to [`crate::upload_chunked`]. The chunks are `{object_key}/0`, `{object_key}/1`, etc.
+    pub src: S3Src<'a>,
+    pub dest: &'a mut tokio::fs::File,
+    pub strategy: DownloadStrategy,
+    /// See [`DownloadInput::client_side_key`]. Each chunk is decrypted on its own.
+    pub client_side_key: Option<ClientSideKey>,
+    pub retry_policy: RetryPolicy,
+    pub progress: DownloadChunkedObjectsProgress,
+    pub amount_limiter: Option<Box<dyn AmountLimiter>>,
+    /// See [`DownloadInput::progress_interval`]
+    pub progress_interval: Option<Duration>,
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Error)]
+pub enum DownloadChunkedObjectsError {
+    #[error("Error getting the tags of the first chunk")]
+    GetTags(GetTagsError),
+    #[error("The tags of the first chunk don't describe the chunks")]
+    Layout(ChunkLayoutError),
+    #[error("Error restoring a chunk")]
+    Restore(RestoreError),
+    #[error("Error seeking in the file")]
+    Seek(io::Error),
+    #[error("Error downloading a chunk")]
+    Download(DownloadError),
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum DownloadChunkedObjectsEvent {
+    GetTagsError(Retrying<SdkError<GetObjectTaggingError>>),
+    SaveProgress(DownloadChunkedObjectsProgress),
+    InitiatingRestore(usize),
+    RestoreEvent(RestoreEvent),
+    StartingChunk(usize),
+    DownloadEvent(DownloadEvent),
+}
+
+/// Downloads the objects created by [`crate::upload_chunked`] into a single file
+/// For cold objects, the restores of all chunks are initiated first, and then the chunks are downloaded in order, each as soon as it is restored.
+pub fn download_chunked_objects(
+    input: DownloadChunkedObjectsInput<'_>,
+) -> impl Straw<(), DownloadChunkedObjectsEvent, DownloadChunkedObjectsError> {
+    sipper(async move |mut sender| {
+        let mut progress = input.progress;
+        let chunk_key = |chunk: usize| format!("{}/{}", input.src.object_key, chunk);
+        let layout = match progress.layout {
+            Some(layout) => layout,
+            None => {
+                let tags = get_tags(
+                    input.client,
+                    S3Src {
+                        bucket: input.src.bucket,
+                        object_key: &chunk_key(0),
+                    },
+                    input.retry_policy,
+                )
+                .with(DownloadChunkedObjectsEvent::GetTagsError)
+                .run(sender.clone())
+                .await
+                .map_err(DownloadChunkedObjectsError::GetTags)?;
+                let layout =
+                    ChunkLayout::from_tags(&tags).map_err(DownloadChunkedObjectsError::Layout)?;
+                progress.layout = Some(layout);
+                sender
+                    .send(DownloadChunkedObjectsEvent::SaveProgress(progress.clone()))
+                    .await;
+                layout
+            }
+        };
+        if let DownloadStrategy::Cold(cold_input) = &input.strategy {
+            while progress.restores_initiated < layout.chunks_count {
+                sender
+                    .send(DownloadChunkedObjectsEvent::InitiatingRestore(
+                        progress.restores_initiated,
+                    ))
+                    .await;
+                let stage = initiate_restore(&RestoreInput {
+                    client: input.client,
+                    src: S3Src {
+                        bucket: input.src.bucket,
+                        object_key: &chunk_key(progress.restores_initiated),
+                    },
+                    tier: cold_input.tier.clone(),
+                    adjust_incompatible_tier: cold_input.adjust_incompatible_tier,
+                    days: cold_input.restore_days,
+                    wait_for_restore_strategy: cold_input.wait_for_restore_stratey.clone(),
+                    retry_policy: input.retry_policy,
+                })
+                .with(DownloadChunkedObjectsEvent::RestoreEvent)
+                .run(sender.clone())
+                .await
+                .map_err(DownloadChunkedObjectsError::Restore)?;
+                if progress.restores.len() == progress.restores_initiated {
+                    progress.restores.push(stage);
+                }
+                progress.restores_initiated += 1;
+                sender
+                    .send(DownloadChunkedObjectsEvent::SaveProgress(progress.clone()))
+                    .await;
+            }
+        }
+        while progress.chunks_downloaded < layout.chunks_count {
+            let chunk = progress.chunks_downloaded;
+            sender
+                .send(DownloadChunkedObjectsEvent::StartingChunk(chunk))
+                .await;
+            // A partially downloaded chunk is downloaded again from its start
+            input
+                .dest
+                .seek(io::SeekFrom::Start(
+                    (chunk * layout.chunk_size.get()) as u64,
+                ))
+                .await
+                .map_err(DownloadChunkedObjectsError::Seek)?;
+            let mut saved_progress = progress.chunk.clone();
+            // Continues checking the restore that was initiated with the others, instead of initiating it again
+            // and waiting a whole poll interval after the previous chunk is downloaded
+            if let Some(stage) = progress.restores.get(chunk) {
+                saved_progress.stage = stage.clone();
+            }
+            download(DownloadInput {
+                client: input.client,
+                src: S3Src {
+                    bucket: input.src.bucket,
+                    object_key: &chunk_key(chunk),
+                },
+                dest: DownloadDest::File(&mut *input.dest),
+                strategy: input.strategy.clone(),
+                customer_key: None,
+                client_side_key: input.client_side_key.clone(),
+                decompress: false,
+                capabilities: Default::default(),
+                retry_policy: input.retry_policy,
+                saved_progress,
+                progress_store: None,
+                amount_limiter: input.amount_limiter.clone(),
+                progress_interval: input.progress_interval,
+                save_policy: Default::default(),
+                split_range_after: None,
+                missing_content_length: Default::default(),
+                control: Default::default(),
+            })
+            .await
+            .with(|event| match event {
+                DownloadEvent::UpdateSavedProgress(saved_progress) => {
+                    if let Some(stage) = progress.restores.get_mut(chunk) {
+                        *stage = saved_progress.stage.clone();
+                    }
+                    progress.chunk = saved_progress;
+                    DownloadChunkedObjectsEvent::SaveProgress(progress.clone())
+                }
+                event => DownloadChunkedObjectsEvent::DownloadEvent(event),
+            })
+            .run(sender.clone())
+            .await
+            .map_err(DownloadChunkedObjectsError::Download)?;
+            progress.chunks_downloaded += 1;
+            progress.chunk = Default::default();
+            sender
+                .send(DownloadChunkedObjectsEvent::SaveProgress(progress.clone()))
+                .await;
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZero;
+
+    use crate::Tags;
+
+    use super::{ChunkLayout, ChunkLayoutError};
+
+    #[test]
+    fn layout_from_tags() {
+        let tags = [
+            ("file", "backup.zfs"),
+            ("total_len", "2500"),
+            ("chunks_count", "3"),
+            ("chunk_size", "1000"),
+            ("chunk_number", "0"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_owned(), value.to_owned()))
+        .collect::<Tags>();
+        assert_eq!(
+            ChunkLayout::from_tags(&tags).unwrap(),
+            ChunkLayout {
+                len: 2500,
+                chunk_size: NonZero::new(1000).unwrap(),
+                chunks_count: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn layout_missing_tag() {
+        let tags = [("total_len", "2500"), ("chunk_size", "1000")]
+            .into_iter()
+            .map(|(key, value)| (key.to_owned(), value.to_owned()))
+            .collect::<Tags>();
+        assert!(matches!(
+            ChunkLayout::from_tags(&tags),
+            Err(ChunkLayoutError::MissingTag("chunks_count"))
+        ));
+    }
+}
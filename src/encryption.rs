@@ -0,0 +1,97 @@
+use std::fmt;
+
+use aws_sdk_s3::types::ServerSideEncryption;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use md5::{Digest, Md5};
+
+/// How S3 encrypts an uploaded object
+#[derive(Debug, Clone, Default)]
+pub enum Encryption {
+    /// Use the bucket's default encryption
+    #[default]
+    BucketDefault,
+    /// SSE-S3, with keys managed by S3
+    S3Managed,
+    /// SSE-KMS. `None` uses the AWS managed key, `aws/s3`.
+    Kms { key_id: Option<String> },
+    /// SSE-C. S3 doesn't store the key, so the same key must be given to download the object,
+    /// see [`crate::DownloadInput::customer_key`].
+    CustomerKey(CustomerKey),
+}
+
+impl Encryption {
+    pub(crate) fn server_side_encryption(&self) -> Option<ServerSideEncryption> {
+        match self {
+            Self::S3Managed => Some(ServerSideEncryption::Aes256),
+            Self::Kms { .. } => Some(ServerSideEncryption::AwsKms),
+            Self::BucketDefault | Self::CustomerKey(_) => None,
+        }
+    }
+
+    pub(crate) fn kms_key_id(&self) -> Option<String> {
+        match self {
+            Self::Kms { key_id } => key_id.clone(),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn customer_key(&self) -> Option<&CustomerKey> {
+        match self {
+            Self::CustomerKey(key) => Some(key),
+            _ => None,
+        }
+    }
+}
+
+/// A 256-bit key for SSE-C.
+/// Only the MD5 of the key is shown when debug printing it.
+#[derive(Clone)]
+pub struct CustomerKey {
+    key: String,
+    key_md5: String,
+}
+
+impl CustomerKey {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self {
+            key: STANDARD.encode(key),
+            key_md5: STANDARD.encode(Md5::digest(key)),
+        }
+    }
+
+    /// The value of the `x-amz-server-side-encryption-customer-algorithm` header
+    pub(crate) fn algorithm(&self) -> String {
+        "AES256".into()
+    }
+
+    /// The base64 key, for the `x-amz-server-side-encryption-customer-key` header
+    pub(crate) fn key(&self) -> String {
+        self.key.clone()
+    }
+
+    /// The base64 MD5 of the key, which S3 uses to check that the key wasn't corrupted
+    pub(crate) fn key_md5(&self) -> String {
+        self.key_md5.clone()
+    }
+}
+
+impl fmt::Debug for CustomerKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CustomerKey")
+            .field("key_md5", &self.key_md5)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CustomerKey;
+
+    #[test]
+    fn customer_key_headers() {
+        let key = CustomerKey::new(std::array::from_fn(|i| i as u8));
+        assert_eq!(key.key(), "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=");
+        assert_eq!(key.key_md5(), "tP/LI3N87DFaSk0aoqYgzg==");
+        assert!(!format!("{key:?}").contains(&key.key()));
+    }
+}
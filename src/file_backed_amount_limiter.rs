@@ -1,45 +1,87 @@
 use std::{
     borrow::Cow,
+    cmp::Reverse,
     io::{self, SeekFrom},
+    path::Path,
+    sync::Arc,
+    time::Duration,
 };
 
 use fs4::tokio::AsyncFileExt;
 use futures::future::BoxFuture;
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
 use ordermap::OrderMap;
-use ron::de::SpannedError;
 use serde::{Deserialize, Serialize};
 use sipper::FutureExt;
 use thiserror::Error;
-use time::{Date, Time, UtcDateTime};
+use time::{Date, UtcDateTime, UtcOffset};
 use tokio::{
     fs::File,
     io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    runtime::Handle,
+    sync::Notify,
+    task::{JoinHandle, spawn_blocking},
     time::sleep,
 };
 
-use crate::{AmountLimiter, AmountReservation, StartOfNextMonthExt};
+use crate::{
+    AmountLimiter, AmountLimiterInspect, AmountLimiterUsage, AmountReservation, QueuedAmount,
+    SerializationError, SerializationFormat, amount_limiter::monthly_available_at,
+    wall_clock::timeout_at_utc,
+};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct QueueItem<'a> {
     description: Cow<'a, str>,
     amount: usize,
     time_added: UtcDateTime,
+    #[serde(default)]
+    priority: i32,
+    /// The reservation was let through, so it is before every waiting reservation, even ones with a higher priority
+    #[serde(default)]
+    started: bool,
+    /// The last time that the process which reserved it was known to be running, see [`FileBackedAmountLimiter::with_stale_after`]
+    #[serde(default)]
+    heartbeat: Option<UtcDateTime>,
+    /// Only items in the same direction wait for each other
+    #[serde(default)]
+    direction: Option<TransferDirection>,
+}
+
+/// Which budget in the file a [`FileBackedAmountLimiter`] uses, for ISPs that count uploads and downloads separately
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransferDirection {
+    Upload,
+    Download,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct FileData<'a> {
     current_month: Date,
+    /// Used by limiters without a direction
     used_this_month: usize,
+    #[serde(default)]
+    used_upload: usize,
+    #[serde(default)]
+    used_download: usize,
     queue: OrderMap<Cow<'a, str>, QueueItem<'a>>,
 }
 
 /// An `[AmountLimiter]` which stores usage info in a file.
-/// Limit gets reset at the start of every month (UTC).
+/// Limit gets reset at the start of every month, in UTC unless [`FileBackedAmountLimiter::with_utc_offset`] is used.
+/// Waiting reservations watch the file, so they start as soon as a reservation before them finishes or is cancelled.
 #[derive(Debug, Clone)]
 pub struct FileBackedAmountLimiter<'a> {
     path: Cow<'a, str>,
     limit: usize,
     description: Cow<'a, str>,
+    overhead: f64,
+    format: SerializationFormat,
+    utc_offset: UtcOffset,
+    recheck_interval: Option<Duration>,
+    priority: i32,
+    stale_after: Option<Duration>,
+    direction: Option<TransferDirection>,
 }
 
 impl<'a> FileBackedAmountLimiter<'a> {
@@ -48,16 +90,245 @@ impl<'a> FileBackedAmountLimiter<'a> {
             path,
             limit,
             description,
+            overhead: 0.0,
+            format: Default::default(),
+            utc_offset: UtcOffset::UTC,
+            recheck_interval: None,
+            priority: 0,
+            stale_after: None,
+            direction: None,
         }
     }
+
+    /// Counts this fraction more than the payload of every upload or download, such as `0.02` for 2%.
+    /// The network usage measured by an ISP also includes TLS, HTTP headers, and retried requests,
+    /// so without this the monthly limit can be exceeded without knowing it.
+    pub fn with_overhead(self, overhead: f64) -> Self {
+        Self { overhead, ..self }
+    }
+
+    /// Every limiter using the same file needs to use the same format
+    pub fn with_format(self, format: SerializationFormat) -> Self {
+        Self { format, ..self }
+    }
+
+    /// Resets the limit at midnight in this offset instead of UTC, such as the time zone that the ISP measures the month in.
+    /// Every limiter using the same file needs to use the same offset.
+    pub fn with_utc_offset(self, utc_offset: UtcOffset) -> Self {
+        Self { utc_offset, ..self }
+    }
+
+    /// Also checks the file at this interval while waiting, in case changes to the file aren't noticed, such as on a network filesystem
+    pub fn with_recheck_interval(self, recheck_interval: Duration) -> Self {
+        Self {
+            recheck_interval: Some(recheck_interval),
+            ..self
+        }
+    }
+
+    /// A limiter using the same file, whose reservations are let through before waiting reservations with a lower priority,
+    /// such as to restore a file before the bulk backups that are waiting.
+    /// Reservations that were already let through aren't stopped. The default priority is `0`.
+    pub fn with_priority(self, priority: i32) -> Self {
+        Self { priority, ..self }
+    }
+
+    /// Removes queue items that weren't refreshed for this long, so that a process that crashed before finishing its reservation doesn't hold back the queue forever.
+    /// Reservations refresh their item a few times in this duration while they wait and run.
+    /// Every limiter using the same file needs to use this, or its reservations could be removed while they're running.
+    pub fn with_stale_after(self, stale_after: Duration) -> Self {
+        Self {
+            stale_after: Some(stale_after),
+            ..self
+        }
+    }
+
+    fn queue_item(&self, amount: usize) -> QueueItem<'a> {
+        QueueItem {
+            description: self.description.clone(),
+            amount,
+            time_added: UtcDateTime::now(),
+            priority: self.priority,
+            started: false,
+            heartbeat: None,
+            direction: self.direction,
+        }
+    }
+
+    /// Refreshes the heartbeat of `id` in the file until it's dropped, if stale items are removed
+    fn heartbeat(&self, id: &str) -> Option<Heartbeat> {
+        let stale_after = self.stale_after?;
+        let path = self.path.clone().into_owned();
+        let format = self.format;
+        let utc_offset = self.utc_offset;
+        let id = id.to_owned();
+        Some(Heartbeat(tokio::spawn(async move {
+            loop {
+                sleep(stale_after / 3).await;
+                // Locking the file blocks the thread, so it's done on another thread, where it can't block the task that has the file locked
+                let path = path.clone();
+                let id = id.clone();
+                let handle = Handle::current();
+                // It is tried again next time if it fails
+                let _ = spawn_blocking(move || {
+                    handle.block_on(refresh_heartbeat(&path, format, utc_offset, &id))
+                })
+                .await;
+            }
+        })))
+    }
+
+    /// Notifies `changed` when the file is written to, or returns `None` if the file can't be watched
+    fn watch(&self, changed: Arc<Notify>) -> Option<RecommendedWatcher> {
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+            if event.is_ok_and(|event| event.kind.is_modify()) {
+                changed.notify_one();
+            }
+        })
+        .ok()?;
+        watcher
+            .watch(Path::new(self.path.as_ref()), RecursiveMode::NonRecursive)
+            .ok()?;
+        Some(watcher)
+    }
+
+    /// Reads the file, without the queue items that are stale
+    async fn open_and_read(&self) -> Result<(DataFile, FileData<'static>), ReadLimiterFileError> {
+        let (file, mut data) =
+            DataFile::open_and_read(self.path.as_ref(), self.format, self.utc_offset).await?;
+        self.remove_stale(&mut data);
+        Ok((file, data))
+    }
+
+    fn remove_stale(&self, data: &mut FileData) {
+        if let Some(stale_after) = self.stale_after {
+            let now = UtcDateTime::now();
+            data.queue
+                .retain(|_, item| now - item.heartbeat.unwrap_or(item.time_added) <= stale_after);
+        }
+    }
+
+    /// Like [`AmountLimiterInspect::usage`], but returns an error instead of panicking if the file can't be read,
+    /// such as to check that the file isn't corrupted
+    pub async fn try_usage(&self) -> Result<AmountLimiterUsage, ReadLimiterFileError> {
+        let (file, data) = self.open_and_read().await?;
+        file.close().await.map_err(ReadLimiterFileError::Unlock)?;
+        Ok(self.usage_of(data))
+    }
+
+    fn usage_of(&self, data: FileData) -> AmountLimiterUsage {
+        let used_this_month = data.used(self.direction);
+        let (mut queue, mut waiting): (Vec<_>, Vec<_>) = data
+            .queue
+            .into_iter()
+            .filter(|(_, item)| item.direction == self.direction)
+            .map(|(id, item)| QueuedAmount {
+                id: id.into_owned(),
+                description: item.description.into_owned(),
+                amount: item.amount,
+                time_added: item.time_added,
+                priority: item.priority,
+                started: item.started,
+            })
+            .partition(|item| item.started);
+        // Stable, so items with the same priority stay in the order they were added
+        waiting.sort_by_key(|item| Reverse(item.priority));
+        queue.append(&mut waiting);
+        AmountLimiterUsage {
+            limit: self.limit,
+            used_this_month,
+            queue,
+        }
+    }
+
+    /// A view of the same file that can only read it, for reporting tools, see [`ReadOnlyFileBackedAmountLimiter`]
+    pub fn read_only(&self) -> ReadOnlyFileBackedAmountLimiter<'a> {
+        ReadOnlyFileBackedAmountLimiter {
+            limiter: self.clone(),
+        }
+    }
+
+    /// A limiter using the same file, which uses a separate budget for this direction, with `limit` as its own monthly limit.
+    /// For example, clone a limiter and use one with [`TransferDirection::Upload`] for uploads and one with [`TransferDirection::Download`] for downloads.
+    /// The limit isn't shared with limiters without a direction.
+    pub fn with_direction(self, direction: TransferDirection, limit: usize) -> Self {
+        Self {
+            direction: Some(direction),
+            limit,
+            ..self
+        }
+    }
+
+    /// A limiter using the same file, with a different description for its queue items.
+    /// Give each transfer its own description, such as its [`crate::LabeledEvent::label`], to tell them apart in the file.
+    pub fn with_description(self, description: Cow<'a, str>) -> Self {
+        Self {
+            description,
+            ..self
+        }
+    }
+}
+
+impl FileData<'_> {
+    fn used(&self, direction: Option<TransferDirection>) -> usize {
+        match direction {
+            None => self.used_this_month,
+            Some(TransferDirection::Upload) => self.used_upload,
+            Some(TransferDirection::Download) => self.used_download,
+        }
+    }
+
+    fn used_mut(&mut self, direction: Option<TransferDirection>) -> &mut usize {
+        match direction {
+            None => &mut self.used_this_month,
+            Some(TransferDirection::Upload) => &mut self.used_upload,
+            Some(TransferDirection::Download) => &mut self.used_download,
+        }
+    }
+
+    /// When `len` can be used, after the items in the limiter's direction that are before `id` in the queue.
+    /// Items that started are before it, and so are waiting items with a higher priority, or the same priority and added earlier.
+    /// If `id` isn't in the queue yet, it would be added last.
+    fn available_at(
+        &self,
+        limiter: &FileBackedAmountLimiter,
+        len: usize,
+        id: &str,
+        now: UtcDateTime,
+    ) -> UtcDateTime {
+        let position = self.queue.get_index_of(id);
+        let queue_total = self
+            .queue
+            .values()
+            .enumerate()
+            .filter(|&(index, item)| {
+                Some(index) != position
+                    && item.direction == limiter.direction
+                    && (item.started
+                        || item.priority > limiter.priority
+                        || (item.priority == limiter.priority
+                            && position.is_none_or(|position| index < position)))
+            })
+            .map(|(_, item)| item.amount)
+            .sum::<usize>();
+        monthly_available_at(
+            self.used(limiter.direction),
+            queue_total,
+            len,
+            limiter.limit,
+            now,
+            limiter.utc_offset,
+        )
+    }
 }
 
 struct DataFile {
     file: File,
+    format: SerializationFormat,
 }
 
 #[derive(Debug, Error)]
-enum OpenAndReadError {
+pub enum ReadLimiterFileError {
     #[error("Failed to open file")]
     Open(io::Error),
     #[error("Failed to lock file")]
@@ -65,7 +336,9 @@ enum OpenAndReadError {
     #[error("Failed to read file")]
     Read(io::Error),
     #[error("Failed to parse file")]
-    Parse(SpannedError),
+    Parse(SerializationError),
+    #[error("Failed to unlock file")]
+    Unlock(io::Error),
 }
 
 #[derive(Debug, Error)]
@@ -76,14 +349,18 @@ enum WriteAndCloseError {
     #[error("Error clearing existing file contents")]
     SetLen(io::Error),
     #[error("Error serializing data")]
-    ToString(ron::Error),
+    ToString(SerializationError),
     #[error("Error writing data")]
     Write(io::Error),
     #[error("Error unlocking file")]
     Unlock(io::Error),
 }
 impl DataFile {
-    pub async fn open_and_read(path: &str) -> Result<(Self, FileData<'static>), OpenAndReadError> {
+    pub async fn open_and_read(
+        path: &str,
+        format: SerializationFormat,
+        utc_offset: UtcOffset,
+    ) -> Result<(Self, FileData<'static>), ReadLimiterFileError> {
         let mut file = tokio::fs::File::options()
             .read(true)
             .write(true)
@@ -91,29 +368,69 @@ impl DataFile {
             .create(true)
             .open(path)
             .await
-            .map_err(OpenAndReadError::Open)?;
-        file.lock_exclusive().map_err(OpenAndReadError::Lock)?;
+            .map_err(ReadLimiterFileError::Open)?;
+        file.lock_exclusive().map_err(ReadLimiterFileError::Lock)?;
         let mut s = String::new();
         file.read_to_string(&mut s)
             .await
-            .map_err(OpenAndReadError::Read)?;
-        let now = UtcDateTime::now();
-        let data = if s.is_empty() {
+            .map_err(ReadLimiterFileError::Read)?;
+        let data = Self::parse(&s, format, utc_offset)?;
+        Ok((Self { file, format }, data))
+    }
+
+    /// Reads the file with a shared lock, without creating or changing it.
+    /// A file that doesn't exist yet is read as empty.
+    pub async fn read_shared(
+        path: &str,
+        format: SerializationFormat,
+        utc_offset: UtcOffset,
+    ) -> Result<FileData<'static>, ReadLimiterFileError> {
+        let mut file = match File::open(path).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Self::parse("", format, utc_offset);
+            }
+            Err(e) => Err(ReadLimiterFileError::Open(e))?,
+        };
+        file.lock_shared().map_err(ReadLimiterFileError::Lock)?;
+        let mut s = String::new();
+        file.read_to_string(&mut s)
+            .await
+            .map_err(ReadLimiterFileError::Read)?;
+        file.unlock_async()
+            .await
+            .map_err(ReadLimiterFileError::Unlock)?;
+        Self::parse(&s, format, utc_offset)
+    }
+
+    /// Parses the contents of the file, with the usage reset if the month changed
+    fn parse(
+        s: &str,
+        format: SerializationFormat,
+        utc_offset: UtcOffset,
+    ) -> Result<FileData<'static>, ReadLimiterFileError> {
+        let now = UtcDateTime::now().to_offset(utc_offset);
+        Ok(if s.is_empty() {
             FileData {
                 current_month: now.date(),
                 queue: Default::default(),
                 used_this_month: 0,
+                used_upload: 0,
+                used_download: 0,
             }
         } else {
-            let mut data = ron::from_str::<FileData>(&s).map_err(OpenAndReadError::Parse)?;
+            let mut data = format
+                .deserialize::<FileData>(s)
+                .map_err(ReadLimiterFileError::Parse)?;
             if (data.current_month.year(), data.current_month.month()) != (now.year(), now.month())
             {
                 data.current_month = now.date();
                 data.used_this_month = 0;
+                data.used_upload = 0;
+                data.used_download = 0;
             }
             data
-        };
-        Ok((Self { file }, data))
+        })
     }
 
     pub async fn write_and_close(mut self, data: &FileData<'_>) -> Result<(), WriteAndCloseError> {
@@ -127,13 +444,15 @@ impl DataFile {
             .map_err(WriteAndCloseError::SetLen)?;
         self.file
             .write_all(
-                ron::Options::default()
-                    .to_string_pretty(data, Default::default())
+                self.format
+                    .serialize_pretty(data)
                     .map_err(WriteAndCloseError::ToString)?
                     .as_bytes(),
             )
             .await
             .map_err(WriteAndCloseError::Write)?;
+        // Otherwise the write can finish after the file is unlocked
+        self.file.flush().await.map_err(WriteAndCloseError::Write)?;
         self.file
             .unlock_async()
             .await
@@ -146,6 +465,30 @@ impl DataFile {
     }
 }
 
+async fn refresh_heartbeat(
+    path: &str,
+    format: SerializationFormat,
+    utc_offset: UtcOffset,
+    id: &str,
+) -> Result<(), ReadLimiterFileError> {
+    let (file, mut data) = DataFile::open_and_read(path, format, utc_offset).await?;
+    match data.queue.get_mut(id) {
+        Some(item) => {
+            item.heartbeat = Some(UtcDateTime::now());
+            let _ = file.write_and_close(&data).await;
+        }
+        None => {
+            let _ = file.close().await;
+        }
+    }
+    Ok(())
+}
+
+/// The amount that is counted for `len` bytes of payload
+fn counted_amount(len: usize, overhead: f64) -> usize {
+    len + (len as f64 * overhead.max(0.0)).ceil() as usize
+}
+
 impl AmountLimiter for FileBackedAmountLimiter<'_> {
     fn reserve<'a>(
         &'a self,
@@ -153,44 +496,43 @@ impl AmountLimiter for FileBackedAmountLimiter<'_> {
         id: &'a str,
     ) -> BoxFuture<'a, Box<dyn AmountReservation + 'a>> {
         async move {
-            let (file, mut data) = DataFile::open_and_read(self.path.as_ref()).await.unwrap();
-            data.queue.entry(id.into()).or_insert(QueueItem {
-                description: self.description.clone(),
-                amount: len,
-                time_added: UtcDateTime::now(),
-            });
+            let len = counted_amount(len, self.overhead);
+            let (file, mut data) = self.open_and_read().await.unwrap();
+            data.queue
+                .entry(id.into())
+                .or_insert_with(|| self.queue_item(len));
             file.write_and_close(&data).await.unwrap();
+            let heartbeat = self.heartbeat(id);
+            let changed = Arc::new(Notify::new());
+            let _watcher = self.watch(changed.clone());
             loop {
-                let (file, data) = DataFile::open_and_read(self.path.as_ref()).await.unwrap();
-                file.close().await.unwrap();
-                let queue_total = data.queue[..data.queue.get_index_of(id).unwrap()]
-                    .iter()
-                    .map(|(_, item)| item.amount)
-                    .sum::<usize>();
-                // "Stretch" the limit if it would be impossible to do the operation with the specified limit
-                if data.used_this_month + queue_total + len <= self.limit.max(len) {
+                let (file, mut data) = self.open_and_read().await.unwrap();
+                if !data.queue.contains_key(id) {
+                    // It was removed as stale, such as because the computer was suspended, so it goes to the back of the queue
+                    data.queue.insert(id.into(), self.queue_item(len));
+                    file.write_and_close(&data).await.unwrap();
+                    continue;
+                }
+                let now = UtcDateTime::now();
+                let available_at = data.available_at(self, len, id, now);
+                if available_at <= now {
+                    data.queue[id].started = true;
+                    file.write_and_close(&data).await.unwrap();
                     break;
-                } else {
-                    // Even if we used more data than allotted this month, we just have to wait for this month to be over and then our limit resets.
-                    // So after waiting that month, we just need to let the items before us in the queue complete.
-                    let months_to_wait = 1 + (queue_total + len) / self.limit;
-                    // It's not *guaranteed* that after that time it will be our turn again, because a process could end up using its reserved data in the next month.
-                    let now = UtcDateTime::now();
-                    let time_to_re_check = {
-                        let mut time_to_recheck = now.date();
-                        for _ in 0..months_to_wait {
-                            time_to_recheck = time_to_recheck.start_of_next_month();
-                        }
-                        time_to_recheck
-                    };
-                    let duration = UtcDateTime::new(time_to_re_check, Time::MIDNIGHT) - now;
-                    // FIXME: Time during suspend doesn't get counted
-                    sleep(duration.try_into().unwrap()).await;
                 }
+                file.close().await.unwrap();
+                let mut check_at = available_at;
+                if let Some(recheck_interval) = self.recheck_interval {
+                    check_at = check_at.min(now + recheck_interval);
+                }
+                // Check again as soon as another process changes the file, such as when a reservation before this one is cancelled
+                let _ = timeout_at_utc(check_at, changed.notified()).await;
             }
             Box::new(FileBackedAmountReservation {
                 limiter: self.clone(),
                 id,
+                amount: len,
+                _heartbeat: heartbeat,
             }) as Box<dyn AmountReservation>
         }
         .boxed()
@@ -201,35 +543,305 @@ impl AmountLimiter for FileBackedAmountLimiter<'_> {
         id: &'a str,
     ) -> BoxFuture<'a, Option<Box<dyn AmountReservation + 'a>>> {
         async {
-            let (_file, data) = DataFile::open_and_read(self.path.as_ref()).await.unwrap();
-            if data.queue.contains_key(id) {
-                Some(Box::new(FileBackedAmountReservation {
+            let (_file, data) = self.open_and_read().await.unwrap();
+            data.queue.get(id).map(|item| {
+                Box::new(FileBackedAmountReservation {
                     limiter: self.clone(),
                     id,
-                }) as Box<dyn AmountReservation>)
-            } else {
-                None
-            }
+                    amount: item.amount,
+                    _heartbeat: self.heartbeat(id),
+                }) as Box<dyn AmountReservation>
+            })
         }
         .boxed()
     }
+
+    fn available_at<'a>(&'a self, len: usize, id: &'a str) -> BoxFuture<'a, UtcDateTime> {
+        async move {
+            let (file, data) = self.open_and_read().await.unwrap();
+            file.close().await.unwrap();
+            data.available_at(
+                self,
+                counted_amount(len, self.overhead),
+                id,
+                UtcDateTime::now(),
+            )
+        }
+        .boxed()
+    }
+}
+
+impl AmountLimiterInspect for FileBackedAmountLimiter<'_> {
+    fn usage(&self) -> BoxFuture<'_, AmountLimiterUsage> {
+        async { self.try_usage().await.unwrap() }.boxed()
+    }
+}
+
+/// A [`FileBackedAmountLimiter`] that can only read its file, for reporting tools such as a status command.
+/// The file is read with a shared lock, so that readers don't wait for each other, and it is never changed,
+/// not even to reset the usage for a new month or remove stale queue items, which are only left out of the usage.
+#[derive(Debug, Clone)]
+pub struct ReadOnlyFileBackedAmountLimiter<'a> {
+    limiter: FileBackedAmountLimiter<'a>,
+}
+
+impl ReadOnlyFileBackedAmountLimiter<'_> {
+    /// Like [`AmountLimiterInspect::usage`], but returns an error instead of panicking if the file can't be read
+    pub async fn try_usage(&self) -> Result<AmountLimiterUsage, ReadLimiterFileError> {
+        let limiter = &self.limiter;
+        let mut data =
+            DataFile::read_shared(limiter.path.as_ref(), limiter.format, limiter.utc_offset)
+                .await?;
+        limiter.remove_stale(&mut data);
+        Ok(limiter.usage_of(data))
+    }
+}
+
+impl AmountLimiterInspect for ReadOnlyFileBackedAmountLimiter<'_> {
+    fn usage(&self) -> BoxFuture<'_, AmountLimiterUsage> {
+        async { self.try_usage().await.unwrap() }.boxed()
+    }
 }
 
 pub struct FileBackedAmountReservation<'a> {
     limiter: FileBackedAmountLimiter<'a>,
     id: &'a str,
+    /// Counted if the queue item was removed as stale before the reservation finished
+    amount: usize,
+    _heartbeat: Option<Heartbeat>,
+}
+
+/// Stops refreshing the heartbeat when the reservation is dropped
+struct Heartbeat(JoinHandle<()>);
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
 }
 
 impl AmountReservation for FileBackedAmountReservation<'_> {
-    fn mark_complete(&self) -> BoxFuture<()> {
+    fn mark_complete(&self) -> BoxFuture<'_, ()> {
         async {
-            let (file, mut data) = DataFile::open_and_read(self.limiter.path.as_ref())
-                .await
-                .unwrap();
-            let item = data.queue.remove(self.id).unwrap();
-            data.used_this_month += item.amount;
+            let (file, mut data) = self.limiter.open_and_read().await.unwrap();
+            *data.used_mut(self.limiter.direction) += data
+                .queue
+                .remove(self.id)
+                .map_or(self.amount, |item| item.amount);
+            file.write_and_close(&data).await.unwrap();
+        }
+        .boxed()
+    }
+
+    fn mark_complete_with_len(&self, len: usize) -> BoxFuture<'_, ()> {
+        async move {
+            let (file, mut data) = self.limiter.open_and_read().await.unwrap();
+            data.queue.remove(self.id);
+            *data.used_mut(self.limiter.direction) += counted_amount(len, self.limiter.overhead);
             file.write_and_close(&data).await.unwrap();
         }
         .boxed()
     }
+
+    fn record_partial(&self, len: usize) -> BoxFuture<'_, ()> {
+        async move {
+            let (file, mut data) = self.limiter.open_and_read().await.unwrap();
+            *data.used_mut(self.limiter.direction) += counted_amount(len, self.limiter.overhead);
+            file.write_and_close(&data).await.unwrap();
+        }
+        .boxed()
+    }
+
+    fn cancel(&self) -> BoxFuture<'_, ()> {
+        async {
+            let (file, mut data) = self.limiter.open_and_read().await.unwrap();
+            data.queue.remove(self.id);
+            file.write_and_close(&data).await.unwrap();
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use time::{UtcDateTime, UtcOffset};
+    use tokio::time::{sleep, timeout};
+
+    use super::{DataFile, FileBackedAmountLimiter, TransferDirection, counted_amount};
+    use crate::{AmountLimiter, AmountLimiterInspect};
+
+    fn temp_path() -> String {
+        std::env::temp_dir()
+            .join(format!("rcs3ud-test-{:016x}.ron", fastrand::u64(..)))
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    #[test]
+    fn adds_overhead() {
+        assert_eq!(counted_amount(1000, 0.0), 1000);
+        assert_eq!(counted_amount(1000, 0.02), 1020);
+        assert_eq!(counted_amount(1, 0.02), 2);
+        assert_eq!(counted_amount(1000, -1.0), 1000);
+    }
+
+    #[tokio::test]
+    async fn counts_partial_transfers() {
+        let path = temp_path();
+        let limiter = FileBackedAmountLimiter::new(path.clone().into(), 10_000, "test".into());
+        let reservation = limiter.reserve(1000, "upload").await;
+        reservation.record_partial(300).await;
+        reservation.mark_complete().await;
+        let (file, data) = DataFile::open_and_read(&path, Default::default(), UtcOffset::UTC)
+            .await
+            .unwrap();
+        file.close().await.unwrap();
+        assert_eq!(data.used_this_month, 1300);
+        assert!(data.queue.is_empty());
+        let _waiting = limiter.reserve(2000, "download").await;
+        let usage = limiter.usage().await;
+        assert_eq!(usage.remaining(), 8700);
+        assert_eq!(usage.queue_total(), 2000);
+        assert_eq!(usage.queue[0].description, "test");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    // Locking the file blocks the thread, so the other limiter needs to run on another thread, like it would in another process
+    #[tokio::test(flavor = "multi_thread")]
+    async fn wakes_up_when_file_changes() {
+        let path = temp_path();
+        let limiter = FileBackedAmountLimiter::new(path.clone().into(), 1000, "test".into());
+        let first = limiter.reserve(800, "first").await;
+        // Another process, which only shares the file
+        let other = FileBackedAmountLimiter::new(path.clone().into(), 1000, "other".into());
+        let second = tokio::spawn(async move {
+            other.reserve(800, "second").await.mark_complete().await;
+        });
+        sleep(Duration::from_millis(100)).await;
+        first.cancel().await;
+        timeout(Duration::from_secs(10), second)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(limiter.usage().await.used_this_month, 800);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn higher_priority_goes_first() {
+        let path = temp_path();
+        let limiter = FileBackedAmountLimiter::new(path.clone().into(), 1000, "test".into());
+        let started = limiter.reserve(600, "started").await;
+        // Waits for "started", since it doesn't fit with it
+        assert!(
+            timeout(Duration::from_millis(50), limiter.reserve(600, "bulk"))
+                .await
+                .is_err()
+        );
+        let urgent = limiter.clone().with_priority(1);
+        let now = UtcDateTime::now();
+        assert!(urgent.available_at(300, "urgent").await <= UtcDateTime::now());
+        assert!(limiter.available_at(300, "after bulk").await > now);
+        let _urgent = urgent.reserve(300, "urgent").await;
+        let usage = limiter.usage().await;
+        assert_eq!(
+            usage
+                .queue
+                .iter()
+                .map(|item| item.id.as_str())
+                .collect::<Vec<_>>(),
+            ["started", "urgent", "bulk"]
+        );
+        started.mark_complete().await;
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn removes_stale_items() {
+        let path = temp_path();
+        // Like a process that crashed, since nothing refreshes it
+        FileBackedAmountLimiter::new(path.clone().into(), 1000, "crashed".into())
+            .reserve(800, "crashed")
+            .await;
+        let limiter = FileBackedAmountLimiter::new(path.clone().into(), 1000, "test".into())
+            .with_stale_after(Duration::from_millis(150));
+        let running = limiter.reserve(100, "running").await;
+        sleep(Duration::from_millis(300)).await;
+        let _new = timeout(Duration::from_secs(10), limiter.reserve(800, "new"))
+            .await
+            .unwrap();
+        let usage = limiter.usage().await;
+        assert_eq!(
+            usage
+                .queue
+                .iter()
+                .map(|item| item.id.as_str())
+                .collect::<Vec<_>>(),
+            ["running", "new"]
+        );
+        running.mark_complete().await;
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn separate_directions() {
+        let path = temp_path();
+        let limiter = FileBackedAmountLimiter::new(path.clone().into(), 1000, "test".into());
+        let upload = limiter
+            .clone()
+            .with_direction(TransferDirection::Upload, 1000);
+        let download = limiter
+            .clone()
+            .with_direction(TransferDirection::Download, 2000);
+        let uploading = upload.reserve(800, "upload").await;
+        // Doesn't wait for the upload, since it's counted separately
+        let downloading = timeout(Duration::from_secs(10), download.reserve(1500, "download"))
+            .await
+            .unwrap();
+        assert!(
+            timeout(
+                Duration::from_millis(50),
+                upload.reserve(800, "second upload")
+            )
+            .await
+            .is_err()
+        );
+        uploading.mark_complete().await;
+        downloading.mark_complete().await;
+        assert_eq!(upload.usage().await.used_this_month, 800);
+        let usage = download.usage().await;
+        assert_eq!(usage.used_this_month, 1500);
+        assert_eq!(usage.remaining(), 500);
+        assert!(usage.queue.is_empty());
+        assert_eq!(limiter.usage().await.used_this_month, 0);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn read_only_does_not_change_file() {
+        let path = temp_path();
+        let limiter = FileBackedAmountLimiter::new(path.clone().into(), 1000, "test".into());
+        // Doesn't create the file
+        assert_eq!(limiter.read_only().usage().await.used_this_month, 0);
+        assert!(std::fs::metadata(&path).is_err());
+        let reservation = limiter.reserve(800, "upload").await;
+        reservation.record_partial(300).await;
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let usage = limiter
+            .clone()
+            .with_stale_after(Duration::ZERO)
+            .read_only()
+            .try_usage()
+            .await
+            .unwrap();
+        assert_eq!(usage.used_this_month, 300);
+        // The stale item is left out, but not removed from the file
+        assert!(usage.queue.is_empty());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), contents);
+        reservation.mark_complete().await;
+        std::fs::remove_file(path).unwrap();
+    }
 }
@@ -0,0 +1,147 @@
+use std::{fmt, time::Duration};
+
+/// Which prefixes to use when formatting bytes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ByteUnits {
+    /// Powers of 1000: kB, MB, GB, ... This is what AWS bills and what most ISPs meter in.
+    #[default]
+    Si,
+    /// Powers of 1024: KiB, MiB, GiB, ...
+    Iec,
+}
+
+impl ByteUnits {
+    fn base(self) -> f64 {
+        match self {
+            Self::Si => 1000.0,
+            Self::Iec => 1024.0,
+        }
+    }
+
+    fn prefixes(self) -> [&'static str; 6] {
+        match self {
+            Self::Si => ["k", "M", "G", "T", "P", "E"],
+            Self::Iec => ["Ki", "Mi", "Gi", "Ti", "Pi", "Ei"],
+        }
+    }
+}
+
+/// Writes `value` bytes with the largest prefix that keeps it at least 1.
+/// The output doesn't depend on the locale, since it always uses `.` as the decimal separator and no thousands separator.
+fn write_bytes(
+    f: &mut fmt::Formatter<'_>,
+    value: f64,
+    units: ByteUnits,
+    suffix: &str,
+) -> fmt::Result {
+    let base = units.base();
+    if value < base {
+        return write!(f, "{value:.0} B{suffix}");
+    }
+    let mut value = value;
+    let mut prefix = "";
+    for p in units.prefixes() {
+        if value < base {
+            break;
+        }
+        value /= base;
+        prefix = p;
+    }
+    if value < 100.0 {
+        write!(f, "{value:.1} {prefix}B{suffix}")
+    } else {
+        write!(f, "{value:.0} {prefix}B{suffix}")
+    }
+}
+
+/// Formats an amount of bytes, such as `1.5 MB`
+#[derive(Debug, Clone, Copy)]
+pub struct HumanBytes {
+    pub len: usize,
+    pub units: ByteUnits,
+}
+
+impl fmt::Display for HumanBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_bytes(f, self.len as f64, self.units, "")
+    }
+}
+
+/// Formats a speed, such as `1.5 MB/s`
+#[derive(Debug, Clone, Copy)]
+pub struct HumanRate {
+    pub bytes_per_second: f64,
+    pub units: ByteUnits,
+}
+
+impl fmt::Display for HumanRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_bytes(f, self.bytes_per_second.max(0.0), self.units, "/s")
+    }
+}
+
+/// Formats a duration with its two largest units, such as `2d 3h`, `5m 10s`, or `250ms` if it's shorter than a second
+#[derive(Debug, Clone, Copy)]
+pub struct HumanDuration(pub Duration);
+
+impl fmt::Display for HumanDuration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.0.as_secs();
+        if secs == 0 {
+            return write!(f, "{}ms", self.0.as_millis());
+        }
+        let parts = [
+            (secs / 86400, "d"),
+            (secs / 3600 % 24, "h"),
+            (secs / 60 % 60, "m"),
+            (secs % 60, "s"),
+        ];
+        let first = parts.iter().position(|(n, _)| *n > 0).unwrap_or(3);
+        let (n, unit) = parts[first];
+        write!(f, "{n}{unit}")?;
+        match parts.get(first + 1) {
+            Some((n, unit)) if *n > 0 => write!(f, " {n}{unit}"),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{ByteUnits, HumanBytes, HumanDuration, HumanRate};
+
+    #[test]
+    fn formats() {
+        let bytes = |len, units| HumanBytes { len, units }.to_string();
+        assert_eq!(bytes(999, ByteUnits::Si), "999 B");
+        assert_eq!(bytes(1_500_000, ByteUnits::Si), "1.5 MB");
+        assert_eq!(bytes(5_000_000_000, ByteUnits::Si), "5.0 GB");
+        assert_eq!(bytes(700_000_000_000, ByteUnits::Si), "700 GB");
+        assert_eq!(bytes(1000, ByteUnits::Iec), "1000 B");
+        assert_eq!(bytes(1024 * 1024, ByteUnits::Iec), "1.0 MiB");
+        assert_eq!(
+            HumanRate {
+                bytes_per_second: 2_500_000.0,
+                units: ByteUnits::Si
+            }
+            .to_string(),
+            "2.5 MB/s"
+        );
+        assert_eq!(
+            HumanDuration(Duration::from_millis(250)).to_string(),
+            "250ms"
+        );
+        assert_eq!(HumanDuration(Duration::from_secs(45)).to_string(), "45s");
+        assert_eq!(
+            HumanDuration(Duration::from_secs(310)).to_string(),
+            "5m 10s"
+        );
+        assert_eq!(HumanDuration(Duration::from_secs(3600)).to_string(), "1h");
+        assert_eq!(
+            HumanDuration(Duration::from_secs(2 * 86400 + 3 * 3600 + 59)).to_string(),
+            "2d 3h"
+        );
+    }
+}
@@ -0,0 +1,230 @@
+use std::{
+    fmt, io,
+    ops::Range,
+    sync::{Arc, Mutex, OnceLock, Weak},
+    time::Duration,
+};
+
+use time::UtcDateTime;
+use tokio::time::{Instant, sleep};
+
+use crate::{OperationScheduler, StartTime};
+
+/// Returns the total number of bytes that went through the network so far, such as the received and sent bytes of an interface
+pub type NetworkProbe = Arc<dyn Fn() -> io::Result<u64> + Send + Sync>;
+
+/// Reads the received and sent bytes of a network interface from `/proc/net/dev`, on Linux
+pub fn proc_net_dev_probe(interface: impl Into<String>) -> NetworkProbe {
+    let interface = interface.into();
+    Arc::new(move || {
+        let s = std::fs::read_to_string("/proc/net/dev")?;
+        s.lines()
+            .filter_map(|line| line.trim_start().split_once(':'))
+            .find(|(name, _)| *name == interface)
+            .and_then(|(_, counters)| {
+                let counters = counters.split_whitespace().collect::<Vec<_>>();
+                // Received bytes are the first column, and sent bytes are the ninth
+                let received = counters.first()?.parse::<u64>().ok()?;
+                let sent = counters.get(8)?.parse::<u64>().ok()?;
+                Some(received + sent)
+            })
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "interface not found"))
+    })
+}
+
+struct Shared {
+    /// When the network became idle, or `None` if it is busy
+    idle_since: Mutex<Option<UtcDateTime>>,
+    /// Started the first time the scheduler is used
+    sampling: OnceLock<()>,
+}
+
+/// Only starts operations when the network has been idle for a while, such as when nobody else is using a shared home connection.
+/// The [`NetworkProbe`] is sampled in the background, starting the first time the scheduler is used, and the network is idle while it
+/// transfers at most `max_bytes_per_second`. If the probe fails, the network counts as busy.
+/// Clones share the samples. Must be used inside a Tokio runtime.
+///
+/// The probe also counts the transfers' own traffic if it measures this machine's interface,
+/// so operations that are already running aren't stopped unless [`IdleNetworkScheduler::pause_outside_windows`] is used,
+/// which is for probes that only measure other traffic, such as the counters of a router.
+#[derive(Clone)]
+pub struct IdleNetworkScheduler {
+    probe: NetworkProbe,
+    max_bytes_per_second: f64,
+    idle_for: Duration,
+    sample_interval: Duration,
+    pause_outside_windows: bool,
+    shared: Arc<Shared>,
+}
+
+impl fmt::Debug for IdleNetworkScheduler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IdleNetworkScheduler")
+            .field("max_bytes_per_second", &self.max_bytes_per_second)
+            .field("idle_for", &self.idle_for)
+            .field("sample_interval", &self.sample_interval)
+            .field("pause_outside_windows", &self.pause_outside_windows)
+            .finish_non_exhaustive()
+    }
+}
+
+impl IdleNetworkScheduler {
+    /// Operations start once the network transferred at most `max_bytes_per_second` for `idle_for`
+    pub fn new(probe: NetworkProbe, max_bytes_per_second: f64, idle_for: Duration) -> Self {
+        Self {
+            probe,
+            max_bytes_per_second,
+            idle_for,
+            sample_interval: Duration::from_secs(10),
+            pause_outside_windows: false,
+            shared: Arc::new(Shared {
+                idle_since: Mutex::new(None),
+                sampling: OnceLock::new(),
+            }),
+        }
+    }
+
+    /// How often the probe is sampled. The default is 10 seconds.
+    pub fn with_sample_interval(self, sample_interval: Duration) -> Self {
+        Self {
+            sample_interval,
+            ..self
+        }
+    }
+
+    /// Chunked uploads stop starting chunks while the network is busy, see [`OperationScheduler::pauses_outside_windows`]
+    pub fn pause_outside_windows(self) -> Self {
+        Self {
+            pause_outside_windows: true,
+            ..self
+        }
+    }
+
+    /// The earliest time that the network can have been idle for long enough
+    fn ready_at(&self) -> UtcDateTime {
+        self.shared.sampling.get_or_init(|| {
+            tokio::spawn(sample(
+                Arc::downgrade(&self.shared),
+                self.probe.clone(),
+                self.max_bytes_per_second,
+                self.sample_interval,
+            ));
+        });
+        let now = UtcDateTime::now();
+        self.shared.idle_since.lock().unwrap().unwrap_or(now) + self.idle_for
+    }
+}
+
+/// Samples the probe until every clone of the scheduler is dropped
+async fn sample(
+    shared: Weak<Shared>,
+    probe: NetworkProbe,
+    max_bytes_per_second: f64,
+    sample_interval: Duration,
+) {
+    let mut previous = None::<(Instant, u64)>;
+    loop {
+        let now = Instant::now();
+        let bytes = probe();
+        let Some(shared) = shared.upgrade() else {
+            break;
+        };
+        let speed = match (previous, &bytes) {
+            (Some((previous_time, previous_bytes)), Ok(bytes)) => Some(
+                bytes.saturating_sub(previous_bytes) as f64
+                    / (now - previous_time).as_secs_f64().max(f64::EPSILON),
+            ),
+            _ => None,
+        };
+        match speed {
+            Some(speed) if speed <= max_bytes_per_second => {
+                shared
+                    .idle_since
+                    .lock()
+                    .unwrap()
+                    .get_or_insert(UtcDateTime::now() - (now - previous.unwrap().0));
+            }
+            // Not known to be idle until there are two samples
+            None if bytes.is_ok() => {}
+            _ => *shared.idle_since.lock().unwrap() = None,
+        }
+        previous = bytes.ok().map(|bytes| (now, bytes));
+        drop(shared);
+        sleep(sample_interval).await;
+    }
+}
+
+impl OperationScheduler for IdleNetworkScheduler {
+    fn get_start_time(&self, bytes_to_upload: usize) -> StartTime {
+        self.get_start_time_after(UtcDateTime::now(), bytes_to_upload)
+    }
+
+    fn get_start_time_after(&self, after: UtcDateTime, _bytes_to_upload: usize) -> StartTime {
+        let start = self.ready_at().max(after);
+        if start <= UtcDateTime::now() {
+            StartTime::Now
+        } else {
+            StartTime::Later(start)
+        }
+    }
+
+    /// Only the time that the network can be idle from is known, so the window lasts until the next sample
+    fn next_window(&self, after: UtcDateTime) -> Option<Range<UtcDateTime>> {
+        let start = self.ready_at().max(after);
+        Some(start..start + self.sample_interval)
+    }
+
+    fn pauses_outside_windows(&self) -> bool {
+        self.pause_outside_windows
+    }
+
+    fn not_ready_until(&self) -> Option<UtcDateTime> {
+        Some(self.ready_at()).filter(|ready_at| *ready_at > UtcDateTime::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            Arc,
+            atomic::{AtomicBool, AtomicU64, Ordering},
+        },
+        time::Duration,
+    };
+
+    use tokio::time::sleep;
+
+    use super::IdleNetworkScheduler;
+    use crate::{OperationScheduler, StartTime};
+
+    #[tokio::test]
+    async fn waits_until_idle() {
+        let bytes = Arc::new(AtomicU64::new(0));
+        let busy = Arc::new(AtomicBool::new(true));
+        let scheduler = IdleNetworkScheduler::new(
+            Arc::new({
+                let bytes = bytes.clone();
+                let busy = busy.clone();
+                move || {
+                    if busy.load(Ordering::Relaxed) {
+                        bytes.fetch_add(1_000_000, Ordering::Relaxed);
+                    }
+                    Ok(bytes.load(Ordering::Relaxed))
+                }
+            }),
+            1000.0,
+            Duration::from_millis(100),
+        )
+        .with_sample_interval(Duration::from_millis(10));
+        assert!(matches!(scheduler.get_start_time(0), StartTime::Later(_)));
+        sleep(Duration::from_millis(200)).await;
+        assert!(scheduler.not_ready_until().is_some());
+        busy.store(false, Ordering::Relaxed);
+        sleep(Duration::from_millis(50)).await;
+        assert!(matches!(scheduler.get_start_time(0), StartTime::Later(_)));
+        sleep(Duration::from_millis(200)).await;
+        assert!(matches!(scheduler.get_start_time(0), StartTime::Now));
+        assert_eq!(scheduler.not_ready_until(), None);
+    }
+}
@@ -1,21 +1,95 @@
 mod amount_limiter;
+mod audit_log;
+mod backup;
+mod capabilities;
+mod checksum;
+mod client_side_encryption;
+mod clock_skew;
+mod compression;
+mod control;
 mod download;
+mod download_chunked_objects;
+mod encryption;
 mod file_backed_amount_limiter;
+mod format;
+mod idle_network_scheduler;
 mod maybe_retryable_sdk_error;
+mod multipart;
 mod operation_scheduler;
+mod progress_store;
+mod repair;
+mod restore;
+#[cfg(feature = "sqs")]
+mod restore_notification;
 mod retry;
+mod save_policy;
+mod scheduler_ext;
+mod serialization;
+mod shared_amount_limiter;
+#[cfg(feature = "sqlite")]
+mod sqlite_amount_limiter;
 mod start_of_next_month;
+mod storage_class_check;
+mod sync_down;
+mod sync_up;
+mod tags;
+mod timestamped;
+mod transfer;
+mod transfer_progress;
+mod tree_hash;
 mod upload;
 mod upload_chunked;
 mod upload_file;
+mod upload_multi;
+mod upload_src;
+mod verify;
+mod wall_clock;
 
 pub use amount_limiter::*;
+pub use audit_log::*;
+pub use backup::*;
+pub use capabilities::*;
+pub use checksum::*;
+pub use client_side_encryption::*;
+pub use clock_skew::*;
+pub use compression::*;
+pub use control::*;
 pub use download::*;
+pub use download_chunked_objects::*;
+pub use encryption::*;
 pub use file_backed_amount_limiter::*;
+pub use format::*;
+pub use idle_network_scheduler::*;
+pub use multipart::*;
 pub use operation_scheduler::*;
+pub use progress_store::*;
+pub use repair::*;
+pub use restore::*;
+pub use retry::{
+    RetriesExhausted, RetryBudget, RetryBudgetSpent, RetryDecision, RetryPolicy, Retrying,
+    record_retry_decisions,
+};
+pub use save_policy::*;
+pub use scheduler_ext::*;
 pub use serde;
+pub use serialization::*;
+pub use shared_amount_limiter::*;
+#[cfg(feature = "sqlite")]
+pub use sqlite_amount_limiter::*;
 pub use start_of_next_month::*;
+pub use storage_class_check::*;
+pub use sync_down::*;
+pub use sync_up::*;
+pub use tags::*;
 pub use time;
+pub use timestamped::*;
+pub use transfer::*;
+pub use transfer_progress::*;
+pub use tree_hash::{TREE_HASH_BLOCK_SIZE, TreeHash, is_tree_hash_aligned, tree_hash_file};
 pub use upload::*;
 pub use upload_chunked::*;
 pub use upload_file::*;
+pub use upload_multi::*;
+pub use upload_src::*;
+pub use verify::*;
+pub use wall_clock::{WALL_CLOCK_CHECK_INTERVAL, sleep_until_utc};
@@ -0,0 +1,416 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Instant,
+};
+
+use aws_sdk_s3::{
+    error::SdkError,
+    operation::{
+        complete_multipart_upload::{CompleteMultipartUploadError, CompleteMultipartUploadOutput},
+        create_multipart_upload::CreateMultipartUploadError,
+    },
+    types::{CompletedMultipartUpload, CompletedPart},
+};
+use serde::{Deserialize, Serialize};
+use sipper::{Sipper, Straw, sipper};
+use thiserror::Error;
+
+use crate::{
+    AmountLimiter, ControlHandle, CustomerKey, ObjectMetadata, OperationScheduler, PartEncryption,
+    RetriesExhausted, RetryPolicy, Retrying, S3Dest, SEGMENT_SIZE, UploadError, UploadEvent,
+    UploadInput, UploadSrc,
+    client_side_encryption::{
+        NONCE_PREFIX_LEN, ObjectEncryption, encrypt_byte_stream, encrypted_len,
+    },
+    maybe_retryable_sdk_error::IntoMaybeRetryable,
+    retry::{KeepRetryingExt, MaybeRetryable},
+    transfer_progress::{RateMeter, report_upload_progress},
+    upload::{count_sent, wait_to_start},
+};
+
+/// S3 requires every part except the last one to be at least 5 MiB
+pub const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+/// S3 allows at most 10,000 parts in a multipart upload
+pub const MAX_PARTS: usize = 10_000;
+/// S3 doesn't allow uploading more than 5 GiB with a single `PutObject` request
+pub const MAX_PUT_OBJECT_SIZE: usize = 5 * 1024 * 1024 * 1024;
+/// [`crate::upload`] uses parts of at least this size when it switches to a multipart upload
+const AUTO_MIN_PART_SIZE: usize = 64 * 1024 * 1024;
+
+/// The part size for [`upload_multipart`], so that there are at most [`MAX_PARTS`] parts.
+/// It's a multiple of [`SEGMENT_SIZE`], so that client-side encrypted parts start at a segment.
+fn auto_part_size(len: usize) -> usize {
+    len.div_ceil(MAX_PARTS)
+        .max(AUTO_MIN_PART_SIZE)
+        .next_multiple_of(SEGMENT_SIZE)
+}
+
+/// Saved state of a multipart upload, so that it can be resumed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MultipartProgress {
+    pub upload_id: String,
+    /// ETags of the uploaded parts, by chunk number. The part number is the chunk number + 1.
+    pub e_tags: BTreeMap<usize, String>,
+    /// The nonce prefix of a client-side encrypted upload, so that the parts uploaded after resuming are encrypted the same way
+    #[serde(default)]
+    pub client_side_nonce: Option<[u8; NONCE_PREFIX_LEN]>,
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Error)]
+pub enum MultipartError {
+    #[error("Error creating the multipart upload")]
+    CreateMultipartUpload(SdkError<CreateMultipartUploadError>),
+    #[error("S3 did not return an upload id")]
+    NoUploadId,
+    #[error("Error completing the multipart upload")]
+    CompleteMultipartUpload(SdkError<CompleteMultipartUploadError>),
+    #[error("Gave up retrying")]
+    RetriesExhausted(#[from] RetriesExhausted),
+}
+
+/// Starts a multipart upload and returns its upload id.
+///
+/// Parts of incomplete multipart uploads are billed at the `STANDARD` rate until the upload is completed or aborted,
+/// so consider adding a lifecycle rule that aborts incomplete multipart uploads.
+pub fn create_multipart_upload<'a>(
+    client: &'a aws_sdk_s3::Client,
+    dest: &'a S3Dest<'a>,
+    tagging: Option<&'a str>,
+    metadata: ObjectMetadata,
+    retry_policy: RetryPolicy,
+) -> impl Straw<String, Retrying<SdkError<CreateMultipartUploadError>>, MultipartError> {
+    sipper(async move |sender| {
+        let customer_key = dest.encryption.customer_key();
+        (async || {
+            client
+                .create_multipart_upload()
+                .bucket(dest.bucket)
+                .key(dest.object_key)
+                .storage_class(dest.storage_class.clone())
+                .set_tagging(tagging.map(str::to_owned))
+                .set_metadata(metadata.user())
+                .set_content_type(metadata.content_type.clone())
+                .set_content_disposition(metadata.content_disposition.clone())
+                .set_server_side_encryption(dest.encryption.server_side_encryption())
+                .set_ssekms_key_id(dest.encryption.kms_key_id())
+                .set_sse_customer_algorithm(customer_key.map(CustomerKey::algorithm))
+                .set_sse_customer_key(customer_key.map(CustomerKey::key))
+                .set_sse_customer_key_md5(customer_key.map(CustomerKey::key_md5))
+                .send()
+                .await
+                .map_err(|e| {
+                    e.into_maybe_retryable()
+                        .map(MultipartError::CreateMultipartUpload)
+                })
+        })
+        .keep_retrying(retry_policy)
+        .run(sender)
+        .await?
+        .upload_id
+        .ok_or(MultipartError::NoUploadId)
+    })
+}
+
+pub struct UploadPartInput<'a> {
+    pub client: &'a aws_sdk_s3::Client,
+    pub src: UploadSrc,
+    pub bucket: &'a str,
+    pub object_key: &'a str,
+    pub upload_id: &'a str,
+    /// Starts at 1
+    pub part_number: i32,
+    /// Must be the same key that the multipart upload was created with, if it uses SSE-C
+    pub customer_key: Option<CustomerKey>,
+    pub client_side_encryption: Option<PartEncryption>,
+    pub retry_policy: RetryPolicy,
+    pub operation_scheduler: Box<dyn OperationScheduler>,
+    /// See [`crate::UploadInput::amount_limiter`]
+    pub amount_limiter: Box<dyn AmountLimiter>,
+    /// See [`crate::UploadInput::control`]
+    pub control: ControlHandle,
+}
+
+/// Uploads one part of a multipart upload and returns its ETag
+pub fn upload_part(input: UploadPartInput<'_>) -> impl Straw<String, UploadEvent, UploadError> {
+    sipper(async move |mut sender| {
+        let result = ({
+            let mut sender = sender.clone();
+            let id = format!(
+                "upload:{}/{}#{}",
+                input.bucket, input.object_key, input.part_number
+            );
+            async move || {
+                let reservation = wait_to_start(
+                    &mut sender,
+                    &*input.amount_limiter,
+                    &*input.operation_scheduler,
+                    &input.control,
+                    input.src.len,
+                    &id,
+                )
+                .await
+                .map_err(|e| MaybeRetryable::NotRetryable(e.into()))?;
+                sender.send(UploadEvent::GettingUploadStream).await;
+                let byte_stream = input
+                    .src
+                    .byte_stream()
+                    .await
+                    .map_err(|e| MaybeRetryable::NotRetryable(UploadError::UploadStream(e)))?;
+                let (byte_stream, content_length) = match &input.client_side_encryption {
+                    Some(encryption) => (
+                        encrypt_byte_stream(
+                            byte_stream,
+                            &encryption.object,
+                            encryption.offset,
+                            input.src.len,
+                            encryption.ends_object,
+                        ),
+                        encrypted_len(input.src.len),
+                    ),
+                    None => (byte_stream, input.src.len),
+                };
+                let sent = Arc::new(AtomicUsize::new(0));
+                let byte_stream = count_sent(byte_stream, sent.clone());
+                sender.send(UploadEvent::StartingUpload).await;
+                // Each attempt sends the part from the start
+                let mut rate_meter = RateMeter::new(0, Instant::now());
+                let upload_part = input.control.run(
+                    input
+                        .client
+                        .upload_part()
+                        .bucket(input.bucket)
+                        .key(input.object_key)
+                        .upload_id(input.upload_id)
+                        .part_number(input.part_number)
+                        .set_sse_customer_algorithm(
+                            input.customer_key.as_ref().map(CustomerKey::algorithm),
+                        )
+                        .set_sse_customer_key(input.customer_key.as_ref().map(CustomerKey::key))
+                        .set_sse_customer_key_md5(
+                            input.customer_key.as_ref().map(CustomerKey::key_md5),
+                        )
+                        .body(byte_stream)
+                        .content_length(content_length.try_into().unwrap())
+                        .send(),
+                );
+                match report_upload_progress(
+                    upload_part,
+                    &mut sender,
+                    &mut rate_meter,
+                    &sent,
+                    content_length,
+                )
+                .await
+                {
+                    Ok(Ok(output)) => {
+                        reservation.mark_complete().await;
+                        output
+                            .e_tag
+                            .ok_or(MaybeRetryable::NotRetryable(UploadError::NoETag))
+                    }
+                    Ok(Err(e)) => {
+                        reservation
+                            .record_partial(sent.load(Ordering::Relaxed))
+                            .await;
+                        Err(e.into_maybe_retryable().map(UploadError::UploadPart))
+                    }
+                    Err(cancelled) => {
+                        // Part of the chunk may have been uploaded already
+                        reservation
+                            .record_partial(sent.load(Ordering::Relaxed))
+                            .await;
+                        reservation.cancel().await;
+                        Err(MaybeRetryable::NotRetryable(cancelled.into()))
+                    }
+                }
+            }
+        })
+        .keep_retrying(input.retry_policy)
+        .with(UploadEvent::UploadPartError)
+        .run(sender.clone())
+        .await;
+        if let Err(UploadError::Cancelled(_)) = &result {
+            sender.send(UploadEvent::Cancelled).await;
+        }
+        result
+    })
+}
+
+/// Combines the uploaded parts into a single object
+pub fn complete_multipart_upload<'a>(
+    client: &'a aws_sdk_s3::Client,
+    bucket: &'a str,
+    object_key: &'a str,
+    progress: &'a MultipartProgress,
+    customer_key: Option<&'a CustomerKey>,
+    retry_policy: RetryPolicy,
+) -> impl Straw<
+    CompleteMultipartUploadOutput,
+    Retrying<SdkError<CompleteMultipartUploadError>>,
+    MultipartError,
+> {
+    sipper(async move |sender| {
+        let parts = CompletedMultipartUpload::builder()
+            .set_parts(Some(
+                progress
+                    .e_tags
+                    .iter()
+                    .map(|(chunk, e_tag)| {
+                        CompletedPart::builder()
+                            .part_number((chunk + 1).try_into().unwrap())
+                            .e_tag(e_tag)
+                            .build()
+                    })
+                    .collect(),
+            ))
+            .build();
+        (async || {
+            client
+                .complete_multipart_upload()
+                .bucket(bucket)
+                .key(object_key)
+                .upload_id(&progress.upload_id)
+                .multipart_upload(parts.clone())
+                .set_sse_customer_algorithm(customer_key.map(CustomerKey::algorithm))
+                .set_sse_customer_key(customer_key.map(CustomerKey::key))
+                .set_sse_customer_key_md5(customer_key.map(CustomerKey::key_md5))
+                .send()
+                .await
+                .map_err(|e| {
+                    e.into_maybe_retryable()
+                        .map(MultipartError::CompleteMultipartUpload)
+                })
+        })
+        .keep_retrying(retry_policy)
+        .run(sender)
+        .await
+    })
+}
+
+/// Uploads the file with a multipart upload, for files that are too big for [`crate::upload`] to upload with one request.
+/// The multipart upload is aborted if it fails.
+pub(crate) fn upload_multipart<'a>(
+    input: &'a UploadInput<'a>,
+    object_key: &'a str,
+    encryption: Option<&'a ObjectEncryption>,
+    metadata: ObjectMetadata,
+) -> impl Straw<CompleteMultipartUploadOutput, UploadEvent, UploadError> {
+    sipper(async move |mut sender| {
+        let dest = S3Dest {
+            bucket: input.dest.bucket,
+            object_key,
+            storage_class: input.dest.storage_class.clone(),
+            encryption: input.dest.encryption.clone(),
+        };
+        let upload_id = create_multipart_upload(
+            input.client,
+            &dest,
+            (!input.tagging.is_empty()).then_some(input.tagging),
+            metadata,
+            input.retry_policy,
+        )
+        .with(UploadEvent::CreateMultipartUploadError)
+        .run(sender.clone())
+        .await
+        .map_err(UploadError::Multipart)?;
+        let mut progress = MultipartProgress {
+            upload_id,
+            e_tags: Default::default(),
+            client_side_nonce: encryption.map(ObjectEncryption::nonce_prefix),
+        };
+        let part_size = auto_part_size(input.src.len);
+        sender
+            .send(UploadEvent::UsingMultipartUpload { part_size })
+            .await;
+        // The progress of each part is turned into the progress of the whole object
+        let sent_len = |len| match encryption {
+            Some(_) => encrypted_len(len),
+            None => len,
+        };
+        let total = sent_len(input.src.len);
+        let mut parts_sent = 0;
+        let mut rate_meter = RateMeter::new(0, Instant::now());
+        let result: Result<_, UploadError> = async {
+            for (chunk, offset) in (0..input.src.len).step_by(part_size).enumerate() {
+                let len = part_size.min(input.src.len - offset);
+                let e_tag = upload_part(UploadPartInput {
+                    client: input.client,
+                    src: UploadSrc {
+                        data: input.src.data.clone(),
+                        offset: input.src.offset + offset,
+                        len,
+                    },
+                    bucket: input.dest.bucket,
+                    object_key,
+                    upload_id: &progress.upload_id,
+                    part_number: (chunk + 1).try_into().unwrap(),
+                    customer_key: input.dest.encryption.customer_key().cloned(),
+                    client_side_encryption: encryption.map(|encryption| PartEncryption {
+                        object: encryption.clone(),
+                        offset,
+                        ends_object: offset + part_size >= input.src.len,
+                    }),
+                    retry_policy: input.retry_policy,
+                    operation_scheduler: input.operation_scheduler.clone(),
+                    amount_limiter: input.amount_limiter.clone(),
+                    control: input.control.clone(),
+                })
+                .with(|event| match event {
+                    UploadEvent::Progress(part) => UploadEvent::Progress(rate_meter.progress(
+                        (parts_sent + part.transferred).min(total),
+                        Some(total),
+                        Instant::now(),
+                    )),
+                    event => event,
+                })
+                .run(sender.clone())
+                .await?;
+                parts_sent += sent_len(len);
+                progress.e_tags.insert(chunk, e_tag);
+            }
+            complete_multipart_upload(
+                input.client,
+                input.dest.bucket,
+                object_key,
+                &progress,
+                input.dest.encryption.customer_key(),
+                input.retry_policy,
+            )
+            .with(UploadEvent::CompleteMultipartUploadError)
+            .run(sender.clone())
+            .await
+            .map_err(UploadError::Multipart)
+        }
+        .await;
+        if result.is_err() {
+            // Otherwise the uploaded parts are billed until a lifecycle rule aborts the upload.
+            // This is best effort, since there is no way to resume the upload anyways.
+            let _ = input
+                .client
+                .abort_multipart_upload()
+                .bucket(input.dest.bucket)
+                .key(object_key)
+                .upload_id(&progress.upload_id)
+                .send()
+                .await;
+        }
+        result
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AUTO_MIN_PART_SIZE, MAX_PARTS, MAX_PUT_OBJECT_SIZE, auto_part_size};
+
+    #[test]
+    fn auto_part_size_stays_under_max_parts() {
+        assert_eq!(auto_part_size(MAX_PUT_OBJECT_SIZE + 1), AUTO_MIN_PART_SIZE);
+        let len: usize = 5 * 1024 * 1024 * 1024 * 1024;
+        assert!(len.div_ceil(auto_part_size(len)) <= MAX_PARTS);
+    }
+}
@@ -1,15 +1,114 @@
-use std::{ops::Range, time::Duration};
+use std::{
+    ops::Range,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use dyn_clone::DynClone;
-use time::{Date, Time, UtcDateTime};
+use time::{Date, PrimitiveDateTime, Time, UtcDateTime, UtcOffset, Weekday};
 
 pub enum StartTime {
     Now,
     Later(UtcDateTime),
 }
 
+/// What to do when an [`OperationScheduler`] returns a [`StartTime::Later`] that already passed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PastStartTime {
+    /// Start right away, as if it returned [`StartTime::Now`]
+    #[default]
+    StartNow,
+    /// Ask the scheduler again, up to this many times, and then start right away.
+    /// For a scheduler that plans a batch of operations and can plan them again.
+    AskAgain(usize),
+}
+
 pub trait OperationScheduler: DynClone {
     fn get_start_time(&self, bytes_to_upload: usize) -> StartTime;
+
+    /// Like [`OperationScheduler::get_start_time`], but the operation can't start before `after`,
+    /// such as because the [`crate::AmountLimiter`] won't let it through until then.
+    fn get_start_time_after(&self, after: UtcDateTime, bytes_to_upload: usize) -> StartTime {
+        match self.get_start_time(bytes_to_upload) {
+            StartTime::Later(time) if time >= after => StartTime::Later(time),
+            _ if after > UtcDateTime::now() => StartTime::Later(after),
+            start_time => start_time,
+        }
+    }
+
+    /// Every time that this is used, an event with the time and what was done is sent, so that scheduling bugs can be noticed
+    fn past_start_time(&self) -> PastStartTime {
+        PastStartTime::StartNow
+    }
+
+    /// The first time range that operations are run in that ends after `after`, starting at `after` if it's inside it.
+    /// Used to [`forecast`] a transfer. Returns `None` if operations aren't limited to windows.
+    fn next_window(&self, after: UtcDateTime) -> Option<Range<UtcDateTime>> {
+        let _ = after;
+        None
+    }
+
+    /// The speed that the schedule is planned with, in bytes per second
+    fn expected_speed(&self) -> Option<f64> {
+        None
+    }
+
+    /// Whether uploads that are split into chunks stop starting chunks when a window from [`OperationScheduler::next_window`] ends,
+    /// and continue when the next one starts. Otherwise only the start of each chunk is scheduled, and a chunk can start near the end of a window.
+    fn pauses_outside_windows(&self) -> bool {
+        false
+    }
+
+    /// Checked after waiting for the start time, right before the operation starts.
+    /// Returns a time to wait until and check again, for schedulers that depend on more than the time,
+    /// such as [`crate::IdleNetworkScheduler`].
+    fn not_ready_until(&self) -> Option<UtcDateTime> {
+        None
+    }
+
+    /// Called with the measured speed of the transfer in bytes per second, such as after each chunk of a chunked upload,
+    /// for schedulers that plan with the speed that it's actually going at
+    fn record_speed(&self, bytes_per_second: f64) {
+        let _ = bytes_per_second;
+    }
+}
+
+/// One of the windows in a [`forecast`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForecastWindow {
+    pub start: UtcDateTime,
+    pub end: UtcDateTime,
+    /// Expected to be transferred in this window
+    pub bytes: usize,
+}
+
+/// How many of `len` bytes are expected to be transferred in each of the scheduler's windows, at `speed` bytes per second.
+/// Empty if the scheduler doesn't have windows.
+pub fn forecast(
+    scheduler: &dyn OperationScheduler,
+    now: UtcDateTime,
+    len: usize,
+    speed: f64,
+) -> Vec<ForecastWindow> {
+    let mut windows = Vec::new();
+    let mut remaining = len;
+    let mut after = now;
+    // A very slow speed would need too many windows to be useful
+    while remaining > 0 && speed > 0.0 && windows.len() < 1000 {
+        let Some(window) = scheduler.next_window(after) else {
+            break;
+        };
+        let bytes = ((window.end - window.start).as_seconds_f64() * speed) as usize;
+        let bytes = bytes.min(remaining);
+        remaining -= bytes;
+        after = window.end;
+        windows.push(ForecastWindow {
+            start: window.start,
+            end: window.end,
+            bytes,
+        });
+    }
+    windows
 }
 
 dyn_clone::clone_trait_object!(OperationScheduler);
@@ -29,6 +128,10 @@ impl OperationScheduler for AnyTime {
 pub struct TimesOfDay {
     intervals: Box<[Range<Time>]>,
     upload_speed: f64,
+    /// Shared by clones, see [`TimesOfDay::measure_speed`]
+    measured_speed: Option<Arc<Mutex<Option<f64>>>>,
+    utc_offset: UtcOffset,
+    pause_outside_windows: bool,
 }
 
 impl TimesOfDay {
@@ -41,9 +144,54 @@ impl TimesOfDay {
         Self {
             intervals,
             upload_speed,
+            measured_speed: None,
+            utc_offset: UtcOffset::UTC,
+            pause_outside_windows: false,
+        }
+    }
+
+    /// The intervals are times of day in this offset instead of UTC, such as `22:00..06:00` for local night hours
+    pub fn with_utc_offset(self, utc_offset: UtcOffset) -> Self {
+        Self { utc_offset, ..self }
+    }
+
+    /// Chunked uploads pause between chunks when an interval ends, instead of running past it, see [`OperationScheduler::pauses_outside_windows`]
+    pub fn pause_outside_windows(self) -> Self {
+        Self {
+            pause_outside_windows: true,
+            ..self
         }
     }
 
+    /// Plans with the speed measured by the transfer, see [`OperationScheduler::record_speed`], instead of the upload speed it was made with.
+    /// The upload speed is still used until the first chunk of a chunked upload finishes.
+    /// Clones share the measured speed, so the rest of the chunks fit in the intervals at the speed that the first chunks went at.
+    pub fn measure_speed(self) -> Self {
+        Self {
+            measured_speed: Some(Default::default()),
+            ..self
+        }
+    }
+
+    fn speed(&self) -> f64 {
+        self.measured_speed
+            .as_ref()
+            .and_then(|measured_speed| *measured_speed.lock().unwrap())
+            .unwrap_or(self.upload_speed)
+    }
+
+    /// [`TimesOfDay::get_start_time`] with `now` and the start time in [`TimesOfDay::utc_offset`]
+    fn get_local_start_time(&self, now: UtcDateTime, bytes_to_upload: usize) -> UtcDateTime {
+        let now = now.to_offset(self.utc_offset);
+        let start = self.get_start_time(
+            UtcDateTime::new(now.date(), now.time()),
+            Duration::from_secs_f64(bytes_to_upload as f64 / self.speed()),
+        );
+        PrimitiveDateTime::new(start.date(), start.time())
+            .assume_offset(self.utc_offset)
+            .to_utc()
+    }
+
     fn get_start_time(&self, now: UtcDateTime, duration: Duration) -> UtcDateTime {
         fn duration_between(start: Time, end: Time) -> time::Duration {
             if start <= end {
@@ -108,12 +256,181 @@ impl TimesOfDay {
 
 impl OperationScheduler for TimesOfDay {
     fn get_start_time(&self, bytes_to_upload: usize) -> StartTime {
+        self.get_start_time_after(UtcDateTime::now(), bytes_to_upload)
+    }
+
+    fn next_window(&self, after: UtcDateTime) -> Option<Range<UtcDateTime>> {
+        let local_date = after.to_offset(self.utc_offset).date();
+        // Starting the day before, since an interval that goes past 12am can contain `after`
+        [
+            local_date.previous_day(),
+            Some(local_date),
+            local_date.next_day(),
+        ]
+        .into_iter()
+        .flatten()
+        .flat_map(|date| {
+            self.intervals.iter().map(move |interval| {
+                let end_date = if interval.end > interval.start {
+                    date
+                } else {
+                    date.next_day().unwrap()
+                };
+                let to_utc = |date, time| {
+                    PrimitiveDateTime::new(date, time)
+                        .assume_offset(self.utc_offset)
+                        .to_utc()
+                };
+                to_utc(date, interval.start)..to_utc(end_date, interval.end)
+            })
+        })
+        .filter(|window| window.end > after)
+        .map(|window| window.start.max(after)..window.end)
+        .min_by_key(|window| window.start)
+    }
+
+    fn expected_speed(&self) -> Option<f64> {
+        Some(self.speed())
+    }
+
+    fn pauses_outside_windows(&self) -> bool {
+        self.pause_outside_windows
+    }
+
+    fn record_speed(&self, bytes_per_second: f64) {
+        if let Some(measured_speed) = &self.measured_speed {
+            *measured_speed.lock().unwrap() = Some(bytes_per_second);
+        }
+    }
+
+    fn get_start_time_after(&self, after: UtcDateTime, bytes_to_upload: usize) -> StartTime {
         let now = UtcDateTime::now();
-        let start = self.get_start_time(
-            now,
-            Duration::from_secs_f64(bytes_to_upload as f64 / self.upload_speed),
-        );
-        StartTime::Later(start)
+        let start = self.get_local_start_time(after.max(now), bytes_to_upload);
+        // Inside an interval, so it isn't a time that already passed by the time it's used
+        if start <= now {
+            StartTime::Now
+        } else {
+            StartTime::Later(start)
+        }
+    }
+}
+
+/// Does the operation in time ranges that depend on the day of the week, such as any time on weekends and only `01:00..05:00` on weekdays.
+/// A range that ends before it starts goes past midnight into the next day, and `00:00..00:00` is the whole day.
+/// Like [`TimesOfDay`], the operation starts in the first range that it fits in, or else the longest range.
+/// Ranges that touch, such as whole days on the weekend, count as one range.
+#[derive(Debug, Clone)]
+pub struct WeeklySchedule {
+    /// By [`Weekday::number_days_from_monday`]
+    days: [Box<[Range<Time>]>; 7],
+    upload_speed: f64,
+    utc_offset: UtcOffset,
+    pause_outside_windows: bool,
+}
+
+impl WeeklySchedule {
+    /// No day has ranges until [`WeeklySchedule::with_day`] is used. Upload speed is in bytes per second.
+    pub fn new(upload_speed: f64) -> Self {
+        Self {
+            days: Default::default(),
+            upload_speed,
+            utc_offset: UtcOffset::UTC,
+            pause_outside_windows: false,
+        }
+    }
+
+    /// Replaces the ranges that operations can run in on `weekday`
+    pub fn with_day(mut self, weekday: Weekday, ranges: Box<[Range<Time>]>) -> Self {
+        self.days[weekday.number_days_from_monday() as usize] = ranges;
+        self
+    }
+
+    /// The days and ranges are in this offset instead of UTC
+    pub fn with_utc_offset(self, utc_offset: UtcOffset) -> Self {
+        Self { utc_offset, ..self }
+    }
+
+    /// See [`TimesOfDay::pause_outside_windows`]
+    pub fn pause_outside_windows(self) -> Self {
+        Self {
+            pause_outside_windows: true,
+            ..self
+        }
+    }
+
+    /// The ranges in the next week that end after `after`, starting at `after` if it's inside one, with ranges that touch merged
+    fn windows(&self, after: UtcDateTime) -> Vec<Range<UtcDateTime>> {
+        let local_date = after.to_offset(self.utc_offset).date();
+        let to_utc = |date, time| {
+            PrimitiveDateTime::new(date, time)
+                .assume_offset(self.utc_offset)
+                .to_utc()
+        };
+        let mut windows = Vec::<Range<UtcDateTime>>::new();
+        // Starting the day before, since a range that goes past midnight can contain `after`
+        let mut date = local_date.previous_day();
+        for _ in 0..9 {
+            let Some(day) = date else {
+                break;
+            };
+            for range in &self.days[day.weekday().number_days_from_monday() as usize] {
+                let end_date = if range.end > range.start {
+                    day
+                } else {
+                    day.next_day().unwrap()
+                };
+                let window = to_utc(day, range.start)..to_utc(end_date, range.end);
+                if window.end <= after {
+                    continue;
+                }
+                let window = window.start.max(after)..window.end;
+                match windows.last_mut() {
+                    Some(last) if window.start <= last.end => last.end = last.end.max(window.end),
+                    _ => windows.push(window),
+                }
+            }
+            date = day.next_day();
+        }
+        windows
+    }
+}
+
+impl OperationScheduler for WeeklySchedule {
+    fn get_start_time(&self, bytes_to_upload: usize) -> StartTime {
+        self.get_start_time_after(UtcDateTime::now(), bytes_to_upload)
+    }
+
+    fn get_start_time_after(&self, after: UtcDateTime, bytes_to_upload: usize) -> StartTime {
+        let now = UtcDateTime::now();
+        let duration = Duration::from_secs_f64(bytes_to_upload as f64 / self.upload_speed);
+        let windows = self.windows(after.max(now));
+        let start = windows
+            .iter()
+            .find(|window| window.end - window.start >= duration)
+            .or_else(|| {
+                windows
+                    .iter()
+                    .rev()
+                    .max_by_key(|window| window.end - window.start)
+            })
+            .map(|window| window.start);
+        match start {
+            Some(start) if start > now => StartTime::Later(start),
+            // Also if no day has ranges, so that the operation doesn't wait forever
+            _ => StartTime::Now,
+        }
+    }
+
+    fn next_window(&self, after: UtcDateTime) -> Option<Range<UtcDateTime>> {
+        self.windows(after).into_iter().next()
+    }
+
+    fn expected_speed(&self) -> Option<f64> {
+        Some(self.upload_speed)
+    }
+
+    fn pauses_outside_windows(&self) -> bool {
+        self.pause_outside_windows
     }
 }
 
@@ -121,9 +438,11 @@ impl OperationScheduler for TimesOfDay {
 mod tests {
     use std::time::Duration;
 
-    use time::{Date, Time, UtcDateTime};
+    use time::{Date, Month, Time, UtcDateTime, UtcOffset, Weekday};
 
-    use crate::TimesOfDay;
+    use crate::{
+        ForecastWindow, OperationScheduler, StartTime, TimesOfDay, WeeklySchedule, forecast,
+    };
 
     #[test]
     fn later_at_night() {
@@ -194,4 +513,122 @@ mod tests {
             UtcDateTime::new(Date::MIN, Time::from_hms(22, 0, 0).unwrap())
         );
     }
+
+    #[test]
+    fn local_night() {
+        // 15:00 in UTC-8 is 23:00 UTC, so 22:00 local time is 06:00 UTC the next day
+        let time = TimesOfDay::new(
+            Box::new([Time::from_hms(22, 0, 0).unwrap()..Time::from_hms(6, 0, 0).unwrap()]),
+            5_000_000.0,
+        )
+        .with_utc_offset(UtcOffset::from_hms(-8, 0, 0).unwrap())
+        .get_local_start_time(
+            UtcDateTime::new(Date::MIN, Time::from_hms(23, 0, 0).unwrap()),
+            5_000_000 * 60 * 60 * 2,
+        );
+        assert_eq!(
+            time,
+            UtcDateTime::new(
+                Date::MIN.next_day().unwrap(),
+                Time::from_hms(6, 0, 0).unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn forecast_across_nights() {
+        let at = |date: Date, hour| UtcDateTime::new(date, Time::from_hms(hour, 0, 0).unwrap());
+        let day = Date::from_ordinal_date(2025, 100).unwrap();
+        let next_day = day.next_day().unwrap();
+        let scheduler = TimesOfDay::new(
+            Box::new([Time::from_hms(22, 0, 0).unwrap()..Time::from_hms(6, 0, 0).unwrap()]),
+            1000.0,
+        );
+        assert_eq!(
+            scheduler.next_window(at(day, 23)),
+            Some(at(day, 23)..at(next_day, 6))
+        );
+        // 8 hours each night, so the last 2 hours are done the next night
+        assert_eq!(
+            forecast(&scheduler, at(day, 15), 1000 * 60 * 60 * 10, 1000.0),
+            [
+                ForecastWindow {
+                    start: at(day, 22),
+                    end: at(next_day, 6),
+                    bytes: 1000 * 60 * 60 * 8,
+                },
+                ForecastWindow {
+                    start: at(next_day, 22),
+                    end: at(next_day.next_day().unwrap(), 6),
+                    bytes: 1000 * 60 * 60 * 2,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn weekends_and_weekday_nights() {
+        let at = |date: Date, hour| UtcDateTime::new(date, Time::from_hms(hour, 0, 0).unwrap());
+        let weekday_night = || {
+            Box::new([Time::from_hms(1, 0, 0).unwrap()..Time::from_hms(5, 0, 0).unwrap()])
+                as Box<[_]>
+        };
+        let whole_day = || Box::new([Time::MIDNIGHT..Time::MIDNIGHT]) as Box<[_]>;
+        let scheduler = WeeklySchedule::new(1000.0)
+            .with_day(Weekday::Friday, weekday_night())
+            .with_day(Weekday::Saturday, whole_day())
+            .with_day(Weekday::Sunday, whole_day())
+            .with_day(Weekday::Monday, weekday_night());
+        let friday = Date::from_calendar_date(2030, Month::March, 1).unwrap();
+        assert_eq!(friday.weekday(), Weekday::Friday);
+        let saturday = friday.next_day().unwrap();
+        let monday = saturday.next_day().unwrap().next_day().unwrap();
+        assert_eq!(
+            scheduler.next_window(at(friday, 12)),
+            Some(at(saturday, 0)..at(monday, 0))
+        );
+        // The night doesn't fit 10 hours, but the weekend does
+        let start = |after, hours: usize| match scheduler
+            .get_start_time_after(after, 1000 * 60 * 60 * hours)
+        {
+            StartTime::Later(start) => start,
+            StartTime::Now => panic!("Should start later"),
+        };
+        assert_eq!(
+            start(at(friday.previous_day().unwrap(), 12), 10),
+            at(saturday, 0)
+        );
+        assert_eq!(
+            start(at(friday.previous_day().unwrap(), 12), 2),
+            at(friday, 1)
+        );
+        // Nothing fits 100 hours, so the longest range is used
+        assert_eq!(start(at(friday, 12), 100), at(saturday, 0));
+    }
+
+    #[test]
+    fn measured_speed() {
+        let date = Date::from_calendar_date(2030, Month::March, 1).unwrap();
+        let at = |hour| UtcDateTime::new(date, Time::from_hms(hour, 0, 0).unwrap());
+        let scheduler = TimesOfDay::new(
+            Box::new([
+                Time::from_hms(1, 0, 0).unwrap()..Time::from_hms(2, 0, 0).unwrap(),
+                Time::from_hms(3, 0, 0).unwrap()..Time::from_hms(7, 0, 0).unwrap(),
+            ]),
+            1000.0,
+        )
+        .measure_speed();
+        let start = |scheduler: &TimesOfDay| match scheduler
+            .get_start_time_after(at(0), 1000 * 60 * 60 * 2)
+        {
+            StartTime::Later(start) => start,
+            StartTime::Now => panic!("Should start later"),
+        };
+        // 2 hours doesn't fit in the first interval
+        assert_eq!(start(&scheduler), at(3));
+        // Measured by a clone, such as the upload of the first chunk
+        scheduler.clone().record_speed(4000.0);
+        assert_eq!(scheduler.expected_speed(), Some(4000.0));
+        assert_eq!(start(&scheduler), at(1));
+    }
 }
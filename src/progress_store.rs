@@ -0,0 +1,173 @@
+use std::{
+    error::Error as StdError,
+    io::{self, ErrorKind},
+    path::PathBuf,
+};
+
+use futures::future::BoxFuture;
+use serde::{Serialize, de::DeserializeOwned};
+use sipper::{FutureExt, Sender, Sipper, Straw};
+use thiserror::Error;
+use tokio::{fs::File, io::AsyncWriteExt};
+
+use crate::{SerializationError, SerializationFormat};
+
+#[derive(Debug, Error)]
+pub enum ProgressStoreError {
+    #[error("Error reading or writing the progress")]
+    Io(io::Error),
+    #[error("Error parsing the saved progress")]
+    Parse(SerializationError),
+    #[error("Error serializing the progress")]
+    Serialize(SerializationError),
+    /// For stores that aren't files, such as a database
+    #[error("Error in the progress store")]
+    Other(Box<dyn StdError + Send + Sync>),
+}
+
+/// Loads and saves the progress of an operation, so that it can be resumed after the program restarts.
+/// The operation loads the progress when it starts, saves it every time it sends the progress as an event,
+/// and clears it when it finishes.
+pub trait ProgressStore<P>: Send + Sync {
+    /// Returns `None` if no progress was saved, so the operation starts from the beginning
+    fn load(&self) -> BoxFuture<'_, Result<Option<P>, ProgressStoreError>>;
+
+    fn save<'a>(&'a self, progress: &'a P) -> BoxFuture<'a, Result<(), ProgressStoreError>>;
+
+    /// Called when the operation succeeded, so that the progress isn't used for the next one
+    fn clear(&self) -> BoxFuture<'_, Result<(), ProgressStoreError>>;
+}
+
+/// Saves the progress as RON, or another [`SerializationFormat`], in a file, which is deleted when the operation succeeds.
+/// The progress is written to a temporary file next to it which then replaces it,
+/// so that a crash while saving leaves the previous progress instead of a partly written file.
+#[derive(Debug, Clone)]
+pub struct FileProgressStore {
+    path: PathBuf,
+    keep_on_success: bool,
+    format: SerializationFormat,
+}
+
+impl FileProgressStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            keep_on_success: false,
+            format: Default::default(),
+        }
+    }
+
+    /// Keeps the file after the operation succeeds, such as to repair or verify a chunked upload later
+    pub fn keep_on_success(mut self) -> Self {
+        self.keep_on_success = true;
+        self
+    }
+
+    pub fn with_format(mut self, format: SerializationFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    fn temp_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".tmp");
+        path.into()
+    }
+}
+
+impl<P: Serialize + DeserializeOwned + Sync> ProgressStore<P> for FileProgressStore {
+    fn load(&self) -> BoxFuture<'_, Result<Option<P>, ProgressStoreError>> {
+        async move {
+            match tokio::fs::read_to_string(&self.path).await {
+                Ok(s) => Ok(Some(
+                    self.format
+                        .deserialize(&s)
+                        .map_err(ProgressStoreError::Parse)?,
+                )),
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(ProgressStoreError::Io(e)),
+            }
+        }
+        .boxed()
+    }
+
+    fn save<'a>(&'a self, progress: &'a P) -> BoxFuture<'a, Result<(), ProgressStoreError>> {
+        async move {
+            let s = self
+                .format
+                .serialize(progress)
+                .map_err(ProgressStoreError::Serialize)?;
+            let temp_path = self.temp_path();
+            async {
+                let mut file = File::create(&temp_path).await?;
+                file.write_all(s.as_bytes()).await?;
+                // Make sure the new progress is on the disk before it replaces the old progress
+                file.sync_all().await?;
+                tokio::fs::rename(&temp_path, &self.path).await
+            }
+            .await
+            .map_err(ProgressStoreError::Io)
+        }
+        .boxed()
+    }
+
+    fn clear(&self) -> BoxFuture<'_, Result<(), ProgressStoreError>> {
+        async move {
+            if self.keep_on_success {
+                return Ok(());
+            }
+            match tokio::fs::remove_file(&self.path).await {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(ProgressStoreError::Io(e)),
+                _ => Ok(()),
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Runs the operation, saving the progress of every event that `saved_progress` returns it for.
+/// The events are still sent, after the progress is saved.
+/// The progress is cleared if the operation succeeds.
+pub(crate) async fn run_saving_progress<O, E, Err, P>(
+    straw: impl Straw<O, E, Err>,
+    store: &dyn ProgressStore<P>,
+    mut sender: Sender<E>,
+    saved_progress: impl Fn(&E) -> Option<&P>,
+) -> Result<Result<O, Err>, ProgressStoreError> {
+    let mut straw = straw.pin();
+    while let Some(event) = straw.sip().await {
+        if let Some(progress) = saved_progress(&event) {
+            store.save(progress).await?;
+        }
+        sender.send(event).await;
+    }
+    let result = straw.await;
+    if result.is_ok() {
+        store.clear().await?;
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileProgressStore, ProgressStore};
+    use crate::UploadChunkedProgress;
+
+    #[tokio::test]
+    async fn file_round_trip() {
+        let path = std::env::temp_dir().join(format!("rcs3ud-test-{:016x}.ron", fastrand::u64(..)));
+        let store: &dyn ProgressStore<UploadChunkedProgress> = &FileProgressStore::new(&path);
+        assert!(store.load().await.unwrap().is_none());
+        let mut progress = UploadChunkedProgress::default();
+        progress.len = Some(3000);
+        progress.completed = [0, 2].into();
+        store.save(&progress).await.unwrap();
+        let loaded = store.load().await.unwrap().unwrap();
+        assert_eq!(loaded.len, Some(3000));
+        assert_eq!(loaded.completed, progress.completed);
+        assert!(!FileProgressStore::new(&path).temp_path().exists());
+        store.clear().await.unwrap();
+        assert!(!path.exists());
+        store.clear().await.unwrap();
+    }
+}
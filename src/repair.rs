@@ -0,0 +1,247 @@
+use std::{io, path::PathBuf};
+
+use aws_sdk_s3::{
+    error::SdkError,
+    operation::head_object::{HeadObjectError, HeadObjectOutput},
+    types::ChecksumMode,
+};
+use sipper::{Sipper, Straw, sipper};
+use thiserror::Error;
+use tokio::fs::metadata;
+
+use crate::{
+    AmountLimiter, ChecksumAlgorithm, KeySuffix, OperationScheduler, RetriesExhausted, RetryPolicy,
+    Retrying, S3Dest, UploadChunkedProgress, UploadData, UploadError, UploadEvent, UploadInput,
+    UploadSrc, checksum::compute_checksum, maybe_retryable_sdk_error::IntoMaybeRetryable,
+    retry::KeepRetryingExt, upload, upload_chunked::chunk_tagging,
+};
+
+pub struct RepairChunkedInput<'a> {
+    pub client: &'a aws_sdk_s3::Client,
+    /// Not used if the upload read several files, since the progress has their paths
+    pub src: PathBuf,
+    pub dest: S3Dest<'a>,
+    /// The progress of the finished [`crate::upload_chunked`], which records the len and chunk size
+    pub progress: &'a UploadChunkedProgress,
+    pub retry_policy: RetryPolicy,
+    pub operation_scheduler: Box<dyn OperationScheduler>,
+    /// Only the repaired chunks are reserved
+    pub amount_limiter: Box<dyn AmountLimiter>,
+    /// Used when re-uploading chunks, so that they can be fully verified the next time
+    pub checksum: Option<ChecksumAlgorithm>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RepairChunkedOutput {
+    /// Chunk numbers that were re-uploaded
+    pub repaired: Vec<usize>,
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Error)]
+pub enum RepairChunkedError {
+    #[error("The progress doesn't have the len and chunk size of the upload")]
+    IncompleteProgress,
+    #[error("Only uploads with separate objects for each chunk can be repaired")]
+    Multipart,
+    #[error("Error getting metadata of file")]
+    Metadata(io::Error),
+    #[error("The file changed since it was uploaded")]
+    SourceChanged,
+    #[error("Error checking a chunk")]
+    HeadObject(#[from] SdkError<HeadObjectError>),
+    #[error("Error reading the file to compute a chunk's checksum")]
+    Checksum(io::Error),
+    #[error("Error re-uploading a chunk")]
+    Upload(UploadError),
+    #[error("Gave up retrying")]
+    RetriesExhausted(#[from] RetriesExhausted),
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum RepairChunkedEvent {
+    CheckingChunk(usize),
+    CheckChunkError(Retrying<SdkError<HeadObjectError>>),
+    ChunkOk(usize),
+    /// Only the size could be checked because the chunk was uploaded without a checksum
+    ChunkOkWithoutChecksum(usize),
+    ChunkMissing(usize),
+    ChunkCorrupt(usize),
+    UploadEvent(UploadEvent),
+}
+
+/// Gets the object's metadata, including its checksum, or `None` if it doesn't exist
+pub(crate) fn head_if_exists<'a, E: From<SdkError<HeadObjectError>> + From<RetriesExhausted>>(
+    client: &'a aws_sdk_s3::Client,
+    bucket: &'a str,
+    object_key: &'a str,
+    retry_policy: RetryPolicy,
+) -> impl Straw<Option<HeadObjectOutput>, Retrying<SdkError<HeadObjectError>>, E> {
+    sipper(async move |sender| {
+        (async || match client
+            .head_object()
+            .bucket(bucket)
+            .key(object_key)
+            .checksum_mode(ChecksumMode::Enabled)
+            .send()
+            .await
+        {
+            Ok(output) => Ok(Some(output)),
+            Err(SdkError::ServiceError(e)) if e.err().is_not_found() => Ok(None),
+            Err(e) => Err(e.into_maybe_retryable().map(E::from)),
+        })
+        .keep_retrying(retry_policy)
+        .run(sender)
+        .await
+    })
+}
+
+/// Compares the remote chunk with the local file
+async fn chunk_status(output: &HeadObjectOutput, src: &UploadSrc) -> io::Result<ChunkStatus> {
+    if output.content_length() != Some(src.len as i64) {
+        return Ok(ChunkStatus::Corrupt);
+    }
+    let remote = match (output.checksum_sha256(), output.checksum_crc32_c()) {
+        (Some(checksum), _) => Some((ChecksumAlgorithm::Sha256, checksum)),
+        (None, Some(checksum)) => Some((ChecksumAlgorithm::Crc32c, checksum)),
+        (None, None) => None,
+    };
+    Ok(match remote {
+        Some((algorithm, remote)) => {
+            if compute_checksum(src, algorithm).await?.to_base64() == remote {
+                ChunkStatus::Ok
+            } else {
+                ChunkStatus::Corrupt
+            }
+        }
+        None => ChunkStatus::OkWithoutChecksum,
+    })
+}
+
+enum ChunkStatus {
+    Ok,
+    OkWithoutChecksum,
+    Corrupt,
+}
+
+/// Checks every chunk of a chunked upload against the local file, and re-uploads the chunks that are missing or different.
+/// Chunks are compared by size, and by checksum if they were uploaded with one.
+pub fn repair_chunked(
+    input: RepairChunkedInput<'_>,
+) -> impl Straw<RepairChunkedOutput, RepairChunkedEvent, RepairChunkedError> {
+    sipper(async move |mut sender| {
+        let (Some(len), Some(chunk_size)) = (input.progress.len, input.progress.chunk_size) else {
+            Err(RepairChunkedError::IncompleteProgress)?
+        };
+        if input.progress.multipart.is_some() {
+            Err(RepairChunkedError::Multipart)?;
+        }
+        let data = if input.progress.sources.is_empty() {
+            let file_len: usize = metadata(&input.src)
+                .await
+                .map_err(RepairChunkedError::Metadata)?
+                .len()
+                .try_into()
+                .unwrap();
+            if file_len != len {
+                Err(RepairChunkedError::SourceChanged)?;
+            }
+            UploadData::File(input.src.clone())
+        } else {
+            // The upload read several files, which the progress has
+            for source in &input.progress.sources {
+                let file_len: usize = metadata(&source.path)
+                    .await
+                    .map_err(RepairChunkedError::Metadata)?
+                    .len()
+                    .try_into()
+                    .unwrap();
+                if file_len != source.len {
+                    Err(RepairChunkedError::SourceChanged)?;
+                }
+            }
+            UploadData::Files(input.progress.sources.clone().into())
+        };
+        let total_chunks = len.div_ceil(chunk_size.get());
+        let mut output = RepairChunkedOutput::default();
+        for chunk in 0..total_chunks {
+            sender.send(RepairChunkedEvent::CheckingChunk(chunk)).await;
+            let object_key = format!("{}/{}", input.dest.object_key, chunk);
+            let src = UploadSrc {
+                data: data.clone(),
+                offset: chunk * chunk_size.get(),
+                len: (len - chunk * chunk_size.get()).min(chunk_size.get()),
+            };
+            let head = head_if_exists::<RepairChunkedError>(
+                input.client,
+                input.dest.bucket,
+                &object_key,
+                input.retry_policy,
+            )
+            .with(RepairChunkedEvent::CheckChunkError)
+            .run(sender.clone())
+            .await?;
+            match &head {
+                Some(head) => {
+                    match chunk_status(head, &src)
+                        .await
+                        .map_err(RepairChunkedError::Checksum)?
+                    {
+                        ChunkStatus::Ok => {
+                            sender.send(RepairChunkedEvent::ChunkOk(chunk)).await;
+                            continue;
+                        }
+                        ChunkStatus::OkWithoutChecksum => {
+                            sender
+                                .send(RepairChunkedEvent::ChunkOkWithoutChecksum(chunk))
+                                .await;
+                            continue;
+                        }
+                        ChunkStatus::Corrupt => {
+                            sender.send(RepairChunkedEvent::ChunkCorrupt(chunk)).await;
+                        }
+                    }
+                }
+                None => {
+                    sender.send(RepairChunkedEvent::ChunkMissing(chunk)).await;
+                }
+            }
+            upload(UploadInput {
+                client: input.client,
+                src,
+                dest: S3Dest {
+                    bucket: input.dest.bucket,
+                    object_key: &object_key,
+                    storage_class: input.dest.storage_class.clone(),
+                    encryption: input.dest.encryption.clone(),
+                },
+                retry_policy: input.retry_policy,
+                operation_scheduler: input.operation_scheduler.clone(),
+                amount_limiter: input.amount_limiter.clone(),
+                tagging: &chunk_tagging(
+                    input.dest.object_key,
+                    len,
+                    total_chunks,
+                    chunk_size,
+                    chunk,
+                ),
+                key_suffix: KeySuffix::None,
+                checksum: input.checksum,
+                multipart_threshold: None,
+                client_side_encryption: None,
+                compression: None,
+                metadata: Default::default(),
+                storage_class_check: None,
+                capabilities: Default::default(),
+                control: Default::default(),
+            })
+            .with(RepairChunkedEvent::UploadEvent)
+            .run(sender.clone())
+            .await
+            .map_err(RepairChunkedError::Upload)?;
+            output.repaired.push(chunk);
+        }
+        Ok(output)
+    })
+}
@@ -0,0 +1,600 @@
+use std::{
+    num::NonZeroU16,
+    time::{Duration, SystemTime},
+};
+
+use aws_sdk_s3::{
+    error::SdkError,
+    operation::{head_object::HeadObjectError, restore_object::RestoreObjectError},
+    types::{ArchiveStatus, GlacierJobParameters, RestoreRequest, StorageClass, Tier},
+};
+use serde::{Deserialize, Serialize};
+use sipper::{Sipper, Straw, sipper};
+use thiserror::Error;
+
+use crate::{
+    RetriesExhausted, RetryPolicy, Retrying, S3Src, maybe_retryable_sdk_error::IntoMaybeRetryable,
+    retry::KeepRetryingExt, sleep_until_utc,
+};
+
+#[derive(Debug, Clone)]
+pub enum WaitForRestoreStrategy {
+    /// Polls the object until it's restored.
+    ///
+    /// # Warning
+    /// Using a short durations, such as duration shorter than 30 minutes, will result in high costs.
+    /// Even with a 30 minute interval, restoring will cost $0.0048 if it takes 48 hours.
+    PollGet(Duration),
+    /// Waits for an `s3:ObjectRestore:Completed` event in an SQS queue, so that nothing is spent on polling while the object is restored.
+    /// Set up an S3 event notification or an EventBridge rule to send the event to the queue.
+    /// Several restores can share a queue, since messages for other objects are left in it.
+    #[cfg(feature = "sqs")]
+    SqsNotification {
+        client: aws_sdk_sqs::Client,
+        queue_url: String,
+        /// Checks the restore status anyways after this long without a notification, in case a notification was missed
+        fallback_interval: Duration,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RestoreInitiatedProgress {
+    /// Contains the time right after the restore request was completed, or the time after the last head object request was completed.
+    pub last_checked: SystemTime,
+    /// The time right after the restore request was completed.
+    /// Progress saved before this was added uses the time it was loaded.
+    #[serde(default = "SystemTime::now")]
+    pub initiated: SystemTime,
+    /// How long AWS says the restore takes for the object's storage class and the tier
+    #[serde(default)]
+    pub expected_duration: Option<ExpectedRestoreDuration>,
+    /// The tier that the restore was requested with, which is different from [`RestoreInput::tier`] if it was adjusted.
+    /// `None` for progress saved before this was added.
+    #[serde(default, with = "optional_tier")]
+    pub tier: Option<Tier>,
+    /// The [`RestoreInput::days`] that the restore was requested with
+    #[serde(default)]
+    pub days: Option<NonZeroU16>,
+    /// The [`WaitForRestoreStrategy::PollGet`] interval that the checks are scheduled with.
+    /// A resumed restore keeps using it, so that changing the interval doesn't move checks that are already scheduled.
+    #[serde(default)]
+    pub poll_interval: Option<Duration>,
+    /// When [`WaitForRestoreStrategy::PollGet`] checks the restore status next.
+    /// `None` for progress saved before this was added, which is checked one interval after [`RestoreInitiatedProgress::last_checked`].
+    #[serde(default)]
+    pub next_check: Option<SystemTime>,
+}
+
+/// The first time on the schedule that is after `now`.
+/// Checks stay on the schedule even if a check is slow, and checks that were missed while the process wasn't running are skipped instead of being done one after another.
+fn next_scheduled_check(scheduled: SystemTime, interval: Duration, now: SystemTime) -> SystemTime {
+    match now.duration_since(scheduled) {
+        Ok(late) if !interval.is_zero() => {
+            let missed = late.as_nanos() / interval.as_nanos() + 1;
+            scheduled + interval.saturating_mul(u32::try_from(missed).unwrap_or(u32::MAX))
+        }
+        Ok(_) => now,
+        Err(_) => scheduled,
+    }
+}
+
+/// Saves a [`Tier`] as its name, since the SDK types aren't serializable
+mod optional_tier {
+    use aws_sdk_s3::types::Tier;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(tier: &Option<Tier>, serializer: S) -> Result<S::Ok, S::Error> {
+        match tier {
+            Some(tier) => serializer.serialize_some(tier.as_str()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Tier>, D::Error> {
+        Ok(Option::<String>::deserialize(deserializer)?.map(|tier| Tier::from(tier.as_str())))
+    }
+}
+
+/// How long a restore usually takes, according to the S3 documentation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExpectedRestoreDuration {
+    pub min: Duration,
+    pub max: Duration,
+}
+
+/// The documented restore time of objects with this storage class, or `None` if it isn't known
+fn expected_restore_duration(
+    storage_class: Option<&StorageClass>,
+    archive_status: Option<&ArchiveStatus>,
+    tier: &Tier,
+) -> Option<ExpectedRestoreDuration> {
+    const MINUTE: u64 = 60;
+    const HOUR: u64 = 60 * MINUTE;
+    let deep = match (storage_class?, archive_status) {
+        (StorageClass::Glacier, _)
+        | (StorageClass::IntelligentTiering, Some(ArchiveStatus::ArchiveAccess)) => false,
+        (StorageClass::DeepArchive, _)
+        | (StorageClass::IntelligentTiering, Some(ArchiveStatus::DeepArchiveAccess)) => true,
+        _ => return None,
+    };
+    let (min, max) = match (tier, deep) {
+        (Tier::Expedited, false) => (MINUTE, 5 * MINUTE),
+        (Tier::Standard, false) => (3 * HOUR, 5 * HOUR),
+        (Tier::Bulk, false) => (5 * HOUR, 12 * HOUR),
+        // Only an upper bound is documented for the deep archive tiers
+        (Tier::Standard, true) => (0, 12 * HOUR),
+        (Tier::Bulk, true) => (0, 48 * HOUR),
+        _ => return None,
+    };
+    Some(ExpectedRestoreDuration {
+        min: Duration::from_secs(min),
+        max: Duration::from_secs(max),
+    })
+}
+
+/// The stages of restoring a cold object.
+/// Applications with their own persistence can save this and drive the restore with [`restore_step`].
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub enum RestoreStage {
+    #[default]
+    WillInitiateRestore,
+    RestoreInitiated(RestoreInitiatedProgress),
+    /// The file is ready to download or downloading
+    RestoreComplete,
+}
+
+pub struct RestoreInput<'a> {
+    pub client: &'a aws_sdk_s3::Client,
+    pub src: S3Src<'a>,
+    pub tier: Tier,
+    /// If the tier can't be used for the object's storage class (such as `Expedited` for `DEEP_ARCHIVE`),
+    /// use the `Standard` tier instead of failing with [`RestoreError::IncompatibleTier`].
+    pub adjust_incompatible_tier: bool,
+    /// How many days the restored copy is kept. It is billed at the `STANDARD` storage class, so don't keep it longer than needed.
+    pub days: NonZeroU16,
+    pub wait_for_restore_strategy: WaitForRestoreStrategy,
+    pub retry_policy: RetryPolicy,
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Error)]
+pub enum RestoreError {
+    #[error("Error restoring the object")]
+    RestoreError(SdkError<RestoreObjectError>),
+    #[error("Could not parse the value of x-amz-restore")]
+    UnknownRestoreString,
+    #[error("Error checking the restore status of the object")]
+    HeadError(SdkError<HeadObjectError>),
+    #[error("The {} tier can't be used to restore {} objects", tier.as_str(), storage_class.as_str())]
+    IncompatibleTier {
+        storage_class: StorageClass,
+        tier: Tier,
+    },
+    #[error("Gave up retrying")]
+    RetriesExhausted(#[from] RetriesExhausted),
+    #[cfg(feature = "sqs")]
+    #[error("Error receiving restore notifications")]
+    ReceiveNotification(
+        aws_sdk_sqs::error::SdkError<aws_sdk_sqs::operation::receive_message::ReceiveMessageError>,
+    ),
+    #[cfg(feature = "sqs")]
+    #[error("Error deleting a restore notification")]
+    DeleteNotification(
+        aws_sdk_sqs::error::SdkError<aws_sdk_sqs::operation::delete_message::DeleteMessageError>,
+    ),
+}
+
+#[derive(Debug)]
+pub enum RestoreEvent {
+    CheckStorageClassError(Retrying<SdkError<HeadObjectError>>),
+    /// The object's storage class can be downloaded without restoring it
+    RestoreNotNeeded,
+    /// The requested tier can't be used for the object's storage class, so a different tier is used
+    TierAdjusted {
+        from: Tier,
+        to: Tier,
+    },
+    RestoreError(Retrying<SdkError<RestoreObjectError>>),
+    RestoreInitiated,
+    /// Restore status was checked, and restoring is in progress
+    NotYetRestored,
+    /// The object is restored and available to download
+    RestoreComplete,
+    CheckStatusError(Retrying<SdkError<HeadObjectError>>),
+    #[cfg(feature = "sqs")]
+    ReceiveNotificationError(
+        Retrying<
+            aws_sdk_sqs::error::SdkError<
+                aws_sdk_sqs::operation::receive_message::ReceiveMessageError,
+            >,
+        >,
+    ),
+    /// A notification said the object is restored, so the restore status will be checked
+    #[cfg(feature = "sqs")]
+    NotificationReceived,
+    #[cfg(feature = "sqs")]
+    DeleteNotificationError(
+        Retrying<
+            aws_sdk_sqs::error::SdkError<
+                aws_sdk_sqs::operation::delete_message::DeleteMessageError,
+            >,
+        >,
+    ),
+}
+
+/// Returns `None` if objects with this storage class can be downloaded without restoring them.
+/// Otherwise returns whether the tier can be used to restore them.
+fn tier_supported(
+    storage_class: Option<&StorageClass>,
+    archive_status: Option<&ArchiveStatus>,
+    tier: &Tier,
+) -> Option<bool> {
+    match storage_class? {
+        StorageClass::Glacier => Some(true),
+        StorageClass::DeepArchive => Some(*tier != Tier::Expedited),
+        // Expedited retrievals aren't available for the Intelligent-Tiering archive access tiers
+        StorageClass::IntelligentTiering => archive_status.map(|_| *tier != Tier::Expedited),
+        _ => None,
+    }
+}
+
+/// Checks the object's storage class and sends the restore request.
+/// This is the [`RestoreStage::WillInitiateRestore`] to [`RestoreStage::RestoreInitiated`] transition,
+/// or to [`RestoreStage::RestoreComplete`] if the object doesn't need to be restored.
+pub fn initiate_restore(
+    input: &RestoreInput<'_>,
+) -> impl Straw<RestoreStage, RestoreEvent, RestoreError> {
+    sipper(async move |mut sender| {
+        let head = (async || {
+            input
+                .client
+                .head_object()
+                .bucket(input.src.bucket)
+                .key(input.src.object_key)
+                .send()
+                .await
+                .map_err(|e| e.into_maybe_retryable().map(RestoreError::HeadError))
+        })
+        .keep_retrying(input.retry_policy)
+        .with(RestoreEvent::CheckStorageClassError)
+        .run(sender.clone())
+        .await?;
+        let tier = match tier_supported(head.storage_class(), head.archive_status(), &input.tier) {
+            None => {
+                sender.send(RestoreEvent::RestoreNotNeeded).await;
+                return Ok(RestoreStage::RestoreComplete);
+            }
+            Some(true) => input.tier.clone(),
+            Some(false) => {
+                if input.adjust_incompatible_tier {
+                    sender
+                        .send(RestoreEvent::TierAdjusted {
+                            from: input.tier.clone(),
+                            to: Tier::Standard,
+                        })
+                        .await;
+                    Tier::Standard
+                } else {
+                    return Err(RestoreError::IncompatibleTier {
+                        // Will always be Some since a restore is needed
+                        storage_class: head.storage_class().unwrap().clone(),
+                        tier: input.tier.clone(),
+                    });
+                }
+            }
+        };
+        match (async || {
+            input
+                .client
+                .restore_object()
+                .bucket(input.src.bucket)
+                .key(input.src.object_key)
+                .restore_request(
+                    RestoreRequest::builder()
+                        .days(input.days.get().into())
+                        .glacier_job_parameters(
+                            GlacierJobParameters::builder()
+                                .tier(tier.clone())
+                                .build()
+                                // Will always be Ok since we specified tier
+                                .unwrap(),
+                        )
+                        .build(),
+                )
+                .send()
+                .await
+                .map_err(|e| e.into_maybe_retryable().map(RestoreError::RestoreError))
+        })
+        .keep_retrying(input.retry_policy)
+        .with(RestoreEvent::RestoreError)
+        .run(sender.clone())
+        .await
+        {
+            Ok(_) => Ok(()),
+            Err(RestoreError::RestoreError(SdkError::ServiceError(e)))
+                if e.err().meta().code() == Some("RestoreAlreadyInProgress") =>
+            {
+                // This is ok, we can just wait for it to be restored
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }?;
+        sender.send(RestoreEvent::RestoreInitiated).await;
+        let now = SystemTime::now();
+        let poll_interval = match &input.wait_for_restore_strategy {
+            WaitForRestoreStrategy::PollGet(poll_interval) => Some(*poll_interval),
+            #[cfg(feature = "sqs")]
+            WaitForRestoreStrategy::SqsNotification { .. } => None,
+        };
+        Ok(RestoreStage::RestoreInitiated(RestoreInitiatedProgress {
+            last_checked: now,
+            initiated: now,
+            expected_duration: expected_restore_duration(
+                head.storage_class(),
+                head.archive_status(),
+                &tier,
+            ),
+            tier: Some(tier),
+            days: Some(input.days),
+            poll_interval,
+            next_check: poll_interval.map(|poll_interval| now + poll_interval),
+        }))
+    })
+}
+
+/// Waits according to the [`WaitForRestoreStrategy`] and then checks the restore status once.
+/// Returns [`RestoreStage::RestoreInitiated`] if the object is still being restored,
+/// [`RestoreStage::RestoreComplete`] if it is restored,
+/// or [`RestoreStage::WillInitiateRestore`] if the restored copy expired and it needs to be restored again.
+pub fn check_restore<'a>(
+    input: &'a RestoreInput<'a>,
+    progress: &'a RestoreInitiatedProgress,
+) -> impl Straw<RestoreStage, RestoreEvent, RestoreError> {
+    sipper(async move |mut sender| {
+        match &input.wait_for_restore_strategy {
+            WaitForRestoreStrategy::PollGet(poll_interval) => {
+                let poll_interval = progress.poll_interval.unwrap_or(*poll_interval);
+                let next_check = progress
+                    .next_check
+                    .unwrap_or(progress.last_checked + poll_interval);
+                sleep_until_utc(next_check.into()).await;
+            }
+            #[cfg(feature = "sqs")]
+            WaitForRestoreStrategy::SqsNotification {
+                client,
+                queue_url,
+                fallback_interval,
+            } => {
+                crate::restore_notification::wait_for_notification(
+                    input,
+                    client,
+                    queue_url,
+                    *fallback_interval,
+                    progress.last_checked,
+                )
+                .run(sender.clone())
+                .await?;
+            }
+        }
+        match (async || {
+            input
+                .client
+                .head_object()
+                .bucket(input.src.bucket)
+                .key(input.src.object_key)
+                .send()
+                .await
+                .map_err(|e| e.into_maybe_retryable().map(RestoreError::HeadError))
+        })
+        .keep_retrying(input.retry_policy)
+        .with(RestoreEvent::CheckStatusError)
+        .run(sender.clone())
+        .await?
+        .restore()
+        {
+            None => {
+                // The restored object probably expired and became cold again since we restored it.
+                // Let's restore it again.
+                Ok(RestoreStage::WillInitiateRestore)
+            }
+            Some(message) => {
+                if message.starts_with("ongoing-request=\"false\"") {
+                    sender.send(RestoreEvent::RestoreComplete).await;
+                    Ok(RestoreStage::RestoreComplete)
+                } else if message.starts_with("ongoing-request=\"true\"") {
+                    sender.send(RestoreEvent::NotYetRestored).await;
+                    let now = SystemTime::now();
+                    let next_check = match &input.wait_for_restore_strategy {
+                        WaitForRestoreStrategy::PollGet(poll_interval) => {
+                            let poll_interval = progress.poll_interval.unwrap_or(*poll_interval);
+                            Some(next_scheduled_check(
+                                progress
+                                    .next_check
+                                    .unwrap_or(progress.last_checked + poll_interval),
+                                poll_interval,
+                                now,
+                            ))
+                        }
+                        #[cfg(feature = "sqs")]
+                        WaitForRestoreStrategy::SqsNotification { .. } => None,
+                    };
+                    Ok(RestoreStage::RestoreInitiated(RestoreInitiatedProgress {
+                        last_checked: now,
+                        next_check,
+                        ..progress.clone()
+                    }))
+                } else {
+                    Err(RestoreError::UnknownRestoreString)
+                }
+            }
+        }
+    })
+}
+
+/// Does one transition of the restore state machine and returns the next stage.
+/// Save the returned stage and call this again until it returns [`RestoreStage::RestoreComplete`].
+pub fn restore_step<'a>(
+    input: &'a RestoreInput<'a>,
+    stage: &'a RestoreStage,
+) -> impl Straw<RestoreStage, RestoreEvent, RestoreError> {
+    sipper(async move |sender| match stage {
+        RestoreStage::WillInitiateRestore => initiate_restore(input).run(sender).await,
+        RestoreStage::RestoreInitiated(progress) => {
+            check_restore(input, progress).run(sender).await
+        }
+        RestoreStage::RestoreComplete => Ok(RestoreStage::RestoreComplete),
+    })
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum WaitUntilRestoredEvent {
+    RestoreEvent(RestoreEvent),
+    UpdateSavedProgress(RestoreStage),
+}
+
+/// Initiates a restore if needed and waits until the object is restored, without downloading it.
+/// This is useful for restoring many objects ahead of time.
+/// Pass `Default::default()` as the stage unless you saved it from [`WaitUntilRestoredEvent::UpdateSavedProgress`].
+pub fn wait_until_restored(
+    input: RestoreInput<'_>,
+    mut stage: RestoreStage,
+) -> impl Straw<(), WaitUntilRestoredEvent, RestoreError> {
+    sipper(async move |mut sender| {
+        while !matches!(stage, RestoreStage::RestoreComplete) {
+            stage = restore_step(&input, &stage)
+                .with(WaitUntilRestoredEvent::RestoreEvent)
+                .run(sender.clone())
+                .await?;
+            sender
+                .send(WaitUntilRestoredEvent::UpdateSavedProgress(stage.clone()))
+                .await;
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use aws_sdk_s3::types::{ArchiveStatus, StorageClass, Tier};
+
+    use super::{
+        ExpectedRestoreDuration, RestoreInitiatedProgress, expected_restore_duration,
+        next_scheduled_check, tier_supported,
+    };
+
+    #[test]
+    fn standard_needs_no_restore() {
+        assert_eq!(tier_supported(None, None, &Tier::Bulk), None);
+        assert_eq!(
+            tier_supported(Some(&StorageClass::GlacierIr), None, &Tier::Bulk),
+            None
+        );
+        assert_eq!(
+            tier_supported(Some(&StorageClass::IntelligentTiering), None, &Tier::Bulk),
+            None
+        );
+    }
+
+    #[test]
+    fn expedited() {
+        assert_eq!(
+            tier_supported(Some(&StorageClass::Glacier), None, &Tier::Expedited),
+            Some(true)
+        );
+        assert_eq!(
+            tier_supported(Some(&StorageClass::DeepArchive), None, &Tier::Expedited),
+            Some(false)
+        );
+        assert_eq!(
+            tier_supported(
+                Some(&StorageClass::IntelligentTiering),
+                Some(&ArchiveStatus::ArchiveAccess),
+                &Tier::Expedited
+            ),
+            Some(false)
+        );
+        assert_eq!(
+            tier_supported(Some(&StorageClass::DeepArchive), None, &Tier::Bulk),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn expected_duration() {
+        let hours = |min: u64, max: u64| {
+            Some(ExpectedRestoreDuration {
+                min: Duration::from_secs(min * 3600),
+                max: Duration::from_secs(max * 3600),
+            })
+        };
+        assert_eq!(
+            expected_restore_duration(Some(&StorageClass::Glacier), None, &Tier::Standard),
+            hours(3, 5)
+        );
+        assert_eq!(
+            expected_restore_duration(Some(&StorageClass::DeepArchive), None, &Tier::Bulk),
+            hours(0, 48)
+        );
+        assert_eq!(
+            expected_restore_duration(
+                Some(&StorageClass::IntelligentTiering),
+                Some(&ArchiveStatus::DeepArchiveAccess),
+                &Tier::Standard
+            ),
+            hours(0, 12)
+        );
+        assert_eq!(
+            expected_restore_duration(Some(&StorageClass::DeepArchive), None, &Tier::Expedited),
+            None
+        );
+        assert_eq!(
+            expected_restore_duration(Some(&StorageClass::Standard), None, &Tier::Bulk),
+            None
+        );
+    }
+
+    #[test]
+    fn initiated_progress_keeps_tier() {
+        let progress = ron::from_str::<RestoreInitiatedProgress>(
+            "(last_checked: (secs_since_epoch: 0, nanos_since_epoch: 0), tier: Some(\"Bulk\"), days: Some(3))",
+        )
+        .unwrap();
+        assert_eq!(progress.tier, Some(Tier::Bulk));
+        let progress =
+            ron::from_str::<RestoreInitiatedProgress>(&ron::to_string(&progress).unwrap()).unwrap();
+        assert_eq!(progress.tier, Some(Tier::Bulk));
+        assert_eq!(progress.days.map(|days| days.get()), Some(3));
+        // Saved before the tier was saved
+        let progress = ron::from_str::<RestoreInitiatedProgress>(
+            "(last_checked: (secs_since_epoch: 0, nanos_since_epoch: 0))",
+        )
+        .unwrap();
+        assert_eq!(progress.tier, None);
+    }
+
+    #[test]
+    fn schedule_without_drift() {
+        let start = SystemTime::UNIX_EPOCH;
+        let interval = Duration::from_secs(1800);
+        // The check took a while, but the next one is still on the schedule
+        assert_eq!(
+            next_scheduled_check(start, interval, start + Duration::from_secs(20)),
+            start + interval
+        );
+        // Missed checks are skipped
+        assert_eq!(
+            next_scheduled_check(start, interval, start + Duration::from_secs(4000)),
+            start + 3 * interval
+        );
+        // Checked before it was scheduled
+        assert_eq!(
+            next_scheduled_check(start + interval, interval, start),
+            start + interval
+        );
+    }
+}
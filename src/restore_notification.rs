@@ -0,0 +1,197 @@
+use std::time::{Duration, SystemTime};
+
+use serde::Deserialize;
+use sipper::{Sipper, Straw, sipper};
+
+use crate::{
+    RestoreError, RestoreEvent, RestoreInput, maybe_retryable_sdk_error::IntoMaybeRetryable,
+    retry::KeepRetryingExt,
+};
+
+/// SQS doesn't allow long polling for longer than this
+const MAX_WAIT_TIME: Duration = Duration::from_secs(20);
+
+#[derive(Deserialize)]
+struct S3Entity {
+    bucket: Bucket,
+    object: Object,
+}
+
+#[derive(Deserialize)]
+struct Bucket {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct Object {
+    key: String,
+}
+
+#[derive(Deserialize)]
+struct S3Record {
+    #[serde(rename = "eventName")]
+    event_name: String,
+    s3: S3Entity,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NotificationBody {
+    /// Sent by S3 event notifications
+    S3 {
+        #[serde(rename = "Records")]
+        records: Vec<S3Record>,
+    },
+    /// Sent by EventBridge
+    EventBridge {
+        #[serde(rename = "detail-type")]
+        detail_type: String,
+        detail: S3Entity,
+    },
+    /// S3 event notifications sent through an SNS topic
+    Sns {
+        #[serde(rename = "Message")]
+        message: String,
+    },
+}
+
+/// Decodes a key in an S3 event notification, which are URL-encoded with `+` for spaces
+fn decode_key(key: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(key.len());
+    let mut iter = key.bytes();
+    while let Some(byte) = iter.next() {
+        bytes.push(match byte {
+            b'+' => b' ',
+            b'%' => {
+                let hex = [iter.next()?, iter.next()?];
+                u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?
+            }
+            byte => byte,
+        });
+    }
+    String::from_utf8(bytes).ok()
+}
+
+impl S3Entity {
+    fn is_object(&self, bucket: &str, object_key: &str) -> bool {
+        self.bucket.name == bucket
+            && (self.object.key == object_key
+                || decode_key(&self.object.key).is_some_and(|key| key == object_key))
+    }
+}
+
+/// Returns `true` if the message body says that the object's restore completed
+fn is_restore_completed(body: &str, bucket: &str, object_key: &str) -> bool {
+    match serde_json::from_str::<NotificationBody>(body) {
+        Ok(NotificationBody::S3 { records }) => records.iter().any(|record| {
+            record.event_name == "ObjectRestore:Completed"
+                && record.s3.is_object(bucket, object_key)
+        }),
+        Ok(NotificationBody::EventBridge {
+            detail_type,
+            detail,
+        }) => detail_type == "Object Restore Completed" && detail.is_object(bucket, object_key),
+        Ok(NotificationBody::Sns { message }) => is_restore_completed(&message, bucket, object_key),
+        Err(_) => false,
+    }
+}
+
+/// Waits until a restore completed notification for the object is received, or until `fallback_interval` after `last_checked`.
+/// The notification for this object is deleted from the queue. Other messages are left for other restores that use the same queue.
+pub(crate) fn wait_for_notification<'a>(
+    input: &'a RestoreInput<'a>,
+    client: &'a aws_sdk_sqs::Client,
+    queue_url: &'a str,
+    fallback_interval: Duration,
+    last_checked: SystemTime,
+) -> impl Straw<(), RestoreEvent, RestoreError> {
+    sipper(async move |mut sender| {
+        loop {
+            let wait_time = fallback_interval
+                .saturating_sub(last_checked.elapsed().unwrap_or_default())
+                .min(MAX_WAIT_TIME);
+            if wait_time.is_zero() {
+                break Ok(());
+            }
+            let output = (async || {
+                client
+                    .receive_message()
+                    .queue_url(queue_url)
+                    .wait_time_seconds(wait_time.as_secs().max(1).try_into().unwrap())
+                    .max_number_of_messages(10)
+                    .send()
+                    .await
+                    .map_err(|e| {
+                        e.into_maybe_retryable()
+                            .map(RestoreError::ReceiveNotification)
+                    })
+            })
+            .keep_retrying(input.retry_policy)
+            .with(RestoreEvent::ReceiveNotificationError)
+            .run(sender.clone())
+            .await?;
+            let notification = output.messages().iter().find(|message| {
+                message.body().is_some_and(|body| {
+                    is_restore_completed(body, input.src.bucket, input.src.object_key)
+                })
+            });
+            if let Some(notification) = notification {
+                sender.send(RestoreEvent::NotificationReceived).await;
+                if let Some(receipt_handle) = notification.receipt_handle() {
+                    (async || {
+                        client
+                            .delete_message()
+                            .queue_url(queue_url)
+                            .receipt_handle(receipt_handle)
+                            .send()
+                            .await
+                            .map_err(|e| {
+                                e.into_maybe_retryable()
+                                    .map(RestoreError::DeleteNotification)
+                            })
+                    })
+                    .keep_retrying(input.retry_policy)
+                    .with(RestoreEvent::DeleteNotificationError)
+                    .run(sender.clone())
+                    .await?;
+                }
+                break Ok(());
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_key, is_restore_completed};
+
+    #[test]
+    fn decodes_keys() {
+        assert_eq!(decode_key("a+b%2Fc.txt").as_deref(), Some("a b/c.txt"));
+        assert_eq!(decode_key("%E2%9C%93").as_deref(), Some("✓"));
+        assert_eq!(decode_key("%2"), None);
+    }
+
+    #[test]
+    fn restore_completed_notifications() {
+        let s3 = r#"{"Records":[{"eventName":"ObjectRestore:Completed","s3":{"bucket":{"name":"rcs3ud"},"object":{"key":"Cold+README.md"}}}]}"#;
+        assert!(is_restore_completed(s3, "rcs3ud", "Cold README.md"));
+        assert!(!is_restore_completed(s3, "rcs3ud", "README.md"));
+        assert!(!is_restore_completed(s3, "other", "Cold README.md"));
+        let post = s3.replace("ObjectRestore:Completed", "ObjectRestore:Post");
+        assert!(!is_restore_completed(&post, "rcs3ud", "Cold README.md"));
+        let event_bridge = r#"{"detail-type":"Object Restore Completed","source":"aws.s3","detail":{"bucket":{"name":"rcs3ud"},"object":{"key":"Cold README.md"}}}"#;
+        assert!(is_restore_completed(
+            event_bridge,
+            "rcs3ud",
+            "Cold README.md"
+        ));
+        let sns = serde_json::json!({ "Type": "Notification", "Message": s3 }).to_string();
+        assert!(is_restore_completed(&sns, "rcs3ud", "Cold README.md"));
+        assert!(!is_restore_completed(
+            "not json",
+            "rcs3ud",
+            "Cold README.md"
+        ));
+    }
+}
@@ -1,6 +1,9 @@
-use std::time::Duration;
+use std::{error::Error, num::NonZeroU32, time::Duration};
 
+use serde::{Deserialize, Serialize};
 use sipper::{Straw, sipper};
+use thiserror::Error;
+use time::UtcDateTime;
 use tokio::time::sleep;
 
 pub enum MaybeRetryable<E, R> {
@@ -17,23 +20,333 @@ impl<E, R> MaybeRetryable<E, R> {
     }
 }
 
+/// How long to wait between attempts of an operation that failed with a retryable error
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    pub initial_delay: Duration,
+    /// The delay is multiplied by this after each failed attempt
+    pub multiplier: f64,
+    pub max_delay: Duration,
+    /// Each delay is randomly shortened by up to this fraction, from 0 to 1,
+    /// so that operations that failed at the same time don't all retry at the same time
+    pub jitter: f64,
+    /// Give up after this many attempts, including the first one
+    pub max_attempts: Option<NonZeroU32>,
+    /// Give up instead of retrying after this time
+    pub deadline: Option<UtcDateTime>,
+}
+
+impl RetryPolicy {
+    /// Always waits the same amount of time
+    pub fn fixed(interval: Duration) -> Self {
+        Self {
+            initial_delay: interval,
+            multiplier: 1.0,
+            max_delay: interval,
+            jitter: 0.0,
+            max_attempts: None,
+            deadline: None,
+        }
+    }
+
+    /// Returns `true` if there shouldn't be another attempt after `attempts` failed attempts
+    fn gives_up(&self, attempts: u32, next_attempt: UtcDateTime) -> bool {
+        self.max_attempts
+            .is_some_and(|max_attempts| attempts >= max_attempts.get())
+            || self
+                .deadline
+                .is_some_and(|deadline| next_attempt > deadline)
+    }
+
+    /// The delay after `retries` failed attempts, where `random` is from 0 to 1.
+    /// A negative delay, such as from a negative `multiplier`, is 0 and a NaN one is `max_delay`, so that a policy can't cause a panic.
+    fn delay(&self, retries: u32, random: f64) -> Duration {
+        let delay = (self.initial_delay.as_secs_f64()
+            * self.multiplier.powi(retries.try_into().unwrap_or(i32::MAX)))
+        .min(self.max_delay.as_secs_f64());
+        let delay = delay * (1.0 - self.jitter.clamp(0.0, 1.0) * random.clamp(0.0, 1.0));
+        Duration::try_from_secs_f64(delay.max(0.0)).unwrap_or(self.max_delay)
+    }
+
+    /// Decides whether and when to try again after `attempts` failed attempts.
+    /// The clock and the random number are given instead of read, so that a recorded decision can be made again.
+    pub fn decide(&self, attempts: u32, decided_at: UtcDateTime, random: f64) -> RetryDecision {
+        // A delay too long to add to the time gives up, since that attempt would never happen
+        let next_attempt = time::Duration::try_from(self.delay(attempts.saturating_sub(1), random))
+            .ok()
+            .and_then(|delay| decided_at.checked_add(delay));
+        RetryDecision {
+            attempts,
+            decided_at,
+            random,
+            next_attempt: next_attempt
+                .filter(|&next_attempt| !self.gives_up(attempts, next_attempt)),
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    /// Starts at 1 second and doubles up to 5 minutes, with 20% jitter, and never gives up
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(1),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(5 * 60),
+            jitter: 0.2,
+            max_attempts: None,
+            deadline: None,
+        }
+    }
+}
+
+/// Limits the retries of all the requests of an operation together, such as all the chunks of [`crate::upload_chunked`].
+/// A [`RetryPolicy`] only limits the retries of each request, so many failing requests can still take days.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RetryBudget {
+    /// Give up after this many retries in total
+    pub max_retries: Option<NonZeroU32>,
+    /// Give up after waiting this long to retry in total
+    pub max_retry_time: Option<Duration>,
+}
+
+/// How much of a [`RetryBudget`] was used
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RetryBudgetSpent {
+    pub retries: u32,
+    pub retry_time: Duration,
+}
+
+impl RetryBudget {
+    pub(crate) fn is_exhausted(&self, spent: &RetryBudgetSpent) -> bool {
+        self.max_retries
+            .is_some_and(|max_retries| spent.retries >= max_retries.get())
+            || self
+                .max_retry_time
+                .is_some_and(|max_retry_time| spent.retry_time >= max_retry_time)
+    }
+}
+
+impl RetryBudgetSpent {
+    /// Counts a retry that waits until `next_attempt`
+    pub(crate) fn add(&mut self, next_attempt: UtcDateTime) {
+        self.retries += 1;
+        self.retry_time +=
+            Duration::try_from(next_attempt - UtcDateTime::now()).unwrap_or_default();
+    }
+}
+
+/// What a [`RetryPolicy`] decided after a retryable error, with everything it was decided from.
+/// See [`record_retry_decisions`].
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RetryDecision {
+    /// Failed attempts so far, including the one that the decision is for
+    pub attempts: u32,
+    pub decided_at: UtcDateTime,
+    /// The random number that the jitter was picked with, from 0 to 1
+    pub random: f64,
+    /// `None` if the policy gave up
+    pub next_attempt: Option<UtcDateTime>,
+}
+
+tokio::task_local! {
+    static RETRY_RECORDER: Box<dyn Fn(RetryDecision) + Send + Sync>;
+}
+
+/// Runs `future`, calling `record` with every decision that a [`RetryPolicy`] makes while it runs,
+/// including the ones that gave up, which aren't sent as [`Retrying`] events.
+/// The decisions can be made again with [`RetryPolicy::decide`], such as to debug a transfer that failed after days of retrying.
+pub async fn record_retry_decisions<F: Future>(
+    record: impl Fn(RetryDecision) + Send + Sync + 'static,
+    future: F,
+) -> F::Output {
+    RETRY_RECORDER.scope(Box::new(record), future).await
+}
+
+/// A retryable error, sent as an event before waiting to try again
+#[derive(Debug)]
+pub struct Retrying<R> {
+    pub error: R,
+    pub next_attempt: UtcDateTime,
+}
+
+/// The [`RetryPolicy`]'s `max_attempts` or `deadline` was reached
+#[derive(Debug, Error)]
+#[error("Gave up after {attempts} attempts")]
+pub struct RetriesExhausted {
+    pub attempts: u32,
+    /// The error of the last attempt
+    #[source]
+    pub last_error: Box<dyn Error + Send + Sync>,
+}
+
 pub trait KeepRetryingExt<T, E, R> {
-    fn keep_retrying(&mut self, interval: Duration) -> impl Straw<T, R, E>;
+    fn keep_retrying(&mut self, policy: RetryPolicy) -> impl Straw<T, Retrying<R>, E>;
 }
 
-impl<T, E, R, F: AsyncFnMut() -> Result<T, MaybeRetryable<E, R>>> KeepRetryingExt<T, E, R> for F {
-    fn keep_retrying(&mut self, interval: Duration) -> impl Straw<T, R, E> {
+impl<
+    T,
+    E: From<RetriesExhausted>,
+    R: Error + Send + Sync + 'static,
+    F: AsyncFnMut() -> Result<T, MaybeRetryable<E, R>>,
+> KeepRetryingExt<T, E, R> for F
+{
+    fn keep_retrying(&mut self, policy: RetryPolicy) -> impl Straw<T, Retrying<R>, E> {
         sipper(async move |mut sender| {
+            let mut attempts = 0;
             loop {
                 match self().await {
                     Ok(value) => break Ok(value),
                     Err(MaybeRetryable::NotRetryable(e)) => break Err(e),
-                    Err(MaybeRetryable::Retryable(e)) => {
-                        sender.send(e).await;
+                    Err(MaybeRetryable::Retryable(error)) => {
+                        attempts += 1;
+                        let decision = policy.decide(attempts, UtcDateTime::now(), fastrand::f64());
+                        let _ = RETRY_RECORDER.try_with(|record| record(decision));
+                        let Some(next_attempt) = decision.next_attempt else {
+                            break Err(RetriesExhausted {
+                                attempts,
+                                last_error: Box::new(error),
+                            }
+                            .into());
+                        };
+                        sender
+                            .send(Retrying {
+                                error,
+                                next_attempt,
+                            })
+                            .await;
+                        sleep(Duration::try_from(next_attempt - decision.decided_at).unwrap())
+                            .await;
                     }
                 };
-                sleep(interval).await;
             }
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{num::NonZeroU32, time::Duration};
+
+    use time::UtcDateTime;
+
+    use super::{RetryBudget, RetryBudgetSpent, RetryDecision, RetryPolicy};
+
+    #[test]
+    fn exponential() {
+        let policy = RetryPolicy {
+            initial_delay: Duration::from_secs(1),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(10),
+            jitter: 0.0,
+            max_attempts: None,
+            deadline: None,
+        };
+        assert_eq!(policy.delay(0, 0.5), Duration::from_secs(1));
+        assert_eq!(policy.delay(2, 0.5), Duration::from_secs(4));
+        assert_eq!(policy.delay(4, 0.5), Duration::from_secs(10));
+        assert_eq!(policy.delay(u32::MAX, 0.5), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn jitter() {
+        let policy = RetryPolicy {
+            jitter: 0.5,
+            ..RetryPolicy::fixed(Duration::from_secs(10))
+        };
+        assert_eq!(policy.delay(3, 0.0), Duration::from_secs(10));
+        assert_eq!(policy.delay(3, 1.0), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn invalid_policy_does_not_panic() {
+        let negative = RetryPolicy {
+            multiplier: -2.0,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(negative.delay(1, 0.5), Duration::ZERO);
+        let nan = RetryPolicy {
+            multiplier: f64::NAN,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(nan.delay(1, 0.0), nan.max_delay);
+        let too_long = RetryPolicy::fixed(Duration::MAX);
+        assert_eq!(
+            too_long.decide(1, UtcDateTime::now(), 0.5).next_attempt,
+            None
+        );
+    }
+
+    #[test]
+    fn gives_up() {
+        let now = UtcDateTime::now();
+        let forever = RetryPolicy::default();
+        assert!(!forever.gives_up(u32::MAX, now));
+
+        let max_attempts = RetryPolicy {
+            max_attempts: NonZeroU32::new(3),
+            ..RetryPolicy::default()
+        };
+        assert!(!max_attempts.gives_up(2, now));
+        assert!(max_attempts.gives_up(3, now));
+
+        let deadline = RetryPolicy {
+            deadline: Some(now),
+            ..RetryPolicy::default()
+        };
+        assert!(!deadline.gives_up(1, now));
+        assert!(deadline.gives_up(1, now + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn decide() {
+        let now = UtcDateTime::now();
+        let policy = RetryPolicy {
+            max_attempts: NonZeroU32::new(3),
+            ..RetryPolicy::default()
+        };
+        let decision = policy.decide(2, now, 0.5);
+        assert_eq!(
+            decision.next_attempt,
+            Some(now + Duration::from_secs_f64(1.8))
+        );
+        // The same inputs always make the same decision
+        assert_eq!(
+            policy.decide(decision.attempts, decision.decided_at, decision.random),
+            decision
+        );
+        assert_eq!(policy.decide(3, now, 0.5).next_attempt, None);
+        // Recorded decisions are made again exactly
+        let recorded: RetryDecision = ron::from_str(&ron::to_string(&decision).unwrap()).unwrap();
+        assert_eq!(recorded, decision);
+    }
+
+    #[test]
+    fn budget() {
+        let spent = RetryBudgetSpent {
+            retries: 10,
+            retry_time: Duration::from_secs(60),
+        };
+        assert!(!RetryBudget::default().is_exhausted(&spent));
+        assert!(
+            RetryBudget {
+                max_retries: NonZeroU32::new(10),
+                max_retry_time: None,
+            }
+            .is_exhausted(&spent)
+        );
+        assert!(
+            !RetryBudget {
+                max_retries: NonZeroU32::new(11),
+                max_retry_time: Some(Duration::from_secs(61)),
+            }
+            .is_exhausted(&spent)
+        );
+        assert!(
+            RetryBudget {
+                max_retries: None,
+                max_retry_time: Some(Duration::from_secs(60)),
+            }
+            .is_exhausted(&spent)
+        );
+    }
+}
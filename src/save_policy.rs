@@ -0,0 +1,103 @@
+use std::{
+    num::NonZeroUsize,
+    time::{Duration, Instant},
+};
+
+/// How often to send progress to be saved while uploading or downloading chunks.
+/// Progress is always saved when the stage changes (such as after getting the file's metadata or after a restore),
+/// and after the last chunk.
+/// With the default policy, progress is saved after every chunk.
+///
+/// Saving less often means more chunks may need to be transferred again after the program restarts.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SaveProgressPolicy {
+    /// Save after this many chunks
+    pub every_chunks: Option<NonZeroUsize>,
+    /// Save if this much time passed since the last save
+    pub every: Option<Duration>,
+}
+
+/// Keeps track of when progress was last saved, to apply a [`SaveProgressPolicy`]
+pub(crate) struct SaveTracker {
+    policy: SaveProgressPolicy,
+    chunks_since_save: usize,
+    last_saved: Instant,
+}
+
+impl SaveTracker {
+    pub fn new(policy: SaveProgressPolicy, now: Instant) -> Self {
+        Self {
+            policy,
+            chunks_since_save: 0,
+            last_saved: now,
+        }
+    }
+
+    /// Call after a chunk is done. Returns `true` if the progress should be saved now.
+    pub fn chunk_done(&mut self, now: Instant, is_last: bool) -> bool {
+        self.chunks_since_save += 1;
+        let save = is_last
+            || match (self.policy.every_chunks, self.policy.every) {
+                (None, None) => true,
+                (every_chunks, every) => {
+                    every_chunks.is_some_and(|n| self.chunks_since_save >= n.get())
+                        || every.is_some_and(|every| now.duration_since(self.last_saved) >= every)
+                }
+            };
+        if save {
+            self.chunks_since_save = 0;
+            self.last_saved = now;
+        }
+        save
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        num::NonZero,
+        time::{Duration, Instant},
+    };
+
+    use super::{SaveProgressPolicy, SaveTracker};
+
+    #[test]
+    fn default_saves_every_chunk() {
+        let now = Instant::now();
+        let mut tracker = SaveTracker::new(Default::default(), now);
+        assert!(tracker.chunk_done(now, false));
+        assert!(tracker.chunk_done(now, false));
+    }
+
+    #[test]
+    fn every_chunks() {
+        let now = Instant::now();
+        let mut tracker = SaveTracker::new(
+            SaveProgressPolicy {
+                every_chunks: Some(NonZero::new(3).unwrap()),
+                every: None,
+            },
+            now,
+        );
+        assert!(!tracker.chunk_done(now, false));
+        assert!(!tracker.chunk_done(now, false));
+        assert!(tracker.chunk_done(now, false));
+        assert!(!tracker.chunk_done(now, false));
+        assert!(tracker.chunk_done(now, true));
+    }
+
+    #[test]
+    fn every_duration() {
+        let start = Instant::now();
+        let mut tracker = SaveTracker::new(
+            SaveProgressPolicy {
+                every_chunks: None,
+                every: Some(Duration::from_secs(10)),
+            },
+            start,
+        );
+        assert!(!tracker.chunk_done(start + Duration::from_secs(5), false));
+        assert!(tracker.chunk_done(start + Duration::from_secs(10), false));
+        assert!(!tracker.chunk_done(start + Duration::from_secs(15), false));
+    }
+}
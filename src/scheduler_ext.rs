@@ -0,0 +1,456 @@
+use std::ops::{Range, RangeInclusive};
+
+use time::{Date, PrimitiveDateTime, Time, UtcDateTime, UtcOffset};
+
+use crate::{OperationScheduler, PastStartTime, StartTime};
+
+/// Combinators for every [`OperationScheduler`]
+pub trait SchedulerExt: OperationScheduler + Clone + Sized {
+    /// Operations don't start on these dates, such as days with video calls all day, or the first day of a billing period.
+    /// A single date is `date..=date`. The dates are in UTC unless [`Except::with_utc_offset`] is used.
+    fn except(self, dates: impl Into<Box<[RangeInclusive<Date>]>>) -> Except<Self> {
+        Except {
+            scheduler: self,
+            dates: dates.into(),
+            utc_offset: UtcOffset::UTC,
+        }
+    }
+
+    /// Operations only start when both schedulers allow it, such as in the night and not on the weekend
+    fn and<S: OperationScheduler + Clone>(self, other: S) -> And<Self, S> {
+        And {
+            first: self,
+            second: other,
+        }
+    }
+
+    /// Operations start when either scheduler allows it
+    fn or<S: OperationScheduler + Clone>(self, other: S) -> Or<Self, S> {
+        Or {
+            first: self,
+            second: other,
+        }
+    }
+
+    /// Operations don't start before `time`, such as after a planned move to a faster connection
+    fn min_start(self, time: UtcDateTime) -> MinStart<Self> {
+        MinStart {
+            scheduler: self,
+            time,
+        }
+    }
+}
+
+impl<S: OperationScheduler + Clone> SchedulerExt for S {}
+
+/// When the scheduler would start an operation that can't start before `after`, with [`StartTime::Now`] as `now`
+fn start_at(
+    scheduler: &dyn OperationScheduler,
+    after: UtcDateTime,
+    now: UtcDateTime,
+    bytes_to_upload: usize,
+) -> UtcDateTime {
+    match scheduler.get_start_time_after(after, bytes_to_upload) {
+        StartTime::Now => now,
+        StartTime::Later(start) => start,
+    }
+}
+
+fn start_time(start: UtcDateTime, now: UtcDateTime) -> StartTime {
+    if start <= now {
+        StartTime::Now
+    } else {
+        StartTime::Later(start)
+    }
+}
+
+/// How many times two schedulers are asked for a time that the other allows, before giving up and starting at the last time asked
+const MAX_ROUNDS: usize = 100;
+
+/// An [`OperationScheduler`] that doesn't start operations on blackout dates, made with [`SchedulerExt::except`].
+/// An operation that would start on a blackout date asks the scheduler again for a start time after the blackout.
+/// Operations that started before a blackout aren't stopped, unless the scheduler [pauses outside windows](OperationScheduler::pauses_outside_windows).
+#[derive(Debug, Clone)]
+pub struct Except<S> {
+    scheduler: S,
+    dates: Box<[RangeInclusive<Date>]>,
+    utc_offset: UtcOffset,
+}
+
+impl<S> Except<S> {
+    /// The dates are in this offset instead of UTC, so that a blackout starts and ends at local midnight
+    pub fn with_utc_offset(self, utc_offset: UtcOffset) -> Self {
+        Self { utc_offset, ..self }
+    }
+
+    /// Each blackout, from midnight at its first date to midnight after its last date
+    fn blackouts(&self) -> impl Iterator<Item = Range<UtcDateTime>> + '_ {
+        let midnight = |date| {
+            PrimitiveDateTime::new(date, Time::MIDNIGHT)
+                .assume_offset(self.utc_offset)
+                .to_utc()
+        };
+        self.dates
+            .iter()
+            .map(move |dates| midnight(*dates.start())..midnight(dates.end().next_day().unwrap()))
+    }
+
+    fn blackout_at(&self, time: UtcDateTime) -> Option<Range<UtcDateTime>> {
+        self.blackouts().find(|blackout| blackout.contains(&time))
+    }
+}
+
+impl<S: OperationScheduler + Clone> OperationScheduler for Except<S> {
+    fn get_start_time(&self, bytes_to_upload: usize) -> StartTime {
+        self.get_start_time_after(UtcDateTime::now(), bytes_to_upload)
+    }
+
+    fn get_start_time_after(&self, mut after: UtcDateTime, bytes_to_upload: usize) -> StartTime {
+        let now = UtcDateTime::now();
+        loop {
+            let start = start_at(&self.scheduler, after, now, bytes_to_upload);
+            match self.blackout_at(start) {
+                Some(blackout) => after = blackout.end,
+                None => break start_time(start, now),
+            }
+        }
+    }
+
+    fn past_start_time(&self) -> PastStartTime {
+        self.scheduler.past_start_time()
+    }
+
+    /// The scheduler's windows without the blackouts. `None` if the scheduler doesn't have windows.
+    fn next_window(&self, mut after: UtcDateTime) -> Option<Range<UtcDateTime>> {
+        loop {
+            let window = self.scheduler.next_window(after)?;
+            if let Some(blackout) = self.blackout_at(window.start) {
+                after = blackout.end;
+                continue;
+            }
+            let end = self
+                .blackouts()
+                .map(|blackout| blackout.start)
+                .filter(|start| window.contains(start))
+                .min()
+                .unwrap_or(window.end);
+            break Some(window.start..end);
+        }
+    }
+
+    fn expected_speed(&self) -> Option<f64> {
+        self.scheduler.expected_speed()
+    }
+
+    fn pauses_outside_windows(&self) -> bool {
+        self.scheduler.pauses_outside_windows()
+    }
+
+    fn not_ready_until(&self) -> Option<UtcDateTime> {
+        self.scheduler.not_ready_until()
+    }
+
+    fn record_speed(&self, bytes_per_second: f64) {
+        self.scheduler.record_speed(bytes_per_second);
+    }
+}
+
+/// An [`OperationScheduler`] that only starts operations when both schedulers allow it, made with [`SchedulerExt::and`].
+/// Its windows are where the windows of both schedulers overlap.
+#[derive(Debug, Clone)]
+pub struct And<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A: OperationScheduler + Clone, B: OperationScheduler + Clone> OperationScheduler
+    for And<A, B>
+{
+    fn get_start_time(&self, bytes_to_upload: usize) -> StartTime {
+        self.get_start_time_after(UtcDateTime::now(), bytes_to_upload)
+    }
+
+    fn get_start_time_after(&self, mut after: UtcDateTime, bytes_to_upload: usize) -> StartTime {
+        let now = UtcDateTime::now();
+        for _ in 0..MAX_ROUNDS {
+            let first = start_at(&self.first, after, now, bytes_to_upload);
+            let second = start_at(&self.second, first, now, bytes_to_upload);
+            if second <= first {
+                return start_time(first, now);
+            }
+            after = second;
+        }
+        start_time(after, now)
+    }
+
+    fn past_start_time(&self) -> PastStartTime {
+        either_past_start_time(&self.first, &self.second)
+    }
+
+    fn next_window(&self, mut after: UtcDateTime) -> Option<Range<UtcDateTime>> {
+        for _ in 0..MAX_ROUNDS {
+            let window = match (
+                self.first.next_window(after),
+                self.second.next_window(after),
+            ) {
+                (None, None) => return None,
+                (Some(window), None) | (None, Some(window)) => return Some(window),
+                (Some(first), Some(second)) => {
+                    first.start.max(second.start)..first.end.min(second.end)
+                }
+            };
+            if window.start < window.end {
+                return Some(window);
+            }
+            // They don't overlap, so look again from where the later one starts
+            after = window.start;
+        }
+        None
+    }
+
+    fn expected_speed(&self) -> Option<f64> {
+        self.first
+            .expected_speed()
+            .or_else(|| self.second.expected_speed())
+    }
+
+    fn pauses_outside_windows(&self) -> bool {
+        self.first.pauses_outside_windows() || self.second.pauses_outside_windows()
+    }
+
+    fn not_ready_until(&self) -> Option<UtcDateTime> {
+        self.first
+            .not_ready_until()
+            .max(self.second.not_ready_until())
+    }
+
+    fn record_speed(&self, bytes_per_second: f64) {
+        self.first.record_speed(bytes_per_second);
+        self.second.record_speed(bytes_per_second);
+    }
+}
+
+/// An [`OperationScheduler`] that starts operations when either scheduler allows it, made with [`SchedulerExt::or`].
+/// Its windows are the windows of both schedulers, with windows that overlap or touch merged.
+#[derive(Debug, Clone)]
+pub struct Or<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A: OperationScheduler + Clone, B: OperationScheduler + Clone> OperationScheduler for Or<A, B> {
+    fn get_start_time(&self, bytes_to_upload: usize) -> StartTime {
+        self.get_start_time_after(UtcDateTime::now(), bytes_to_upload)
+    }
+
+    fn get_start_time_after(&self, after: UtcDateTime, bytes_to_upload: usize) -> StartTime {
+        let now = UtcDateTime::now();
+        start_time(
+            start_at(&self.first, after, now, bytes_to_upload).min(start_at(
+                &self.second,
+                after,
+                now,
+                bytes_to_upload,
+            )),
+            now,
+        )
+    }
+
+    fn past_start_time(&self) -> PastStartTime {
+        either_past_start_time(&self.first, &self.second)
+    }
+
+    /// `None` if either scheduler doesn't have windows, since operations can run at any time
+    fn next_window(&self, after: UtcDateTime) -> Option<Range<UtcDateTime>> {
+        let first = self.first.next_window(after)?;
+        let second = self.second.next_window(after)?;
+        let mut window = if first.start <= second.start {
+            first
+        } else {
+            second
+        };
+        for _ in 0..MAX_ROUNDS {
+            let end = [
+                self.first.next_window(window.end),
+                self.second.next_window(window.end),
+            ]
+            .into_iter()
+            .flatten()
+            .filter(|next| next.start <= window.end)
+            .map(|next| next.end)
+            .max();
+            match end {
+                Some(end) if end > window.end => window.end = end,
+                _ => break,
+            }
+        }
+        Some(window)
+    }
+
+    fn expected_speed(&self) -> Option<f64> {
+        self.first
+            .expected_speed()
+            .or_else(|| self.second.expected_speed())
+    }
+
+    fn pauses_outside_windows(&self) -> bool {
+        self.first.pauses_outside_windows() || self.second.pauses_outside_windows()
+    }
+
+    /// Ready if either is ready
+    fn not_ready_until(&self) -> Option<UtcDateTime> {
+        Option::zip(self.first.not_ready_until(), self.second.not_ready_until())
+            .map(|(first, second)| first.min(second))
+    }
+
+    fn record_speed(&self, bytes_per_second: f64) {
+        self.first.record_speed(bytes_per_second);
+        self.second.record_speed(bytes_per_second);
+    }
+}
+
+/// Asks again if either scheduler does, as many times as the one that asks the most
+fn either_past_start_time(
+    first: &dyn OperationScheduler,
+    second: &dyn OperationScheduler,
+) -> PastStartTime {
+    match (first.past_start_time(), second.past_start_time()) {
+        (PastStartTime::AskAgain(first), PastStartTime::AskAgain(second)) => {
+            PastStartTime::AskAgain(first.max(second))
+        }
+        (PastStartTime::AskAgain(times), _) | (_, PastStartTime::AskAgain(times)) => {
+            PastStartTime::AskAgain(times)
+        }
+        (PastStartTime::StartNow, PastStartTime::StartNow) => PastStartTime::StartNow,
+    }
+}
+
+/// An [`OperationScheduler`] that doesn't start operations before a time, made with [`SchedulerExt::min_start`]
+#[derive(Debug, Clone)]
+pub struct MinStart<S> {
+    scheduler: S,
+    time: UtcDateTime,
+}
+
+impl<S: OperationScheduler + Clone> OperationScheduler for MinStart<S> {
+    fn get_start_time(&self, bytes_to_upload: usize) -> StartTime {
+        self.get_start_time_after(UtcDateTime::now(), bytes_to_upload)
+    }
+
+    fn get_start_time_after(&self, after: UtcDateTime, bytes_to_upload: usize) -> StartTime {
+        let now = UtcDateTime::now();
+        start_time(
+            start_at(&self.scheduler, after.max(self.time), now, bytes_to_upload),
+            now,
+        )
+    }
+
+    fn past_start_time(&self) -> PastStartTime {
+        self.scheduler.past_start_time()
+    }
+
+    /// The scheduler's windows after the time. `None` if the scheduler doesn't have windows.
+    fn next_window(&self, after: UtcDateTime) -> Option<Range<UtcDateTime>> {
+        self.scheduler.next_window(after.max(self.time))
+    }
+
+    fn expected_speed(&self) -> Option<f64> {
+        self.scheduler.expected_speed()
+    }
+
+    fn pauses_outside_windows(&self) -> bool {
+        self.scheduler.pauses_outside_windows()
+    }
+
+    fn not_ready_until(&self) -> Option<UtcDateTime> {
+        self.scheduler.not_ready_until()
+    }
+
+    fn record_speed(&self, bytes_per_second: f64) {
+        self.scheduler.record_speed(bytes_per_second);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::{Date, Month, Time, UtcDateTime, Weekday};
+
+    use crate::{AnyTime, OperationScheduler, SchedulerExt, StartTime, TimesOfDay, WeeklySchedule};
+
+    #[test]
+    fn skips_blackout_dates() {
+        let at = |date: Date, hour| UtcDateTime::new(date, Time::from_hms(hour, 0, 0).unwrap());
+        let day = |day| Date::from_calendar_date(2030, Month::March, day).unwrap();
+        let scheduler = TimesOfDay::new(
+            Box::new([Time::from_hms(1, 0, 0).unwrap()..Time::from_hms(5, 0, 0).unwrap()]),
+            1000.0,
+        )
+        .except([day(2)..=day(2), day(3)..=day(4)]);
+        match scheduler.get_start_time_after(at(day(1), 12), 1000) {
+            StartTime::Later(start) => assert_eq!(start, at(day(5), 1)),
+            StartTime::Now => panic!("Should start later"),
+        }
+        assert_eq!(
+            scheduler.next_window(at(day(1), 12)),
+            Some(at(day(5), 1)..at(day(5), 5))
+        );
+
+        // A whole weekend is cut short by a blackout on Sunday
+        let saturday = day(2);
+        assert_eq!(saturday.weekday(), Weekday::Saturday);
+        let whole_day = || Box::new([Time::MIDNIGHT..Time::MIDNIGHT]) as Box<[_]>;
+        let scheduler = WeeklySchedule::new(1000.0)
+            .with_day(Weekday::Saturday, whole_day())
+            .with_day(Weekday::Sunday, whole_day())
+            .except([day(3)..=day(3)]);
+        assert_eq!(
+            scheduler.next_window(at(day(1), 12)),
+            Some(at(saturday, 0)..at(day(3), 0))
+        );
+    }
+
+    #[test]
+    fn combines_schedulers() {
+        let at = |date: Date, hour| UtcDateTime::new(date, Time::from_hms(hour, 0, 0).unwrap());
+        let day = |day| Date::from_calendar_date(2030, Month::March, day).unwrap();
+        let start = |scheduler: &dyn OperationScheduler, after| match scheduler
+            .get_start_time_after(after, 1000)
+        {
+            StartTime::Later(start) => start,
+            StartTime::Now => panic!("Should start later"),
+        };
+        let nights = TimesOfDay::new(
+            Box::new([Time::from_hms(1, 0, 0).unwrap()..Time::from_hms(5, 0, 0).unwrap()]),
+            1000.0,
+        );
+        let weekend_afternoons = WeeklySchedule::new(1000.0)
+            .with_day(
+                Weekday::Saturday,
+                Box::new([Time::from_hms(3, 0, 0).unwrap()..Time::from_hms(18, 0, 0).unwrap()]),
+            )
+            .with_day(
+                Weekday::Sunday,
+                Box::new([Time::from_hms(12, 0, 0).unwrap()..Time::from_hms(18, 0, 0).unwrap()]),
+            );
+        // Friday
+        let after = at(day(1), 12);
+
+        let and = nights.clone().and(weekend_afternoons.clone());
+        assert_eq!(start(&and, after), at(day(2), 3));
+        assert_eq!(and.next_window(after), Some(at(day(2), 3)..at(day(2), 5)));
+        // Sunday afternoon never overlaps with a night
+        assert_eq!(
+            and.next_window(at(day(2), 12)),
+            Some(at(day(9), 3)..at(day(9), 5))
+        );
+
+        let or = nights.clone().or(weekend_afternoons);
+        assert_eq!(start(&or, after), at(day(2), 1));
+        assert_eq!(or.next_window(after), Some(at(day(2), 1)..at(day(2), 18)));
+        assert_eq!(AnyTime.or(nights.clone()).next_window(after), None);
+
+        let min_start = nights.min_start(at(day(4), 0));
+        assert_eq!(start(&min_start, after), at(day(4), 1));
+        assert_eq!(start(&AnyTime.min_start(after), at(day(1), 0)), after);
+    }
+}
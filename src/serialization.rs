@@ -0,0 +1,84 @@
+use ron::de::SpannedError;
+use serde::{Serialize, de::DeserializeOwned};
+use thiserror::Error;
+
+/// The format that state files, such as a [`crate::FileProgressStore`] or a [`crate::FileBackedAmountLimiter`], are written in.
+/// Use JSON to read or write them with other tools, such as scripts and dashboards.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SerializationFormat {
+    #[default]
+    Ron,
+    Json,
+}
+
+#[derive(Debug, Error)]
+pub enum SerializationError {
+    #[error("Error serializing RON")]
+    Ron(ron::Error),
+    #[error("Error parsing RON")]
+    RonParse(SpannedError),
+    #[error("Error serializing or parsing JSON")]
+    Json(serde_json::Error),
+}
+
+impl SerializationFormat {
+    pub(crate) fn serialize<T: Serialize + ?Sized>(
+        self,
+        value: &T,
+    ) -> Result<String, SerializationError> {
+        match self {
+            Self::Ron => ron::to_string(value).map_err(SerializationError::Ron),
+            Self::Json => serde_json::to_string(value).map_err(SerializationError::Json),
+        }
+    }
+
+    /// For files that people are expected to read
+    pub(crate) fn serialize_pretty<T: Serialize + ?Sized>(
+        self,
+        value: &T,
+    ) -> Result<String, SerializationError> {
+        match self {
+            Self::Ron => ron::Options::default()
+                .to_string_pretty(value, Default::default())
+                .map_err(SerializationError::Ron),
+            Self::Json => serde_json::to_string_pretty(value).map_err(SerializationError::Json),
+        }
+    }
+
+    pub(crate) fn deserialize<T: DeserializeOwned>(self, s: &str) -> Result<T, SerializationError> {
+        match self {
+            Self::Ron => ron::from_str(s).map_err(SerializationError::RonParse),
+            Self::Json => serde_json::from_str(s).map_err(SerializationError::Json),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SerializationFormat;
+    use crate::UploadChunkedProgress;
+
+    #[test]
+    fn round_trip() {
+        let mut progress = UploadChunkedProgress::default();
+        progress.len = Some(3000);
+        progress.completed = [0, 2].into();
+        for format in [SerializationFormat::Ron, SerializationFormat::Json] {
+            for s in [
+                format.serialize(&progress).unwrap(),
+                format.serialize_pretty(&progress).unwrap(),
+            ] {
+                let parsed = format.deserialize::<UploadChunkedProgress>(&s).unwrap();
+                assert_eq!(parsed.len, Some(3000));
+                assert_eq!(parsed.completed, progress.completed);
+            }
+        }
+        assert!(
+            SerializationFormat::Json
+                .deserialize::<UploadChunkedProgress>(
+                    &SerializationFormat::Ron.serialize(&progress).unwrap()
+                )
+                .is_err()
+        );
+    }
+}
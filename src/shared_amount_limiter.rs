@@ -0,0 +1,255 @@
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use ordermap::OrderMap;
+use serde::{Deserialize, Serialize};
+use sipper::FutureExt;
+use time::{Date, UtcDateTime, UtcOffset};
+use tokio::sync::{Mutex, MutexGuard, Notify};
+
+use crate::{
+    AmountLimiter, AmountReservation, ProgressStore, ProgressStoreError,
+    amount_limiter::monthly_available_at, wall_clock::timeout_at_utc,
+};
+
+/// The usage and queue of a [`SharedAmountLimiter`], which its [`ProgressStore`] saves
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SharedAmountLimiterState {
+    pub current_month: Date,
+    pub used_this_month: usize,
+    /// The amount reserved by each id, in the order that they were reserved
+    pub queue: OrderMap<String, usize>,
+}
+
+impl SharedAmountLimiterState {
+    fn new(now: UtcDateTime) -> Self {
+        Self {
+            current_month: now.date(),
+            used_this_month: 0,
+            queue: Default::default(),
+        }
+    }
+
+    /// When `len` can be used, after the reservations before `id` in the queue, or every reservation if `id` isn't in the queue yet
+    fn available_at(&self, len: usize, id: &str, limit: usize, now: UtcDateTime) -> UtcDateTime {
+        let queue_ahead = self.queue.get_index_of(id).unwrap_or(self.queue.len());
+        let queue_total = self.queue[..queue_ahead].values().sum::<usize>();
+        monthly_available_at(
+            self.used_this_month,
+            queue_total,
+            len,
+            limit,
+            now,
+            UtcOffset::UTC,
+        )
+    }
+
+    /// Resets the usage if the month changed since it was last used
+    fn roll_over(&mut self, now: UtcDateTime) {
+        if (self.current_month.year(), self.current_month.month()) != (now.year(), now.month()) {
+            self.current_month = now.date();
+            self.used_this_month = 0;
+        }
+    }
+}
+
+struct Inner {
+    limit: usize,
+    state: Mutex<SharedAmountLimiterState>,
+    /// Notified every time the state changes, so that waiting reservations check again
+    changed: Notify,
+    store: Option<Arc<dyn ProgressStore<SharedAmountLimiterState>>>,
+}
+
+/// An [`AmountLimiter`] which keeps the usage in memory, for a long-running process which does many uploads and downloads at the same time.
+/// Clones share the same usage and queue. Reservations are let through in the order that they were made.
+/// Unlike [`crate::FileBackedAmountLimiter`], nothing is locked or read from a file, and waiting reservations are woken up without a file watcher.
+/// Limit gets reset at the start of every month (UTC).
+#[derive(Clone)]
+pub struct SharedAmountLimiter {
+    inner: Arc<Inner>,
+}
+
+impl SharedAmountLimiter {
+    pub fn new(limit: usize) -> Self {
+        Self::with_state(
+            limit,
+            SharedAmountLimiterState::new(UtcDateTime::now()),
+            None,
+        )
+    }
+
+    /// Loads the usage from `store`, and saves it there every time it changes, so that it isn't lost when the process restarts.
+    /// For example, a [`crate::FileProgressStore`] that keeps the file on success.
+    pub async fn with_store(
+        limit: usize,
+        store: Arc<dyn ProgressStore<SharedAmountLimiterState>>,
+    ) -> Result<Self, ProgressStoreError> {
+        let state = store
+            .load()
+            .await?
+            .unwrap_or_else(|| SharedAmountLimiterState::new(UtcDateTime::now()));
+        Ok(Self::with_state(limit, state, Some(store)))
+    }
+
+    fn with_state(
+        limit: usize,
+        state: SharedAmountLimiterState,
+        store: Option<Arc<dyn ProgressStore<SharedAmountLimiterState>>>,
+    ) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                limit,
+                state: Mutex::new(state),
+                changed: Notify::new(),
+                store,
+            }),
+        }
+    }
+
+    /// A copy of the current usage and queue
+    pub async fn state(&self) -> SharedAmountLimiterState {
+        self.lock().await.clone()
+    }
+
+    async fn lock(&self) -> MutexGuard<'_, SharedAmountLimiterState> {
+        let mut state = self.inner.state.lock().await;
+        state.roll_over(UtcDateTime::now());
+        state
+    }
+
+    /// Changes the state, saves it, and wakes up the waiting reservations
+    async fn update(&self, f: impl FnOnce(&mut SharedAmountLimiterState)) {
+        let mut state = self.lock().await;
+        f(&mut state);
+        if let Some(store) = &self.inner.store {
+            store.save(&state).await.unwrap();
+        }
+        drop(state);
+        self.inner.changed.notify_waiters();
+    }
+}
+
+impl AmountLimiter for SharedAmountLimiter {
+    fn reserve<'a>(
+        &'a self,
+        len: usize,
+        id: &'a str,
+    ) -> BoxFuture<'a, Box<dyn AmountReservation + 'a>> {
+        async move {
+            self.update(|state| {
+                state.queue.entry(id.to_owned()).or_insert(len);
+            })
+            .await;
+            loop {
+                // Created before checking, so that a change after checking isn't missed
+                let changed = self.inner.changed.notified();
+                let now = UtcDateTime::now();
+                let available_at = self
+                    .lock()
+                    .await
+                    .available_at(len, id, self.inner.limit, now);
+                if available_at <= now {
+                    break;
+                }
+                // Wait for a reservation to finish, or for the limit to reset
+                let _ = timeout_at_utc(available_at, changed).await;
+            }
+            Box::new(SharedAmountReservation { limiter: self, id }) as Box<dyn AmountReservation>
+        }
+        .boxed()
+    }
+
+    fn get_reservation<'a>(
+        &'a self,
+        id: &'a str,
+    ) -> BoxFuture<'a, Option<Box<dyn AmountReservation + 'a>>> {
+        async move {
+            self.lock().await.queue.contains_key(id).then(|| {
+                Box::new(SharedAmountReservation { limiter: self, id })
+                    as Box<dyn AmountReservation>
+            })
+        }
+        .boxed()
+    }
+
+    fn available_at<'a>(&'a self, len: usize, id: &'a str) -> BoxFuture<'a, UtcDateTime> {
+        async move {
+            self.lock()
+                .await
+                .available_at(len, id, self.inner.limit, UtcDateTime::now())
+        }
+        .boxed()
+    }
+}
+
+pub struct SharedAmountReservation<'a> {
+    limiter: &'a SharedAmountLimiter,
+    id: &'a str,
+}
+
+impl AmountReservation for SharedAmountReservation<'_> {
+    fn mark_complete(&self) -> BoxFuture<'_, ()> {
+        self.limiter
+            .update(|state| {
+                if let Some(amount) = state.queue.remove(self.id) {
+                    state.used_this_month += amount;
+                }
+            })
+            .boxed()
+    }
+
+    fn mark_complete_with_len(&self, len: usize) -> BoxFuture<'_, ()> {
+        self.limiter
+            .update(move |state| {
+                state.queue.remove(self.id);
+                state.used_this_month += len;
+            })
+            .boxed()
+    }
+
+    fn record_partial(&self, len: usize) -> BoxFuture<'_, ()> {
+        self.limiter
+            .update(move |state| state.used_this_month += len)
+            .boxed()
+    }
+
+    fn cancel(&self) -> BoxFuture<'_, ()> {
+        self.limiter
+            .update(|state| {
+                state.queue.remove(self.id);
+            })
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::{sleep, timeout};
+
+    use super::SharedAmountLimiter;
+    use crate::AmountLimiter;
+
+    #[tokio::test]
+    async fn waits_for_earlier_reservations() {
+        let limiter = SharedAmountLimiter::new(1000);
+        let first = limiter.reserve(800, "first").await;
+        assert!(
+            timeout(Duration::from_millis(50), limiter.reserve(800, "second"))
+                .await
+                .is_err()
+        );
+        // The second reservation keeps its place in the queue, and is let through as soon as the first is cancelled
+        let (second, ()) = futures::join!(limiter.reserve(800, "second"), async {
+            sleep(Duration::from_millis(10)).await;
+            first.cancel().await;
+        });
+        second.record_partial(100).await;
+        second.mark_complete().await;
+        let state = limiter.state().await;
+        assert_eq!(state.used_this_month, 900);
+        assert!(state.queue.is_empty());
+    }
+}
@@ -0,0 +1,355 @@
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use futures::future::BoxFuture;
+use rusqlite::{Connection, OptionalExtension, Transaction, TransactionBehavior, params};
+use sipper::FutureExt;
+use time::{UtcDateTime, UtcOffset};
+use tokio::task::spawn_blocking;
+
+use crate::{
+    AmountLimiter, AmountLimiterInspect, AmountLimiterUsage, AmountReservation, QueuedAmount,
+    amount_limiter::monthly_available_at, sleep_until_utc,
+};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS usage (
+    month TEXT PRIMARY KEY,
+    used INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS queue (
+    position INTEGER PRIMARY KEY AUTOINCREMENT,
+    id TEXT NOT NULL UNIQUE,
+    description TEXT NOT NULL,
+    amount INTEGER NOT NULL,
+    time_added INTEGER NOT NULL,
+    started INTEGER NOT NULL DEFAULT 0
+);
+";
+
+/// An [`AmountLimiter`] which stores the usage and queue in an SQLite database.
+/// Every change is done in a transaction, so many processes can share the database, even on different machines through a network filesystem that SQLite supports.
+/// Usage is stored for each month (UTC), so the limit is reset at the start of every month.
+/// Waiting reservations check the database every [`SqliteAmountLimiter::with_poll_interval`].
+#[derive(Debug, Clone)]
+pub struct SqliteAmountLimiter {
+    path: Arc<PathBuf>,
+    limit: usize,
+    description: String,
+    poll_interval: Duration,
+}
+
+impl SqliteAmountLimiter {
+    pub fn new(path: impl Into<PathBuf>, limit: usize, description: impl Into<String>) -> Self {
+        Self {
+            path: Arc::new(path.into()),
+            limit,
+            description: description.into(),
+            poll_interval: Duration::from_secs(1),
+        }
+    }
+
+    /// How often waiting reservations check if a reservation before them finished. The default is 1 second.
+    pub fn with_poll_interval(self, poll_interval: Duration) -> Self {
+        Self {
+            poll_interval,
+            ..self
+        }
+    }
+
+    /// A limiter using the same database, with a different description for its queue items
+    pub fn with_description(&self, description: impl Into<String>) -> Self {
+        Self {
+            description: description.into(),
+            ..self.clone()
+        }
+    }
+
+    /// Runs `f` in a transaction that locks the database for writing, on a thread where it can block
+    async fn transaction<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Transaction) -> rusqlite::Result<T> + Send + 'static,
+    ) -> rusqlite::Result<T> {
+        let path = self.path.clone();
+        spawn_blocking(move || {
+            let mut connection = Connection::open(path.as_path())?;
+            // Other processes lock the database while they change it
+            connection.busy_timeout(Duration::from_secs(60))?;
+            connection.execute_batch(SCHEMA)?;
+            let transaction =
+                connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let output = f(&transaction)?;
+            transaction.commit()?;
+            Ok(output)
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Like [`AmountLimiterInspect::usage`], but returns an error instead of panicking if the database can't be read
+    pub async fn try_usage(&self) -> rusqlite::Result<AmountLimiterUsage> {
+        let limit = self.limit;
+        self.transaction(move |transaction| {
+            let used_this_month = used_this_month(transaction, UtcDateTime::now())?;
+            let queue = transaction
+                .prepare(
+                    "SELECT id, description, amount, time_added, started FROM queue ORDER BY started DESC, position",
+                )?
+                .query_map([], |row| {
+                    Ok(QueuedAmount {
+                        id: row.get(0)?,
+                        description: row.get(1)?,
+                        amount: row.get(2)?,
+                        time_added: UtcDateTime::from_unix_timestamp(row.get(3)?).unwrap(),
+                        priority: 0,
+                        started: row.get(4)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(AmountLimiterUsage {
+                limit,
+                used_this_month,
+                queue,
+            })
+        })
+        .await
+    }
+
+    async fn try_available_at(
+        &self,
+        len: usize,
+        id: &str,
+        start: bool,
+    ) -> rusqlite::Result<UtcDateTime> {
+        let limit = self.limit;
+        let id = id.to_owned();
+        self.transaction(move |transaction| {
+            let now = UtcDateTime::now();
+            let available_at = available_at(transaction, len, &id, limit, now)?;
+            if start && available_at <= now {
+                transaction.execute("UPDATE queue SET started = 1 WHERE id = ?1", params![id])?;
+            }
+            Ok(available_at)
+        })
+        .await
+    }
+
+    async fn change(&self, id: &str, change: Change) -> rusqlite::Result<()> {
+        let id = id.to_owned();
+        self.transaction(move |transaction| {
+            let used = match change {
+                Change::Complete => transaction
+                    .query_row(
+                        "DELETE FROM queue WHERE id = ?1 RETURNING amount",
+                        params![id],
+                        |row| row.get(0),
+                    )
+                    .optional()?
+                    .unwrap_or(0),
+                Change::CompleteWithLen(len) => {
+                    transaction.execute("DELETE FROM queue WHERE id = ?1", params![id])?;
+                    len
+                }
+                Change::Partial(len) => len,
+                Change::Cancel => {
+                    transaction.execute("DELETE FROM queue WHERE id = ?1", params![id])?;
+                    0
+                }
+            };
+            transaction.execute(
+                "INSERT INTO usage (month, used) VALUES (?1, ?2) ON CONFLICT (month) DO UPDATE SET used = used + ?2",
+                params![month(UtcDateTime::now()), used],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+enum Change {
+    Complete,
+    CompleteWithLen(usize),
+    Partial(usize),
+    Cancel,
+}
+
+fn month(now: UtcDateTime) -> String {
+    format!("{}-{:02}", now.year(), now.month() as u8)
+}
+
+fn used_this_month(transaction: &Transaction, now: UtcDateTime) -> rusqlite::Result<usize> {
+    Ok(transaction
+        .query_row(
+            "SELECT used FROM usage WHERE month = ?1",
+            params![month(now)],
+            |row| row.get(0),
+        )
+        .optional()?
+        .unwrap_or(0))
+}
+
+/// When `len` can be used, after the reservations that started and the ones before `id` in the queue, or every reservation if `id` isn't in the queue
+fn available_at(
+    transaction: &Transaction,
+    len: usize,
+    id: &str,
+    limit: usize,
+    now: UtcDateTime,
+) -> rusqlite::Result<UtcDateTime> {
+    let queue_total = transaction.query_row(
+        "SELECT COALESCE(SUM(amount), 0) FROM queue
+        WHERE id != ?1 AND (started OR position < COALESCE((SELECT position FROM queue WHERE id = ?1), 9223372036854775807))",
+        params![id],
+        |row| row.get(0),
+    )?;
+    Ok(monthly_available_at(
+        used_this_month(transaction, now)?,
+        queue_total,
+        len,
+        limit,
+        now,
+        UtcOffset::UTC,
+    ))
+}
+
+impl AmountLimiter for SqliteAmountLimiter {
+    fn reserve<'a>(
+        &'a self,
+        len: usize,
+        id: &'a str,
+    ) -> BoxFuture<'a, Box<dyn AmountReservation + 'a>> {
+        async move {
+            let description = self.description.clone();
+            let owned_id = id.to_owned();
+            self.transaction(move |transaction| {
+                transaction.execute(
+                    "INSERT OR IGNORE INTO queue (id, description, amount, time_added) VALUES (?1, ?2, ?3, ?4)",
+                    params![owned_id, description, len, UtcDateTime::now().unix_timestamp()],
+                )?;
+                Ok(())
+            })
+            .await
+            .unwrap();
+            loop {
+                let available_at = self.try_available_at(len, id, true).await.unwrap();
+                let now = UtcDateTime::now();
+                if available_at <= now {
+                    break;
+                }
+                sleep_until_utc(available_at.min(now + self.poll_interval)).await;
+            }
+            Box::new(SqliteAmountReservation { limiter: self, id }) as Box<dyn AmountReservation>
+        }
+        .boxed()
+    }
+
+    fn get_reservation<'a>(
+        &'a self,
+        id: &'a str,
+    ) -> BoxFuture<'a, Option<Box<dyn AmountReservation + 'a>>> {
+        async move {
+            let owned_id = id.to_owned();
+            let exists = self
+                .transaction(move |transaction| {
+                    transaction
+                        .query_row(
+                            "SELECT 1 FROM queue WHERE id = ?1",
+                            params![owned_id],
+                            |_| Ok(()),
+                        )
+                        .optional()
+                })
+                .await
+                .unwrap()
+                .is_some();
+            exists.then(|| {
+                Box::new(SqliteAmountReservation { limiter: self, id })
+                    as Box<dyn AmountReservation>
+            })
+        }
+        .boxed()
+    }
+
+    fn available_at<'a>(&'a self, len: usize, id: &'a str) -> BoxFuture<'a, UtcDateTime> {
+        async move { self.try_available_at(len, id, false).await.unwrap() }.boxed()
+    }
+}
+
+impl AmountLimiterInspect for SqliteAmountLimiter {
+    fn usage(&self) -> BoxFuture<'_, AmountLimiterUsage> {
+        async { self.try_usage().await.unwrap() }.boxed()
+    }
+}
+
+pub struct SqliteAmountReservation<'a> {
+    limiter: &'a SqliteAmountLimiter,
+    id: &'a str,
+}
+
+impl AmountReservation for SqliteAmountReservation<'_> {
+    fn mark_complete(&self) -> BoxFuture<'_, ()> {
+        async {
+            self.limiter
+                .change(self.id, Change::Complete)
+                .await
+                .unwrap()
+        }
+        .boxed()
+    }
+
+    fn mark_complete_with_len(&self, len: usize) -> BoxFuture<'_, ()> {
+        async move {
+            self.limiter
+                .change(self.id, Change::CompleteWithLen(len))
+                .await
+                .unwrap()
+        }
+        .boxed()
+    }
+
+    fn record_partial(&self, len: usize) -> BoxFuture<'_, ()> {
+        async move {
+            self.limiter
+                .change(self.id, Change::Partial(len))
+                .await
+                .unwrap()
+        }
+        .boxed()
+    }
+
+    fn cancel(&self) -> BoxFuture<'_, ()> {
+        async { self.limiter.change(self.id, Change::Cancel).await.unwrap() }.boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::{sleep, timeout};
+
+    use super::SqliteAmountLimiter;
+    use crate::{AmountLimiter, AmountLimiterInspect};
+
+    #[tokio::test]
+    async fn waits_for_earlier_reservations() {
+        let path = std::env::temp_dir().join(format!("rcs3ud-test-{:016x}.db", fastrand::u64(..)));
+        let limiter = SqliteAmountLimiter::new(&path, 1000, "test")
+            .with_poll_interval(Duration::from_millis(10));
+        let first = limiter.reserve(800, "first").await;
+        assert!(
+            timeout(Duration::from_millis(50), limiter.reserve(800, "second"))
+                .await
+                .is_err()
+        );
+        let (second, ()) = futures::join!(limiter.reserve(800, "second"), async {
+            sleep(Duration::from_millis(10)).await;
+            first.cancel().await;
+        });
+        second.record_partial(100).await;
+        second.mark_complete().await;
+        let usage = limiter.usage().await;
+        assert_eq!(usage.used_this_month, 900);
+        assert!(usage.queue.is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,150 @@
+use aws_sdk_s3::types::StorageClass;
+use sipper::{Sipper, Straw, sipper};
+
+use crate::{
+    CustomerKey, MAX_PUT_OBJECT_SIZE, UploadError, UploadEvent, UploadInput,
+    maybe_retryable_sdk_error::IntoMaybeRetryable, retry::KeepRetryingExt,
+};
+
+/// What an upload does after it finishes, when the object isn't in the storage class that it was uploaded with.
+/// This can happen when a lifecycle rule of the bucket transitions objects right away, or when an S3-compatible endpoint ignores the storage class.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageClassCheck {
+    /// Sends [`UploadEvent::StorageClassMismatch`]
+    Warn,
+    /// Also copies the object onto itself with the right storage class, which makes a new version if the bucket has versioning.
+    /// Objects that were already archived, such as to `GLACIER`, can't be copied without restoring them, and objects larger than
+    /// [`MAX_PUT_OBJECT_SIZE`] can't be copied in one request, so those are only warned about.
+    Correct,
+}
+
+/// The `x-amz-copy-source` header, which is URL-encoded
+pub(crate) fn copy_source(bucket: &str, object_key: &str, version_id: Option<&str>) -> String {
+    let encoded = format!("{bucket}/{object_key}")
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                char::from(byte).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect::<String>();
+    match version_id {
+        Some(version_id) => format!("{encoded}?versionId={version_id}"),
+        None => encoded,
+    }
+}
+
+/// The object that an upload made, which is a new one if [`StorageClassCheck::Correct`] copied it
+pub(crate) struct UploadedObject {
+    pub(crate) e_tag: Option<String>,
+    pub(crate) version_id: Option<String>,
+}
+
+/// Checks the storage class of the uploaded object, and corrects it if `check` is [`StorageClassCheck::Correct`].
+/// Returns the object afterwards, since copying changes the ETag of a multipart object and makes a new version.
+pub(crate) fn check_storage_class<'a>(
+    input: &'a UploadInput<'_>,
+    check: StorageClassCheck,
+    object_key: &'a str,
+    uploaded: UploadedObject,
+) -> impl Straw<UploadedObject, UploadEvent, UploadError> + 'a {
+    sipper(async move |mut sender| {
+        sender.send(UploadEvent::CheckingStorageClass).await;
+        let customer_key = input.dest.encryption.customer_key();
+        let head = (async || {
+            input
+                .client
+                .head_object()
+                .bucket(input.dest.bucket)
+                .key(object_key)
+                .set_version_id(uploaded.version_id.clone())
+                .set_sse_customer_algorithm(customer_key.map(CustomerKey::algorithm))
+                .set_sse_customer_key(customer_key.map(CustomerKey::key))
+                .set_sse_customer_key_md5(customer_key.map(CustomerKey::key_md5))
+                .send()
+                .await
+                .map_err(|e| e.into_maybe_retryable().map(UploadError::HeadObject))
+        })
+        .keep_retrying(input.retry_policy)
+        .with(UploadEvent::CheckStorageClassError)
+        .run(sender.clone())
+        .await?;
+        // S3 leaves out the storage class of `STANDARD` objects
+        let actual = head
+            .storage_class()
+            .cloned()
+            .unwrap_or(StorageClass::Standard);
+        if actual == input.dest.storage_class {
+            return Ok(uploaded);
+        }
+        let archived = matches!(actual, StorageClass::Glacier | StorageClass::DeepArchive)
+            || head.archive_status().is_some();
+        let correcting = check == StorageClassCheck::Correct
+            && !archived
+            && head.content_length().unwrap_or_default() as usize <= MAX_PUT_OBJECT_SIZE;
+        sender
+            .send(UploadEvent::StorageClassMismatch {
+                requested: input.dest.storage_class.clone(),
+                actual,
+                correcting,
+            })
+            .await;
+        if !correcting {
+            return Ok(uploaded);
+        }
+        // The metadata and tags are copied, but the encryption has to be given again
+        let output = (async || {
+            input
+                .client
+                .copy_object()
+                .bucket(input.dest.bucket)
+                .key(object_key)
+                .copy_source(copy_source(
+                    input.dest.bucket,
+                    object_key,
+                    uploaded.version_id.as_deref(),
+                ))
+                .storage_class(input.dest.storage_class.clone())
+                .set_server_side_encryption(input.dest.encryption.server_side_encryption())
+                .set_ssekms_key_id(input.dest.encryption.kms_key_id())
+                .set_sse_customer_algorithm(customer_key.map(CustomerKey::algorithm))
+                .set_sse_customer_key(customer_key.map(CustomerKey::key))
+                .set_sse_customer_key_md5(customer_key.map(CustomerKey::key_md5))
+                .set_copy_source_sse_customer_algorithm(customer_key.map(CustomerKey::algorithm))
+                .set_copy_source_sse_customer_key(customer_key.map(CustomerKey::key))
+                .set_copy_source_sse_customer_key_md5(customer_key.map(CustomerKey::key_md5))
+                .send()
+                .await
+                .map_err(|e| e.into_maybe_retryable().map(UploadError::CopyObject))
+        })
+        .keep_retrying(input.retry_policy)
+        .with(UploadEvent::CopyObjectError)
+        .run(sender)
+        .await?;
+        Ok(UploadedObject {
+            e_tag: output
+                .copy_object_result
+                .and_then(|result| result.e_tag)
+                .or(uploaded.e_tag),
+            version_id: output.version_id.or(uploaded.version_id),
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::copy_source;
+
+    #[test]
+    fn encodes_copy_source() {
+        assert_eq!(
+            copy_source("bucket", "backups/my file+1.tar", None),
+            "bucket/backups/my%20file%2B1.tar"
+        );
+        assert_eq!(
+            copy_source("bucket", "ü", Some("abc")),
+            "bucket/%C3%BC?versionId=abc"
+        );
+    }
+}
@@ -0,0 +1,467 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{self, ErrorKind},
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use aws_sdk_s3::{
+    error::SdkError, operation::list_objects_v2::ListObjectsV2Error, types::ObjectStorageClass,
+};
+use serde::{Deserialize, Serialize};
+use sipper::{Sipper, Straw, sipper};
+use thiserror::Error;
+use tokio::fs::{File, copy, create_dir_all, metadata};
+
+use crate::{
+    AmountLimiter, Cancelled, ControlHandle, DUPLICATES_MANIFEST, DownloadColdInput, DownloadDest,
+    DownloadError, DownloadEvent, DownloadInput, DownloadStrategy, RestoreError, RestoreEvent,
+    RestoreInput, RetriesExhausted, RetryPolicy, Retrying, S3Src, SavedProgress,
+    SerializationError, SerializationFormat, download, initiate_restore,
+    sync_up::{RemoteObject, list_objects},
+};
+
+pub struct SyncDownInput<'a> {
+    pub client: &'a aws_sdk_s3::Client,
+    pub bucket: &'a str,
+    /// Each object under the prefix is downloaded to `{dest}/{path}`, where `path` is the rest of the key after the prefix
+    pub prefix: &'a str,
+    /// The local directory, which is created if it doesn't exist
+    pub dest: PathBuf,
+    /// How to restore objects in the `GLACIER` and `DEEP_ARCHIVE` storage classes
+    pub cold: DownloadColdInput,
+    pub retry_policy: RetryPolicy,
+    /// The total size of the objects that need to be downloaded is reserved at once
+    pub amount_limiter: Option<Box<dyn AmountLimiter>>,
+    pub progress: SyncDownProgress,
+    /// See [`DownloadInput::progress_interval`]
+    pub progress_interval: Option<Duration>,
+    pub control: ControlHandle,
+}
+
+/// Saved state of a [`sync_down`], so that it can be resumed
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct SyncDownProgress {
+    /// Paths of the objects that were downloaded or were already up to date
+    pub done: BTreeSet<String>,
+    /// Paths of the cold objects that restores were initiated for
+    pub restores_initiated: BTreeSet<String>,
+    /// Progress of the object that is being downloaded
+    pub current: Option<(String, SavedProgress)>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SyncDownOutput {
+    pub downloaded: Vec<String>,
+    pub up_to_date: Vec<String>,
+    /// Paths of the duplicate files in the [`DUPLICATES_MANIFEST`] that were copied from their original
+    pub copied_duplicates: Vec<String>,
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Error)]
+pub enum SyncDownError {
+    #[error("Error listing the objects under the prefix")]
+    ListObjects(#[from] SdkError<ListObjectsV2Error>),
+    #[error("Gave up retrying")]
+    RetriesExhausted(#[from] RetriesExhausted),
+    /// Downloading it would write outside of the directory
+    #[error("The key {0} can't be used as a path in the directory")]
+    UnsafeKey(String),
+    #[error("Error getting metadata of a local file")]
+    Metadata(io::Error),
+    #[error("Error creating a local file or directory")]
+    Create(io::Error),
+    #[error("Error restoring {path}")]
+    Restore { path: String, source: RestoreError },
+    #[error("Error downloading {path}")]
+    Download { path: String, source: DownloadError },
+    #[error("Error parsing the duplicates manifest")]
+    ParseManifest(SerializationError),
+    #[error("Error copying {original} to its duplicate {path}")]
+    CopyDuplicate {
+        path: String,
+        original: String,
+        source: io::Error,
+    },
+    #[error("The sync was cancelled")]
+    Cancelled(#[from] Cancelled),
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum SyncDownEvent {
+    ListingObjects,
+    ListObjectsError(Retrying<SdkError<ListObjectsV2Error>>),
+    UpToDate(String),
+    ReservingDownloadAmount(usize),
+    InitiatingRestore(String),
+    RestoreEvent { path: String, event: RestoreEvent },
+    Downloading(String),
+    DownloadEvent { path: String, event: DownloadEvent },
+    Downloaded(String),
+    DownloadingManifest,
+    CopyingDuplicate { path: String, original: String },
+    SaveProgress(SyncDownProgress),
+    MarkingReservationComplete,
+}
+
+/// Returns `false` if the path could point outside of the directory, such as `../secret`
+fn is_safe_path(path: &str) -> bool {
+    !path.is_empty()
+        && Path::new(path)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+/// Returns `true` if the local file has the same size as the object, and wasn't modified before the object was uploaded.
+/// If either modified time is unknown, only the size is compared.
+fn is_up_to_date(len: usize, modified: Option<SystemTime>, remote: &RemoteObject) -> bool {
+    remote.len == len
+        && !matches!(
+            (modified, remote.last_modified),
+            (Some(modified), Some(last_modified)) if modified < last_modified
+        )
+}
+
+fn is_cold(remote: &RemoteObject) -> bool {
+    matches!(
+        remote.storage_class,
+        Some(ObjectStorageClass::Glacier | ObjectStorageClass::DeepArchive)
+    )
+}
+
+/// Downloads the objects under an S3 prefix that are missing or different in a local directory.
+/// Local files that don't have an object are kept.
+/// The duplicate files in a [`DUPLICATES_MANIFEST`] from [`crate::sync_up`] are copied from their original after downloading.
+///
+/// Restores are initiated for all cold objects before anything is downloaded, so that they are restored at the same time.
+pub fn sync_down(
+    input: SyncDownInput<'_>,
+) -> impl Straw<SyncDownOutput, SyncDownEvent, SyncDownError> {
+    sipper(async move |mut sender| {
+        let mut progress = input.progress;
+        sender.send(SyncDownEvent::ListingObjects).await;
+        let objects = list_objects::<SyncDownError>(
+            input.client,
+            input.bucket,
+            input.prefix,
+            input.retry_policy,
+        )
+        .with(SyncDownEvent::ListObjectsError)
+        .run(sender.clone())
+        .await?;
+        let mut output = SyncDownOutput::default();
+        let manifest_key = format!("{}{DUPLICATES_MANIFEST}", input.prefix);
+        // Small, so it isn't counted towards the amount limit
+        let duplicates = if objects.contains_key(&manifest_key) {
+            sender.send(SyncDownEvent::DownloadingManifest).await;
+            let mut manifest = Vec::new();
+            download(DownloadInput {
+                client: input.client,
+                src: S3Src {
+                    bucket: input.bucket,
+                    object_key: &manifest_key,
+                },
+                dest: DownloadDest::Writer(&mut manifest),
+                strategy: DownloadStrategy::Warm,
+                customer_key: None,
+                client_side_key: None,
+                decompress: false,
+                capabilities: Default::default(),
+                retry_policy: input.retry_policy,
+                saved_progress: Default::default(),
+                progress_store: None,
+                amount_limiter: None,
+                progress_interval: None,
+                save_policy: Default::default(),
+                split_range_after: None,
+                missing_content_length: Default::default(),
+                control: input.control.clone(),
+            })
+            .await
+            .with(|event| SyncDownEvent::DownloadEvent {
+                path: DUPLICATES_MANIFEST.to_owned(),
+                event,
+            })
+            .run(sender.clone())
+            .await
+            .map_err(|source| SyncDownError::Download {
+                path: DUPLICATES_MANIFEST.to_owned(),
+                source,
+            })?;
+            SerializationFormat::Json
+                .deserialize::<BTreeMap<String, String>>(&String::from_utf8_lossy(&manifest))
+                .map_err(SyncDownError::ParseManifest)?
+        } else {
+            Default::default()
+        };
+        let mut to_download = Vec::new();
+        for (key, remote) in &objects {
+            if *key == manifest_key {
+                continue;
+            }
+            let relative_path = &key[input.prefix.len()..];
+            // Keys ending with a `/` are usually empty "folders" made by the S3 console
+            if relative_path.is_empty() || relative_path.ends_with('/') {
+                continue;
+            }
+            if !is_safe_path(relative_path) {
+                Err(SyncDownError::UnsafeKey(key.clone()))?;
+            }
+            if progress.done.contains(relative_path) {
+                continue;
+            }
+            let path = input.dest.join(relative_path);
+            let up_to_date = match metadata(&path).await {
+                Ok(metadata) => is_up_to_date(
+                    metadata.len().try_into().unwrap(),
+                    metadata.modified().ok(),
+                    remote,
+                ),
+                Err(e) if e.kind() == ErrorKind::NotFound => false,
+                Err(e) => Err(SyncDownError::Metadata(e))?,
+            };
+            if up_to_date
+                && progress
+                    .current
+                    .as_ref()
+                    .is_none_or(|(current, _)| current != relative_path)
+            {
+                sender
+                    .send(SyncDownEvent::UpToDate(relative_path.to_owned()))
+                    .await;
+                output.up_to_date.push(relative_path.to_owned());
+            } else {
+                to_download.push((key, relative_path, path, remote));
+            }
+        }
+        let id = format!("sync_down:{}/{}", input.bucket, input.prefix);
+        let reservation = match &input.amount_limiter {
+            Some(amount_limiter) => Some(match amount_limiter.get_reservation(&id).await {
+                Some(reservation) => reservation,
+                None => {
+                    let len = to_download.iter().map(|(_, _, _, remote)| remote.len).sum();
+                    sender
+                        .send(SyncDownEvent::ReservingDownloadAmount(len))
+                        .await;
+                    input.control.reserve(&**amount_limiter, len, &id).await?
+                }
+            }),
+            None => None,
+        };
+        for (key, relative_path, _, remote) in &to_download {
+            if !is_cold(remote) || progress.restores_initiated.contains(*relative_path) {
+                continue;
+            }
+            sender
+                .send(SyncDownEvent::InitiatingRestore(relative_path.to_string()))
+                .await;
+            initiate_restore(&RestoreInput {
+                client: input.client,
+                src: S3Src {
+                    bucket: input.bucket,
+                    object_key: key,
+                },
+                tier: input.cold.tier.clone(),
+                adjust_incompatible_tier: input.cold.adjust_incompatible_tier,
+                days: input.cold.restore_days,
+                wait_for_restore_strategy: input.cold.wait_for_restore_stratey.clone(),
+                retry_policy: input.retry_policy,
+            })
+            .with(|event| SyncDownEvent::RestoreEvent {
+                path: relative_path.to_string(),
+                event,
+            })
+            .run(sender.clone())
+            .await
+            .map_err(|source| SyncDownError::Restore {
+                path: relative_path.to_string(),
+                source,
+            })?;
+            progress
+                .restores_initiated
+                .insert(relative_path.to_string());
+            sender
+                .send(SyncDownEvent::SaveProgress(progress.clone()))
+                .await;
+        }
+        for (key, relative_path, path, remote) in to_download {
+            sender
+                .send(SyncDownEvent::Downloading(relative_path.to_owned()))
+                .await;
+            let (resuming, saved_progress) = match progress.current.take() {
+                Some((current, saved_progress)) if current == relative_path => {
+                    (true, saved_progress)
+                }
+                _ => (false, Default::default()),
+            };
+            progress.current = Some((relative_path.to_owned(), saved_progress.clone()));
+            if let Some(parent) = path.parent() {
+                create_dir_all(parent)
+                    .await
+                    .map_err(SyncDownError::Create)?;
+            }
+            // Keep the bytes that were already downloaded when resuming
+            let mut file = File::options()
+                .write(true)
+                .create(true)
+                .truncate(!resuming)
+                .open(&path)
+                .await
+                .map_err(SyncDownError::Create)?;
+            download(DownloadInput {
+                client: input.client,
+                src: S3Src {
+                    bucket: input.bucket,
+                    object_key: key,
+                },
+                dest: DownloadDest::File(&mut file),
+                strategy: if is_cold(remote) {
+                    DownloadStrategy::Cold(input.cold.clone())
+                } else {
+                    DownloadStrategy::Warm
+                },
+                customer_key: None,
+                client_side_key: None,
+                decompress: false,
+                capabilities: Default::default(),
+                retry_policy: input.retry_policy,
+                saved_progress,
+                progress_store: None,
+                // The whole sync was already reserved
+                amount_limiter: None,
+                progress_interval: input.progress_interval,
+                save_policy: Default::default(),
+                split_range_after: None,
+                missing_content_length: Default::default(),
+                control: input.control.clone(),
+            })
+            .await
+            .with(|event| match event {
+                DownloadEvent::UpdateSavedProgress(saved_progress) => {
+                    progress.current = Some((relative_path.to_owned(), saved_progress));
+                    SyncDownEvent::SaveProgress(progress.clone())
+                }
+                event => SyncDownEvent::DownloadEvent {
+                    path: relative_path.to_owned(),
+                    event,
+                },
+            })
+            .run(sender.clone())
+            .await
+            .map_err(|source| SyncDownError::Download {
+                path: relative_path.to_owned(),
+                source,
+            })?;
+            progress.current = None;
+            progress.done.insert(relative_path.to_owned());
+            sender
+                .send(SyncDownEvent::SaveProgress(progress.clone()))
+                .await;
+            sender
+                .send(SyncDownEvent::Downloaded(relative_path.to_owned()))
+                .await;
+            output.downloaded.push(relative_path.to_owned());
+        }
+        if let Some(reservation) = reservation {
+            sender.send(SyncDownEvent::MarkingReservationComplete).await;
+            reservation.mark_complete().await;
+        }
+        for (relative_path, original) in &duplicates {
+            if !is_safe_path(relative_path) || !is_safe_path(original) {
+                Err(SyncDownError::UnsafeKey(format!(
+                    "{}{relative_path}",
+                    input.prefix
+                )))?;
+            }
+            if progress.done.contains(relative_path) {
+                continue;
+            }
+            let path = input.dest.join(relative_path);
+            let original_path = input.dest.join(original);
+            let copy_error = |source| SyncDownError::CopyDuplicate {
+                path: relative_path.clone(),
+                original: original.clone(),
+                source,
+            };
+            let original_metadata = metadata(&original_path).await.map_err(copy_error)?;
+            let up_to_date = match metadata(&path).await {
+                Ok(metadata) => {
+                    metadata.len() == original_metadata.len()
+                        && matches!(
+                            (metadata.modified(), original_metadata.modified()),
+                            (Ok(modified), Ok(original_modified)) if modified >= original_modified
+                        )
+                }
+                Err(e) if e.kind() == ErrorKind::NotFound => false,
+                Err(e) => Err(SyncDownError::Metadata(e))?,
+            };
+            if up_to_date {
+                sender
+                    .send(SyncDownEvent::UpToDate(relative_path.clone()))
+                    .await;
+                output.up_to_date.push(relative_path.clone());
+            } else {
+                sender
+                    .send(SyncDownEvent::CopyingDuplicate {
+                        path: relative_path.clone(),
+                        original: original.clone(),
+                    })
+                    .await;
+                if let Some(parent) = path.parent() {
+                    create_dir_all(parent)
+                        .await
+                        .map_err(SyncDownError::Create)?;
+                }
+                copy(&original_path, &path).await.map_err(copy_error)?;
+                output.copied_duplicates.push(relative_path.clone());
+            }
+            progress.done.insert(relative_path.clone());
+            sender
+                .send(SyncDownEvent::SaveProgress(progress.clone()))
+                .await;
+        }
+        Ok(output)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use crate::sync_up::RemoteObject;
+
+    use super::{is_safe_path, is_up_to_date};
+
+    #[test]
+    fn safe_paths() {
+        assert!(is_safe_path("photos/2025/cat.jpg"));
+        assert!(!is_safe_path("../cat.jpg"));
+        assert!(!is_safe_path("photos/../../cat.jpg"));
+        assert!(!is_safe_path("/etc/passwd"));
+        assert!(!is_safe_path(""));
+    }
+
+    #[test]
+    fn up_to_date_files() {
+        let uploaded = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let remote = RemoteObject {
+            len: 10,
+            last_modified: Some(uploaded),
+            storage_class: None,
+        };
+        assert!(is_up_to_date(10, None, &remote));
+        assert!(!is_up_to_date(11, None, &remote));
+        assert!(is_up_to_date(
+            10,
+            Some(uploaded + Duration::from_secs(1)),
+            &remote
+        ));
+        assert!(!is_up_to_date(
+            10,
+            Some(uploaded - Duration::from_secs(1)),
+            &remote
+        ));
+    }
+}
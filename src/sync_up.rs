@@ -0,0 +1,791 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use aws_sdk_s3::{
+    error::SdkError,
+    operation::{
+        copy_object::CopyObjectError, delete_object::DeleteObjectError,
+        head_object::HeadObjectError, list_objects_v2::ListObjectsV2Error,
+    },
+    types::{ObjectStorageClass, StorageClass},
+};
+use serde::{Deserialize, Serialize};
+use sipper::{Sipper, Straw, sipper};
+use thiserror::Error;
+use tokio::fs::read_dir;
+
+use crate::{
+    AmountLimiter, ChecksumAlgorithm, ControlHandle, CustomerKey, Encryption, KeySuffix,
+    OperationScheduler, RetriesExhausted, RetryPolicy, Retrying, S3Dest, SerializationError,
+    SerializationFormat, UploadData, UploadError, UploadEvent, UploadInput, UploadSrc,
+    checksum::compute_checksum, maybe_retryable_sdk_error::IntoMaybeRetryable,
+    repair::head_if_exists, retry::KeepRetryingExt, storage_class_check::copy_source, upload,
+};
+
+/// How [`sync_up`] decides if a file needs to be uploaded
+#[derive(Debug, Clone, Copy, Default)]
+pub enum SyncCompare {
+    /// Upload the file if its size is different from the object's, or if it was modified after the object was uploaded
+    #[default]
+    SizeAndModified,
+    /// Upload the file if its size or checksum is different from the object's.
+    /// Every file is read to compute its checksum, and files are uploaded with this checksum so they can be compared next time.
+    /// Objects without a checksum, such as multipart uploads, are always uploaded again.
+    Checksum(ChecksumAlgorithm),
+}
+
+pub struct SyncUpInput<'a> {
+    pub client: &'a aws_sdk_s3::Client,
+    /// The local directory. Symlinks in it are not followed.
+    pub src: PathBuf,
+    pub bucket: &'a str,
+    /// Each file is uploaded to `{prefix}{path}`, where `path` is relative to [`Self::src`] and uses `/` as the separator.
+    /// End the prefix with a `/` to put the files in a "folder".
+    pub prefix: &'a str,
+    pub storage_class: StorageClass,
+    pub encryption: Encryption,
+    pub retry_policy: RetryPolicy,
+    pub operation_scheduler: Box<dyn OperationScheduler>,
+    pub amount_limiter: Box<dyn AmountLimiter>,
+    pub compare: SyncCompare,
+    /// With [`SyncCompare::SizeAndModified`], a file with the same size as its object that was modified after it,
+    /// such as one that was only touched, is only uploaded if its checksum is different from the object's.
+    /// Files are uploaded with this checksum so that they can be compared. Objects without one are uploaded again.
+    pub skip_touched: Option<ChecksumAlgorithm>,
+    /// Also delete the objects under the prefix that don't have a local file, after uploading
+    pub mirror: Option<Mirror>,
+    /// Upload the contents of duplicate files once, such as in a photo library with copied photos.
+    /// Hard links to the same file are duplicates, and so are files with the same size and checksum of this algorithm.
+    /// Only the first path of each file is uploaded, and the others are saved in the [`DUPLICATES_MANIFEST`] object,
+    /// which [`crate::sync_down`] copies the file to them with.
+    pub find_duplicates: Option<ChecksumAlgorithm>,
+    pub progress: SyncUpProgress,
+    pub control: ControlHandle,
+}
+
+/// Makes [`sync_up`] delete the objects under the prefix that don't have a local file, so that the prefix is an exact copy of the directory.
+/// Keys ending with a `/`, which are usually empty "folders" made by the S3 console, are kept.
+#[derive(Debug, Clone, Default)]
+pub struct Mirror {
+    /// Fail without deleting anything if more than this many objects would be deleted
+    pub max_deletes: Option<usize>,
+    /// Fail without deleting anything if more than this fraction, from 0 to 1, of the objects under the prefix would be deleted
+    pub max_delete_fraction: Option<f64>,
+    /// The keys that a dry run returned in [`SyncUpOutput::would_delete`].
+    /// Only these objects are deleted, so that nothing is deleted without a dry run first.
+    /// `None` for a dry run, which doesn't delete anything.
+    pub confirmed_deletes: Option<BTreeSet<String>>,
+    /// Move the objects into this prefix instead of deleting them, so that a mistake can be undone.
+    /// `{prefix}{path}` is copied to `{trash_prefix}{path}` in the same storage class, and then deleted.
+    /// Objects under it are never deleted by the mirror, even if it is under the prefix.
+    /// Add a lifecycle rule to the bucket that expires the objects under it, to empty the trash after a while.
+    /// Archived objects and objects larger than [`crate::MAX_PUT_OBJECT_SIZE`] can't be copied, so the sync fails on them.
+    pub trash_prefix: Option<String>,
+}
+
+/// The object under the prefix that lists the duplicate files of [`SyncUpInput::find_duplicates`],
+/// as JSON of the path of each duplicate to the path of the file that was uploaded
+pub const DUPLICATES_MANIFEST: &str = ".rcs3ud-duplicates.json";
+
+/// Saved state of a [`sync_up`], so that a restarted sync doesn't compare the same files again
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct SyncUpProgress {
+    /// Paths of the files that were uploaded or were already up to date
+    pub done: BTreeSet<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SyncUpOutput {
+    pub uploaded: Vec<String>,
+    pub up_to_date: Vec<String>,
+    /// Keys of the objects that [`Mirror`] deleted, or moved into [`Mirror::trash_prefix`]
+    pub deleted: Vec<String>,
+    /// Keys of the objects that [`Mirror`] would delete, but weren't in [`Mirror::confirmed_deletes`]
+    pub would_delete: BTreeSet<String>,
+    /// The path of each duplicate file that wasn't uploaded, to the path of the file that was
+    pub duplicates: BTreeMap<String, String>,
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Error)]
+pub enum SyncUpError {
+    #[error("Error reading a directory")]
+    ReadDir(io::Error),
+    #[error("Error getting metadata of a file")]
+    Metadata(io::Error),
+    #[error("The path {0:?} is not valid UTF-8, so it can't be used in an object key")]
+    NonUtf8Path(PathBuf),
+    #[error("Error listing the objects under the prefix")]
+    ListObjects(#[from] SdkError<ListObjectsV2Error>),
+    #[error("Error getting the checksum of an object")]
+    HeadObject(#[from] SdkError<HeadObjectError>),
+    #[error("Error reading a file to compute its checksum")]
+    Checksum(io::Error),
+    #[error("Error uploading {path}")]
+    Upload { path: String, source: UploadError },
+    /// Nothing was deleted or uploaded
+    #[error(
+        "Mirroring would delete {count} of the {total} objects under the prefix, which is more than allowed"
+    )]
+    TooManyDeletes { count: usize, total: usize },
+    #[error("Error deleting an object")]
+    DeleteObject(#[from] SdkError<DeleteObjectError>),
+    #[error("Error copying an object into the trash")]
+    CopyObject(#[from] SdkError<CopyObjectError>),
+    #[error("Error serializing the duplicates manifest")]
+    SerializeManifest(SerializationError),
+    #[error("Gave up retrying")]
+    RetriesExhausted(#[from] RetriesExhausted),
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum SyncUpEvent {
+    ListingFiles,
+    ListingObjects,
+    ListObjectsError(Retrying<SdkError<ListObjectsV2Error>>),
+    CheckObjectError(Retrying<SdkError<HeadObjectError>>),
+    FindingDuplicates,
+    /// The file isn't uploaded because it has the same contents as `original`
+    Duplicate {
+        path: String,
+        original: String,
+    },
+    UpToDate(String),
+    Uploading(String),
+    UploadEvent {
+        path: String,
+        event: UploadEvent,
+    },
+    Uploaded(String),
+    /// [`Mirror`] would delete this object, but it wasn't confirmed with a dry run
+    WouldDelete(String),
+    Deleting(String),
+    /// Copying the object into [`Mirror::trash_prefix`] before deleting it
+    MovingToTrash {
+        key: String,
+        trash_key: String,
+    },
+    CopyObjectError(Retrying<SdkError<CopyObjectError>>),
+    DeleteObjectError(Retrying<SdkError<DeleteObjectError>>),
+    Deleted(String),
+    SaveProgress(SyncUpProgress),
+}
+
+pub(crate) struct LocalFile {
+    /// Relative to the directory, with `/` as the separator
+    pub relative_path: String,
+    pub path: PathBuf,
+    pub len: usize,
+    pub modified: Option<SystemTime>,
+    /// The device and inode on Unix, which are the same for hard links to the same file
+    pub id: Option<(u64, u64)>,
+}
+
+/// Lists every file under `dir`, sorted by relative path. Symlinks are skipped.
+pub(crate) async fn list_files(dir: &Path) -> Result<Vec<LocalFile>, SyncUpError> {
+    let mut files = Vec::new();
+    let mut dirs = vec![(dir.to_owned(), String::new())];
+    while let Some((dir, relative_dir)) = dirs.pop() {
+        let mut entries = read_dir(&dir).await.map_err(SyncUpError::ReadDir)?;
+        while let Some(entry) = entries.next_entry().await.map_err(SyncUpError::ReadDir)? {
+            let name = entry
+                .file_name()
+                .into_string()
+                .map_err(|_| SyncUpError::NonUtf8Path(entry.path()))?;
+            let relative_path = format!("{relative_dir}{name}");
+            let metadata = entry.metadata().await.map_err(SyncUpError::Metadata)?;
+            if metadata.is_dir() {
+                dirs.push((entry.path(), format!("{relative_path}/")));
+            } else if metadata.is_file() {
+                #[cfg(unix)]
+                let id = {
+                    use std::os::unix::fs::MetadataExt;
+                    Some((metadata.dev(), metadata.ino()))
+                };
+                #[cfg(not(unix))]
+                let id = None;
+                files.push(LocalFile {
+                    relative_path,
+                    path: entry.path(),
+                    len: metadata.len().try_into().unwrap(),
+                    modified: metadata.modified().ok(),
+                    id,
+                });
+            }
+        }
+    }
+    files.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+    Ok(files)
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct RemoteObject {
+    pub len: usize,
+    pub last_modified: Option<SystemTime>,
+    pub storage_class: Option<ObjectStorageClass>,
+}
+
+/// Lists every object under the prefix, by key
+pub(crate) fn list_objects<'a, E: From<SdkError<ListObjectsV2Error>> + From<RetriesExhausted>>(
+    client: &'a aws_sdk_s3::Client,
+    bucket: &'a str,
+    prefix: &'a str,
+    retry_policy: RetryPolicy,
+) -> impl Straw<BTreeMap<String, RemoteObject>, Retrying<SdkError<ListObjectsV2Error>>, E> {
+    sipper(async move |sender| {
+        (async || {
+            let mut objects = BTreeMap::new();
+            let mut continuation_token = None;
+            loop {
+                let output = client
+                    .list_objects_v2()
+                    .bucket(bucket)
+                    .prefix(prefix)
+                    .set_continuation_token(continuation_token)
+                    .send()
+                    .await
+                    .map_err(|e| e.into_maybe_retryable().map(E::from))?;
+                for object in output.contents() {
+                    if let Some(key) = object.key() {
+                        objects.insert(
+                            key.to_owned(),
+                            RemoteObject {
+                                len: object.size().unwrap_or_default().try_into().unwrap(),
+                                last_modified: object
+                                    .last_modified()
+                                    .and_then(|time| SystemTime::try_from(*time).ok()),
+                                storage_class: object.storage_class().cloned(),
+                            },
+                        );
+                    }
+                }
+                match output.next_continuation_token {
+                    Some(token) => continuation_token = Some(token),
+                    None => break Ok(objects),
+                }
+            }
+        })
+        .keep_retrying(retry_policy)
+        .run(sender)
+        .await
+    })
+}
+
+/// Returns `true` if the file has a different size than the object, or was modified after the object was uploaded.
+/// If either modified time is unknown, only the size is compared.
+pub(crate) fn is_changed(
+    len: usize,
+    modified: Option<SystemTime>,
+    remote: Option<&RemoteObject>,
+) -> bool {
+    match remote {
+        Some(remote) => {
+            remote.len != len
+                || matches!(
+                    (modified, remote.last_modified),
+                    (Some(modified), Some(last_modified)) if modified > last_modified
+                )
+        }
+        None => true,
+    }
+}
+
+impl Mirror {
+    /// Returns `true` if deleting `count` of the `total` objects under the prefix is within the limits
+    fn allows(&self, count: usize, total: usize) -> bool {
+        self.max_deletes.is_none_or(|max| count <= max)
+            && self
+                .max_delete_fraction
+                .is_none_or(|max| count as f64 <= max * total as f64)
+    }
+}
+
+/// Returns the keys of the objects that don't have a local file, except for the ones in the trash
+fn extraneous_objects(
+    objects: &BTreeMap<String, RemoteObject>,
+    local_keys: &BTreeSet<String>,
+    trash_prefix: Option<&str>,
+) -> BTreeSet<String> {
+    objects
+        .keys()
+        .filter(|key| {
+            !key.ends_with('/')
+                && !local_keys.contains(*key)
+                && trash_prefix.is_none_or(|trash_prefix| !key.starts_with(trash_prefix))
+        })
+        .cloned()
+        .collect()
+}
+
+/// Returns the path of each file that has the same contents as an earlier file, to the path of the earlier file.
+/// Only files with the same size as another file are read to compute their checksum. Empty files are never duplicates.
+async fn find_duplicates(
+    files: &[LocalFile],
+    algorithm: ChecksumAlgorithm,
+) -> Result<BTreeMap<String, String>, SyncUpError> {
+    let mut duplicates = BTreeMap::new();
+    let mut by_id = HashMap::new();
+    let mut by_len = BTreeMap::<_, Vec<_>>::new();
+    for file in files.iter().filter(|file| file.len > 0) {
+        match file.id.and_then(|id| by_id.get(&id)) {
+            Some(original) => {
+                duplicates.insert(file.relative_path.clone(), String::clone(original));
+            }
+            None => {
+                if let Some(id) = file.id {
+                    by_id.insert(id, file.relative_path.clone());
+                }
+                by_len.entry(file.len).or_default().push(file);
+            }
+        }
+    }
+    for same_len in by_len.into_values().filter(|files| files.len() > 1) {
+        let mut by_checksum = HashMap::new();
+        for file in same_len {
+            let checksum = compute_checksum(
+                &UploadSrc {
+                    data: UploadData::File(file.path.clone()),
+                    offset: 0,
+                    len: file.len,
+                },
+                algorithm,
+            )
+            .await
+            .map_err(SyncUpError::Checksum)?
+            .to_base64();
+            match by_checksum.get(&checksum) {
+                Some(original) => {
+                    duplicates.insert(file.relative_path.clone(), String::clone(original));
+                }
+                None => {
+                    by_checksum.insert(checksum, file.relative_path.clone());
+                }
+            }
+        }
+    }
+    Ok(duplicates)
+}
+
+/// Returns `true` if the object has a checksum of `algorithm` that matches the file's
+fn same_checksum<'a>(
+    client: &'a aws_sdk_s3::Client,
+    bucket: &'a str,
+    object_key: &'a str,
+    src: &'a UploadSrc,
+    algorithm: ChecksumAlgorithm,
+    retry_policy: RetryPolicy,
+) -> impl Straw<bool, Retrying<SdkError<HeadObjectError>>, SyncUpError> + 'a {
+    sipper(async move |sender| {
+        let head = head_if_exists::<SyncUpError>(client, bucket, object_key, retry_policy)
+            .run(sender)
+            .await?;
+        let remote_checksum = head.as_ref().and_then(|head| match algorithm {
+            ChecksumAlgorithm::Sha256 => head.checksum_sha256(),
+            ChecksumAlgorithm::Crc32c => head.checksum_crc32_c(),
+        });
+        Ok(match remote_checksum {
+            Some(remote_checksum) => {
+                compute_checksum(src, algorithm)
+                    .await
+                    .map_err(SyncUpError::Checksum)?
+                    .to_base64()
+                    == remote_checksum
+            }
+            None => false,
+        })
+    })
+}
+
+/// Uploads the files in a local directory that are missing or different under an S3 prefix.
+/// Objects under the prefix that don't have a local file are kept, unless [`SyncUpInput::mirror`] is set.
+pub fn sync_up(mut input: SyncUpInput<'_>) -> impl Straw<SyncUpOutput, SyncUpEvent, SyncUpError> {
+    sipper(async move |mut sender| {
+        sender.send(SyncUpEvent::ListingFiles).await;
+        let mut files = list_files(&input.src).await?;
+        // Its key is used by the manifest
+        files.retain(|file| file.relative_path != DUPLICATES_MANIFEST);
+        let duplicates = match input.find_duplicates {
+            Some(algorithm) => {
+                sender.send(SyncUpEvent::FindingDuplicates).await;
+                find_duplicates(&files, algorithm).await?
+            }
+            None => Default::default(),
+        };
+        let manifest_key = format!("{}{DUPLICATES_MANIFEST}", input.prefix);
+        sender.send(SyncUpEvent::ListingObjects).await;
+        let objects = list_objects::<SyncUpError>(
+            input.client,
+            input.bucket,
+            input.prefix,
+            input.retry_policy,
+        )
+        .with(SyncUpEvent::ListObjectsError)
+        .run(sender.clone())
+        .await?;
+        // Checked before uploading anything, so that a mistake such as the wrong directory doesn't change the prefix at all
+        let extraneous = match &input.mirror {
+            Some(mirror) => {
+                // Objects uploaded for the duplicates before are deleted, since they are copied from the original instead
+                let local_keys = files
+                    .iter()
+                    .filter(|file| !duplicates.contains_key(&file.relative_path))
+                    .map(|file| format!("{}{}", input.prefix, file.relative_path))
+                    .chain(input.find_duplicates.map(|_| manifest_key.clone()))
+                    .collect();
+                let extraneous =
+                    extraneous_objects(&objects, &local_keys, mirror.trash_prefix.as_deref());
+                if !mirror.allows(extraneous.len(), objects.len()) {
+                    Err(SyncUpError::TooManyDeletes {
+                        count: extraneous.len(),
+                        total: objects.len(),
+                    })?;
+                }
+                extraneous
+            }
+            None => Default::default(),
+        };
+        let mut output = SyncUpOutput::default();
+        for file in files {
+            if input.progress.done.contains(&file.relative_path) {
+                continue;
+            }
+            if let Some(original) = duplicates.get(&file.relative_path) {
+                sender
+                    .send(SyncUpEvent::Duplicate {
+                        path: file.relative_path.clone(),
+                        original: original.clone(),
+                    })
+                    .await;
+                input.progress.done.insert(file.relative_path);
+                sender
+                    .send(SyncUpEvent::SaveProgress(input.progress.clone()))
+                    .await;
+                continue;
+            }
+            let object_key = format!("{}{}", input.prefix, file.relative_path);
+            let remote = objects.get(&object_key);
+            let src = UploadSrc {
+                data: UploadData::File(file.path),
+                offset: 0,
+                len: file.len,
+            };
+            let checksum = match input.compare {
+                SyncCompare::SizeAndModified => input.skip_touched,
+                SyncCompare::Checksum(algorithm) => Some(algorithm),
+            };
+            let changed = match input.compare {
+                SyncCompare::SizeAndModified => is_changed(file.len, file.modified, remote),
+                SyncCompare::Checksum(_) => true,
+            };
+            // Only the checksum can tell if a file with the same size has different contents
+            let changed = match (checksum, remote) {
+                (Some(algorithm), Some(remote)) if changed && remote.len == file.len => {
+                    !same_checksum(
+                        input.client,
+                        input.bucket,
+                        &object_key,
+                        &src,
+                        algorithm,
+                        input.retry_policy,
+                    )
+                    .with(SyncUpEvent::CheckObjectError)
+                    .run(sender.clone())
+                    .await?
+                }
+                _ => changed,
+            };
+            if changed {
+                sender
+                    .send(SyncUpEvent::Uploading(file.relative_path.clone()))
+                    .await;
+                upload(UploadInput {
+                    client: input.client,
+                    src,
+                    dest: S3Dest {
+                        bucket: input.bucket,
+                        object_key: &object_key,
+                        storage_class: input.storage_class.clone(),
+                        encryption: input.encryption.clone(),
+                    },
+                    retry_policy: input.retry_policy,
+                    operation_scheduler: input.operation_scheduler.clone(),
+                    amount_limiter: input.amount_limiter.clone(),
+                    tagging: Default::default(),
+                    key_suffix: KeySuffix::None,
+                    checksum,
+                    multipart_threshold: None,
+                    client_side_encryption: None,
+                    compression: None,
+                    metadata: Default::default(),
+                    storage_class_check: None,
+                    capabilities: Default::default(),
+                    control: input.control.clone(),
+                })
+                .with(|event| SyncUpEvent::UploadEvent {
+                    path: file.relative_path.clone(),
+                    event,
+                })
+                .run(sender.clone())
+                .await
+                .map_err(|source| SyncUpError::Upload {
+                    path: file.relative_path.clone(),
+                    source,
+                })?;
+                sender
+                    .send(SyncUpEvent::Uploaded(file.relative_path.clone()))
+                    .await;
+                output.uploaded.push(file.relative_path.clone());
+            } else {
+                sender
+                    .send(SyncUpEvent::UpToDate(file.relative_path.clone()))
+                    .await;
+                output.up_to_date.push(file.relative_path.clone());
+            }
+            input.progress.done.insert(file.relative_path);
+            sender
+                .send(SyncUpEvent::SaveProgress(input.progress.clone()))
+                .await;
+        }
+        // Uploaded even without duplicates if there is one from before, so that it doesn't list files that aren't duplicates anymore
+        if input.find_duplicates.is_some()
+            && (!duplicates.is_empty() || objects.contains_key(&manifest_key))
+        {
+            let manifest = SerializationFormat::Json
+                .serialize(&duplicates)
+                .map_err(SyncUpError::SerializeManifest)?;
+            sender
+                .send(SyncUpEvent::Uploading(DUPLICATES_MANIFEST.to_owned()))
+                .await;
+            upload(UploadInput {
+                client: input.client,
+                src: UploadSrc::from_bytes(manifest),
+                dest: S3Dest {
+                    bucket: input.bucket,
+                    object_key: &manifest_key,
+                    // So that sync_down can read it without restoring it
+                    storage_class: StorageClass::Standard,
+                    encryption: input.encryption.clone(),
+                },
+                retry_policy: input.retry_policy,
+                operation_scheduler: input.operation_scheduler.clone(),
+                amount_limiter: input.amount_limiter.clone(),
+                tagging: Default::default(),
+                key_suffix: KeySuffix::None,
+                checksum: None,
+                multipart_threshold: None,
+                client_side_encryption: None,
+                compression: None,
+                metadata: Default::default(),
+                storage_class_check: None,
+                capabilities: Default::default(),
+                control: input.control.clone(),
+            })
+            .with(|event| SyncUpEvent::UploadEvent {
+                path: DUPLICATES_MANIFEST.to_owned(),
+                event,
+            })
+            .run(sender.clone())
+            .await
+            .map_err(|source| SyncUpError::Upload {
+                path: DUPLICATES_MANIFEST.to_owned(),
+                source,
+            })?;
+            sender
+                .send(SyncUpEvent::Uploaded(DUPLICATES_MANIFEST.to_owned()))
+                .await;
+        }
+        output.duplicates = duplicates;
+        let confirmed_deletes = input
+            .mirror
+            .as_ref()
+            .and_then(|mirror| mirror.confirmed_deletes.as_ref());
+        let trash_prefix = input
+            .mirror
+            .as_ref()
+            .and_then(|mirror| mirror.trash_prefix.as_deref());
+        let customer_key = input.encryption.customer_key();
+        for key in extraneous {
+            if !confirmed_deletes.is_some_and(|confirmed| confirmed.contains(&key)) {
+                sender.send(SyncUpEvent::WouldDelete(key.clone())).await;
+                output.would_delete.insert(key);
+                continue;
+            }
+            if let Some(trash_prefix) = trash_prefix {
+                let trash_key = format!("{trash_prefix}{}", &key[input.prefix.len()..]);
+                sender
+                    .send(SyncUpEvent::MovingToTrash {
+                        key: key.clone(),
+                        trash_key: trash_key.clone(),
+                    })
+                    .await;
+                // The metadata and tags are copied, but the storage class and encryption have to be given again
+                let storage_class = objects[&key]
+                    .storage_class
+                    .as_ref()
+                    .map_or(input.storage_class.clone(), |storage_class| {
+                        StorageClass::from(storage_class.as_str())
+                    });
+                (async || {
+                    input
+                        .client
+                        .copy_object()
+                        .bucket(input.bucket)
+                        .key(&trash_key)
+                        .copy_source(copy_source(input.bucket, &key, None))
+                        .storage_class(storage_class.clone())
+                        .set_server_side_encryption(input.encryption.server_side_encryption())
+                        .set_ssekms_key_id(input.encryption.kms_key_id())
+                        .set_sse_customer_algorithm(customer_key.map(CustomerKey::algorithm))
+                        .set_sse_customer_key(customer_key.map(CustomerKey::key))
+                        .set_sse_customer_key_md5(customer_key.map(CustomerKey::key_md5))
+                        .set_copy_source_sse_customer_algorithm(
+                            customer_key.map(CustomerKey::algorithm),
+                        )
+                        .set_copy_source_sse_customer_key(customer_key.map(CustomerKey::key))
+                        .set_copy_source_sse_customer_key_md5(
+                            customer_key.map(CustomerKey::key_md5),
+                        )
+                        .send()
+                        .await
+                        .map_err(|e| e.into_maybe_retryable().map(SyncUpError::from))
+                })
+                .keep_retrying(input.retry_policy)
+                .with(SyncUpEvent::CopyObjectError)
+                .run(sender.clone())
+                .await?;
+            }
+            sender.send(SyncUpEvent::Deleting(key.clone())).await;
+            (async || {
+                input
+                    .client
+                    .delete_object()
+                    .bucket(input.bucket)
+                    .key(&key)
+                    .send()
+                    .await
+                    .map_err(|e| e.into_maybe_retryable().map(SyncUpError::from))
+            })
+            .keep_retrying(input.retry_policy)
+            .with(SyncUpEvent::DeleteObjectError)
+            .run(sender.clone())
+            .await?;
+            sender.send(SyncUpEvent::Deleted(key.clone())).await;
+            output.deleted.push(key);
+        }
+        Ok(output)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        time::{Duration, SystemTime},
+    };
+
+    use super::{Mirror, RemoteObject, find_duplicates, is_changed, list_files};
+    use crate::ChecksumAlgorithm;
+
+    #[test]
+    fn changed_files() {
+        let uploaded = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let remote = RemoteObject {
+            len: 10,
+            last_modified: Some(uploaded),
+            storage_class: None,
+        };
+        assert!(is_changed(10, None, None));
+        assert!(is_changed(11, None, Some(&remote)));
+        assert!(!is_changed(10, None, Some(&remote)));
+        assert!(!is_changed(
+            10,
+            Some(uploaded - Duration::from_secs(1)),
+            Some(&remote)
+        ));
+        assert!(is_changed(
+            10,
+            Some(uploaded + Duration::from_secs(1)),
+            Some(&remote)
+        ));
+    }
+
+    #[test]
+    fn extraneous_objects() {
+        let object = RemoteObject {
+            len: 10,
+            last_modified: None,
+            storage_class: None,
+        };
+        let objects = [
+            "backup/a",
+            "backup/b",
+            "backup/c",
+            "backup/old/",
+            "backup/trash/a",
+        ]
+        .into_iter()
+        .map(|key| (key.to_owned(), object.clone()))
+        .collect::<BTreeMap<_, _>>();
+        let local_keys = ["backup/a".to_owned(), "backup/b".to_owned()].into();
+        assert_eq!(
+            super::extraneous_objects(&objects, &local_keys, None),
+            ["backup/c".to_owned(), "backup/trash/a".to_owned()].into()
+        );
+        assert_eq!(
+            super::extraneous_objects(&objects, &local_keys, Some("backup/trash/")),
+            ["backup/c".to_owned()].into()
+        );
+    }
+
+    #[test]
+    fn mirror_limits() {
+        assert!(Mirror::default().allows(100, 100));
+        let max_deletes = Mirror {
+            max_deletes: Some(1),
+            ..Default::default()
+        };
+        assert!(max_deletes.allows(1, 5));
+        assert!(!max_deletes.allows(2, 5));
+        let max_delete_fraction = Mirror {
+            max_delete_fraction: Some(0.5),
+            ..Default::default()
+        };
+        assert!(max_delete_fraction.allows(2, 4));
+        assert!(!max_delete_fraction.allows(3, 4));
+    }
+
+    #[tokio::test]
+    async fn finds_duplicates() {
+        let dir = std::env::temp_dir().join(format!("rcs3ud-test-{:016x}", fastrand::u64(..)));
+        tokio::fs::create_dir_all(dir.join("copies")).await.unwrap();
+        tokio::fs::write(dir.join("a.jpg"), "cat").await.unwrap();
+        tokio::fs::write(dir.join("copies/a.jpg"), "cat")
+            .await
+            .unwrap();
+        tokio::fs::hard_link(dir.join("a.jpg"), dir.join("link.jpg"))
+            .await
+            .unwrap();
+        // Same size, different contents
+        tokio::fs::write(dir.join("b.jpg"), "dog").await.unwrap();
+        tokio::fs::write(dir.join("empty"), "").await.unwrap();
+        tokio::fs::write(dir.join("empty2"), "").await.unwrap();
+        let files = list_files(&dir).await.unwrap();
+        let duplicates = find_duplicates(&files, ChecksumAlgorithm::Crc32c)
+            .await
+            .unwrap();
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+        assert_eq!(
+            duplicates,
+            [
+                ("copies/a.jpg".to_owned(), "a.jpg".to_owned()),
+                ("link.jpg".to_owned(), "a.jpg".to_owned()),
+            ]
+            .into()
+        );
+    }
+}
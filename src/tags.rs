@@ -0,0 +1,100 @@
+use aws_sdk_s3::{
+    error::SdkError,
+    operation::{
+        get_object_tagging::GetObjectTaggingError, put_object_tagging::PutObjectTaggingError,
+    },
+    types::{Tag, Tagging},
+};
+use ordermap::OrderMap;
+use sipper::{Sipper, Straw, sipper};
+use thiserror::Error;
+
+use crate::{
+    RetriesExhausted, RetryPolicy, Retrying, S3Src, maybe_retryable_sdk_error::IntoMaybeRetryable,
+    retry::KeepRetryingExt,
+};
+
+/// An object's tags, in the order S3 returned them.
+pub type Tags = OrderMap<String, String>;
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Error)]
+pub enum GetTagsError {
+    #[error("Error getting the object's tags")]
+    GetObjectTagging(SdkError<GetObjectTaggingError>),
+    #[error("Gave up retrying")]
+    RetriesExhausted(#[from] RetriesExhausted),
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Error)]
+pub enum PutTagsError {
+    #[error("Invalid tag")]
+    Build(aws_sdk_s3::error::BuildError),
+    #[error("Error setting the object's tags")]
+    PutObjectTagging(SdkError<PutObjectTaggingError>),
+    #[error("Gave up retrying")]
+    RetriesExhausted(#[from] RetriesExhausted),
+}
+
+/// Gets the tags of an object, such as the chunk layout tags written by [`crate::upload_chunked`].
+pub fn get_tags<'a>(
+    client: &'a aws_sdk_s3::Client,
+    src: S3Src<'a>,
+    retry_policy: RetryPolicy,
+) -> impl Straw<Tags, Retrying<SdkError<GetObjectTaggingError>>, GetTagsError> {
+    sipper(async move |sender| {
+        let output = (async || {
+            client
+                .get_object_tagging()
+                .bucket(src.bucket)
+                .key(src.object_key)
+                .send()
+                .await
+                .map_err(|e| e.into_maybe_retryable().map(GetTagsError::GetObjectTagging))
+        })
+        .keep_retrying(retry_policy)
+        .run(sender)
+        .await?;
+        Ok(output
+            .tag_set
+            .into_iter()
+            .map(|tag| (tag.key, tag.value))
+            .collect())
+    })
+}
+
+/// Replaces all of the tags of an object.
+/// To change some tags while keeping the rest, use [`get_tags`] first.
+pub fn put_tags<'a>(
+    client: &'a aws_sdk_s3::Client,
+    src: S3Src<'a>,
+    tags: &'a Tags,
+    retry_policy: RetryPolicy,
+) -> impl Straw<(), Retrying<SdkError<PutObjectTaggingError>>, PutTagsError> {
+    sipper(async move |sender| {
+        let tagging = Tagging::builder()
+            .set_tag_set(Some(
+                tags.iter()
+                    .map(|(key, value)| Tag::builder().key(key).value(value).build())
+                    .collect::<Result<_, _>>()
+                    .map_err(PutTagsError::Build)?,
+            ))
+            .build()
+            .map_err(PutTagsError::Build)?;
+        (async || {
+            client
+                .put_object_tagging()
+                .bucket(src.bucket)
+                .key(src.object_key)
+                .tagging(tagging.clone())
+                .send()
+                .await
+                .map_err(|e| e.into_maybe_retryable().map(PutTagsError::PutObjectTagging))
+        })
+        .keep_retrying(retry_policy)
+        .run(sender)
+        .await?;
+        Ok(())
+    })
+}
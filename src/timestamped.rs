@@ -0,0 +1,53 @@
+use std::time::{Duration, Instant};
+
+use sipper::Straw;
+use time::UtcDateTime;
+
+/// An event with when it was sent.
+/// Events that are handled later, such as by a task that writes them to a log, still have the time that they happened.
+#[derive(Debug, Clone)]
+pub struct Timestamped<E> {
+    pub time: UtcDateTime,
+    /// The time since the operation started, measured with a monotonic clock.
+    /// Unlike [`Timestamped::time`], it never goes backwards if the system clock is changed.
+    pub elapsed: Duration,
+    pub event: E,
+}
+
+/// Runs the operation, wrapping every event with the time that it was sent
+pub fn timestamped<O, E, Err>(straw: impl Straw<O, E, Err>) -> impl Straw<O, Timestamped<E>, Err> {
+    let start = Instant::now();
+    straw.with(move |event| Timestamped {
+        time: UtcDateTime::now(),
+        elapsed: start.elapsed(),
+        event,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use sipper::{Sipper, sipper};
+
+    use super::timestamped;
+
+    #[tokio::test]
+    async fn events_are_in_order() {
+        let mut straw = timestamped(sipper(async |mut sender| {
+            for i in 0..3 {
+                sender.send(i).await;
+            }
+            Ok::<_, ()>(())
+        }))
+        .pin();
+        let mut events = Vec::new();
+        while let Some(event) = straw.sip().await {
+            events.push(event);
+        }
+        straw.await.unwrap();
+        assert_eq!(
+            events.iter().map(|event| event.event).collect::<Vec<_>>(),
+            [0, 1, 2]
+        );
+        assert!(events.is_sorted_by_key(|event| event.elapsed));
+    }
+}
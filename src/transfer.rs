@@ -0,0 +1,171 @@
+use std::{num::NonZeroUsize, sync::Arc};
+
+use sipper::{Sipper, Straw, sipper};
+use thiserror::Error;
+
+use crate::{
+    DownloadChunkedObjectsError, DownloadChunkedObjectsEvent, DownloadChunkedObjectsInput,
+    DownloadError, DownloadEvent, DownloadInput, DownloadOutput, RestoreError, RestoreInput,
+    RestoreStage, SyncDownError, SyncDownEvent, SyncDownInput, SyncDownOutput, SyncUpError,
+    SyncUpEvent, SyncUpInput, SyncUpOutput, UploadChunkedError, UploadChunkedEvent,
+    UploadChunkedInput, UploadChunkedOutput, UploadError, UploadEvent, UploadInput,
+    UploadMultiError, UploadMultiEvent, UploadMultiInput, UploadMultiOutput, UploadOutput,
+    WaitUntilRestoredEvent, download, download_chunked, download_chunked_objects, sync_down,
+    sync_up, upload, upload_chunked, upload_multi, wait_until_restored,
+};
+
+/// Any of the operations, so that an application can start and observe them all the same way with [`run`]
+pub enum Transfer<'a> {
+    Upload(UploadInput<'a>),
+    UploadChunked(UploadChunkedInput<'a>),
+    UploadMulti(UploadMultiInput<'a>),
+    Download(DownloadInput<'a>),
+    DownloadChunked {
+        input: DownloadInput<'a>,
+        chunk_size: NonZeroUsize,
+    },
+    DownloadChunkedObjects(DownloadChunkedObjectsInput<'a>),
+    /// See [`wait_until_restored`]
+    Restore {
+        input: RestoreInput<'a>,
+        stage: RestoreStage,
+    },
+    SyncUp(SyncUpInput<'a>),
+    SyncDown(SyncDownInput<'a>),
+}
+
+/// The output of the [`Transfer`] that was run. Transfers without an output only have a variant for consistency.
+#[derive(Debug, Clone)]
+pub enum TransferOutput {
+    Upload(UploadOutput),
+    UploadChunked(UploadChunkedOutput),
+    UploadMulti(UploadMultiOutput),
+    Download(DownloadOutput),
+    DownloadChunkedObjects,
+    Restore,
+    SyncUp(SyncUpOutput),
+    SyncDown(SyncDownOutput),
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Error)]
+pub enum TransferError {
+    #[error("Error uploading")]
+    Upload(#[from] UploadError),
+    #[error("Error uploading in chunks")]
+    UploadChunked(#[from] UploadChunkedError),
+    #[error("Error uploading to several destinations")]
+    UploadMulti(#[from] UploadMultiError),
+    #[error("Error downloading")]
+    Download(#[from] DownloadError),
+    #[error("Error downloading chunked objects")]
+    DownloadChunkedObjects(#[from] DownloadChunkedObjectsError),
+    #[error("Error restoring")]
+    Restore(#[from] RestoreError),
+    #[error("Error syncing up")]
+    SyncUp(#[from] SyncUpError),
+    #[error("Error syncing down")]
+    SyncDown(#[from] SyncDownError),
+}
+
+/// The event of the [`Transfer`] that is running.
+/// [`Transfer::Download`] and [`Transfer::DownloadChunked`] both send [`TransferEvent::Download`].
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum TransferEvent {
+    Upload(UploadEvent),
+    UploadChunked(UploadChunkedEvent),
+    UploadMulti(UploadMultiEvent),
+    Download(DownloadEvent),
+    DownloadChunkedObjects(DownloadChunkedObjectsEvent),
+    Restore(WaitUntilRestoredEvent),
+    SyncUp(SyncUpEvent),
+    SyncDown(SyncDownEvent),
+}
+
+/// A [`TransferEvent`] with the label of the transfer that sent it
+#[derive(Debug)]
+pub struct LabeledEvent {
+    pub label: Arc<str>,
+    pub event: TransferEvent,
+}
+
+/// Runs any [`Transfer`], with one event, output, and error type for all of them.
+/// The inputs already have the client and [`crate::ControlHandle`], so nothing else is needed.
+pub fn run(transfer: Transfer<'_>) -> impl Straw<TransferOutput, TransferEvent, TransferError> {
+    sipper(async move |sender| {
+        Ok(match transfer {
+            Transfer::Upload(input) => TransferOutput::Upload(
+                upload(input)
+                    .with(TransferEvent::Upload)
+                    .run(sender)
+                    .await?,
+            ),
+            Transfer::UploadChunked(input) => TransferOutput::UploadChunked(
+                upload_chunked(input)
+                    .with(TransferEvent::UploadChunked)
+                    .run(sender)
+                    .await?,
+            ),
+            Transfer::UploadMulti(input) => TransferOutput::UploadMulti(
+                upload_multi(input)
+                    .with(TransferEvent::UploadMulti)
+                    .run(sender)
+                    .await?,
+            ),
+            Transfer::Download(input) => TransferOutput::Download(
+                download(input)
+                    .await
+                    .with(TransferEvent::Download)
+                    .run(sender)
+                    .await?,
+            ),
+            Transfer::DownloadChunked { input, chunk_size } => TransferOutput::Download(
+                download_chunked(input, chunk_size)
+                    .await
+                    .with(TransferEvent::Download)
+                    .run(sender)
+                    .await?,
+            ),
+            Transfer::DownloadChunkedObjects(input) => {
+                download_chunked_objects(input)
+                    .with(TransferEvent::DownloadChunkedObjects)
+                    .run(sender)
+                    .await?;
+                TransferOutput::DownloadChunkedObjects
+            }
+            Transfer::Restore { input, stage } => {
+                wait_until_restored(input, stage)
+                    .with(TransferEvent::Restore)
+                    .run(sender)
+                    .await?;
+                TransferOutput::Restore
+            }
+            Transfer::SyncUp(input) => TransferOutput::SyncUp(
+                sync_up(input)
+                    .with(TransferEvent::SyncUp)
+                    .run(sender)
+                    .await?,
+            ),
+            Transfer::SyncDown(input) => TransferOutput::SyncDown(
+                sync_down(input)
+                    .with(TransferEvent::SyncDown)
+                    .run(sender)
+                    .await?,
+            ),
+        })
+    })
+}
+
+/// Like [`run`], but every event has the label, so that the events of concurrent transfers can be told apart.
+/// To see the label in a [`crate::FileBackedAmountLimiter`]'s queue too, use [`crate::FileBackedAmountLimiter::with_description`].
+pub fn run_labeled(
+    label: impl Into<Arc<str>>,
+    transfer: Transfer<'_>,
+) -> impl Straw<TransferOutput, LabeledEvent, TransferError> {
+    let label = label.into();
+    run(transfer).with(move |event| LabeledEvent {
+        label: label.clone(),
+        event,
+    })
+}
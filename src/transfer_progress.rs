@@ -0,0 +1,199 @@
+use std::{
+    collections::VecDeque,
+    pin::pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use futures::future::{Either, select};
+use sipper::Sender;
+use tokio::time::sleep;
+
+use crate::UploadEvent;
+
+/// How often [`UploadEvent::Progress`] is sent while the data is being sent
+pub const UPLOAD_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// [`TransferProgress::bytes_per_second`] is the speed over this long
+pub const SPEED_WINDOW: Duration = Duration::from_secs(5);
+
+/// How far an upload or a download is, for drawing a progress bar
+#[derive(Debug, Clone, Copy)]
+pub struct TransferProgress {
+    /// Bytes sent or received so far, including the bytes from before the transfer was resumed
+    pub transferred: usize,
+    /// `None` if the length isn't known, see [`crate::MissingContentLength::StreamToEof`]
+    pub total: Option<usize>,
+    /// The speed over the last [`SPEED_WINDOW`]. `None` until some time has passed.
+    pub bytes_per_second: Option<f64>,
+    /// The average speed since the transfer was started or resumed. `None` until some time has passed.
+    pub average_bytes_per_second: Option<f64>,
+    /// How long the rest of the transfer takes at [`TransferProgress::bytes_per_second`].
+    /// `None` if the total or the speed isn't known, or if nothing is being transferred.
+    pub eta: Option<Duration>,
+}
+
+/// Measures the speeds of a [`TransferProgress`]
+pub(crate) struct RateMeter {
+    started: Instant,
+    start_bytes: usize,
+    /// The oldest sample is the last one from before the [`SPEED_WINDOW`]
+    samples: VecDeque<(Instant, usize)>,
+}
+
+impl RateMeter {
+    pub(crate) fn new(start_bytes: usize, now: Instant) -> Self {
+        Self {
+            started: now,
+            start_bytes,
+            samples: VecDeque::from([(now, start_bytes)]),
+        }
+    }
+
+    /// The average speed since the meter was created
+    pub(crate) fn rate(&self, bytes: usize, now: Instant) -> Option<f64> {
+        let elapsed = now.duration_since(self.started).as_secs_f64();
+        (elapsed > 0.0).then(|| bytes.saturating_sub(self.start_bytes) as f64 / elapsed)
+    }
+
+    /// The progress at `now`, after `transferred` bytes
+    pub(crate) fn progress(
+        &mut self,
+        transferred: usize,
+        total: Option<usize>,
+        now: Instant,
+    ) -> TransferProgress {
+        self.samples.push_back((now, transferred));
+        while self
+            .samples
+            .get(1)
+            .is_some_and(|(time, _)| now.duration_since(*time) >= SPEED_WINDOW)
+        {
+            self.samples.pop_front();
+        }
+        let (oldest_time, oldest_bytes) = self.samples[0];
+        let elapsed = now.duration_since(oldest_time).as_secs_f64();
+        // Bytes go back when a retry sends them again
+        let bytes_per_second =
+            (elapsed > 0.0).then(|| transferred.saturating_sub(oldest_bytes) as f64 / elapsed);
+        TransferProgress {
+            transferred,
+            total,
+            bytes_per_second,
+            average_bytes_per_second: self.rate(transferred, now),
+            eta: total.zip(bytes_per_second).and_then(|(total, speed)| {
+                (speed > 0.0).then(|| {
+                    Duration::from_secs_f64(total.saturating_sub(transferred) as f64 / speed)
+                })
+            }),
+        }
+    }
+}
+
+/// Decides which progress events to send, based on an interval such as [`crate::DownloadInput::progress_interval`]
+pub(crate) struct ProgressThrottle {
+    interval: Option<Duration>,
+    last_sent: Option<Instant>,
+    /// There is progress that wasn't sent yet
+    pub(crate) pending: bool,
+}
+
+impl ProgressThrottle {
+    pub(crate) fn new(interval: Option<Duration>) -> Self {
+        Self {
+            interval,
+            last_sent: None,
+            pending: false,
+        }
+    }
+
+    /// Returns `true` if the progress should be sent now
+    pub(crate) fn update(&mut self, now: Instant) -> bool {
+        let send = match (self.interval, self.last_sent) {
+            (Some(interval), Some(last_sent)) => now.duration_since(last_sent) >= interval,
+            _ => true,
+        };
+        if send {
+            self.last_sent = Some(now);
+        }
+        self.pending = !send;
+        send
+    }
+}
+
+/// Runs `future`, which sends a body that counts its bytes in `sent`,
+/// and sends [`UploadEvent::Progress`] every [`UPLOAD_PROGRESS_INTERVAL`] until it finishes
+pub(crate) async fn report_upload_progress<F: Future>(
+    future: F,
+    sender: &mut Sender<UploadEvent>,
+    meter: &mut RateMeter,
+    sent: &AtomicUsize,
+    total: usize,
+) -> F::Output {
+    let mut future = pin!(future);
+    loop {
+        match select(future.as_mut(), pin!(sleep(UPLOAD_PROGRESS_INTERVAL))).await {
+            Either::Left((output, _)) => break output,
+            Either::Right(_) => {
+                let progress =
+                    meter.progress(sent.load(Ordering::Relaxed), Some(total), Instant::now());
+                sender.send(UploadEvent::Progress(progress)).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{ProgressThrottle, RateMeter};
+
+    #[test]
+    fn no_interval_sends_everything() {
+        let mut throttle = ProgressThrottle::new(None);
+        let now = Instant::now();
+        assert!(throttle.update(now));
+        assert!(throttle.update(now));
+        assert!(!throttle.pending);
+    }
+
+    #[test]
+    fn coalesces_within_interval() {
+        let mut throttle = ProgressThrottle::new(Some(Duration::from_secs(1)));
+        let start = Instant::now();
+        assert!(throttle.update(start));
+        assert!(!throttle.update(start + Duration::from_millis(500)));
+        assert!(throttle.pending);
+        assert!(throttle.update(start + Duration::from_secs(1)));
+        assert!(!throttle.pending);
+    }
+
+    #[test]
+    fn rate_since_resume() {
+        let start = Instant::now();
+        let meter = RateMeter::new(1000, start);
+        assert_eq!(meter.rate(1000, start), None);
+        assert_eq!(
+            meter.rate(3000, start + Duration::from_secs(2)),
+            Some(1000.0)
+        );
+    }
+
+    #[test]
+    fn recent_speed_and_eta() {
+        let start = Instant::now();
+        let mut meter = RateMeter::new(0, start);
+        let at = |secs| start + Duration::from_secs(secs);
+        meter.progress(10_000, Some(20_000), at(5));
+        meter.progress(10_000, Some(20_000), at(8));
+        // Stalled for the last 5 seconds, so only the average is above 0
+        let progress = meter.progress(10_000, Some(20_000), at(10));
+        assert_eq!(progress.bytes_per_second, Some(0.0));
+        assert_eq!(progress.average_bytes_per_second, Some(1000.0));
+        assert_eq!(progress.eta, None);
+        let progress = meter.progress(15_000, Some(20_000), at(15));
+        assert_eq!(progress.bytes_per_second, Some(1000.0));
+        assert_eq!(progress.eta, Some(Duration::from_secs(5)));
+    }
+}
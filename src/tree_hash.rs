@@ -0,0 +1,151 @@
+use std::{fmt, io, path::Path};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+
+use crate::{UploadData, UploadSrc};
+
+/// Size of the blocks that a [`TreeHash`] hashes
+pub const TREE_HASH_BLOCK_SIZE: usize = 1024 * 1024;
+
+/// A SHA-256 tree hash of a whole file, computed the same way as the tree hash of S3 Glacier,
+/// so that a downloaded file can be checked with any tool that supports it:
+/// 1. The SHA-256 of every 1 MiB block of the file, where the last block can be shorter.
+///    An empty file has one block, which is empty.
+/// 2. Until one hash is left, each pair of hashes is replaced with the SHA-256 of the two concatenated.
+///    A hash at the end without a pair is moved up to the next level as is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TreeHash(pub [u8; 32]);
+
+impl TreeHash {
+    /// Combines the tree hashes of the chunks of a file, in order, into the tree hash of the whole file.
+    /// Only correct if every chunk except the last is the same size, and [`is_tree_hash_aligned`].
+    pub fn combine(hashes: impl IntoIterator<Item = TreeHash>) -> Option<TreeHash> {
+        let mut level = hashes.into_iter().map(|hash| hash.0).collect::<Vec<_>>();
+        while level.len() > 1 {
+            level = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => Sha256::new()
+                        .chain_update(left)
+                        .chain_update(right)
+                        .finalize()
+                        .into(),
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect();
+        }
+        level.pop().map(TreeHash)
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+}
+
+impl fmt::Display for TreeHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+/// Whether the tree hashes of chunks of this size can be combined into the tree hash of the whole file,
+/// which is when it is a power of two times [`TREE_HASH_BLOCK_SIZE`]
+pub fn is_tree_hash_aligned(chunk_size: usize) -> bool {
+    chunk_size.is_multiple_of(TREE_HASH_BLOCK_SIZE)
+        && (chunk_size / TREE_HASH_BLOCK_SIZE).is_power_of_two()
+}
+
+/// Computes the tree hash of a file, such as to check a file after it was downloaded
+pub async fn tree_hash_file(path: impl AsRef<Path>) -> io::Result<TreeHash> {
+    let path = path.as_ref();
+    let len = tokio::fs::metadata(path).await?.len();
+    compute_tree_hash(&UploadSrc {
+        data: UploadData::File(path.into()),
+        offset: 0,
+        len: len.try_into().unwrap(),
+    })
+    .await
+}
+
+/// Reads the data and computes its tree hash
+pub(crate) async fn compute_tree_hash(src: &UploadSrc) -> io::Result<TreeHash> {
+    let mut reader = src.reader().await?;
+    let mut block = Vec::with_capacity(TREE_HASH_BLOCK_SIZE);
+    let mut hashes = Vec::new();
+    loop {
+        block.clear();
+        (&mut reader)
+            .take(TREE_HASH_BLOCK_SIZE as u64)
+            .read_to_end(&mut block)
+            .await?;
+        // Empty data still has one block
+        if block.is_empty() && !hashes.is_empty() {
+            break;
+        }
+        hashes.push(TreeHash(Sha256::digest(&block).into()));
+        if block.len() < TREE_HASH_BLOCK_SIZE {
+            break;
+        }
+    }
+    Ok(TreeHash::combine(hashes).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use sha2::{Digest, Sha256};
+
+    use super::{TREE_HASH_BLOCK_SIZE, TreeHash, compute_tree_hash, is_tree_hash_aligned};
+    use crate::UploadSrc;
+
+    #[tokio::test]
+    async fn chunks_combine_into_whole_file() {
+        let data = (0..TREE_HASH_BLOCK_SIZE * 5 + 100)
+            .map(|i| i as u8)
+            .collect::<Vec<_>>();
+        let whole = compute_tree_hash(&UploadSrc::from_bytes(data.clone()))
+            .await
+            .unwrap();
+        // 3 blocks deep: ((0 1) (2 3)) and then (4 5), with the last block short
+        let block = |i: usize| -> [u8; 32] {
+            Sha256::digest(
+                &data[i * TREE_HASH_BLOCK_SIZE..((i + 1) * TREE_HASH_BLOCK_SIZE).min(data.len())],
+            )
+            .into()
+        };
+        let pair = |left: [u8; 32], right: [u8; 32]| -> [u8; 32] {
+            Sha256::new()
+                .chain_update(left)
+                .chain_update(right)
+                .finalize()
+                .into()
+        };
+        assert_eq!(
+            whole.0,
+            pair(
+                pair(pair(block(0), block(1)), pair(block(2), block(3))),
+                pair(block(4), block(5))
+            )
+        );
+        let chunk_size = TREE_HASH_BLOCK_SIZE * 2;
+        assert!(is_tree_hash_aligned(chunk_size));
+        assert!(!is_tree_hash_aligned(TREE_HASH_BLOCK_SIZE * 3));
+        let mut chunks = Vec::new();
+        for offset in (0..data.len()).step_by(chunk_size) {
+            let mut src = UploadSrc::from_bytes(data.clone());
+            src.offset = offset;
+            src.len = chunk_size.min(data.len() - offset);
+            chunks.push(compute_tree_hash(&src).await.unwrap());
+        }
+        assert_eq!(TreeHash::combine(chunks), Some(whole));
+        assert_eq!(
+            compute_tree_hash(&UploadSrc::from_bytes(Vec::new()))
+                .await
+                .unwrap()
+                .to_hex(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+}
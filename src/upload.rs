@@ -1,43 +1,130 @@
-use std::{io, path::PathBuf, time::Duration};
+use std::{
+    collections::HashMap,
+    io,
+    num::NonZeroUsize,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    task::{Context, Poll, ready},
+    time::Instant,
+};
 
 use crate::{
-    AmountLimiter, OperationScheduler, StartTime,
+    AmountLimiter, AmountReservation, Cancelled, Capabilities, Checksum, ChecksumAlgorithm,
+    ClientSideEncryptionError, ClientSideKey, Compression, ControlHandle, CustomerKey, Degradation,
+    Encryption, MAX_PUT_OBJECT_SIZE, MultipartError, OperationScheduler, PastStartTime,
+    RetriesExhausted, RetryPolicy, Retrying, StartTime, StorageClassCheck, TransferProgress,
+    UploadData, UploadSrc,
+    checksum::compute_checksum,
+    client_side_encryption::{ObjectEncryption, encrypt_byte_stream, encrypted_len},
+    compression::compress_to_temp_file,
     maybe_retryable_sdk_error::IntoMaybeRetryable,
+    multipart::upload_multipart,
     retry::{KeepRetryingExt, MaybeRetryable},
+    sleep_until_utc,
+    storage_class_check::{UploadedObject, check_storage_class},
+    transfer_progress::{RateMeter, report_upload_progress},
 };
 use aws_sdk_s3::{
     error::SdkError,
-    operation::put_object::PutObjectError,
-    primitives::{ByteStreamError, FsBuilder, Length},
+    operation::{
+        complete_multipart_upload::CompleteMultipartUploadError, copy_object::CopyObjectError,
+        create_multipart_upload::CreateMultipartUploadError, head_object::HeadObjectError,
+        list_objects_v2::ListObjectsV2Error, put_object::PutObjectError,
+        upload_part::UploadPartError,
+    },
+    primitives::{ByteStream, ByteStreamError, SdkBody},
     types::StorageClass,
 };
-use sipper::{Sipper, Straw, sipper};
+use bytes::Bytes;
+use http_body::{Body, Frame, SizeHint};
+use sipper::{Sender, Sipper, Straw, sipper};
 use thiserror::Error;
 use time::UtcDateTime;
-use tokio::time::sleep;
 
 pub struct S3Dest<'a> {
     pub bucket: &'a str,
     pub object_key: &'a str,
     pub storage_class: StorageClass,
+    pub encryption: Encryption,
+}
+
+/// Makes every upload go to a new key, for simple versioned backups on buckets without versioning.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum KeySuffix {
+    #[default]
+    None,
+    /// Appends the UTC time when the upload started, such as `backup.zfs.2025-07-20T03-00-00Z`
+    Timestamp,
+    /// Appends the next unused number, such as `backup.zfs.3`.
+    /// Existing numbers are found by listing the objects that start with `{object_key}.`
+    Sequence,
 }
 
-pub struct UploadSrc {
-    pub path: PathBuf,
-    pub offset: usize,
-    pub len: usize,
+/// Stored with the object and returned when it's downloaded
+#[derive(Debug, Clone, Default)]
+pub struct ObjectMetadata {
+    /// Sent as `x-amz-meta-*` headers.
+    /// Keys that start with `rcs3ud-` are used by [`UploadInput::client_side_encryption`] and [`UploadInput::compression`], which replace them.
+    pub user: HashMap<String, String>,
+    pub content_type: Option<String>,
+    pub content_disposition: Option<String>,
+}
+
+impl ObjectMetadata {
+    pub(crate) fn user(&self) -> Option<HashMap<String, String>> {
+        (!self.user.is_empty()).then(|| self.user.clone())
+    }
 }
 
 pub struct UploadInput<'a> {
     pub client: &'a aws_sdk_s3::Client,
     pub src: UploadSrc,
     pub dest: S3Dest<'a>,
-    pub retry_interval: Duration,
+    pub retry_policy: RetryPolicy,
     pub operation_scheduler: Box<dyn OperationScheduler>,
-    /// Note that if an upload fails in the middle of uploading, we don't know how much data was actually uploaded.
-    /// So we assume that the entire file len was uploaded before the operation failed.
+    /// If an attempt fails or is cancelled in the middle of uploading, the bytes that it sent are counted with [`AmountReservation::record_partial`].
     pub amount_limiter: Box<dyn AmountLimiter>,
     pub tagging: &'a str,
+    pub key_suffix: KeySuffix,
+    /// Computes a checksum of the file before uploading it, so that S3 rejects the upload if the data gets corrupted on the way.
+    /// This reads the file an extra time. Not used for multipart uploads.
+    pub checksum: Option<ChecksumAlgorithm>,
+    /// Files larger than this are uploaded with a multipart upload instead of a single request.
+    /// `None` uses [`MAX_PUT_OBJECT_SIZE`], the largest file that S3 accepts in a single request.
+    pub multipart_threshold: Option<NonZeroUsize>,
+    /// Encrypts the file before uploading it, see [`ClientSideKey`].
+    /// [`UploadInput::checksum`] is not used, since S3 only sees the encrypted data.
+    pub client_side_encryption: Option<ClientSideKey>,
+    /// Compresses the file into the temporary directory before uploading it, and records the original and compressed sizes in the object's metadata.
+    /// The amount limiter reserves the compressed length, and the compressed file is encrypted if there is [`UploadInput::client_side_encryption`].
+    /// Set [`crate::DownloadInput::decompress`] to get the original file back.
+    pub compression: Option<Compression>,
+    pub metadata: ObjectMetadata,
+    /// Checks the storage class of the object after it's uploaded, with a `HeadObject` request
+    pub storage_class_check: Option<StorageClassCheck>,
+    /// Features that the endpoint doesn't have are skipped, see [`crate::probe_capabilities`]
+    pub capabilities: Capabilities,
+    /// Pauses or cancels the upload. Cancelling while waiting to retry takes effect when the next attempt would start.
+    pub control: ControlHandle,
+}
+
+#[derive(Debug, Clone)]
+pub struct UploadOutput {
+    /// The key that the object was uploaded to, including the [`KeySuffix`]
+    pub object_key: String,
+    /// The ETag that S3 returned. For a multipart upload, this isn't the MD5 of the object.
+    /// If [`StorageClassCheck::Correct`] copied the object, this is the ETag of the copy.
+    pub e_tag: Option<String>,
+    /// Only set if the bucket has versioning enabled.
+    /// If [`StorageClassCheck::Correct`] copied the object, this is the version of the copy.
+    pub version_id: Option<String>,
+    /// The checksum that S3 verified the object with, if it was uploaded with one
+    pub checksum: Option<Checksum>,
+    /// The length of the object, after it was compressed and encrypted
+    pub bytes_sent: usize,
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -45,76 +132,504 @@ pub struct UploadInput<'a> {
 pub enum UploadError {
     #[error("Error getting file metadata")]
     Metadata(io::Error),
+    #[error("Error reading the file to compute its checksum")]
+    Checksum(io::Error),
     #[error("Error getting upload stream")]
     UploadStream(ByteStreamError),
     #[error("Error uploading file")]
     PutObject(SdkError<PutObjectError>),
+    #[error("Error listing objects to find the next sequence number")]
+    ListObjects(SdkError<ListObjectsV2Error>),
+    #[error("Error uploading part")]
+    UploadPart(SdkError<UploadPartError>),
+    #[error("S3 did not return an ETag for the uploaded part")]
+    NoETag,
+    #[error("Error creating or completing the multipart upload")]
+    Multipart(MultipartError),
+    #[error("Error encrypting the file")]
+    ClientSideEncryption(ClientSideEncryptionError),
+    #[error("Error compressing the file")]
+    Compress(io::Error),
+    #[error("Error checking the storage class of the uploaded object")]
+    HeadObject(SdkError<HeadObjectError>),
+    #[error("Error copying the object to correct its storage class")]
+    CopyObject(SdkError<CopyObjectError>),
+    #[error("Gave up retrying")]
+    RetriesExhausted(#[from] RetriesExhausted),
+    #[error("The upload was cancelled")]
+    Cancelled(#[from] Cancelled),
 }
 
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
 pub enum UploadEvent {
+    /// A feature was skipped or substituted because of [`UploadInput::capabilities`]
+    Degraded(Degradation),
+    ListObjectsError(Retrying<SdkError<ListObjectsV2Error>>),
+    /// The key that will be uploaded to, after applying the [`KeySuffix`]
+    ChoseObjectKey(String),
+    Compressing,
+    Compressed {
+        original_size: usize,
+        compressed_size: usize,
+    },
+    ComputingChecksum,
+    /// The checksum that S3 verified the object with. Record it to verify the object later.
+    ChecksumComputed(Checksum),
     ReservingUploadAmount,
+    /// Other operations are before this one in the [`UploadInput::amount_limiter`]'s queue, or the limit was reached.
+    /// The limiter expects to let the upload through at this time.
+    WaitingForAmount(UtcDateTime),
     GettingUploadStream,
+    /// The earliest time that both the [`UploadInput::amount_limiter`] and the [`UploadInput::operation_scheduler`] allow the upload to start
     ScheduledStart(UtcDateTime),
+    /// The [`UploadInput::operation_scheduler`] returned a start time that already passed.
+    /// It is asked again if its [`crate::OperationScheduler::past_start_time`] allows it, otherwise the upload starts now.
+    PastStartTime {
+        time: UtcDateTime,
+        asking_again: bool,
+    },
     StartingUpload,
-    UploadError(SdkError<PutObjectError>),
+    /// Sent every [`crate::UPLOAD_PROGRESS_INTERVAL`] while the data is being sent.
+    /// For a multipart upload, this is the progress of the whole object.
+    Progress(TransferProgress),
+    UploadError(Retrying<SdkError<PutObjectError>>),
+    UploadPartError(Retrying<SdkError<UploadPartError>>),
+    /// The file is larger than [`UploadInput::multipart_threshold`], so it will be uploaded in parts of this size
+    UsingMultipartUpload {
+        part_size: usize,
+    },
+    CreateMultipartUploadError(Retrying<SdkError<CreateMultipartUploadError>>),
+    CompleteMultipartUploadError(Retrying<SdkError<CompleteMultipartUploadError>>),
+    /// Getting the storage class of the uploaded object, see [`UploadInput::storage_class_check`]
+    CheckingStorageClass,
+    CheckStorageClassError(Retrying<SdkError<HeadObjectError>>),
+    /// The object isn't in the storage class that it was uploaded with, such as because of a lifecycle rule of the bucket.
+    /// If `correcting`, it's copied with the `requested` storage class next.
+    StorageClassMismatch {
+        requested: StorageClass,
+        actual: StorageClass,
+        correcting: bool,
+    },
+    CopyObjectError(Retrying<SdkError<CopyObjectError>>),
+    /// Waiting for [`ControlHandle::resume`] before starting the upload
+    Paused,
+    /// Sent last when the upload is cancelled
+    Cancelled,
 }
 
-pub fn upload(input: UploadInput<'_>) -> impl Straw<(), UploadEvent, UploadError> {
-    sipper(async move |sender| {
-        ({
-            let mut sender = sender.clone();
-            let id = format!("upload:{}/{}", input.dest.bucket, input.dest.object_key);
-            async move || {
-                sender.send(UploadEvent::ReservingUploadAmount).await;
-                let reservation = input.amount_limiter.reserve(input.src.len, &id).await;
-                match input.operation_scheduler.get_start_time(input.src.len) {
-                    StartTime::Now => {}
-                    StartTime::Later(time) => {
-                        sender.send(UploadEvent::ScheduledStart(time)).await;
-                        let duration = time - UtcDateTime::now();
-                        if let Ok(duration) = duration.try_into() {
-                            // FIXME: If the computer suspends, the sleep will be too long
-                            sleep(duration).await
-                        } else {
-                            // Negative duration, so we should start right away
+/// Counts the bytes of the body as they are sent, including when the SDK retries the request.
+/// If the upload fails, the count is given to [`AmountReservation::record_partial`].
+pub(crate) fn count_sent(byte_stream: ByteStream, sent: Arc<AtomicUsize>) -> ByteStream {
+    ByteStream::new(byte_stream.into_inner().map(move |body| {
+        SdkBody::from_body_1_x(CountingBody {
+            inner: body,
+            sent: sent.clone(),
+        })
+    }))
+}
+
+struct CountingBody {
+    inner: SdkBody,
+    sent: Arc<AtomicUsize>,
+}
+
+impl Body for CountingBody {
+    type Data = Bytes;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        let frame = ready!(Pin::new(&mut this.inner).poll_frame(cx));
+        if let Some(Ok(frame)) = &frame
+            && let Some(data) = frame.data_ref()
+        {
+            this.sent.fetch_add(data.len(), Ordering::Relaxed);
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Waits until the earliest time that both the amount limiter and the scheduler allow, reserves the amount,
+/// and then waits while the upload is paused.
+/// Waiting for both at once means that a time window isn't missed while waiting for the amount, or the other way around.
+/// If the upload is cancelled, the reservation is released.
+pub(crate) async fn wait_to_start<'a>(
+    sender: &mut Sender<UploadEvent>,
+    amount_limiter: &'a dyn AmountLimiter,
+    operation_scheduler: &dyn OperationScheduler,
+    control: &ControlHandle,
+    len: usize,
+    id: &'a str,
+) -> Result<Box<dyn AmountReservation + 'a>, Cancelled> {
+    let available_at = control.run(amount_limiter.available_at(len, id)).await?;
+    if available_at > UtcDateTime::now() {
+        sender
+            .send(UploadEvent::WaitingForAmount(available_at))
+            .await;
+    }
+    let mut asked_again = 0;
+    let start_time = loop {
+        match operation_scheduler.get_start_time_after(available_at, len) {
+            StartTime::Later(time) if time <= UtcDateTime::now() => {
+                let asking_again = matches!(
+                    operation_scheduler.past_start_time(),
+                    PastStartTime::AskAgain(times) if asked_again < times
+                );
+                sender
+                    .send(UploadEvent::PastStartTime { time, asking_again })
+                    .await;
+                if !asking_again {
+                    break StartTime::Now;
+                }
+                asked_again += 1;
+            }
+            start_time => break start_time,
+        }
+    };
+    if let StartTime::Later(time) = start_time {
+        sender.send(UploadEvent::ScheduledStart(time)).await;
+        control.run(sleep_until_utc(time)).await?;
+    }
+    while let Some(time) = operation_scheduler.not_ready_until() {
+        sender.send(UploadEvent::ScheduledStart(time)).await;
+        control.run(sleep_until_utc(time)).await?;
+    }
+    sender.send(UploadEvent::ReservingUploadAmount).await;
+    // Usually right away, unless another operation reserved the amount while this one was waiting
+    let reservation = control.reserve(amount_limiter, len, id).await?;
+    let result = async {
+        if control.is_paused() {
+            sender.send(UploadEvent::Paused).await;
+        }
+        control.wait_while_paused().await
+    }
+    .await;
+    match result {
+        Ok(()) => Ok(reservation),
+        Err(cancelled) => {
+            reservation.cancel().await;
+            Err(cancelled)
+        }
+    }
+}
+
+pub fn upload(input: UploadInput<'_>) -> impl Straw<UploadOutput, UploadEvent, UploadError> {
+    upload_impl(input, None)
+}
+
+/// Like [`upload`], but uses `checksum` instead of computing it, if it's `Some`
+pub(crate) fn upload_impl(
+    mut input: UploadInput<'_>,
+    mut checksum: Option<Checksum>,
+) -> impl Straw<UploadOutput, UploadEvent, UploadError> {
+    sipper(async move |mut sender| {
+        if !input.capabilities.tagging && !input.tagging.is_empty() {
+            input.tagging = "";
+            sender
+                .send(UploadEvent::Degraded(Degradation::Tagging))
+                .await;
+        }
+        if !input.capabilities.checksums && (input.checksum.is_some() || checksum.is_some()) {
+            input.checksum = None;
+            checksum = None;
+            sender
+                .send(UploadEvent::Degraded(Degradation::Checksum))
+                .await;
+        }
+        let storage_class = input.capabilities.storage_class(&input.dest.storage_class);
+        if storage_class != input.dest.storage_class {
+            sender
+                .send(UploadEvent::Degraded(Degradation::StorageClass {
+                    requested: input.dest.storage_class.clone(),
+                    used: storage_class.clone(),
+                }))
+                .await;
+            input.dest.storage_class = storage_class;
+        }
+        let object_key = match input.key_suffix {
+            KeySuffix::None => input.dest.object_key.to_owned(),
+            KeySuffix::Timestamp => {
+                let now = UtcDateTime::now();
+                format!(
+                    "{}.{}-{:02}-{:02}T{:02}-{:02}-{:02}Z",
+                    input.dest.object_key,
+                    now.year(),
+                    u8::from(now.month()),
+                    now.day(),
+                    now.hour(),
+                    now.minute(),
+                    now.second()
+                )
+            }
+            KeySuffix::Sequence => {
+                let prefix = format!("{}.", input.dest.object_key);
+                let next = (async || {
+                    let mut next = 0;
+                    let mut continuation_token = None;
+                    loop {
+                        let output = input
+                            .client
+                            .list_objects_v2()
+                            .bucket(input.dest.bucket)
+                            .prefix(&prefix)
+                            .set_continuation_token(continuation_token)
+                            .send()
+                            .await
+                            .map_err(|e| e.into_maybe_retryable().map(UploadError::ListObjects))?;
+                        for n in output.contents().iter().filter_map(|object| {
+                            object.key()?.strip_prefix(&prefix)?.parse::<u64>().ok()
+                        }) {
+                            next = next.max(n + 1);
+                        }
+                        match output.next_continuation_token {
+                            Some(token) => continuation_token = Some(token),
+                            None => break Ok(next),
                         }
                     }
+                })
+                .keep_retrying(input.retry_policy)
+                .with(UploadEvent::ListObjectsError)
+                .run(sender.clone())
+                .await?;
+                format!("{prefix}{next}")
+            }
+        };
+        sender
+            .send(UploadEvent::ChoseObjectKey(object_key.clone()))
+            .await;
+        let encryption = input
+            .client_side_encryption
+            .clone()
+            .map(ObjectEncryption::new)
+            .transpose()
+            .map_err(UploadError::ClientSideEncryption)?;
+        let compressed = match input.compression {
+            Some(compression) => {
+                sender.send(UploadEvent::Compressing).await;
+                let compressed = compress_to_temp_file(&input.src, compression)
+                    .await
+                    .map_err(UploadError::Compress)?;
+                sender
+                    .send(UploadEvent::Compressed {
+                        original_size: input.src.len,
+                        compressed_size: compressed.len,
+                    })
+                    .await;
+                let metadata = compression.metadata(input.src.len, compressed.len);
+                input.src = UploadSrc {
+                    data: UploadData::File(compressed.path.clone()),
+                    offset: 0,
+                    len: compressed.len,
                 };
+                Some((compressed, metadata))
+            }
+            None => None,
+        };
+        let metadata = {
+            let mut metadata = input.metadata.clone();
+            if let Some(encryption) = &encryption {
+                metadata.user.extend(encryption.metadata());
+            }
+            if let Some((_, compression_metadata)) = &compressed {
+                metadata.user.extend(compression_metadata.clone());
+            }
+            metadata
+        };
+        let bytes_sent = if encryption.is_some() {
+            encrypted_len(input.src.len)
+        } else {
+            input.src.len
+        };
+        if input.src.len
+            > input
+                .multipart_threshold
+                .map_or(MAX_PUT_OBJECT_SIZE, NonZeroUsize::get)
+        {
+            let output = upload_multipart(&input, &object_key, encryption.as_ref(), metadata)
+                .run(sender.clone())
+                .await?;
+            let uploaded = UploadedObject {
+                e_tag: output.e_tag,
+                version_id: output.version_id,
+            };
+            let uploaded = match input.storage_class_check {
+                Some(check) => {
+                    check_storage_class(&input, check, &object_key, uploaded)
+                        .run(sender)
+                        .await?
+                }
+                None => uploaded,
+            };
+            return Ok(UploadOutput {
+                object_key,
+                e_tag: uploaded.e_tag,
+                version_id: uploaded.version_id,
+                checksum: None,
+                bytes_sent,
+            });
+        }
+        // A given checksum is of the file before it was compressed
+        let checksum = match (checksum.filter(|_| compressed.is_none()), input.checksum) {
+            _ if encryption.is_some() => None,
+            (Some(checksum), _) => Some(checksum),
+            (None, Some(algorithm)) => {
+                sender.send(UploadEvent::ComputingChecksum).await;
+                let checksum = compute_checksum(&input.src, algorithm)
+                    .await
+                    .map_err(UploadError::Checksum)?;
+                sender.send(UploadEvent::ChecksumComputed(checksum)).await;
+                Some(checksum)
+            }
+            (None, None) => None,
+        };
+        let result = ({
+            let mut sender = sender.clone();
+            // The suffixed key, so that uploads to the same base key don't share a reservation
+            let id = format!("upload:{}/{}", input.dest.bucket, object_key);
+            let object_key = &object_key;
+            let checksum = &checksum;
+            let encryption = &encryption;
+            let metadata = &metadata;
+            let input = &input;
+            async move || {
+                let reservation = wait_to_start(
+                    &mut sender,
+                    &*input.amount_limiter,
+                    &*input.operation_scheduler,
+                    &input.control,
+                    input.src.len,
+                    &id,
+                )
+                .await
+                .map_err(|e| MaybeRetryable::NotRetryable(e.into()))?;
                 sender.send(UploadEvent::GettingUploadStream).await;
-                let byte_stream = FsBuilder::new()
-                    .path(&input.src.path)
-                    .offset(input.src.offset as u64)
-                    .length(Length::Exact(input.src.len as u64))
-                    .build()
+                let byte_stream = input
+                    .src
+                    .byte_stream()
                     .await
                     .map_err(|e| MaybeRetryable::NotRetryable(UploadError::UploadStream(e)))?;
+                let (byte_stream, content_length) = match encryption {
+                    Some(encryption) => (
+                        encrypt_byte_stream(byte_stream, encryption, 0, input.src.len, true),
+                        encrypted_len(input.src.len),
+                    ),
+                    None => (byte_stream, input.src.len),
+                };
+                let sent = Arc::new(AtomicUsize::new(0));
+                let byte_stream = count_sent(byte_stream, sent.clone());
                 sender.send(UploadEvent::StartingUpload).await;
-                match input
-                    .client
-                    .put_object()
-                    .bucket(input.dest.bucket)
-                    .key(input.dest.object_key)
-                    .storage_class(input.dest.storage_class.clone())
-                    .body(byte_stream)
-                    .content_length(input.src.len.try_into().unwrap())
-                    .tagging(input.tagging)
-                    .send()
-                    .await
+                // Each attempt sends the data from the start
+                let mut rate_meter = RateMeter::new(0, Instant::now());
+                let put_object = input.control.run(
+                    input
+                        .client
+                        .put_object()
+                        .bucket(input.dest.bucket)
+                        .key(object_key)
+                        .storage_class(input.dest.storage_class.clone())
+                        .body(byte_stream)
+                        .content_length(content_length.try_into().unwrap())
+                        .set_tagging((!input.tagging.is_empty()).then(|| input.tagging.to_owned()))
+                        .set_metadata(metadata.user())
+                        .set_content_type(metadata.content_type.clone())
+                        .set_content_disposition(metadata.content_disposition.clone())
+                        .set_server_side_encryption(input.dest.encryption.server_side_encryption())
+                        .set_ssekms_key_id(input.dest.encryption.kms_key_id())
+                        .set_sse_customer_algorithm(
+                            input
+                                .dest
+                                .encryption
+                                .customer_key()
+                                .map(CustomerKey::algorithm),
+                        )
+                        .set_sse_customer_key(
+                            input.dest.encryption.customer_key().map(CustomerKey::key),
+                        )
+                        .set_sse_customer_key_md5(
+                            input
+                                .dest
+                                .encryption
+                                .customer_key()
+                                .map(CustomerKey::key_md5),
+                        )
+                        .set_checksum_sha256(match checksum {
+                            Some(Checksum::Sha256(_)) => checksum.as_ref().map(Checksum::to_base64),
+                            _ => None,
+                        })
+                        .set_checksum_crc32_c(match checksum {
+                            Some(Checksum::Crc32c(_)) => checksum.as_ref().map(Checksum::to_base64),
+                            _ => None,
+                        })
+                        .send(),
+                );
+                match report_upload_progress(
+                    put_object,
+                    &mut sender,
+                    &mut rate_meter,
+                    &sent,
+                    content_length,
+                )
+                .await
                 {
-                    Ok(output) => {
+                    Ok(Ok(output)) => {
                         reservation.mark_complete().await;
                         Ok(output)
                     }
-                    Err(e) => Err(e.into_maybe_retryable().map(UploadError::PutObject)),
+                    Ok(Err(e)) => {
+                        reservation
+                            .record_partial(sent.load(Ordering::Relaxed))
+                            .await;
+                        Err(e.into_maybe_retryable().map(UploadError::PutObject))
+                    }
+                    Err(cancelled) => {
+                        // Part of the file may have been uploaded already
+                        reservation
+                            .record_partial(sent.load(Ordering::Relaxed))
+                            .await;
+                        reservation.cancel().await;
+                        Err(MaybeRetryable::NotRetryable(cancelled.into()))
+                    }
                 }
             }
         })
-        .keep_retrying(input.retry_interval)
+        .keep_retrying(input.retry_policy)
         .with(UploadEvent::UploadError)
-        .run(sender)
-        .await?;
-        Ok(())
+        .run(sender.clone())
+        .await;
+        if let Err(UploadError::Cancelled(_)) = &result {
+            sender.send(UploadEvent::Cancelled).await;
+        }
+        let output = result?;
+        let uploaded = UploadedObject {
+            e_tag: output.e_tag,
+            version_id: output.version_id,
+        };
+        let uploaded = match input.storage_class_check {
+            Some(check) => {
+                check_storage_class(&input, check, &object_key, uploaded)
+                    .run(sender)
+                    .await?
+            }
+            None => uploaded,
+        };
+        Ok(UploadOutput {
+            object_key,
+            e_tag: uploaded.e_tag,
+            version_id: uploaded.version_id,
+            checksum,
+            bytes_sent,
+        })
     })
 }
@@ -1,37 +1,182 @@
 use std::{
+    collections::{BTreeMap, BTreeSet},
     io::{self},
+    iter, mem,
     num::NonZeroUsize,
     path::PathBuf,
-    time::Duration,
+    sync::Mutex,
+    time::{Instant, SystemTime},
 };
 
+use futures::{StreamExt, stream::FuturesUnordered};
 use serde::{Deserialize, Serialize};
-use sipper::{Sipper, Straw, sipper};
+use sipper::{Sender, Sipper, Straw, sipper};
 use thiserror::Error;
+use time::UtcDateTime;
 use tokio::fs::metadata;
 
 use crate::{
-    AmountLimiter, OperationScheduler, S3Dest, UploadError, UploadEvent, UploadInput, UploadSrc,
-    upload,
+    AmountLimiter, Cancelled, Capabilities, Checksum, ChecksumAlgorithm, ClientSideEncryptionError,
+    ClientSideKey, ControlHandle, Degradation, ForecastWindow, KeySuffix, MAX_PARTS, MIN_PART_SIZE,
+    MultipartError, MultipartProgress, ObjectMetadata, OperationScheduler, PartEncryption,
+    ProgressStore, ProgressStoreError, RetryBudget, RetryBudgetSpent, RetryPolicy, Retrying,
+    S3Dest, SEGMENT_SIZE, SaveProgressPolicy, SourceFile, TREE_HASH_BLOCK_SIZE, TreeHash,
+    UploadData, UploadError, UploadEvent, UploadInput, UploadPartInput, UploadSrc,
+    client_side_encryption::ObjectEncryption, complete_multipart_upload, create_multipart_upload,
+    forecast, is_tree_hash_aligned, progress_store::run_saving_progress, save_policy::SaveTracker,
+    sleep_until_utc, source_files, tree_hash::compute_tree_hash, upload, upload_part,
+};
+use aws_sdk_s3::{
+    error::SdkError,
+    operation::{
+        complete_multipart_upload::CompleteMultipartUploadError,
+        create_multipart_upload::CreateMultipartUploadError,
+    },
 };
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct UploadChunkedProgress {
     pub len: Option<usize>,
-    pub parts_uploaded: usize,
+    /// Modified time of the file when the upload was started, if the OS supports it.
+    /// Used together with `len` to detect that the file changed before resuming.
+    #[serde(default)]
+    pub modified: Option<SystemTime>,
+    /// Where each file starts, if the upload has [`UploadChunkedInput::more_srcs`].
+    /// A resumed upload checks that none of them changed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<SourceFile>,
+    /// The chunk size that the upload was started with.
+    /// The offsets and tags of the chunks that were already uploaded depend on it,
+    /// so a resumed upload keeps using this chunk size even if a different one is requested.
+    #[serde(default)]
+    pub chunk_size: Option<NonZeroUsize>,
+    /// Only in progress saved by older versions, which uploaded chunks in order. Replaced by `completed`.
+    #[serde(default, skip_serializing)]
+    parts_uploaded: usize,
+    /// Chunk numbers that were uploaded. With [`UploadChunkedInput::max_concurrency`] above 1, chunks can finish out of order.
+    #[serde(default)]
+    pub completed: BTreeSet<usize>,
+    /// Stats of each uploaded chunk, by chunk number.
+    /// Progress saved by older versions will not have stats for the chunks it uploaded.
+    #[serde(default)]
+    pub chunks: BTreeMap<usize, ChunkStats>,
+    /// Set if the chunks are parts of a multipart upload.
+    /// A resumed upload keeps the mode it was started with.
+    #[serde(default)]
+    pub multipart: Option<MultipartProgress>,
+    /// Set when every chunk was uploaded, if [`UploadChunkedInput::tree_hash`] is on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tree_hash: Option<TreeHash>,
+}
+
+impl UploadChunkedProgress {
+    pub fn total_retries(&self) -> usize {
+        self.chunks.values().map(|chunk| chunk.retries).sum()
+    }
+
+    /// Average upload speed in bytes per second, across all chunks that have stats.
+    /// When chunks are uploaded in parallel, this is the speed of each upload, not the combined speed.
+    pub fn average_speed(&self) -> Option<f64> {
+        let (len, millis) = self.chunks.values().fold((0, 0), |(len, millis), chunk| {
+            (len + chunk.len, millis + chunk.upload_millis)
+        });
+        if millis > 0 {
+            Some(len as f64 / (millis as f64 / 1000.0))
+        } else {
+            None
+        }
+    }
+}
+
+/// Kept small because a large file can have thousands of chunks.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ChunkStats {
+    pub len: usize,
+    /// Time from the first attempt to start uploading until the upload succeeded, including retries.
+    /// Time spent waiting for the amount limiter or scheduler before the first attempt is not counted.
+    pub upload_millis: u64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub retries: usize,
+    /// The checksum the chunk was uploaded with, if any.
+    /// Lets [`crate::verify_chunked`] check the chunks without the local file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<Checksum>,
+    /// Only with [`UploadChunkedInput::tree_hash`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tree_hash: Option<TreeHash>,
+}
+
+fn is_zero(n: &usize) -> bool {
+    *n == 0
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub enum ChunkedUploadMode {
+    /// Each chunk is uploaded as a separate object named `{object_key}/{chunk_number}`,
+    /// tagged with the information needed to put the file back together.
+    /// Unlike multipart uploads, chunks are billed at the requested storage class as soon as they are uploaded.
+    #[default]
+    SeparateObjects,
+    /// Each chunk is uploaded as a part of an S3 multipart upload, so the result is a single object.
+    /// Chunks must be at least [`MIN_PART_SIZE`] and there can be at most [`MAX_PARTS`] of them.
+    Multipart,
 }
 
 pub struct UploadChunkedInput<'a> {
     pub client: &'a aws_sdk_s3::Client,
     pub src: PathBuf,
+    /// Files that continue `src`, in order, such as the other volumes of a split archive.
+    /// They are uploaded one after another as if they were one file, without concatenating them first.
+    pub more_srcs: Vec<PathBuf>,
+    /// The length of `src`, for a file whose metadata doesn't have it, such as a block device like `/dev/sda`.
+    /// The first `src_len` bytes are uploaded with positioned reads. Not used with `more_srcs`.
+    pub src_len: Option<usize>,
     pub dest: S3Dest<'a>,
-    pub retry_interval: Duration,
+    pub retry_policy: RetryPolicy,
+    /// Limits the retries of all chunks together. When it runs out, the chunks that are uploading are stopped,
+    /// the progress is saved, and the upload fails with [`UploadChunkedError::RetryBudgetExhausted`].
+    pub retry_budget: RetryBudget,
+    /// See [`UploadInput::capabilities`]. Without tagging, the chunks of [`ChunkedUploadMode::SeparateObjects`] are uploaded without the tags
+    /// that describe how to put the file back together, so the saved progress is needed to download them.
+    pub capabilities: Capabilities,
     pub operation_scheduler: Box<dyn OperationScheduler>,
-    /// Note that if an upload fails in the middle of uploading, we don't know how much data was actually uploaded.
-    /// So we assume that the entire file len was uploaded before the operation failed.
+    /// See [`crate::UploadInput::amount_limiter`]
     pub amount_limiter: Box<dyn AmountLimiter>,
     pub chunk_size: NonZeroUsize,
+    /// How many chunks to upload at the same time
+    pub max_concurrency: NonZeroUsize,
     pub progress: UploadChunkedProgress,
+    /// Loads the progress when the upload starts, instead of using [`UploadChunkedInput::progress`] if there is saved progress,
+    /// and saves it on every [`UploadChunkedEvent::SaveProgress`], so that the events don't need to be saved.
+    pub progress_store: Option<Box<dyn ProgressStore<UploadChunkedProgress>>>,
+    pub mode: ChunkedUploadMode,
+    /// How often to send [`UploadChunkedEvent::SaveProgress`] between chunks
+    pub save_policy: SaveProgressPolicy,
+    /// See [`UploadInput::checksum`]. Only used with [`ChunkedUploadMode::SeparateObjects`].
+    pub checksum: Option<ChecksumAlgorithm>,
+    /// See [`UploadInput::client_side_encryption`]. With [`ChunkedUploadMode::SeparateObjects`], each chunk is encrypted as its own object.
+    /// With [`ChunkedUploadMode::Multipart`], the chunk size must be a multiple of [`SEGMENT_SIZE`].
+    pub client_side_encryption: Option<ClientSideKey>,
+    /// Computes the [`TreeHash`] of the file from the tree hash of each chunk as it is uploaded, and saves it in [`UploadChunkedProgress::tree_hash`].
+    /// The chunk size must be [`is_tree_hash_aligned`] if the file has more than one chunk.
+    pub tree_hash: bool,
+    /// Pauses or cancels the upload. When it is cancelled or its deadline passes, the chunks that are uploading are stopped,
+    /// and the progress is saved so that the upload can be resumed later.
+    pub control: ControlHandle,
+}
+
+#[derive(Debug, Clone)]
+pub struct UploadChunkedOutput {
+    /// The length of the file
+    pub len: usize,
+    /// The length of the chunks that were uploaded by this call, without the ones that were uploaded before it was resumed
+    pub bytes_sent: usize,
+    /// Only for [`ChunkedUploadMode::Multipart`], see [`crate::UploadOutput::e_tag`]
+    pub e_tag: Option<String>,
+    /// Only for [`ChunkedUploadMode::Multipart`] on a bucket with versioning enabled
+    pub version_id: Option<String>,
+    /// See [`UploadChunkedInput::tree_hash`]
+    pub tree_hash: Option<TreeHash>,
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -41,77 +186,699 @@ pub enum UploadChunkedError {
     Metadata(io::Error),
     #[error("Error uploading a chunk")]
     Upload(UploadError),
+    /// Resuming would upload a mix of the old and new contents of the file
+    #[error("The file changed since the upload was started")]
+    SourceChanged,
+    #[error("Chunks must be at least {MIN_PART_SIZE} bytes for multipart uploads")]
+    ChunkTooSmallForMultipart,
+    #[error("Empty files can't be uploaded with a multipart upload")]
+    EmptyMultipart,
+    #[error("Multipart uploads can have at most {MAX_PARTS} parts, but the file has {0} chunks")]
+    TooManyParts(usize),
+    #[error(
+        "Chunks of client-side encrypted multipart uploads must be a multiple of {SEGMENT_SIZE} bytes"
+    )]
+    ChunkNotMultipleOfSegment,
+    #[error(
+        "The chunk size must be a power of two times {TREE_HASH_BLOCK_SIZE} bytes to compute the tree hash"
+    )]
+    ChunkNotAlignedForTreeHash,
+    #[error("Error reading the file to compute its tree hash")]
+    TreeHash(io::Error),
+    /// Resuming would upload parts that are encrypted differently than the parts that were already uploaded
+    #[error("Client-side encryption was turned on or off since the multipart upload was started")]
+    ClientSideEncryptionChanged,
+    #[error("Error encrypting the file")]
+    ClientSideEncryption(ClientSideEncryptionError),
+    #[error("Error creating or completing the multipart upload")]
+    Multipart(MultipartError),
+    /// The progress was saved, so the upload can be resumed
+    #[error("The upload was cancelled")]
+    Cancelled(Cancelled),
+    #[error("Gave up after {} retries, waiting {:?} in total", .0.retries, .0.retry_time)]
+    RetryBudgetExhausted(RetryBudgetSpent),
+    #[error("Error loading or saving the progress")]
+    ProgressStore(#[from] ProgressStoreError),
+    /// The progress was saved by an older version, and the lengths of its chunks don't match any chunk size
+    #[error("The progress doesn't say which chunk size its chunks were uploaded with")]
+    UnknownChunkSize,
 }
 
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
 pub enum UploadChunkedEvent {
     GettingMetadata,
+    /// The progress was saved with a different chunk size than the one in the input.
+    /// The upload continues with the recorded chunk size.
+    ChunkSizeMismatch {
+        recorded: NonZeroUsize,
+        requested: NonZeroUsize,
+    },
+    /// A feature was skipped or substituted because of [`UploadChunkedInput::capabilities`]
+    Degraded(Degradation),
     StartingChunk(usize),
+    /// The [`UploadChunkedInput::operation_scheduler`]'s window ended, so no more chunks are started until this time.
+    /// See [`OperationScheduler::pauses_outside_windows`].
+    ScheduledPause(UtcDateTime),
+    ScheduledResume,
+    /// How much of the rest of the upload is expected to be done in each window of the [`UploadChunkedInput::operation_scheduler`], see [`crate::forecast`].
+    /// Sent when the upload starts, and again when the measured speed is more than 10% off from the speed it was forecast with.
+    /// Not sent if the scheduler doesn't have windows.
+    Forecast(Vec<ForecastWindow>),
     SaveProgress(UploadChunkedProgress),
-    UploadEvent(UploadEvent),
+    /// An event from uploading this chunk. Chunks are uploaded at the same time, so their events are interleaved.
+    UploadEvent {
+        chunk: usize,
+        event: UploadEvent,
+    },
+    CreateMultipartUploadError(Retrying<SdkError<CreateMultipartUploadError>>),
+    CompleteMultipartUploadError(Retrying<SdkError<CompleteMultipartUploadError>>),
+    /// Sent last when the upload is cancelled or its deadline passes, after the progress is saved
+    Cancelled,
+}
+
+/// When the next window starts, if the scheduler pauses outside its windows and it's between them now
+fn paused_until(scheduler: &dyn OperationScheduler) -> Option<UtcDateTime> {
+    if !scheduler.pauses_outside_windows() {
+        return None;
+    }
+    let now = UtcDateTime::now();
+    scheduler
+        .next_window(now)
+        .map(|window| window.start)
+        .filter(|start| *start > now)
+}
+
+/// How far off the measured speed can be from the forecast before the forecast is sent again
+const FORECAST_SPEED_CHANGE: f64 = 0.1;
+
+async fn send_forecast(
+    sender: &mut Sender<UploadChunkedEvent>,
+    scheduler: &dyn OperationScheduler,
+    len: usize,
+    speed: f64,
+) {
+    let windows = forecast(scheduler, UtcDateTime::now(), len, speed);
+    if !windows.is_empty() {
+        sender.send(UploadChunkedEvent::Forecast(windows)).await;
+    }
+}
+
+/// The tags that describe how to put the file back together
+pub(crate) fn chunk_tagging(
+    object_key: &str,
+    len: usize,
+    total_chunks: usize,
+    chunk_size: NonZeroUsize,
+    chunk_number: usize,
+) -> String {
+    format!(
+        "file={object_key}&total_len={len}&chunks_count={total_chunks}&chunk_size={chunk_size}&chunk_number={chunk_number}"
+    )
 }
 
 pub fn upload_chunked(
-    input: UploadChunkedInput<'_>,
-) -> impl Straw<(), UploadChunkedEvent, UploadChunkedError> {
+    mut input: UploadChunkedInput<'_>,
+) -> impl Straw<UploadChunkedOutput, UploadChunkedEvent, UploadChunkedError> {
+    sipper(async move |sender| match input.progress_store.take() {
+        None => upload_chunked_impl(input).run(sender).await,
+        Some(store) => {
+            if let Some(progress) = store.load().await? {
+                input.progress = progress;
+            }
+            run_saving_progress(
+                upload_chunked_impl(input),
+                &*store,
+                sender,
+                |event| match event {
+                    UploadChunkedEvent::SaveProgress(progress) => Some(progress),
+                    _ => None,
+                },
+            )
+            .await?
+        }
+    })
+}
+
+/// Finds the chunk size of progress saved by older versions, which didn't save it, from the lengths of the chunks.
+/// Returns `None` if a chunk doesn't have stats or no chunk size matches their lengths.
+fn recover_chunk_size(
+    progress: &UploadChunkedProgress,
+    len: usize,
+    requested: NonZeroUsize,
+) -> Option<NonZeroUsize> {
+    let matches = |chunk_size: NonZeroUsize| {
+        progress.completed.iter().all(|&chunk| {
+            let offset = chunk * chunk_size.get();
+            progress.chunks.get(&chunk).is_some_and(|stats| {
+                offset < len && stats.len == (len - offset).min(chunk_size.get())
+            })
+        })
+    };
+    // Every chunk except the last one is exactly the chunk size
+    let longest = progress
+        .chunks
+        .values()
+        .map(|stats| stats.len)
+        .max()
+        .and_then(NonZeroUsize::new);
+    iter::once(requested)
+        .chain(longest)
+        .find(|&chunk_size| matches(chunk_size))
+}
+
+fn upload_chunked_impl(
+    mut input: UploadChunkedInput<'_>,
+) -> impl Straw<UploadChunkedOutput, UploadChunkedEvent, UploadChunkedError> {
     sipper(async move |mut sender| {
-        let mut progress = input.progress;
-        let len = if let Some(len) = progress.len {
-            len
-        } else {
-            sender.send(UploadChunkedEvent::GettingMetadata).await;
-            let len = metadata(&input.src)
+        let mut progress = mem::take(&mut input.progress);
+        if progress.completed.is_empty() {
+            // Progress saved by older versions, which uploaded chunks in order
+            progress.completed.extend(0..progress.parts_uploaded);
+        }
+        if !input.capabilities.tagging && matches!(input.mode, ChunkedUploadMode::SeparateObjects) {
+            sender
+                .send(UploadChunkedEvent::Degraded(Degradation::Tagging))
+                .await;
+        }
+        if !input.capabilities.checksums && input.checksum.is_some() {
+            input.checksum = None;
+            sender
+                .send(UploadChunkedEvent::Degraded(Degradation::Checksum))
+                .await;
+        }
+        let storage_class = input.capabilities.storage_class(&input.dest.storage_class);
+        if storage_class != input.dest.storage_class {
+            sender
+                .send(UploadChunkedEvent::Degraded(Degradation::StorageClass {
+                    requested: input.dest.storage_class.clone(),
+                    used: storage_class.clone(),
+                }))
+                .await;
+            input.dest.storage_class = storage_class;
+        }
+        sender.send(UploadChunkedEvent::GettingMetadata).await;
+        let (len, modified, sources) = if let Some(len) =
+            input.src_len.filter(|_| input.more_srcs.is_empty())
+        {
+            // The modified time of a device doesn't change when its contents do
+            (len, None, Vec::new())
+        } else if input.more_srcs.is_empty() {
+            let metadata = metadata(&input.src)
                 .await
-                .map_err(UploadChunkedError::Metadata)?
-                .len()
-                .try_into()
-                .unwrap();
+                .map_err(UploadChunkedError::Metadata)?;
+            let len = metadata.len().try_into().unwrap();
+            (len, metadata.modified().ok(), Vec::new())
+        } else {
+            let sources =
+                source_files(iter::once(input.src.clone()).chain(input.more_srcs.iter().cloned()))
+                    .await
+                    .map_err(UploadChunkedError::Metadata)?;
+            // Each file has its own modified time
+            let len = sources.last().map_or(0, |file| file.offset + file.len);
+            (len, None, sources)
+        };
+        if let Some(recorded_len) = progress.len {
+            // Progress saved by older versions doesn't have the modified time, so only the len can be checked
+            if recorded_len != len
+                || (progress.modified.is_some() && progress.modified != modified)
+                || progress.sources != sources
+            {
+                Err(UploadChunkedError::SourceChanged)?;
+            }
+        } else {
             progress.len = Some(len);
+            progress.modified = modified;
+            progress.sources = sources.clone();
             sender
                 .send(UploadChunkedEvent::SaveProgress(progress.clone()))
                 .await;
-            len
+        }
+        let chunk_size = match progress.chunk_size {
+            Some(recorded) => {
+                if recorded != input.chunk_size {
+                    sender
+                        .send(UploadChunkedEvent::ChunkSizeMismatch {
+                            recorded,
+                            requested: input.chunk_size,
+                        })
+                        .await;
+                }
+                recorded
+            }
+            None => {
+                let chunk_size = recover_chunk_size(&progress, len, input.chunk_size)
+                    .ok_or(UploadChunkedError::UnknownChunkSize)?;
+                if chunk_size != input.chunk_size {
+                    sender
+                        .send(UploadChunkedEvent::ChunkSizeMismatch {
+                            recorded: chunk_size,
+                            requested: input.chunk_size,
+                        })
+                        .await;
+                }
+                progress.chunk_size = Some(chunk_size);
+                sender
+                    .send(UploadChunkedEvent::SaveProgress(progress.clone()))
+                    .await;
+                chunk_size
+            }
         };
-        let total_chunks = len.div_ceil(input.chunk_size.into());
-        while progress.parts_uploaded < total_chunks {
-            upload(UploadInput {
-                client: input.client,
-                amount_limiter: input.amount_limiter.clone(),
-                dest: S3Dest {
-                    bucket: input.dest.bucket,
-                    object_key: &format!("{}/{}", input.dest.object_key, progress.parts_uploaded),
-                    storage_class: input.dest.storage_class.clone(),
-                },
-                operation_scheduler: input.operation_scheduler.clone(),
-                retry_interval: input.retry_interval,
-                src: {
-                    let len = (len - progress.parts_uploaded * input.chunk_size.get())
-                        .min(input.chunk_size.get());
-                    UploadSrc {
-                        len,
-                        path: input.src.clone(),
-                        offset: progress.parts_uploaded * input.chunk_size.get(),
-                    }
+        let total_chunks = len.div_ceil(chunk_size.get());
+        if input.tree_hash && total_chunks > 1 && !is_tree_hash_aligned(chunk_size.get()) {
+            Err(UploadChunkedError::ChunkNotAlignedForTreeHash)?;
+        }
+        let data = if !sources.is_empty() {
+            UploadData::Files(sources.into())
+        } else if input.src_len.is_some() {
+            UploadData::Device(input.src.clone())
+        } else {
+            UploadData::File(input.src.clone())
+        };
+        let chunk_src = |chunk: usize| UploadSrc {
+            len: (len - chunk * chunk_size.get()).min(chunk_size.get()),
+            data: data.clone(),
+            offset: chunk * chunk_size.get(),
+        };
+        // Only start a multipart upload for a new upload, since the mode can't change after chunks were uploaded
+        if let ChunkedUploadMode::Multipart = input.mode
+            && progress.multipart.is_none()
+            && progress.completed.is_empty()
+        {
+            // A multipart upload needs at least one part, and would be left behind unfinished
+            if total_chunks == 0 {
+                Err(UploadChunkedError::EmptyMultipart)?;
+            }
+            if total_chunks > MAX_PARTS {
+                Err(UploadChunkedError::TooManyParts(total_chunks))?;
+            }
+            if total_chunks > 1 && chunk_size.get() < MIN_PART_SIZE {
+                Err(UploadChunkedError::ChunkTooSmallForMultipart)?;
+            }
+            if input.client_side_encryption.is_some() && chunk_size.get() % SEGMENT_SIZE != 0 {
+                Err(UploadChunkedError::ChunkNotMultipleOfSegment)?;
+            }
+            let encryption = input
+                .client_side_encryption
+                .clone()
+                .map(ObjectEncryption::new)
+                .transpose()
+                .map_err(UploadChunkedError::ClientSideEncryption)?;
+            let upload_id = create_multipart_upload(
+                input.client,
+                &input.dest,
+                None,
+                ObjectMetadata {
+                    user: encryption
+                        .as_ref()
+                        .map(ObjectEncryption::metadata)
+                        .unwrap_or_default(),
+                    ..Default::default()
                 },
-                tagging: &format!(
-                    "file={}&total_len={}&chunks_count={}&chunk_size={}&chunk_number={}",
-                    input.dest.object_key,
-                    len,
-                    total_chunks,
-                    input.chunk_size,
-                    progress.parts_uploaded
-                ),
-            })
-            .with(UploadChunkedEvent::UploadEvent)
+                input.retry_policy,
+            )
+            .with(UploadChunkedEvent::CreateMultipartUploadError)
             .run(sender.clone())
             .await
-            .map_err(UploadChunkedError::Upload)?;
-            progress.parts_uploaded += 1;
+            .map_err(UploadChunkedError::Multipart)?;
+            progress.multipart = Some(MultipartProgress {
+                upload_id,
+                e_tags: Default::default(),
+                client_side_nonce: encryption.as_ref().map(ObjectEncryption::nonce_prefix),
+            });
             sender
                 .send(UploadChunkedEvent::SaveProgress(progress.clone()))
                 .await;
         }
-        Ok(())
+        let mut save_tracker = SaveTracker::new(input.save_policy, Instant::now());
+        let upload_id = progress
+            .multipart
+            .as_ref()
+            .map(|multipart| multipart.upload_id.clone());
+        let part_encryption = match &progress.multipart {
+            Some(multipart) => match (multipart.client_side_nonce, &input.client_side_encryption) {
+                (Some(nonce_prefix), Some(key)) => Some(ObjectEncryption::with_nonce_prefix(
+                    key.clone(),
+                    nonce_prefix,
+                )),
+                (None, None) => None,
+                _ => Err(UploadChunkedError::ClientSideEncryptionChanged)?,
+            },
+            None => None,
+        };
+        let chunk_sender = sender.clone();
+        // Cancelled to stop the chunks when the retry budget runs out or a chunk fails
+        let control = input.control.child();
+        let retry_budget_spent = Mutex::new(RetryBudgetSpent::default());
+        let upload_chunk = |chunk: usize| {
+            let sender = chunk_sender.clone();
+            let input = &input;
+            let control = &control;
+            let retry_budget_spent = &retry_budget_spent;
+            let part_encryption = &part_encryption;
+            let upload_id = upload_id.as_deref();
+            let src = chunk_src(chunk);
+            // Read again next to the upload, since the upload streams the data without keeping it
+            let tree_hash_src = input.tree_hash.then(|| chunk_src(chunk));
+            async move {
+                let chunk_len = src.len;
+                let mut started = None;
+                let mut retries = 0;
+                let mut checksum = None;
+                let on_event = |event: UploadEvent| {
+                    match &event {
+                        UploadEvent::ChecksumComputed(computed) => {
+                            checksum = Some(*computed);
+                        }
+                        UploadEvent::StartingUpload => {
+                            started.get_or_insert_with(Instant::now);
+                        }
+                        UploadEvent::UploadError(Retrying { next_attempt, .. })
+                        | UploadEvent::UploadPartError(Retrying { next_attempt, .. }) => {
+                            retries += 1;
+                            let mut spent = retry_budget_spent.lock().unwrap();
+                            spent.add(*next_attempt);
+                            if input.retry_budget.is_exhausted(&spent) {
+                                control.cancel();
+                            }
+                        }
+                        _ => {}
+                    }
+                    UploadChunkedEvent::UploadEvent { chunk, event }
+                };
+                let upload_result = async {
+                    match upload_id {
+                        None => upload(UploadInput {
+                            client: input.client,
+                            amount_limiter: input.amount_limiter.clone(),
+                            dest: S3Dest {
+                                bucket: input.dest.bucket,
+                                object_key: &format!("{}/{}", input.dest.object_key, chunk),
+                                storage_class: input.dest.storage_class.clone(),
+                                encryption: input.dest.encryption.clone(),
+                            },
+                            operation_scheduler: input.operation_scheduler.clone(),
+                            retry_policy: input.retry_policy,
+                            src,
+                            tagging: &if input.capabilities.tagging {
+                                chunk_tagging(
+                                    input.dest.object_key,
+                                    len,
+                                    total_chunks,
+                                    chunk_size,
+                                    chunk,
+                                )
+                            } else {
+                                String::new()
+                            },
+                            key_suffix: KeySuffix::None,
+                            checksum: input.checksum,
+                            multipart_threshold: None,
+                            client_side_encryption: input.client_side_encryption.clone(),
+                            compression: None,
+                            metadata: Default::default(),
+                            storage_class_check: None,
+                            capabilities: Default::default(),
+                            control: control.clone(),
+                        })
+                        .with(on_event)
+                        .run(sender)
+                        .await
+                        .map(|_| None),
+                        Some(upload_id) => upload_part(UploadPartInput {
+                            client: input.client,
+                            src,
+                            bucket: input.dest.bucket,
+                            object_key: input.dest.object_key,
+                            upload_id,
+                            part_number: (chunk + 1).try_into().unwrap(),
+                            customer_key: input.dest.encryption.customer_key().cloned(),
+                            client_side_encryption: part_encryption.as_ref().map(|encryption| {
+                                PartEncryption {
+                                    object: encryption.clone(),
+                                    offset: chunk * chunk_size.get(),
+                                    ends_object: chunk == total_chunks - 1,
+                                }
+                            }),
+                            retry_policy: input.retry_policy,
+                            operation_scheduler: input.operation_scheduler.clone(),
+                            amount_limiter: input.amount_limiter.clone(),
+                            control: control.clone(),
+                        })
+                        .with(on_event)
+                        .run(sender)
+                        .await
+                        .map(Some),
+                    }
+                };
+                let tree_hash = async {
+                    match &tree_hash_src {
+                        Some(src) => compute_tree_hash(src).await.map(Some),
+                        None => Ok(None),
+                    }
+                };
+                let (upload_result, tree_hash) = futures::join!(upload_result, tree_hash);
+                let stats = |tree_hash| ChunkStats {
+                    len: chunk_len,
+                    upload_millis: started.map_or(0, |started| {
+                        started.elapsed().as_millis().try_into().unwrap()
+                    }),
+                    retries,
+                    checksum,
+                    tree_hash,
+                };
+                let result = match (upload_result, tree_hash) {
+                    (Ok(e_tag), Ok(tree_hash)) => Ok((e_tag, stats(tree_hash))),
+                    (Err(e), _) => Err(UploadChunkedError::Upload(e)),
+                    (Ok(_), Err(e)) => Err(UploadChunkedError::TreeHash(e)),
+                };
+                (chunk, result)
+            }
+        };
+        let mut pending = (0..total_chunks)
+            .filter(|chunk| !progress.completed.contains(chunk))
+            .collect::<Vec<_>>()
+            .into_iter();
+        let mut remaining_len = pending
+            .clone()
+            .map(|chunk| (len - chunk * chunk_size.get()).min(chunk_size.get()))
+            .sum::<usize>();
+        let mut forecast_speed = input.operation_scheduler.expected_speed();
+        if let Some(speed) = forecast_speed {
+            send_forecast(
+                &mut sender,
+                &*input.operation_scheduler,
+                remaining_len,
+                speed,
+            )
+            .await;
+        }
+        // Of the chunks uploaded by this run, since the speed may have changed since the upload was started
+        let mut measured_len = 0;
+        let mut measured_millis = 0;
+        let mut uploading = FuturesUnordered::new();
+        let mut cancelled = None;
+        // The first chunk that failed. The other chunks are stopped, so that the progress is saved before returning the error.
+        let mut failed = None;
+        let mut bytes_sent = 0;
+        loop {
+            let paused_until = paused_until(&*input.operation_scheduler);
+            while cancelled.is_none()
+                && failed.is_none()
+                && paused_until.is_none()
+                && uploading.len() < input.max_concurrency.get()
+                && let Some(chunk) = pending.next()
+            {
+                sender.send(UploadChunkedEvent::StartingChunk(chunk)).await;
+                uploading.push(upload_chunk(chunk));
+            }
+            // Chunks that started before the window ended are finished first
+            if let Some(paused_until) = paused_until
+                && cancelled.is_none()
+                && failed.is_none()
+                && uploading.is_empty()
+                && pending.len() > 0
+            {
+                sender
+                    .send(UploadChunkedEvent::ScheduledPause(paused_until))
+                    .await;
+                match input.control.run(sleep_until_utc(paused_until)).await {
+                    Ok(()) => sender.send(UploadChunkedEvent::ScheduledResume).await,
+                    Err(reason) => cancelled = Some(reason),
+                }
+                continue;
+            }
+            let Some((chunk, result)) = uploading.next().await else {
+                break;
+            };
+            let (e_tag, stats) = match result {
+                Ok(uploaded) => uploaded,
+                // Let the other chunks stop on their own, so that the ones that finish are recorded
+                Err(UploadChunkedError::Upload(UploadError::Cancelled(reason))) => {
+                    cancelled.get_or_insert(reason);
+                    continue;
+                }
+                Err(e) => {
+                    if failed.is_none() {
+                        control.cancel();
+                        failed = Some(e);
+                    }
+                    continue;
+                }
+            };
+            if let (Some(multipart), Some(e_tag)) = (&mut progress.multipart, e_tag) {
+                multipart.e_tags.insert(chunk, e_tag);
+            }
+            bytes_sent += stats.len;
+            remaining_len -= stats.len;
+            measured_len += stats.len;
+            measured_millis += stats.upload_millis;
+            if measured_millis > 0 && remaining_len > 0 {
+                let speed = measured_len as f64 / (measured_millis as f64 / 1000.0);
+                input.operation_scheduler.record_speed(speed);
+                if forecast_speed.is_none_or(|forecast_speed| {
+                    (speed / forecast_speed - 1.0).abs() > FORECAST_SPEED_CHANGE
+                }) {
+                    forecast_speed = Some(speed);
+                    send_forecast(
+                        &mut sender,
+                        &*input.operation_scheduler,
+                        remaining_len,
+                        speed,
+                    )
+                    .await;
+                }
+            }
+            progress.chunks.insert(chunk, stats);
+            progress.completed.insert(chunk);
+            if save_tracker.chunk_done(Instant::now(), progress.completed.len() == total_chunks) {
+                sender
+                    .send(UploadChunkedEvent::SaveProgress(progress.clone()))
+                    .await;
+            }
+        }
+        if let Some(e) = failed {
+            sender
+                .send(UploadChunkedEvent::SaveProgress(progress.clone()))
+                .await;
+            Err(e)?;
+        }
+        if let Some(reason) = cancelled {
+            sender
+                .send(UploadChunkedEvent::SaveProgress(progress.clone()))
+                .await;
+            let spent = *retry_budget_spent.lock().unwrap();
+            if !input.control.is_cancelled() && input.retry_budget.is_exhausted(&spent) {
+                Err(UploadChunkedError::RetryBudgetExhausted(spent))?;
+            }
+            sender.send(UploadChunkedEvent::Cancelled).await;
+            Err(UploadChunkedError::Cancelled(reason))?;
+        }
+        if input.tree_hash && progress.tree_hash.is_none() {
+            let mut hashes = Vec::with_capacity(total_chunks);
+            for chunk in 0..total_chunks {
+                hashes.push(
+                    match progress
+                        .chunks
+                        .get(&chunk)
+                        .and_then(|stats| stats.tree_hash)
+                    {
+                        Some(tree_hash) => tree_hash,
+                        // Uploaded before the upload was resumed with tree hashes turned on
+                        None => compute_tree_hash(&chunk_src(chunk))
+                            .await
+                            .map_err(UploadChunkedError::TreeHash)?,
+                    },
+                );
+            }
+            // An empty file has no chunks, but still has a tree hash
+            progress.tree_hash = Some(match TreeHash::combine(hashes) {
+                Some(tree_hash) => tree_hash,
+                None => compute_tree_hash(&UploadSrc::from_bytes(Vec::new()))
+                    .await
+                    .map_err(UploadChunkedError::TreeHash)?,
+            });
+            sender
+                .send(UploadChunkedEvent::SaveProgress(progress.clone()))
+                .await;
+        }
+        let mut output = UploadChunkedOutput {
+            len,
+            bytes_sent,
+            e_tag: None,
+            version_id: None,
+            tree_hash: progress.tree_hash,
+        };
+        if let Some(multipart) = &progress.multipart {
+            let completed = complete_multipart_upload(
+                input.client,
+                input.dest.bucket,
+                input.dest.object_key,
+                multipart,
+                input.dest.encryption.customer_key(),
+                input.retry_policy,
+            )
+            .with(UploadChunkedEvent::CompleteMultipartUploadError)
+            .run(sender.clone())
+            .await
+            .map_err(UploadChunkedError::Multipart)?;
+            output.e_tag = completed.e_tag;
+            output.version_id = completed.version_id;
+        }
+        Ok(output)
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+
+    use super::{ChunkStats, UploadChunkedProgress, recover_chunk_size};
+
+    #[test]
+    fn old_progress_is_not_written_back() {
+        let progress =
+            ron::from_str::<UploadChunkedProgress>("(len: Some(3000), parts_uploaded: 2)").unwrap();
+        assert_eq!(progress.parts_uploaded, 2);
+        assert!(
+            !ron::to_string(&progress)
+                .unwrap()
+                .contains("parts_uploaded")
+        );
+    }
+
+    #[test]
+    fn chunk_size_is_recovered_from_chunk_lengths() {
+        let stats = |len| ChunkStats {
+            len,
+            upload_millis: 0,
+            retries: 0,
+            checksum: None,
+            tree_hash: None,
+        };
+        let mut progress = UploadChunkedProgress {
+            len: Some(2500),
+            completed: [0, 2].into(),
+            chunks: [(0, stats(1000)), (2, stats(500))].into(),
+            ..Default::default()
+        };
+        let size = |n| NonZeroUsize::new(n).unwrap();
+        assert_eq!(
+            recover_chunk_size(&progress, 2500, size(1000)),
+            Some(size(1000))
+        );
+        assert_eq!(
+            recover_chunk_size(&progress, 2500, size(700)),
+            Some(size(1000))
+        );
+        // Without stats, the chunk size can't be checked
+        progress.chunks.remove(&0);
+        assert_eq!(recover_chunk_size(&progress, 2500, size(700)), None);
+        // Nothing was uploaded, so any chunk size works
+        progress.completed.clear();
+        assert_eq!(
+            recover_chunk_size(&progress, 2500, size(700)),
+            Some(size(700))
+        );
+    }
+}
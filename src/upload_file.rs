@@ -2,13 +2,52 @@ use std::{io, path::PathBuf};
 
 use tokio::fs::metadata;
 
-use crate::UploadSrc;
+use crate::{SourceFile, UploadData, UploadSrc};
 
 pub async fn upload_file(path: PathBuf) -> Result<UploadSrc, io::Error> {
     let len = metadata(&path).await?.len().try_into().unwrap();
     Ok(UploadSrc {
         len,
-        path,
+        data: UploadData::File(path),
         offset: 0,
     })
 }
+
+/// Uploads `len` bytes of a file whose metadata doesn't have its length, such as a block device like `/dev/sda` for a raw disk image
+pub fn upload_device(path: PathBuf, len: usize) -> UploadSrc {
+    UploadSrc {
+        len,
+        data: UploadData::Device(path),
+        offset: 0,
+    }
+}
+
+/// Uploads files one after another as if they were one file, such as the volumes of an archive that was already split,
+/// without concatenating them first
+pub async fn upload_files(
+    paths: impl IntoIterator<Item = PathBuf>,
+) -> Result<UploadSrc, io::Error> {
+    let files = source_files(paths).await?;
+    Ok(UploadSrc {
+        len: files.last().map_or(0, |file| file.offset + file.len),
+        data: UploadData::Files(files.into()),
+        offset: 0,
+    })
+}
+
+/// Reads the length and modified time of each file, and where it starts when they are read one after another
+pub async fn source_files(
+    paths: impl IntoIterator<Item = PathBuf>,
+) -> Result<Vec<SourceFile>, io::Error> {
+    let mut files = Vec::<SourceFile>::new();
+    for path in paths {
+        let metadata = metadata(&path).await?;
+        files.push(SourceFile {
+            offset: files.last().map_or(0, |file| file.offset + file.len),
+            len: metadata.len().try_into().unwrap(),
+            modified: metadata.modified().ok(),
+            path,
+        });
+    }
+    Ok(files)
+}
@@ -0,0 +1,153 @@
+use std::{collections::BTreeMap, io, num::NonZeroUsize};
+
+use serde::{Deserialize, Serialize};
+use sipper::{Sipper, Straw, sipper};
+use thiserror::Error;
+
+use crate::{
+    AmountLimiter, Checksum, ChecksumAlgorithm, ControlHandle, KeySuffix, ObjectMetadata,
+    OperationScheduler, RetryPolicy, S3Dest, UploadError, UploadEvent, UploadInput, UploadSrc,
+    checksum::compute_checksum, upload::upload_impl,
+};
+
+/// One of the places that [`upload_multi`] uploads to
+pub struct UploadMultiDest<'a> {
+    /// Use a different client for a bucket in a different region or account
+    pub client: &'a aws_sdk_s3::Client,
+    pub dest: S3Dest<'a>,
+}
+
+pub struct UploadMultiInput<'a> {
+    pub src: UploadSrc,
+    pub dests: Vec<UploadMultiDest<'a>>,
+    pub retry_policy: RetryPolicy,
+    pub operation_scheduler: Box<dyn OperationScheduler>,
+    /// Each destination is reserved separately, since the file is uploaded once for each
+    pub amount_limiter: Box<dyn AmountLimiter>,
+    pub tagging: &'a str,
+    /// The same for every destination
+    pub metadata: ObjectMetadata,
+    pub key_suffix: KeySuffix,
+    /// Computed once and used for every destination
+    pub checksum: Option<ChecksumAlgorithm>,
+    /// See [`UploadInput::multipart_threshold`]
+    pub multipart_threshold: Option<NonZeroUsize>,
+    pub progress: UploadMultiProgress,
+    pub control: ControlHandle,
+}
+
+/// Saved state of an [`upload_multi`], so that a restarted upload doesn't upload to the same destination again
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct UploadMultiProgress {
+    /// The object key that was uploaded to, by the index of the destination
+    pub uploaded: BTreeMap<usize, String>,
+    /// Computed before the first upload, so that it doesn't need to be computed again
+    pub checksum: Option<Checksum>,
+}
+
+#[derive(Debug, Clone)]
+pub struct UploadMultiOutput {
+    /// The key that each destination was uploaded to, in the same order as [`UploadMultiInput::dests`]
+    pub object_keys: Vec<String>,
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Error)]
+pub enum UploadMultiError {
+    #[error("Error reading the file to compute its checksum")]
+    Checksum(io::Error),
+    #[error("Error uploading to destination {dest}")]
+    Upload { dest: usize, source: UploadError },
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum UploadMultiEvent {
+    ComputingChecksum,
+    /// The checksum that S3 verified the objects with
+    ChecksumComputed(Checksum),
+    /// An event from uploading to the destination with this index
+    UploadEvent {
+        dest: usize,
+        event: UploadEvent,
+    },
+    Uploaded {
+        dest: usize,
+        object_key: String,
+    },
+    SaveProgress(UploadMultiProgress),
+}
+
+/// Uploads a file to several destinations, such as a primary copy in one region and a copy in another region.
+/// The destinations are uploaded to one after another, with the same schedule and amount limiter.
+pub fn upload_multi(
+    mut input: UploadMultiInput<'_>,
+) -> impl Straw<UploadMultiOutput, UploadMultiEvent, UploadMultiError> {
+    sipper(async move |mut sender| {
+        if input.progress.checksum.is_none()
+            && let Some(algorithm) = input.checksum
+        {
+            sender.send(UploadMultiEvent::ComputingChecksum).await;
+            let checksum = compute_checksum(&input.src, algorithm)
+                .await
+                .map_err(UploadMultiError::Checksum)?;
+            sender
+                .send(UploadMultiEvent::ChecksumComputed(checksum))
+                .await;
+            input.progress.checksum = Some(checksum);
+            sender
+                .send(UploadMultiEvent::SaveProgress(input.progress.clone()))
+                .await;
+        }
+        let mut object_keys = Vec::with_capacity(input.dests.len());
+        for (index, dest) in input.dests.into_iter().enumerate() {
+            if let Some(object_key) = input.progress.uploaded.get(&index) {
+                object_keys.push(object_key.clone());
+                continue;
+            }
+            let output = upload_impl(
+                UploadInput {
+                    client: dest.client,
+                    src: input.src.clone(),
+                    dest: dest.dest,
+                    retry_policy: input.retry_policy,
+                    operation_scheduler: input.operation_scheduler.clone(),
+                    amount_limiter: input.amount_limiter.clone(),
+                    tagging: input.tagging,
+                    key_suffix: input.key_suffix,
+                    checksum: input.checksum,
+                    multipart_threshold: input.multipart_threshold,
+                    client_side_encryption: None,
+                    compression: None,
+                    metadata: input.metadata.clone(),
+                    storage_class_check: None,
+                    capabilities: Default::default(),
+                    control: input.control.clone(),
+                },
+                input.progress.checksum,
+            )
+            .with(|event| UploadMultiEvent::UploadEvent { dest: index, event })
+            .run(sender.clone())
+            .await
+            .map_err(|source| UploadMultiError::Upload {
+                dest: index,
+                source,
+            })?;
+            sender
+                .send(UploadMultiEvent::Uploaded {
+                    dest: index,
+                    object_key: output.object_key.clone(),
+                })
+                .await;
+            input
+                .progress
+                .uploaded
+                .insert(index, output.object_key.clone());
+            sender
+                .send(UploadMultiEvent::SaveProgress(input.progress.clone()))
+                .await;
+            object_keys.push(output.object_key);
+        }
+        Ok(UploadMultiOutput { object_keys })
+    })
+}
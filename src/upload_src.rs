@@ -0,0 +1,383 @@
+use std::{
+    fmt, io,
+    io::SeekFrom,
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, ready},
+    time::SystemTime,
+};
+
+use aws_sdk_s3::primitives::{ByteStream, ByteStreamError, FsBuilder, Length, SdkBody};
+use bytes::Bytes;
+use futures::{Stream, future::BoxFuture, stream};
+use http_body::{Body, Frame, SizeHint};
+use serde::{Deserialize, Serialize};
+use sipper::FutureExt;
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, ReadBuf},
+    sync::{Mutex, OwnedMutexGuard},
+    task::spawn_blocking,
+};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+pub type UploadReader = Box<dyn AsyncRead + Send + Sync + Unpin>;
+
+/// Opens data that isn't in a file or in memory, such as a database dump or a `tar` pipe that can be started again.
+/// The data is read more than once, such as to compute the checksum and to retry the upload, so it must be the same every time.
+pub trait OpenReader: Send + Sync {
+    /// Returns a reader that starts `offset` bytes into the data.
+    /// Nothing is read past the [`UploadSrc::len`].
+    fn open(&self, offset: u64) -> BoxFuture<'_, io::Result<UploadReader>>;
+}
+
+impl<F, Fut> OpenReader for F
+where
+    F: Fn(u64) -> Fut + Send + Sync,
+    Fut: Future<Output = io::Result<UploadReader>> + Send + 'static,
+{
+    fn open(&self, offset: u64) -> BoxFuture<'_, io::Result<UploadReader>> {
+        self(offset).boxed()
+    }
+}
+
+/// A reader that can seek, shared by every read of the data.
+/// Each reader locks it until it is dropped.
+struct SharedSeekable<R>(Arc<Mutex<R>>);
+
+impl<R: AsyncRead + AsyncSeek + Send + Sync + Unpin + 'static> OpenReader for SharedSeekable<R> {
+    fn open(&self, offset: u64) -> BoxFuture<'_, io::Result<UploadReader>> {
+        async move {
+            let mut reader = self.0.clone().lock_owned().await;
+            reader.seek(SeekFrom::Start(offset)).await?;
+            Ok(Box::new(LockedReader(reader)) as UploadReader)
+        }
+        .boxed()
+    }
+}
+
+struct LockedReader<R>(OwnedMutexGuard<R>);
+
+impl<R: AsyncRead + Unpin> AsyncRead for LockedReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().0).poll_read(cx, buf)
+    }
+}
+
+/// One of the files of [`UploadData::Files`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceFile {
+    pub path: PathBuf,
+    /// Where the file starts in the data
+    pub offset: usize,
+    pub len: usize,
+    /// Used to detect that the file changed before resuming, if the OS supports it
+    #[serde(default)]
+    pub modified: Option<SystemTime>,
+}
+
+/// Where the data of an [`UploadSrc`] is
+#[derive(Clone)]
+pub enum UploadData {
+    File(PathBuf),
+    /// Files that are read one after another as if they were one file, such as the volumes of a split archive,
+    /// see [`crate::upload_files`]
+    Files(Arc<[SourceFile]>),
+    /// A file whose metadata doesn't have its length, such as a block device like `/dev/sda`, see [`crate::upload_device`].
+    /// It is read with positioned reads, and never past the [`UploadSrc::len`].
+    Device(PathBuf),
+    Bytes(Bytes),
+    Reader(Arc<dyn OpenReader>),
+}
+
+impl fmt::Debug for UploadData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(path) => f.debug_tuple("File").field(path).finish(),
+            Self::Files(files) => f.debug_tuple("Files").field(files).finish(),
+            Self::Device(path) => f.debug_tuple("Device").field(path).finish(),
+            Self::Bytes(bytes) => f.debug_tuple("Bytes").field(&bytes.len()).finish(),
+            Self::Reader(_) => f.write_str("Reader"),
+        }
+    }
+}
+
+/// The `len` bytes starting at `offset` of the data, see [`crate::upload_file`] for a whole file
+#[derive(Debug, Clone)]
+pub struct UploadSrc {
+    pub data: UploadData,
+    pub offset: usize,
+    pub len: usize,
+}
+
+impl UploadSrc {
+    /// Uploads data that is already in memory, without writing it to a temporary file
+    pub fn from_bytes(bytes: impl Into<Bytes>) -> Self {
+        let bytes = bytes.into();
+        Self {
+            len: bytes.len(),
+            data: UploadData::Bytes(bytes),
+            offset: 0,
+        }
+    }
+
+    /// Uploads `len` bytes from the start of a reader that can seek.
+    /// It is seeked back to the start every time the data is read.
+    pub fn from_seekable(
+        reader: impl AsyncRead + AsyncSeek + Send + Sync + Unpin + 'static,
+        len: usize,
+    ) -> Self {
+        Self::from_reader(SharedSeekable(Arc::new(Mutex::new(reader))), len)
+    }
+
+    /// Uploads `len` bytes of data that is opened with `open` every time it is read
+    pub fn from_reader(open: impl OpenReader + 'static, len: usize) -> Self {
+        Self {
+            data: UploadData::Reader(Arc::new(open)),
+            offset: 0,
+            len,
+        }
+    }
+
+    /// Reads the `len` bytes, such as to compute the checksum
+    pub(crate) async fn reader(&self) -> io::Result<UploadReader> {
+        let reader: UploadReader = match &self.data {
+            UploadData::File(path) => {
+                let mut file = File::open(path).await?;
+                file.seek(SeekFrom::Start(self.offset as u64)).await?;
+                Box::new(file)
+            }
+            UploadData::Files(files) => {
+                let mut reader: UploadReader = Box::new(tokio::io::empty());
+                let end = self.offset + self.len;
+                for source in files.iter().filter(|source| {
+                    source.offset + source.len > self.offset && source.offset < end
+                }) {
+                    let start = self.offset.saturating_sub(source.offset);
+                    let mut file = File::open(&source.path).await?;
+                    file.seek(SeekFrom::Start(start as u64)).await?;
+                    reader = Box::new(reader.chain(file.take((source.len - start) as u64)));
+                }
+                reader
+            }
+            UploadData::Device(path) => {
+                let file = Arc::new(std::fs::File::open(path)?);
+                let end = (self.offset + self.len) as u64;
+                Box::new(StreamReader::new(Box::pin(stream::try_unfold(
+                    self.offset as u64,
+                    move |offset| {
+                        let file = file.clone();
+                        async move {
+                            if offset >= end {
+                                return Ok::<_, io::Error>(None);
+                            }
+                            let mut buf = vec![0; (end - offset).min(DEVICE_READ_SIZE) as usize];
+                            let read = spawn_blocking(move || {
+                                read_at(&file, &mut buf, offset).map(|read| {
+                                    buf.truncate(read);
+                                    buf
+                                })
+                            })
+                            .await
+                            .unwrap()?;
+                            if read.is_empty() {
+                                return Ok(None);
+                            }
+                            let next = offset + read.len() as u64;
+                            Ok(Some((Bytes::from(read), next)))
+                        }
+                    },
+                ))))
+            }
+            UploadData::Bytes(bytes) => Box::new(io::Cursor::new(self.slice(bytes)?)),
+            UploadData::Reader(open) => open.open(self.offset as u64).await?,
+        };
+        Ok(Box::new(reader.take(self.len as u64)))
+    }
+
+    fn slice(&self, bytes: &Bytes) -> io::Result<Bytes> {
+        bytes
+            .get(self.offset..self.offset + self.len)
+            .map(|slice| bytes.slice_ref(slice))
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))
+    }
+
+    /// The body of the upload request.
+    /// Only files and bytes can be retried by the SDK, but the upload retries read the data again anyways.
+    pub(crate) async fn byte_stream(&self) -> Result<ByteStream, ByteStreamError> {
+        match &self.data {
+            UploadData::File(path) => {
+                FsBuilder::new()
+                    .path(path)
+                    .offset(self.offset as u64)
+                    .length(Length::Exact(self.len as u64))
+                    .build()
+                    .await
+            }
+            UploadData::Files(files) => match files.iter().find(|source| {
+                source.offset <= self.offset && self.offset + self.len <= source.offset + source.len
+            }) {
+                // Can be retried by the SDK if it's all in one file
+                Some(source) => {
+                    FsBuilder::new()
+                        .path(&source.path)
+                        .offset((self.offset - source.offset) as u64)
+                        .length(Length::Exact(self.len as u64))
+                        .build()
+                        .await
+                }
+                None => self.reader_byte_stream().await,
+            },
+            UploadData::Bytes(bytes) => Ok(ByteStream::from(self.slice(bytes)?)),
+            // The SDK reads the length of the file from its metadata
+            UploadData::Device(_) | UploadData::Reader(_) => self.reader_byte_stream().await,
+        }
+    }
+
+    async fn reader_byte_stream(&self) -> Result<ByteStream, ByteStreamError> {
+        Ok(ByteStream::new(SdkBody::from_body_1_x(ReaderBody {
+            inner: ReaderStream::new(self.reader().await?),
+            remaining: self.len,
+        })))
+    }
+}
+
+/// How much of a [`UploadData::Device`] is read at a time
+const DEVICE_READ_SIZE: u64 = 1024 * 1024;
+
+/// Reads at `offset` without seeking, so the file doesn't need to support it
+fn read_at(file: &std::fs::File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::FileExt::read_at(file, buf, offset)
+    }
+    #[cfg(windows)]
+    {
+        std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+    }
+}
+
+struct ReaderBody {
+    inner: ReaderStream<UploadReader>,
+    /// Bytes that weren't read yet
+    remaining: usize,
+}
+
+impl Body for ReaderBody {
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+            Some(Ok(bytes)) => {
+                this.remaining = this.remaining.saturating_sub(bytes.len());
+                Poll::Ready(Some(Ok(Frame::data(bytes))))
+            }
+            Some(Err(e)) => Poll::Ready(Some(Err(e))),
+            // S3 was told the length, so a reader that ends too early would make the request hang
+            None if this.remaining > 0 => {
+                Poll::Ready(Some(Err(io::ErrorKind::UnexpectedEof.into())))
+            }
+            None => Poll::Ready(None),
+        }
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::with_exact(self.remaining as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use tokio::io::AsyncReadExt;
+
+    use super::{UploadReader, UploadSrc};
+    use crate::{upload_device, upload_files};
+
+    async fn read(src: &UploadSrc) -> Vec<u8> {
+        let mut data = Vec::new();
+        src.reader()
+            .await
+            .unwrap()
+            .read_to_end(&mut data)
+            .await
+            .unwrap();
+        data
+    }
+
+    #[tokio::test]
+    async fn reads_range() {
+        let data = b"0123456789".to_vec();
+        let srcs = [
+            UploadSrc::from_bytes(data.clone()),
+            UploadSrc::from_seekable(Cursor::new(data.clone()), data.len()),
+            UploadSrc::from_reader(
+                move |offset| {
+                    let data = data.clone();
+                    async move {
+                        Ok(Box::new(Cursor::new(data[offset as usize..].to_vec())) as UploadReader)
+                    }
+                },
+                10,
+            ),
+        ];
+        for mut src in srcs {
+            assert_eq!(read(&src).await, b"0123456789");
+            // Read twice, since retries read the data again
+            assert_eq!(read(&src).await, b"0123456789");
+            src.offset = 3;
+            src.len = 4;
+            assert_eq!(read(&src).await, b"3456");
+        }
+        let mut src = UploadSrc::from_bytes(&b"0123"[..]);
+        src.len = 5;
+        assert!(src.reader().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_across_files() {
+        let dir = std::env::temp_dir();
+        let paths = ["012", "", "3456", "789"].map(|contents| {
+            let path = dir.join(format!("rcs3ud-test-{:016x}", fastrand::u64(..)));
+            std::fs::write(&path, contents).unwrap();
+            path
+        });
+        let mut src = upload_files(paths.clone()).await.unwrap();
+        assert_eq!(src.len, 10);
+        assert_eq!(read(&src).await, b"0123456789");
+        src.offset = 2;
+        src.len = 6;
+        assert_eq!(read(&src).await, b"234567");
+        src.offset = 4;
+        src.len = 2;
+        assert_eq!(read(&src).await, b"45");
+        for path in paths {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_device() {
+        let path = std::env::temp_dir().join(format!("rcs3ud-test-{:016x}", fastrand::u64(..)));
+        let data = (0..3 * 1024 * 1024).map(|i| i as u8).collect::<Vec<_>>();
+        std::fs::write(&path, &data).unwrap();
+        // Less than the whole file, like a partition that is only partly used
+        let mut src = upload_device(path.clone(), data.len() - 100);
+        assert_eq!(read(&src).await, data[..data.len() - 100]);
+        src.offset = 1024 * 1024 - 3;
+        src.len = 1024 * 1024 + 6;
+        assert_eq!(read(&src).await, data[src.offset..src.offset + src.len]);
+        std::fs::remove_file(path).unwrap();
+    }
+}
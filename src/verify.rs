@@ -0,0 +1,111 @@
+use aws_sdk_s3::{error::SdkError, operation::head_object::HeadObjectError};
+use sipper::{Sipper, Straw, sipper};
+use thiserror::Error;
+
+use crate::{
+    Checksum, RetriesExhausted, RetryPolicy, Retrying, S3Src, UploadChunkedProgress,
+    repair::head_if_exists,
+};
+
+/// What [`verify_chunked`] found. Chunks that are missing are not in the other lists.
+#[derive(Debug, Clone, Default)]
+pub struct VerifyChunkedOutput {
+    pub missing: Vec<usize>,
+    pub wrong_size: Vec<usize>,
+    /// The checksum stored by S3 is different from the one the chunk was uploaded with
+    pub wrong_checksum: Vec<usize>,
+    /// The progress doesn't have a checksum for these chunks, so only their size was checked
+    pub no_checksum: Vec<usize>,
+    /// Sum of the sizes of the remote objects
+    pub remote_len: usize,
+}
+
+impl VerifyChunkedOutput {
+    /// Returns `true` if every chunk exists and matches the progress.
+    /// Chunks in [`Self::no_checksum`] are considered ok.
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.wrong_size.is_empty() && self.wrong_checksum.is_empty()
+    }
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Error)]
+pub enum VerifyChunkedError {
+    #[error("The progress doesn't have the len and chunk size of the upload")]
+    IncompleteProgress,
+    #[error("Error checking a chunk")]
+    HeadObject(#[from] SdkError<HeadObjectError>),
+    #[error("Gave up retrying")]
+    RetriesExhausted(#[from] RetriesExhausted),
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum VerifyChunkedEvent {
+    CheckingChunk(usize),
+    CheckChunkError(Retrying<SdkError<HeadObjectError>>),
+}
+
+/// Checks a finished [`crate::upload_chunked`] using only its progress and S3, without the local file.
+/// Useful before deleting the local copy of a backup.
+///
+/// Every chunk must exist with the expected size.
+/// Chunks that were uploaded with a checksum must have the same checksum in S3.
+/// Multipart uploads are checked as a single object with the total size.
+pub fn verify_chunked<'a>(
+    client: &'a aws_sdk_s3::Client,
+    src: S3Src<'a>,
+    progress: &'a UploadChunkedProgress,
+    retry_policy: RetryPolicy,
+) -> impl Straw<VerifyChunkedOutput, VerifyChunkedEvent, VerifyChunkedError> {
+    sipper(async move |mut sender| {
+        let (Some(len), Some(chunk_size)) = (progress.len, progress.chunk_size) else {
+            Err(VerifyChunkedError::IncompleteProgress)?
+        };
+        let mut output = VerifyChunkedOutput::default();
+        // A multipart upload is one object, so it is checked like a single chunk
+        let (total_chunks, chunk_size) = match progress.multipart {
+            Some(_) => (1, len),
+            None => (len.div_ceil(chunk_size.get()), chunk_size.get()),
+        };
+        for chunk in 0..total_chunks {
+            sender.send(VerifyChunkedEvent::CheckingChunk(chunk)).await;
+            let object_key = match progress.multipart {
+                Some(_) => src.object_key.to_owned(),
+                None => format!("{}/{}", src.object_key, chunk),
+            };
+            let Some(head) =
+                head_if_exists::<VerifyChunkedError>(client, src.bucket, &object_key, retry_policy)
+                    .with(VerifyChunkedEvent::CheckChunkError)
+                    .run(sender.clone())
+                    .await?
+            else {
+                output.missing.push(chunk);
+                continue;
+            };
+            let remote_len = head.content_length().unwrap_or_default() as usize;
+            output.remote_len += remote_len;
+            if remote_len != (len - chunk * chunk_size).min(chunk_size) {
+                output.wrong_size.push(chunk);
+                continue;
+            }
+            let recorded = match progress.multipart {
+                Some(_) => None,
+                None => progress.chunks.get(&chunk).and_then(|stats| stats.checksum),
+            };
+            match recorded {
+                Some(checksum) => {
+                    let remote = match checksum {
+                        Checksum::Sha256(_) => head.checksum_sha256(),
+                        Checksum::Crc32c(_) => head.checksum_crc32_c(),
+                    };
+                    if remote != Some(checksum.to_base64().as_str()) {
+                        output.wrong_checksum.push(chunk);
+                    }
+                }
+                None => output.no_checksum.push(chunk),
+            }
+        }
+        Ok(output)
+    })
+}
@@ -0,0 +1,57 @@
+use std::{pin::pin, time::Duration};
+
+use futures::future::{Either, select};
+use time::UtcDateTime;
+use tokio::time::sleep;
+
+/// How often [`sleep_until_utc`] checks the clock
+pub const WALL_CLOCK_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Sleeps until the system clock reaches `time`, returning right away if it already did.
+/// Tokio's timers don't count the time that the computer is suspended, so one long sleep wakes up late after a suspend.
+/// Instead, this sleeps for at most [`WALL_CLOCK_CHECK_INTERVAL`] at a time and checks the clock again.
+pub async fn sleep_until_utc(time: UtcDateTime) {
+    while let Ok(remaining) = Duration::try_from(time - UtcDateTime::now())
+        && !remaining.is_zero()
+    {
+        sleep(remaining.min(WALL_CLOCK_CHECK_INTERVAL)).await;
+    }
+}
+
+/// Runs the future until it finishes or the system clock reaches `time`, like [`tokio::time::timeout`] with [`sleep_until_utc`]
+pub(crate) async fn timeout_at_utc<F: Future>(time: UtcDateTime, future: F) -> Option<F::Output> {
+    match select(pin!(future), pin!(sleep_until_utc(time))).await {
+        Either::Left((output, _)) => Some(output),
+        Either::Right(((), _)) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use time::UtcDateTime;
+
+    use super::{sleep_until_utc, timeout_at_utc};
+
+    #[tokio::test]
+    async fn sleeps_until_time() {
+        let started = Instant::now();
+        sleep_until_utc(UtcDateTime::now() - Duration::from_secs(1)).await;
+        assert!(started.elapsed() < Duration::from_millis(50));
+        sleep_until_utc(UtcDateTime::now() + Duration::from_millis(50)).await;
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(
+            timeout_at_utc(
+                UtcDateTime::now() + Duration::from_millis(10),
+                std::future::pending::<()>()
+            )
+            .await,
+            None
+        );
+        assert_eq!(
+            timeout_at_utc(UtcDateTime::now(), async { 1 }).await,
+            Some(1)
+        );
+    }
+}